@@ -3,7 +3,7 @@ extern crate gsr_jit;
 
 use notify::{Watcher, RecursiveMode, DebouncedEvent, watcher};
 use std::{sync::mpsc::channel, time::Duration, fs::read_to_string};
-use gsr_jit::{JitMemory, parse_file, compile};
+use gsr_jit::{JitMemory, ScriptError, parse_file, compile};
 
 fn main() {
 
@@ -20,7 +20,7 @@ fn main() {
 
     clear_console();
     assemble(&mut jit_mem, &file);
-    println!("{}", exec::<u64>(&jit_mem.as_ref().unwrap()));
+    print_result(exec::<u64>(jit_mem.as_ref().unwrap()));
 
     loop {
         match rx.recv() {
@@ -28,7 +28,7 @@ fn main() {
                 clear_console();
                 file = read_to_string(file_path).unwrap();
                 assemble(&mut jit_mem, &file);
-                println!("{}", exec::<u64>(&jit_mem.as_ref().unwrap()));
+                print_result(exec::<u64>(jit_mem.as_ref().unwrap()));
             },
             Ok(_) => { },
             Err(e) => println!("watch error: {:?}", e),
@@ -46,6 +46,13 @@ fn assemble(jit: &mut Option<JitMemory>, file_str: &str) {
     *jit = Some(JitMemory::from_assembly_buf(&assembly_buf).unwrap());
 }
 
-fn exec<T>(mem: &JitMemory) -> T {
-    mem.run::<T>()()
+fn exec<T>(mem: &JitMemory) -> Result<T, ScriptError> {
+    mem.call::<T>()
+}
+
+fn print_result(result: Result<u64, ScriptError>) {
+    match result {
+        Ok(value) => println!("{}", value),
+        Err(error) => println!("error: {}", error),
+    }
 }
\ No newline at end of file
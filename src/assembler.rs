@@ -0,0 +1,906 @@
+//! Minimal x86-64 instruction encoder.
+//!
+//! Only the instructions that the function compiler actually emits are
+//! implemented. Every function is assembled into its own buffer, calls to
+//! other script functions are recorded as relocations and patched when the
+//! module is linked.
+
+use compiler::{GlobalLabel, Trap};
+use logger::LogMessage;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn ext(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+/// Integer argument registers of the System V AMD64 calling convention
+pub const INT_ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Xmm {
+    Xmm0 = 0,
+    Xmm1 = 1,
+    Xmm2 = 2,
+    Xmm3 = 3,
+    Xmm4 = 4,
+    Xmm5 = 5,
+    Xmm6 = 6,
+    Xmm7 = 7,
+}
+
+/// Floating point argument registers of the System V AMD64 calling convention
+pub const SSE_ARG_REGS: [Xmm; 8] = [Xmm::Xmm0, Xmm::Xmm1, Xmm::Xmm2, Xmm::Xmm3, Xmm::Xmm4, Xmm::Xmm5, Xmm::Xmm6, Xmm::Xmm7];
+
+/// Condition codes, encoded as the low nibble of `jcc` / `setcc`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cond {
+    Overflow = 0x0,
+    NoOverflow = 0x1,
+    Below = 0x2,
+    AboveEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    BelowEqual = 0x6,
+    Above = 0x7,
+    Sign = 0x8,
+    NoSign = 0x9,
+    Parity = 0xA,
+    NoParity = 0xB,
+    Less = 0xC,
+    GreaterEqual = 0xD,
+    LessEqual = 0xE,
+    Greater = 0xF,
+}
+
+impl Cond {
+    pub fn negate(self) -> Cond {
+        use self::Cond::*;
+        match self {
+            Overflow => NoOverflow,
+            NoOverflow => Overflow,
+            Below => AboveEqual,
+            AboveEqual => Below,
+            Equal => NotEqual,
+            NotEqual => Equal,
+            BelowEqual => Above,
+            Above => BelowEqual,
+            Sign => NoSign,
+            NoSign => Sign,
+            Parity => NoParity,
+            NoParity => Parity,
+            Less => GreaterEqual,
+            GreaterEqual => Less,
+            LessEqual => Greater,
+            Greater => LessEqual,
+        }
+    }
+}
+
+/// Two-operand integer ALU instructions, encoded as `op r/m64, r64`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AluOp {
+    Add = 0x01,
+    Or = 0x09,
+    Adc = 0x11,
+    Sbb = 0x19,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+impl AluOp {
+    /// The `/digit` used by the immediate forms (`81 /n id`)
+    fn digit(self) -> u8 {
+        (self as u8) >> 3
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShiftOp {
+    Rol = 0,
+    Ror = 1,
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// SSE arithmetic, encoded as `F3 0F op` (scalar single), `F2 0F op` (scalar double) or `0F op` (packed single)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FloatOp {
+    Sqrt = 0x51,
+    Add = 0x58,
+    Mul = 0x59,
+    Sub = 0x5C,
+    Min = 0x5D,
+    Div = 0x5E,
+    Max = 0x5F,
+}
+
+/// Predicate of `cmpss` / `cmpsd`, the result is a mask of all ones or all zeros
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FloatCmp {
+    Eq = 0,
+    Lt = 1,
+    Le = 2,
+    Unordered = 3,
+    NotEq = 4,
+    NotLt = 5,
+    NotLe = 6,
+    Ordered = 7,
+}
+
+/// Packed operations on four (SSE) or eight (AVX) 32-bit lanes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PackedOp {
+    /// `addps`, `mulps`, ..
+    Float(FloatOp),
+    /// `cmpps`
+    Cmp(FloatCmp),
+    /// `andps`, the bitwise operations work on integer lanes as well
+    And,
+    /// `andnps`, `dst = !dst & src`
+    AndNot,
+    Or,
+    Xor,
+    /// `paddd`
+    AddInt,
+    /// `psubd`
+    SubInt,
+    /// `pmulld`, needs SSE4.1 (or AVX2 for 256 bits)
+    MulInt,
+    /// `pcmpeqd`
+    EqInt,
+    /// `pcmpgtd`, signed
+    GtInt,
+}
+
+/// The encoding of a `PackedOp`, `[66] 0F [38] opcode /r [imm]`
+struct PackedEncoding {
+    prefix_66: bool,
+    map_38: bool,
+    opcode: u8,
+    imm: Option<u8>,
+}
+
+impl PackedOp {
+    fn encoding(self) -> PackedEncoding {
+        let (prefix_66, map_38, opcode, imm) = match self {
+            PackedOp::Float(op) => (false, false, op as u8, None),
+            PackedOp::Cmp(cmp) => (false, false, 0xC2, Some(cmp as u8)),
+            PackedOp::And => (false, false, 0x54, None),
+            PackedOp::AndNot => (false, false, 0x55, None),
+            PackedOp::Or => (false, false, 0x56, None),
+            PackedOp::Xor => (false, false, 0x57, None),
+            PackedOp::AddInt => (true, false, 0xFE, None),
+            PackedOp::SubInt => (true, false, 0xFA, None),
+            PackedOp::MulInt => (true, true, 0x40, None),
+            PackedOp::EqInt => (true, false, 0x76, None),
+            PackedOp::GtInt => (true, false, 0x66, None),
+        };
+        PackedEncoding { prefix_66, map_38, opcode, imm }
+    }
+}
+
+/// Rounding mode of `roundss` / `roundsd`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoundMode {
+    Nearest = 0,
+    Floor = 1,
+    Ceil = 2,
+    Trunc = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// A call to (or the address of) another script function whose offset is only known after linking
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CallRelocation {
+    /// Offset of the rel32 operand inside the function buffer
+    pub position: usize,
+    pub target: GlobalLabel,
+}
+
+/// A RIP-relative reference into the read-only data, which is placed after the code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DataRelocation {
+    /// Offset of the rel32 operand inside the function buffer
+    pub position: usize,
+    /// Offset of the referenced data inside the read-only data
+    pub offset: usize,
+}
+
+/// A place that can trap, numbered while linking
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrapRelocation {
+    /// Offset of the imm32 operand that holds the number of the site
+    pub position: usize,
+    pub trap: Trap,
+}
+
+/// A logging macro, numbered while linking
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageRelocation {
+    /// Offset of the imm32 operand that holds the number of the message
+    pub position: usize,
+    pub message: LogMessage,
+}
+
+/// The assembled code of one function, with the references that are patched while linking
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineCode {
+    pub code: Vec<u8>,
+    pub calls: Vec<CallRelocation>,
+    pub data: Vec<DataRelocation>,
+    pub traps: Vec<TrapRelocation>,
+    pub messages: Vec<MessageRelocation>,
+}
+
+#[derive(Debug, Default)]
+pub struct Assembler {
+    pub code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// (position of the rel32 operand, label it jumps to)
+    fixups: Vec<(usize, Label)>,
+    pub calls: Vec<CallRelocation>,
+    pub data: Vec<DataRelocation>,
+    pub traps: Vec<TrapRelocation>,
+    pub messages: Vec<MessageRelocation>,
+}
+
+impl Assembler {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    pub fn emit_u8(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    pub fn emit_u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn emit_u64(&mut self, value: u64) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn patch_u32(&mut self, position: usize, value: u32) {
+        self.code[position..position + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // -- labels
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Resolves all local jumps. Panics if a label was used but never bound,
+    /// which would be a bug in the compiler, not in the script.
+    pub fn finish(mut self) -> MachineCode {
+        for &(position, label) in &self.fixups {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            let rel = target as i64 - (position as i64 + 4);
+            let bytes = (rel as i32).to_le_bytes();
+            self.code[position..position + 4].copy_from_slice(&bytes);
+        }
+        self.fixups.clear();
+        MachineCode { code: self.code, calls: self.calls, data: self.data, traps: self.traps, messages: self.messages }
+    }
+
+    fn emit_rel32_to(&mut self, label: Label) {
+        let position = self.code.len();
+        self.fixups.push((position, label));
+        self.emit_u32(0);
+    }
+
+    // -- encoding helpers
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8, force: bool) {
+        let rex = 0x40
+            | (if w { 0x08 } else { 0 })
+            | (if reg >= 8 { 0x04 } else { 0 })
+            | (if index >= 8 { 0x02 } else { 0 })
+            | (if base >= 8 { 0x01 } else { 0 });
+        if rex != 0x40 || force {
+            self.emit_u8(rex);
+        }
+    }
+
+    fn modrm_rr(&mut self, reg: u8, rm: u8) {
+        self.emit_u8(0xC0 | ((reg & 7) << 3) | (rm & 7));
+    }
+
+    fn modrm_mem(&mut self, reg: u8, base: Reg, disp: i32) {
+        let reg = (reg & 7) << 3;
+        let needs_sib = base.low() == 4;
+        let (mode, disp_len) = if disp == 0 && base.low() != 5 {
+            (0x00, 0)
+        } else if (-128..=127).contains(&disp) {
+            (0x40, 1)
+        } else {
+            (0x80, 4)
+        };
+        self.emit_u8(mode | reg | base.low());
+        if needs_sib {
+            self.emit_u8(0x24);
+        }
+        match disp_len {
+            1 => self.emit_u8(disp as i8 as u8),
+            4 => self.emit_u32(disp as u32),
+            _ => { },
+        }
+    }
+
+    /// `op reg, rm` or `op rm, reg` on registers, operand size in bytes
+    fn op_rr(&mut self, prefix: &[u8], opcode: &[u8], size: u8, reg: u8, rm: u8) {
+        if size == 2 {
+            self.emit_u8(0x66);
+        }
+        self.emit(prefix);
+        self.rex(size == 8, reg, 0, rm, size == 1 && (reg >= 4 || rm >= 4));
+        self.emit(opcode);
+        self.modrm_rr(reg, rm);
+    }
+
+    fn op_mem(&mut self, prefix: &[u8], opcode: &[u8], size: u8, reg: u8, base: Reg, disp: i32) {
+        if size == 2 {
+            self.emit_u8(0x66);
+        }
+        self.emit(prefix);
+        self.rex(size == 8, reg, 0, base as u8, size == 1 && reg >= 4);
+        self.emit(opcode);
+        self.modrm_mem(reg, base, disp);
+    }
+
+    // -- data movement
+
+    /// `mov dst, src` (64 bit)
+    pub fn mov_rr(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[0x89], 8, src as u8, dst as u8);
+    }
+
+    /// Loads an immediate, using `movabs` only if a 64-bit immediate is necessary
+    pub fn mov_ri(&mut self, dst: Reg, value: u64) {
+        if value <= u64::from(u32::MAX) {
+            // mov r32, imm32 (zero-extends into the full register)
+            self.rex(false, 0, 0, dst as u8, false);
+            self.emit_u8(0xB8 + dst.low());
+            self.emit_u32(value as u32);
+        } else if (value as i64) < 0 && value as i64 >= i64::from(i32::MIN) {
+            // mov r/m64, imm32 (sign-extended)
+            self.rex(true, 0, 0, dst as u8, false);
+            self.emit_u8(0xC7);
+            self.modrm_rr(0, dst as u8);
+            self.emit_u32(value as u32);
+        } else {
+            // movabs r64, imm64
+            self.rex(true, 0, 0, dst as u8, false);
+            self.emit_u8(0xB8 + dst.low());
+            self.emit_u64(value);
+        }
+    }
+
+    /// Loads `size` bytes from `[base + disp]` into `dst`, extending to 64 bits
+    pub fn load(&mut self, dst: Reg, base: Reg, disp: i32, size: u8, signed: bool) {
+        match (size, signed) {
+            (8, _) => self.op_mem(&[], &[0x8B], 8, dst as u8, base, disp),
+            (4, true) => self.op_mem(&[], &[0x63], 8, dst as u8, base, disp),
+            (4, false) => self.op_mem(&[], &[0x8B], 4, dst as u8, base, disp),
+            (2, true) => self.op_mem(&[], &[0x0F, 0xBF], 8, dst as u8, base, disp),
+            (2, false) => self.op_mem(&[], &[0x0F, 0xB7], 4, dst as u8, base, disp),
+            (1, true) => self.op_mem(&[], &[0x0F, 0xBE], 8, dst as u8, base, disp),
+            (1, false) => self.op_mem(&[], &[0x0F, 0xB6], 4, dst as u8, base, disp),
+            _ => panic!("invalid load size {}", size),
+        }
+    }
+
+    /// Stores the low `size` bytes of `src` to `[base + disp]`
+    pub fn store(&mut self, base: Reg, disp: i32, src: Reg, size: u8) {
+        match size {
+            1 => self.op_mem(&[], &[0x88], 1, src as u8, base, disp),
+            2 | 4 | 8 => self.op_mem(&[], &[0x89], size, src as u8, base, disp),
+            _ => panic!("invalid store size {}", size),
+        }
+    }
+
+    /// `lea dst, [base + disp]`
+    pub fn lea(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.op_mem(&[], &[0x8D], 8, dst as u8, base, disp);
+    }
+
+    /// `lea dst, [rip + rel32]` pointing to `offset` in the read-only data, patched at link time
+    pub fn lea_rodata(&mut self, dst: Reg, offset: usize) {
+        self.rex(true, dst as u8, 0, 0, false);
+        self.emit_u8(0x8D);
+        self.emit_u8(0x05 | (dst.low() << 3));
+        let position = self.code.len();
+        self.data.push(DataRelocation { position, offset });
+        self.emit_u32(0);
+    }
+
+    /// `lea dst, [rip + rel32]` pointing to the start of a script function, patched at link time
+    pub fn lea_fn(&mut self, dst: Reg, target: GlobalLabel) {
+        self.rex(true, dst as u8, 0, 0, false);
+        self.emit_u8(0x8D);
+        self.emit_u8(0x05 | (dst.low() << 3));
+        let position = self.code.len();
+        self.calls.push(CallRelocation { position, target });
+        self.emit_u32(0);
+    }
+
+    /// Sign- or zero-extends the low `size` bytes of `src` into all of `dst`
+    pub fn extend(&mut self, dst: Reg, src: Reg, size: u8, signed: bool) {
+        match (size, signed) {
+            (8, _) => if dst != src { self.mov_rr(dst, src) },
+            (4, true) => self.op_rr(&[], &[0x63], 8, dst as u8, src as u8),
+            // mov r32, r32 clears the upper half
+            (4, false) => self.op_rr(&[], &[0x89], 4, src as u8, dst as u8),
+            (2, true) => self.op_rr(&[], &[0x0F, 0xBF], 8, dst as u8, src as u8),
+            (2, false) => self.op_rr(&[], &[0x0F, 0xB7], 4, dst as u8, src as u8),
+            (1, true) => {
+                self.rex(true, dst as u8, 0, src as u8, src as u8 >= 4);
+                self.emit(&[0x0F, 0xBE]);
+                self.modrm_rr(dst as u8, src as u8);
+            },
+            (1, false) => {
+                self.rex(false, dst as u8, 0, src as u8, src as u8 >= 4);
+                self.emit(&[0x0F, 0xB6]);
+                self.modrm_rr(dst as u8, src as u8);
+            },
+            _ => panic!("invalid extension size {}", size),
+        }
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.emit_u8(0x50 + reg.low());
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.emit_u8(0x58 + reg.low());
+    }
+
+    // -- arithmetic
+
+    /// `op dst, src` (64 bit)
+    pub fn alu_rr(&mut self, op: AluOp, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[op as u8], 8, src as u8, dst as u8);
+    }
+
+    /// `op dst, imm32` (64 bit, immediate is sign-extended)
+    pub fn alu_ri(&mut self, op: AluOp, dst: Reg, imm: i32) {
+        self.rex(true, 0, 0, dst as u8, false);
+        if (-128..=127).contains(&imm) {
+            self.emit_u8(0x83);
+            self.modrm_rr(op.digit(), dst as u8);
+            self.emit_u8(imm as i8 as u8);
+        } else {
+            self.emit_u8(0x81);
+            self.modrm_rr(op.digit(), dst as u8);
+            self.emit_u32(imm as u32);
+        }
+    }
+
+    /// `sub rsp, imm32` with a 32-bit immediate that can be patched later.
+    /// Returns the position of the immediate.
+    pub fn sub_rsp_patchable(&mut self) -> usize {
+        self.emit(&[0x48, 0x81, 0xEC]);
+        let position = self.code.len();
+        self.emit_u32(0);
+        position
+    }
+
+    /// `imul dst, src` (64 bit)
+    pub fn imul_rr(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[0x0F, 0xAF], 8, dst as u8, src as u8);
+    }
+
+    /// `op dst, src` on the low `size` bytes, which sets the overflow and carry flags for that size
+    pub fn alu_rr_sized(&mut self, op: AluOp, dst: Reg, src: Reg, size: u8) {
+        // the 8-bit forms have the opcode below the others
+        let opcode = if size == 1 { op as u8 - 1 } else { op as u8 };
+        self.op_rr(&[], &[opcode], size, src as u8, dst as u8);
+    }
+
+    /// `op [base + disp], src` on `size` bytes of memory, i.e. `add dword [rbp - 8], eax`
+    pub fn alu_mr(&mut self, op: AluOp, base: Reg, disp: i32, src: Reg, size: u8) {
+        let opcode = if size == 1 { op as u8 - 1 } else { op as u8 };
+        self.op_mem(&[], &[opcode], size, src as u8, base, disp);
+    }
+
+    /// `mul src` / `imul src` on the low `size` bytes: the double width product of the
+    /// accumulator and `src` is stored in `ah:al`, `dx:ax`, `edx:eax` or `rdx:rax`. The
+    /// overflow and carry flags are set if the upper half is needed.
+    pub fn mul_sized(&mut self, src: Reg, size: u8, signed: bool) {
+        let opcode = if size == 1 { 0xF6 } else { 0xF7 };
+        self.op_rr(&[], &[opcode], size, if signed { 5 } else { 4 }, src as u8);
+    }
+
+    pub fn neg(&mut self, reg: Reg) {
+        self.op_rr(&[], &[0xF7], 8, 3, reg as u8);
+    }
+
+    pub fn not(&mut self, reg: Reg) {
+        self.op_rr(&[], &[0xF7], 8, 2, reg as u8);
+    }
+
+    /// Shifts `reg` by `cl`
+    pub fn shift_cl(&mut self, op: ShiftOp, reg: Reg) {
+        self.op_rr(&[], &[0xD3], 8, op as u8, reg as u8);
+    }
+
+    /// Shifts or rotates the lowest `size` bytes of `reg` by `cl`
+    pub fn shift_cl_sized(&mut self, op: ShiftOp, reg: Reg, size: u8) {
+        let opcode = if size == 1 { 0xD2 } else { 0xD3 };
+        self.op_rr(&[], &[opcode], size, op as u8, reg as u8);
+    }
+
+    /// Shifts `reg` by an immediate
+    pub fn shift_ri(&mut self, op: ShiftOp, reg: Reg, amount: u8) {
+        self.op_rr(&[], &[0xC1], 8, op as u8, reg as u8);
+        self.emit_u8(amount);
+    }
+
+    /// `test a, b` (64 bit)
+    pub fn test_rr(&mut self, a: Reg, b: Reg) {
+        self.op_rr(&[], &[0x85], 8, b as u8, a as u8);
+    }
+
+    /// `bswap reg` (64 bit)
+    pub fn bswap(&mut self, reg: Reg) {
+        self.rex(true, 0, 0, reg as u8, false);
+        self.emit(&[0x0F, 0xC8 + reg.low()]);
+    }
+
+    /// `bsf dst, src` (64 bit), index of the lowest set bit, `dst` is undefined and ZF set if `src` is zero
+    pub fn bsf(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[0x0F, 0xBC], 8, dst as u8, src as u8);
+    }
+
+    /// `bsr dst, src` (64 bit), index of the highest set bit, `dst` is undefined and ZF set if `src` is zero
+    pub fn bsr(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[0x0F, 0xBD], 8, dst as u8, src as u8);
+    }
+
+    /// `popcnt dst, src` (64 bit), needs `CpuFeatures::popcnt`
+    pub fn popcnt(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[0xF3], &[0x0F, 0xB8], 8, dst as u8, src as u8);
+    }
+
+    /// `lzcnt dst, src` (64 bit), needs `CpuFeatures::lzcnt`. Other CPUs execute it as `bsr`.
+    pub fn lzcnt(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[0xF3], &[0x0F, 0xBD], 8, dst as u8, src as u8);
+    }
+
+    /// `tzcnt dst, src` (64 bit), needs `CpuFeatures::bmi1`. Other CPUs execute it as `bsf`.
+    pub fn tzcnt(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(&[0xF3], &[0x0F, 0xBC], 8, dst as u8, src as u8);
+    }
+
+    /// Sign-extends rax into rdx:rax
+    pub fn cqo(&mut self) {
+        self.emit(&[0x48, 0x99]);
+    }
+
+    /// Unsigned (`div`) or signed (`idiv`) division of rdx:rax by `reg`
+    pub fn div(&mut self, reg: Reg, signed: bool) {
+        self.op_rr(&[], &[0xF7], 8, if signed { 7 } else { 6 }, reg as u8);
+    }
+
+    /// `cmovcc dst, src` (64 bit)
+    pub fn cmov(&mut self, cond: Cond, dst: Reg, src: Reg) {
+        self.op_rr(&[], &[0x0F, 0x40 + cond as u8], 8, dst as u8, src as u8);
+    }
+
+    /// `setcc` into the low byte of `reg`, followed by a zero extension
+    pub fn setcc(&mut self, cond: Cond, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, reg as u8 >= 4);
+        self.emit(&[0x0F, 0x90 + cond as u8]);
+        self.modrm_rr(0, reg as u8);
+        self.extend(reg, reg, 1, false);
+    }
+
+    // -- scalar floating point
+
+    fn scalar_prefix(double: bool) -> u8 {
+        if double { 0xF2 } else { 0xF3 }
+    }
+
+    /// `movss` / `movsd` from `[base + disp]`
+    pub fn load_float(&mut self, dst: Xmm, base: Reg, disp: i32, double: bool) {
+        self.op_mem(&[Self::scalar_prefix(double)], &[0x0F, 0x10], 4, dst as u8, base, disp);
+    }
+
+    /// `movss` / `movsd` to `[base + disp]`
+    pub fn store_float(&mut self, base: Reg, disp: i32, src: Xmm, double: bool) {
+        self.op_mem(&[Self::scalar_prefix(double)], &[0x0F, 0x11], 4, src as u8, base, disp);
+    }
+
+    /// `movaps dst, src`, copies the whole register
+    pub fn movaps(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x28], 4, dst as u8, src as u8);
+    }
+
+    /// `movq xmm, r64`
+    pub fn movq_xr(&mut self, dst: Xmm, src: Reg) {
+        self.op_rr(&[0x66], &[0x0F, 0x6E], 8, dst as u8, src as u8);
+    }
+
+    /// `movq r64, xmm`
+    pub fn movq_rx(&mut self, dst: Reg, src: Xmm) {
+        self.op_rr(&[0x66], &[0x0F, 0x7E], 8, src as u8, dst as u8);
+    }
+
+    /// `addss` / `addsd` etc., `dst = dst op src`
+    pub fn float_op(&mut self, op: FloatOp, dst: Xmm, src: Xmm, double: bool) {
+        self.op_rr(&[Self::scalar_prefix(double)], &[0x0F, op as u8], 4, dst as u8, src as u8);
+    }
+
+    pub fn xorps(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x57], 4, dst as u8, src as u8);
+    }
+
+    pub fn andps(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x54], 4, dst as u8, src as u8);
+    }
+
+    /// `andnps dst, src`: `dst = !dst & src`
+    pub fn andnps(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x55], 4, dst as u8, src as u8);
+    }
+
+    pub fn orps(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x56], 4, dst as u8, src as u8);
+    }
+
+    /// `cmpss` / `cmpsd`: sets the low lane of `dst` to all ones if `dst cmp src`, otherwise to zero
+    pub fn cmp_float(&mut self, cmp: FloatCmp, dst: Xmm, src: Xmm, double: bool) {
+        self.op_rr(&[Self::scalar_prefix(double)], &[0x0F, 0xC2], 4, dst as u8, src as u8);
+        self.emit_u8(cmp as u8);
+    }
+
+    /// `ucomiss` / `ucomisd`: unordered compare, sets ZF, PF and CF like an unsigned `cmp`
+    pub fn ucomis(&mut self, a: Xmm, b: Xmm, double: bool) {
+        let prefix: &[u8] = if double { &[0x66] } else { &[] };
+        self.op_rr(prefix, &[0x0F, 0x2E], 4, a as u8, b as u8);
+    }
+
+    /// `cvtsi2ss` / `cvtsi2sd` from a signed 64-bit integer
+    pub fn cvt_int_to_float(&mut self, dst: Xmm, src: Reg, double: bool) {
+        self.op_rr(&[Self::scalar_prefix(double)], &[0x0F, 0x2A], 8, dst as u8, src as u8);
+    }
+
+    /// `cvttss2si` / `cvttsd2si` to a signed 64-bit integer, truncating
+    pub fn cvt_float_to_int(&mut self, dst: Reg, src: Xmm, double: bool) {
+        self.op_rr(&[Self::scalar_prefix(double)], &[0x0F, 0x2C], 8, dst as u8, src as u8);
+    }
+
+    /// `cvtss2sd` if `to_double`, otherwise `cvtsd2ss`
+    pub fn cvt_float(&mut self, dst: Xmm, src: Xmm, to_double: bool) {
+        self.op_rr(&[Self::scalar_prefix(!to_double)], &[0x0F, 0x5A], 4, dst as u8, src as u8);
+    }
+
+    // -- packed single precision, four f32 lanes
+
+    /// `movups` from `[base + disp]`, no alignment required
+    pub fn load_packed(&mut self, dst: Xmm, base: Reg, disp: i32) {
+        self.op_mem(&[], &[0x0F, 0x10], 4, dst as u8, base, disp);
+    }
+
+    /// `movups` to `[base + disp]`
+    pub fn store_packed(&mut self, base: Reg, disp: i32, src: Xmm) {
+        self.op_mem(&[], &[0x0F, 0x11], 4, src as u8, base, disp);
+    }
+
+    /// `addps` / `mulps` etc., `dst = dst op src` for every lane
+    pub fn packed_op(&mut self, op: FloatOp, dst: Xmm, src: Xmm) {
+        self.op_rr(&[], &[0x0F, op as u8], 4, dst as u8, src as u8);
+    }
+
+    /// `shufps dst, src, imm`: lanes 0 and 1 are selected from `dst`, lanes 2 and 3 from `src`,
+    /// two bits of `imm` per lane
+    pub fn shufps(&mut self, dst: Xmm, src: Xmm, imm: u8) {
+        self.op_rr(&[], &[0x0F, 0xC6], 4, dst as u8, src as u8);
+        self.emit_u8(imm);
+    }
+
+    /// `dst = dst op src` for four 32-bit lanes
+    pub fn packed(&mut self, op: PackedOp, dst: Xmm, src: Xmm) {
+        let encoding = op.encoding();
+        let prefix: &[u8] = if encoding.prefix_66 { &[0x66] } else { &[] };
+        if encoding.map_38 {
+            self.op_rr(prefix, &[0x0F, 0x38, encoding.opcode], 4, dst as u8, src as u8);
+        } else {
+            self.op_rr(prefix, &[0x0F, encoding.opcode], 4, dst as u8, src as u8);
+        }
+        if let Some(imm) = encoding.imm {
+            self.emit_u8(imm);
+        }
+    }
+
+    /// `pshufd dst, src, imm`, like `shufps` but all lanes are selected from `src`
+    pub fn pshufd(&mut self, dst: Xmm, src: Xmm, imm: u8) {
+        self.op_rr(&[0x66], &[0x0F, 0x70], 4, dst as u8, src as u8);
+        self.emit_u8(imm);
+    }
+
+    /// `pmuludq dst, src`: the 64-bit products of the unsigned lanes 0 and 2
+    pub fn pmuludq(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[0x66], &[0x0F, 0xF4], 4, dst as u8, src as u8);
+    }
+
+    /// `psrlq xmm, amount`, shifts both 64-bit halves right
+    pub fn psrlq(&mut self, xmm: Xmm, amount: u8) {
+        self.op_rr(&[0x66], &[0x0F, 0x73], 4, 2, xmm as u8);
+        self.emit_u8(amount);
+    }
+
+    /// `punpckldq dst, src`: interleaves the lanes 0 and 1 of both, `(dst0, src0, dst1, src1)`
+    pub fn punpckldq(&mut self, dst: Xmm, src: Xmm) {
+        self.op_rr(&[0x66], &[0x0F, 0x62], 4, dst as u8, src as u8);
+    }
+
+    /// `movmskps dst, src`: the sign bits of the four lanes
+    pub fn movmskps(&mut self, dst: Reg, src: Xmm) {
+        self.op_rr(&[], &[0x0F, 0x50], 4, dst as u8, src as u8);
+    }
+
+    // -- SSE4.1, only emitted if `CpuFeatures::sse41` is set
+
+    /// `roundss` / `roundsd dst, src, mode`, without raising the precision exception
+    pub fn round_float(&mut self, mode: RoundMode, dst: Xmm, src: Xmm, double: bool) {
+        let opcode = if double { 0x0B } else { 0x0A };
+        self.op_rr(&[0x66], &[0x0F, 0x3A, opcode], 4, dst as u8, src as u8);
+        self.emit_u8(mode as u8 | 0x08);
+    }
+
+    // -- AVX, only emitted if `CpuFeatures::avx` is set
+
+    /// Three-byte VEX prefix. `map` selects `0F` (1), `0F 38` (2) or `0F 3A` (3), `pp` the
+    /// implied prefix (none, `66`, `F3`, `F2`), `long` 256-bit registers, `vvvv` the extra source.
+    fn vex(&mut self, map: u8, pp: u8, long: bool, vvvv: u8, reg: u8, rm: u8) {
+        // the R, X and B bits and vvvv are inverted
+        let byte1 = (if reg < 8 { 0x80 } else { 0 }) | 0x40 | (if rm < 8 { 0x20 } else { 0 }) | map;
+        let byte2 = ((!vvvv & 0x0F) << 3) | (if long { 0x04 } else { 0 }) | pp;
+        self.emit(&[0xC4, byte1, byte2]);
+    }
+
+    /// `vbroadcastss dst, [base + disp]`, loads an `f32` into every lane (VEX.128.66.0F38.W0 18 /r)
+    pub fn vbroadcastss(&mut self, dst: Xmm, base: Reg, disp: i32) {
+        self.vex(2, 1, false, 0, dst as u8, base as u8);
+        self.emit_u8(0x18);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    /// `dst = a op b` for eight 32-bit lanes of ymm registers, the integer operations need AVX2
+    pub fn vpacked(&mut self, op: PackedOp, dst: Xmm, a: Xmm, b: Xmm) {
+        let encoding = op.encoding();
+        let map = if encoding.map_38 { 2 } else { 1 };
+        let pp = if encoding.prefix_66 { 1 } else { 0 };
+        self.vex(map, pp, true, a as u8, dst as u8, b as u8);
+        self.emit_u8(encoding.opcode);
+        self.modrm_rr(dst as u8, b as u8);
+        if let Some(imm) = encoding.imm {
+            self.emit_u8(imm);
+        }
+    }
+
+    /// `vmovups ymm, [base + disp]`
+    pub fn vload_packed(&mut self, dst: Xmm, base: Reg, disp: i32) {
+        self.vex(1, 0, true, 0, dst as u8, base as u8);
+        self.emit_u8(0x10);
+        self.modrm_mem(dst as u8, base, disp);
+    }
+
+    /// `vmovups [base + disp], ymm`
+    pub fn vstore_packed(&mut self, base: Reg, disp: i32, src: Xmm) {
+        self.vex(1, 0, true, 0, src as u8, base as u8);
+        self.emit_u8(0x11);
+        self.modrm_mem(src as u8, base, disp);
+    }
+
+    /// Clears the upper halves of the ymm registers, which avoids a penalty for the SSE
+    /// instructions that follow
+    pub fn vzeroupper(&mut self) {
+        self.emit(&[0xC5, 0xF8, 0x77]);
+    }
+
+    // -- control flow
+
+    pub fn jmp(&mut self, label: Label) {
+        self.emit_u8(0xE9);
+        self.emit_rel32_to(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.emit(&[0x0F, 0x80 + cond as u8]);
+        self.emit_rel32_to(label);
+    }
+
+    /// `call rel32` to another script function, patched at link time
+    pub fn call_fn(&mut self, target: GlobalLabel) {
+        self.emit_u8(0xE8);
+        let position = self.code.len();
+        self.calls.push(CallRelocation { position, target });
+        self.emit_u32(0);
+    }
+
+    /// `call reg`
+    pub fn call_r(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.emit_u8(0xFF);
+        self.modrm_rr(2, reg as u8);
+    }
+
+    /// `mov edi, site; call handler`. The handler doesn't return, the site is numbered at link time.
+    pub fn trap(&mut self, trap: Trap, handler: GlobalLabel) {
+        self.emit_u8(0xBF);
+        let position = self.code.len();
+        self.traps.push(TrapRelocation { position, trap });
+        self.emit_u32(0);
+        self.call_fn(handler);
+    }
+
+    /// `mov esi, message`, the number of a logging macro, patched at link time
+    pub fn mov_message(&mut self, message: LogMessage) {
+        self.emit_u8(0xBE);
+        let position = self.code.len();
+        self.messages.push(MessageRelocation { position, message });
+        self.emit_u32(0);
+    }
+
+    pub fn ret(&mut self) {
+        self.emit_u8(0xC3);
+    }
+
+    /// `ud2`, raises an invalid opcode exception
+    pub fn ud2(&mut self) {
+        self.emit(&[0x0F, 0x0B]);
+    }
+}
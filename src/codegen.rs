@@ -0,0 +1,2100 @@
+//! Translates the body of a single function into machine code.
+//!
+//! The code generator is a simple tree walker without any optimization:
+//! every expression leaves its (normalized, i.e. sign- or zero-extended to
+//! 64 bits) result in `rax`, or in `xmm0` for floating point values. Structs, options,
+//! results and matrices live in stack slots, `rax` holds their address. Closures are compiled as
+//! separate functions when they are first encountered. The
+//! types of unsuffixed literals are inferred in a first pass over the function,
+//! see `infer.rs`. Arguments, locals and intermediate values live
+//! in stack slots that are addressed relative to `rbp`. Calls follow the
+//! System V AMD64 calling convention, so script functions can be called
+//! directly by the host and vice versa.
+
+use std::mem;
+use quote::ToTokens;
+use syn::{
+    BinOp, Block, Expr, ExprBinary, ExprBreak, ExprCall, ExprCast, ExprClosure, ExprContinue, ExprField, ExprIf, ExprLit,
+    ExprPath, ExprReturn,
+    ExprMethodCall, ExprStruct, ExprTuple, ExprUnary, FnArg, FloatSuffix, Item, Lit, LitFloat, LitInt, Member, Path, ReturnType, Stmt,
+    Type, UnOp,
+};
+use assembler::{AluOp, Assembler, Cond, FloatOp, Label, MachineCode, Reg, ShiftOp, Xmm, INT_ARG_REGS, SSE_ARG_REGS};
+use compiler::{
+    AssembleError, AssembleFunctionError, Closure, CompileMode, GetReturnTypeInnerError, GlobalLabel, Program, Ret,
+    ScriptError, SharedData, SourceLocation, StaticFloatLiteral, StaticIntLiteral, Trap, FN_EPILOGUE, FN_PROLOGUE,
+    new_global_label, try_match_u64_value,
+};
+use infer::Inference;
+use resolve::{Def, ModuleId, ModuleTree, Namespace, path_to_string};
+use types::{Field, StructId, StructKind, TypeTable};
+use self::borrow::{Borrow, Moved};
+use self::intrinsics::{IntOp, int_type_item};
+use self::vector::builtin_type_item;
+
+mod array;
+mod assign;
+mod borrow;
+mod fixed;
+mod intrinsics;
+mod macros;
+mod matrix;
+mod iter;
+mod operators;
+mod option;
+mod pattern;
+mod simd;
+mod vector;
+mod wide;
+
+/// Argument and return types of a function, resolved from its declaration
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnSignature {
+    pub arguments: Vec<Ret>,
+    pub return_type: Ret,
+}
+
+impl FnSignature {
+    /// Resolves the types of a function that is declared in `module`
+    pub fn new<'a, I: IntoIterator<Item = &'a FnArg>>(modules: &ModuleTree, types: &mut TypeTable, module: ModuleId,
+                                                       arguments: I, return_type: Option<&Type>)
+    -> Result<Self, AssembleError>
+    {
+        let mut argument_types = Vec::new();
+        for arg in arguments {
+            match *arg {
+                FnArg::Captured(ref c) => argument_types.push(types.resolve(modules, module, &c.ty)?),
+                _ => return Err(unsupported(arg)),
+            }
+        }
+        let return_type = match return_type {
+            Some(t) => types.resolve_value(modules, module, t)?,
+            None => Ret::Void,
+        };
+        Ok(FnSignature { arguments: argument_types, return_type })
+    }
+
+    /// Size of the arguments that a caller passes on the stack
+    pub fn stack_size(&self, types: &TypeTable) -> i32 {
+        assign_arguments(types, &self.arguments, returns_in_memory(types, self.return_type)).1
+    }
+}
+
+/// The code of a function or closure
+#[derive(Debug, Copy, Clone)]
+pub enum FnBody<'a> {
+    Block(&'a [Stmt]),
+    /// The body of a closure, `|x| x * 2`
+    Expr(&'a Expr),
+}
+
+/// Everything about a function that the code generator needs from the AST
+#[derive(Debug, Clone)]
+pub struct FnSource<'a> {
+    /// Used in error messages
+    pub name: String,
+    /// Paths in the body are resolved relative to this module
+    pub module: ModuleId,
+    pub arguments: Vec<&'a FnArg>,
+    pub body: FnBody<'a>,
+    /// The function is a closure inside of an `unsafe` block
+    pub in_unsafe: bool,
+}
+
+/// Register class of one eightbyte of a value, as in the System V ABI
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ArgClass {
+    Integer,
+    Sse,
+    /// Structs larger than 16 bytes are passed on the stack and returned through a pointer
+    Memory,
+}
+
+/// Splits a value into the eightbytes that are passed in separate registers.
+/// Inside of the function, the eightbytes of a value are held in `rax` and `rdx`
+/// (integer class) or `xmm0` and `xmm1` (SSE class). Struct values are held in
+/// memory, `rax` contains their address.
+fn classify(types: &TypeTable, ty: Ret) -> Vec<ArgClass> {
+    match ty {
+        Ret::Void => vec![],
+        Ret::Str | Ret::Slice(_) => vec![ArgClass::Integer, ArgClass::Integer],
+        _ if wide::is_wide(ty) => vec![ArgClass::Integer, ArgClass::Integer],
+        // a matrix argument is a pointer to a copy, see `returns_in_memory` for results
+        _ if is_passed_by_pointer(ty) => vec![ArgClass::Integer],
+        // a whole vector is passed in one register
+        Ret::Float(_) | Ret::Vec(_) | Ret::Quat | Ret::Simd(_) => vec![ArgClass::Sse],
+        Ret::Struct(_) | Ret::Option(_) | Ret::Result(_) => {
+            let size = types.size_of(ty);
+            let count = (align_up(size, 8) / 8) as usize;
+            if size > 16 {
+                return vec![ArgClass::Memory; count];
+            }
+            // an eightbyte is only passed in an SSE register if it contains nothing but floats
+            let mut classes = vec![ArgClass::Sse; count];
+            mark_integer_eightbytes(types, ty, 0, &mut classes);
+            classes
+        },
+        _ => vec![ArgClass::Integer],
+    }
+}
+
+fn mark_integer_eightbytes(types: &TypeTable, ty: Ret, offset: i32, classes: &mut [ArgClass]) {
+    match ty {
+        Ret::Float(_) | Ret::Vec(_) | Ret::Quat | Ret::Mat(_) | Ret::Simd(_) | Ret::Void => { },
+        Ret::Struct(id) => for field in &types.struct_def(id).fields {
+            mark_integer_eightbytes(types, field.ty, offset + field.offset, classes);
+        },
+        Ret::Option(id) => {
+            mark_integer_eightbytes(types, Ret::Bool, offset, classes);
+            mark_integer_eightbytes(types, types.option(id), offset + types.option_value_offset(id), classes);
+        },
+        // the payload is a union, an eightbyte is an integer if it is one in either type
+        Ret::Result(id) => {
+            let (ok, err) = types.result(id);
+            let payload = offset + types.result_payload_offset(id);
+            mark_integer_eightbytes(types, Ret::Bool, offset, classes);
+            mark_integer_eightbytes(types, ok, payload, classes);
+            mark_integer_eightbytes(types, err, payload, classes);
+        },
+        _ => for eightbyte in offset / 8..(offset + ty.size() + 7) / 8 {
+            classes[eightbyte as usize] = ArgClass::Integer;
+        },
+    }
+}
+
+/// Whether a function returning `ty` gets a hidden pointer to the memory for the result
+fn returns_in_memory(types: &TypeTable, ty: Ret) -> bool {
+    is_passed_by_pointer(ty) || classify(types, ty).first() == Some(&ArgClass::Memory)
+}
+
+/// Matrices and 8-lane SIMD vectors are passed as a pointer to a copy and returned
+/// through a hidden pointer. Unlike `__m256`, this does not depend on AVX support.
+fn is_passed_by_pointer(ty: Ret) -> bool {
+    match ty {
+        Ret::Mat(_) => true,
+        Ret::Simd(s) => s.is_wide(),
+        _ => false,
+    }
+}
+
+/// Whether values of the type are held in memory, with their address in `rax`
+fn is_memory_value(ty: Ret) -> bool {
+    matches!(ty, Ret::Struct(_) | Ret::Option(_) | Ret::Result(_)) || is_passed_by_pointer(ty)
+}
+
+/// Registers that hold the eightbytes of a value while it is being computed
+const VALUE_REGS: [Reg; 2] = [Reg::Rax, Reg::Rdx];
+const SSE_VALUE_REGS: [Xmm; 2] = [Xmm::Xmm0, Xmm::Xmm1];
+
+/// Where one eightbyte of a value is held or passed
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ArgPart {
+    Register(Reg),
+    Sse(Xmm),
+    /// Offset relative to the stack pointer at the time of the call
+    Stack(i32),
+}
+
+/// The registers that hold (or return) a value with the given eightbytes
+fn value_parts(classes: &[ArgClass]) -> Vec<ArgPart> {
+    let mut int_regs = VALUE_REGS.iter().cloned();
+    let mut sse_regs = SSE_VALUE_REGS.iter().cloned();
+    classes.iter().map(|class| match *class {
+        ArgClass::Integer => ArgPart::Register(int_regs.next().unwrap()),
+        ArgClass::Sse => ArgPart::Sse(sse_regs.next().unwrap()),
+        ArgClass::Memory => unreachable!("values in memory are never held in registers"),
+    }).collect()
+}
+
+/// Bytes of a value that are moved with one register, a vector fills a whole xmm register
+fn register_size(ty: Ret) -> i32 {
+    match ty {
+        Ret::Vec(v) => v.size(),
+        Ret::Quat | Ret::Simd(_) => 16,
+        _ => 8,
+    }
+}
+
+/// Assigns argument registers and stack slots to a list of argument types.
+/// Returns the location of each eightbyte and the size of the stack area.
+/// If the result is returned in memory, `rdi` holds the pointer to it.
+fn assign_arguments(types: &TypeTable, arguments: &[Ret], hidden_return: bool) -> (Vec<Vec<ArgPart>>, i32) {
+    let mut int_regs = INT_ARG_REGS.iter().cloned();
+    let mut sse_regs = SSE_ARG_REGS.iter().cloned();
+    let mut stack_offset = 0;
+    let mut locations = Vec::with_capacity(arguments.len());
+
+    if hidden_return {
+        int_regs.next();
+    }
+
+    for ty in arguments {
+        let classes = classify(types, *ty);
+        let int_count = classes.iter().filter(|c| **c == ArgClass::Integer).count();
+        let sse_count = classes.iter().filter(|c| **c == ArgClass::Sse).count();
+        let in_memory = classes.contains(&ArgClass::Memory);
+        // a value that does not fit into the remaining registers is passed on the stack entirely
+        if !in_memory && int_regs.len() >= int_count && sse_regs.len() >= sse_count {
+            locations.push(classes.iter().map(|class| match *class {
+                ArgClass::Integer => ArgPart::Register(int_regs.next().unwrap()),
+                _ => ArgPart::Sse(sse_regs.next().unwrap()),
+            }).collect());
+        } else {
+            let eightbytes = match *ty {
+                _ if is_passed_by_pointer(*ty) => 1,
+                _ => {
+                    // `Vec3` and `Vec4` are passed like `__m128`, which is 16-byte aligned on the stack
+                    if types.align_of(*ty) == 16 {
+                        stack_offset = align_up(stack_offset, 16);
+                    }
+                    align_up(types.size_of(*ty), 8) / 8
+                },
+            };
+            let parts = (0..eightbytes).map(|_| {
+                stack_offset += 8;
+                ArgPart::Stack(stack_offset - 8)
+            }).collect();
+            locations.push(parts);
+        }
+    }
+
+    (locations, stack_offset)
+}
+
+fn member_name(member: &Member) -> String {
+    match *member {
+        Member::Named(ref ident) => ident.to_string(),
+        Member::Unnamed(ref index) => index.index.to_string(),
+    }
+}
+
+fn unsupported<T: ToTokens>(node: &T) -> AssembleError {
+    AssembleFunctionError::UnsupportedExpression(node.into_token_stream().to_string()).into()
+}
+
+/// A region of the stack frame, `[rbp + disp, rbp + disp + size)`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Slot {
+    pub disp: i32,
+    pub size: i32,
+}
+
+/// Hands out stack slots below `rbp`. Freed slots are reused first-fit,
+/// the frame size is the high water mark of all allocations.
+#[derive(Debug, Default)]
+struct Frame {
+    size: i32,
+    free: Vec<Slot>,
+}
+
+fn align_up(value: i32, align: i32) -> i32 {
+    (value + align - 1) / align * align
+}
+
+impl Frame {
+    fn alloc(&mut self, size: i32, align: i32) -> Slot {
+        let size = size.max(1);
+        let align = align.max(1);
+        if let Some(index) = self.free.iter().position(|s| s.size >= size && s.disp % align == 0) {
+            let slot = self.free.remove(index);
+            if slot.size > size {
+                self.free.push(Slot { disp: slot.disp + size, size: slot.size - size });
+            }
+            return Slot { disp: slot.disp, size };
+        }
+        self.size = align_up(self.size + size, align);
+        Slot { disp: -self.size, size }
+    }
+
+    fn free(&mut self, slot: Slot) {
+        self.free.push(slot);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CallTarget {
+    Script(GlobalLabel),
+    /// Address of a native function registered in `HostFunctions`
+    Host(usize),
+    /// A function pointer, saved in the given slot while the arguments are evaluated
+    Indirect(Slot),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Local {
+    name: String,
+    ty: Ret,
+    slot: Slot,
+    /// Bound with `ref` or `ref mut`, the slot is a part of another local and `*name` is its value
+    by_ref: bool,
+    /// A `ref` binding whose slot is a copy, assigning to `*name` would not change the original
+    detached: bool,
+    /// `mut name` can be assigned, `ref mut name` can be assigned through `*name`
+    mutable: bool,
+}
+
+/// The number of locals and local slots when a block or match arm started, see `FnCompiler::enter_scope`
+struct Scope {
+    locals: usize,
+    slots: usize,
+}
+
+/// A loop around the current code, which `continue` and `break` jump out of
+struct Loop {
+    /// Where the next iteration starts
+    next: Label,
+    end: Label,
+    /// The moves before the loop, an iteration can't move anything else before it continues
+    moved: Vec<Moved>,
+    /// The moves before the `break`s, which are still moved after the loop
+    broken: Vec<Moved>,
+}
+
+/// Compiles a function or closure. The first pass only infers the types of unsuffixed
+/// literals (and the return type of a closure without annotation), its code is discarded.
+/// Returns the code and the return type.
+pub fn compile_function(program: &Program, shared: &mut SharedData, source: &FnSource, arguments: &[Ret],
+                        return_type: Option<Ret>, captures: &[String])
+-> Result<(MachineCode, Ret), AssembleError>
+{
+    let mut inference = Inference::default();
+    let (_, found) = FnCompiler::new(program, shared, &mut inference, source, arguments, return_type, captures).compile()?;
+    inference.apply_defaults();
+    let return_type = inference.resolve(found);
+    FnCompiler::new(program, shared, &mut inference, source, arguments, Some(return_type), captures).compile()
+}
+
+pub struct FnCompiler<'a> {
+    program: &'a Program,
+    shared: &'a mut SharedData,
+    infer: &'a mut Inference,
+    source: &'a FnSource<'a>,
+    arguments: &'a [Ret],
+    /// `None` until the return type of a closure without annotation is known
+    return_type: Option<Ret>,
+    /// Locals of the enclosing functions of a closure, which it can't use
+    captures: &'a [String],
+    asm: Assembler,
+    frame: Frame,
+    /// Bytes needed at `[rsp]` for arguments that are passed on the stack
+    outgoing_args_size: i32,
+    locals: Vec<Local>,
+    /// Slots of the locals that are in scope, reused by the statements after their block
+    local_slots: Vec<Slot>,
+    /// Struct values that are not stored in a local, freed at the end of the statement
+    temps: Vec<Slot>,
+    /// Parts of locals that were moved out and can't be used until they are assigned
+    moved: Vec<Moved>,
+    /// Parts of locals that `&` and `&mut` references refer to
+    borrows: Vec<Borrow>,
+    /// The loops around the current code, the innermost one last
+    loops: Vec<Loop>,
+    /// The code after the current one is unreachable, i.e. after a `return`
+    diverges: bool,
+    /// Compiling the inlined closure of `any` or `all`, which can't return
+    in_predicate: bool,
+    /// Compiling an `unsafe` block, which can use `static mut` items
+    in_unsafe: bool,
+    /// Holds the address for the result, if it is returned in memory
+    return_slot: Option<Slot>,
+    epilogue: Label,
+}
+
+impl<'a> FnCompiler<'a> {
+
+    pub fn new(program: &'a Program, shared: &'a mut SharedData, infer: &'a mut Inference, source: &'a FnSource<'a>,
+               arguments: &'a [Ret], return_type: Option<Ret>, captures: &'a [String]) -> Self
+    {
+        let mut asm = Assembler::new();
+        let epilogue = asm.new_label();
+        FnCompiler {
+            program,
+            shared,
+            infer,
+            source,
+            arguments,
+            return_type,
+            captures,
+            asm,
+            frame: Frame::default(),
+            outgoing_args_size: 0,
+            locals: Vec::new(),
+            local_slots: Vec::new(),
+            temps: Vec::new(),
+            moved: Vec::new(),
+            borrows: Vec::new(),
+            loops: Vec::new(),
+            diverges: false,
+            in_predicate: false,
+            in_unsafe: source.in_unsafe,
+            return_slot: None,
+            epilogue,
+        }
+    }
+
+    pub fn compile(mut self) -> Result<(MachineCode, Ret), AssembleError> {
+        self.asm.emit(&FN_PROLOGUE);
+        let frame_size_position = self.asm.sub_rsp_patchable();
+        self.store_arguments()?;
+        self.compile_body()?;
+        self.asm.bind(self.epilogue);
+        self.asm.emit(&FN_EPILOGUE);
+
+        // keep rsp 16-byte aligned at every call site
+        let frame_size = align_up(self.frame.size, 16) + align_up(self.outgoing_args_size, 16);
+        self.asm.patch_u32(frame_size_position, frame_size as u32);
+        let return_type = self.return_type.unwrap_or(Ret::Void);
+        Ok((self.asm.finish(), return_type))
+    }
+
+    fn fn_name(&self) -> String {
+        self.source.name.clone()
+    }
+
+    fn location<T: ToTokens>(&self, node: &T) -> SourceLocation {
+        let tokens = node.into_token_stream();
+        let start = tokens.clone().into_iter().next().map(|token| token.span().start());
+        SourceLocation {
+            function: self.fn_name(),
+            expression: tokens.to_string(),
+            line: start.map_or(0, |start| start.line as u32),
+            column: start.map_or(0, |start| start.column as u32 + 1),
+        }
+    }
+
+    /// Whether integer overflow raises a `ScriptError` instead of wrapping around
+    fn checks_overflow(&self) -> bool {
+        self.program.mode == CompileMode::Debug
+    }
+
+    /// Stops the script and returns `error` to the host if `cond` holds
+    fn trap_if(&mut self, cond: Cond, error: ScriptError) {
+        let skip = self.asm.new_label();
+        self.asm.jcc(cond.negate(), skip);
+        self.asm.trap(Trap::Error(error), self.program.trap_handler);
+        self.asm.bind(skip);
+    }
+
+    fn size_of(&self, ty: Ret) -> i32 {
+        self.shared.types.size_of(ty)
+    }
+
+    /// Size of a value in a slot that is read and written in whole eightbytes
+    fn eightbytes_size(&self, ty: Ret) -> i32 {
+        align_up(self.size_of(ty), 8)
+    }
+
+    fn resolve_type(&mut self, ty: &Type) -> Result<Ret, AssembleError> {
+        self.shared.types.resolve(&self.program.modules, self.source.module, ty)
+    }
+
+    /// Resolves a type that can't be a reference, see `TypeTable::resolve_value`
+    fn resolve_value_type(&mut self, ty: &Type) -> Result<Ret, AssembleError> {
+        self.shared.types.resolve_value(&self.program.modules, self.source.module, ty)
+    }
+
+    /// Alignment of a slot for a value, at least 8 since slots are accessed in eightbytes.
+    /// Native code may use aligned loads for the 16-byte aligned types.
+    fn slot_align(&self, ty: Ret) -> i32 {
+        self.shared.types.align_of(ty).max(8)
+    }
+
+    /// Allocates a slot for a struct or matrix value that lives until the end of the statement
+    fn alloc_temp(&mut self, ty: Ret) -> Slot {
+        let slot = self.frame.alloc(self.eightbytes_size(ty), self.slot_align(ty));
+        self.temps.push(slot);
+        slot
+    }
+
+    /// Copies the arguments from the argument registers into stack slots
+    fn store_arguments(&mut self) -> Result<(), AssembleError> {
+        let source = self.source;
+        let hidden_return = match self.return_type {
+            Some(ty) => returns_in_memory(&self.shared.types, ty),
+            None => false,
+        };
+        if hidden_return {
+            let slot = self.frame.alloc(8, 8);
+            self.asm.store(Reg::Rbp, slot.disp, Reg::Rdi, 8);
+            self.return_slot = Some(slot);
+        }
+
+        let (locations, _) = assign_arguments(&self.shared.types, self.arguments, hidden_return);
+        // matrices are copied and patterns are matched after all argument registers are saved
+        let mut matrices = Vec::new();
+        let mut patterns = Vec::new();
+        for ((arg, ty), parts) in source.arguments.iter().zip(self.arguments.iter()).zip(locations) {
+            let pat = match **arg {
+                FnArg::Captured(ref c) => &c.pat,
+                // closure arguments without a type annotation
+                FnArg::Inferred(ref p) => p,
+                _ => return Err(unsupported(*arg)),
+            };
+            let slot = match parts.first() {
+                // a matrix argument is a pointer to a copy that the caller made
+                Some(&part) if is_passed_by_pointer(*ty) => {
+                    let pointer = match part {
+                        ArgPart::Stack(offset) => 16 + offset,
+                        _ => {
+                            let pointer = self.frame.alloc(8, 8);
+                            self.store_part(part, Reg::Rbp, pointer.disp, 8);
+                            pointer.disp
+                        },
+                    };
+                    let slot = self.frame.alloc(self.size_of(*ty), self.slot_align(*ty));
+                    matrices.push((pointer, slot));
+                    slot
+                },
+                // arguments passed on the stack are above the saved rbp and the return address
+                Some(&ArgPart::Stack(offset)) => Slot { disp: 16 + offset, size: self.size_of(*ty) },
+                _ => {
+                    let slot = self.frame.alloc(self.eightbytes_size(*ty), 8);
+                    let size = if parts.len() == 1 && !is_memory_value(*ty) { ty.size() } else { 8 };
+                    for (i, part) in parts.iter().enumerate() {
+                        self.store_part(*part, Reg::Rbp, slot.disp + 8 * i as i32, size);
+                    }
+                    slot
+                },
+            };
+
+            patterns.push((pat, *ty, slot));
+        }
+
+        for (pointer, slot) in matrices {
+            self.asm.load(Reg::Rax, Reg::Rbp, pointer, 8, false);
+            self.copy_memory(Reg::Rbp, slot.disp, Reg::Rax, 0, slot.size);
+        }
+        for (pat, ty, slot) in patterns {
+            self.bind_irrefutable(pat, ty, slot.disp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores `size` bytes of a register to `[base + disp]`
+    fn store_part(&mut self, part: ArgPart, base: Reg, disp: i32, size: i32) {
+        match part {
+            ArgPart::Register(reg) => self.asm.store(base, disp, reg, size as u8),
+            ArgPart::Sse(xmm) if size == 16 => self.asm.store_packed(base, disp, xmm),
+            ArgPart::Sse(xmm) => self.asm.store_float(base, disp, xmm, size == 8),
+            ArgPart::Stack(_) => unreachable!("values are never held on the stack"),
+        }
+    }
+
+    /// Loads `size` bytes from `[base + disp]` into a register
+    fn load_part(&mut self, part: ArgPart, base: Reg, disp: i32, size: i32, signed: bool) {
+        match part {
+            ArgPart::Register(reg) => self.asm.load(reg, base, disp, size as u8, signed),
+            ArgPart::Sse(xmm) if size == 16 => self.asm.load_packed(xmm, base, disp),
+            ArgPart::Sse(xmm) => self.asm.load_float(xmm, base, disp, size == 8),
+            ArgPart::Stack(_) => unreachable!("values are never held on the stack"),
+        }
+    }
+
+    /// Copies `size` bytes from `[src + src_disp]` to `[dst + dst_disp]`, using `rcx`
+    fn copy_memory(&mut self, dst: Reg, dst_disp: i32, src: Reg, src_disp: i32, size: i32) {
+        let mut offset = 0;
+        for &chunk in &[8, 4, 2, 1] {
+            while size - offset >= chunk {
+                self.asm.load(Reg::Rcx, src, src_disp + offset, chunk as u8, false);
+                self.asm.store(dst, dst_disp + offset, Reg::Rcx, chunk as u8);
+                offset += chunk;
+            }
+        }
+    }
+
+    /// Stores the value of type `ty` (in `rax` / `rdx` / `xmm0`) to `[base + disp]`.
+    /// A struct or matrix is copied from the address in `rax`, `base` must not be `rcx` then.
+    fn store_value(&mut self, ty: Ret, base: Reg, disp: i32) {
+        if is_memory_value(ty) {
+            let size = self.size_of(ty);
+            self.copy_memory(base, disp, Reg::Rax, 0, size);
+            return;
+        }
+        let parts = value_parts(&classify(&self.shared.types, ty));
+        let size = if parts.len() == 1 { ty.size() } else { 8 };
+        for (i, part) in parts.iter().enumerate() {
+            self.store_part(*part, base, disp + 8 * i as i32, size);
+        }
+    }
+
+    /// Loads a value of type `ty` from `[base + disp]` into `rax` / `rdx` / `xmm0`,
+    /// or the address of a struct or matrix into `rax`
+    fn load_value(&mut self, ty: Ret, base: Reg, disp: i32) {
+        if is_memory_value(ty) {
+            self.asm.lea(Reg::Rax, base, disp);
+            return;
+        }
+        let parts = value_parts(&classify(&self.shared.types, ty));
+        let size = if parts.len() == 1 { ty.size() } else { 8 };
+        for (i, part) in parts.iter().enumerate() {
+            self.load_part(*part, base, disp + 8 * i as i32, size, ty.is_signed());
+        }
+    }
+
+    /// Saves the current value to a new stack slot, i.e. while the other side of a binary expression is computed
+    fn spill(&mut self, ty: Ret) -> Slot {
+        let slot = self.frame.alloc(self.eightbytes_size(ty), 8);
+        self.store_value(ty, Reg::Rbp, slot.disp);
+        slot
+    }
+
+    /// Records that both types have to be equal, like `Inference::unify`.
+    /// Tuples, arrays, slices, options and results are the same if their elements are.
+    fn unify(&mut self, a: Ret, b: Ret) -> bool {
+        let a = self.infer.resolve(a);
+        let b = self.infer.resolve(b);
+        let types = &self.shared.types;
+        match (a, b) {
+            (Ret::Option(x), Ret::Option(y)) if x != y => {
+                let (x, y) = (types.option(x), types.option(y));
+                self.unify(x, y)
+            },
+            (Ret::Result(x), Ret::Result(y)) if x != y => {
+                let ((x_ok, x_err), (y_ok, y_err)) = (types.result(x), types.result(y));
+                self.unify(x_ok, y_ok) && self.unify(x_err, y_err)
+            },
+            (Ret::Ref(x), Ret::Ref(y)) if x != y => {
+                let (x, y) = (types.reference(x), types.reference(y));
+                x.mutable == y.mutable && self.unify(x.elem, y.elem)
+            },
+            (Ret::Slice(x), Ret::Slice(y)) if x != y => {
+                let (x, y) = (types.slice(x), types.slice(y));
+                x.mutable == y.mutable && self.unify(x.elem, y.elem)
+            },
+            (Ret::Struct(x), Ret::Struct(y)) if x != y => {
+                if let (Some((x, x_len)), Some((y, y_len))) = (types.array(x), types.array(y)) {
+                    return x_len == y_len && self.unify(x, y);
+                }
+                match (types.tuple_elements(x).map(|e| e.to_vec()), types.tuple_elements(y).map(|e| e.to_vec())) {
+                    (Some(xs), Some(ys)) if xs.len() == ys.len() => xs.into_iter().zip(ys).all(|(x, y)| self.unify(x, y)),
+                    _ => false,
+                }
+            },
+            _ => self.infer.unify(a, b),
+        }
+    }
+
+    /// Checks that `found` can be the same type as `expected`
+    fn expect_type(&mut self, expected: Ret, found: Ret) -> Result<(), AssembleError> {
+        if self.unify(expected, found) {
+            return Ok(());
+        }
+        let expected = self.infer.resolve(expected);
+        let found = self.infer.resolve(found);
+        Err(AssembleFunctionError::TypeMismatch { function: self.fn_name(), expected, found }.into())
+    }
+
+    fn expect_return_type(&mut self, found: Ret) -> Result<(), AssembleError> {
+        let expected = match self.return_type {
+            Some(ty) => ty,
+            None => {
+                // the first returned value determines the return type of a closure, which can't
+                // return a reference to one of its locals
+                let found = self.infer.resolve(found);
+                if self.shared.types.contains_ref(found) {
+                    return Err(AssembleFunctionError::UnsupportedType(self.shared.types.type_name(found)).into());
+                }
+                self.return_type = Some(found);
+                return Ok(());
+            },
+        };
+        if self.unify(expected, found) {
+            Ok(())
+        } else {
+            Err(AssembleFunctionError::ReturnTypeMismatch(self.fn_name()).into())
+        }
+    }
+
+    /// Compiles the returned value and moves it to where the caller expects it
+    fn compile_return_value(&mut self, expr: &Expr) -> Result<(), AssembleError> {
+        let found = self.compile_expr(expr, self.return_type)?;
+        self.expect_return_type(found)?;
+        let ty = self.infer.resolve(found);
+        self.check_returned_borrows(ty)?;
+        self.move_return_value(ty);
+        Ok(())
+    }
+
+    /// Moves the value in `rax` / `rdx` / `xmm0` to where the caller expects the result
+    fn move_return_value(&mut self, ty: Ret) {
+        if returns_in_memory(&self.shared.types, ty) {
+            // without a slot this is the first pass of a closure, whose code is discarded
+            if let Some(slot) = self.return_slot {
+                self.asm.load(Reg::Rdx, Reg::Rbp, slot.disp, 8, false);
+                self.store_value(ty, Reg::Rdx, 0);
+                self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+            }
+        } else if is_memory_value(ty) {
+            // copy to a slot first, so that the loads don't read past the end of the struct
+            let slot = self.spill(ty);
+            for (i, part) in value_parts(&classify(&self.shared.types, ty)).iter().enumerate() {
+                self.load_part(*part, Reg::Rbp, slot.disp + 8 * i as i32, 8, false);
+            }
+            self.frame.free(slot);
+        }
+    }
+
+    fn compile_body(&mut self) -> Result<(), AssembleError> {
+        let statements = match self.source.body {
+            FnBody::Block(statements) => statements,
+            // `|x| { .. }`, the block can contain `return`
+            FnBody::Expr(Expr::Block(b)) => &b.block.stmts[..],
+            FnBody::Expr(e) => return self.compile_return_value(e),
+        };
+        let return_type = self.return_type.unwrap_or(Ret::Void);
+
+        let (last, rest) = match statements.split_last() {
+            Some(s) => s,
+            None => {
+                return if return_type == Ret::Void {
+                    Ok(())
+                } else {
+                    Err(AssembleFunctionError::GetReturnTypeError(GetReturnTypeInnerError::EmptyFunction).into())
+                };
+            },
+        };
+
+        for stmt in rest {
+            self.compile_statement(stmt)?;
+        }
+
+        match *last {
+            Stmt::Expr(ref e) => self.compile_return_value(e)?,
+            Stmt::Semi(Expr::Return(_), _) => {
+                self.compile_statement(last)?;
+            },
+            Stmt::Item(Item::Macro(ref m)) if macros::is_diverging(&m.mac) => {
+                self.compile_statement(last)?;
+            },
+            _ => {
+                self.compile_statement(last)?;
+                if return_type != Ret::Void {
+                    return Err(AssembleFunctionError::ReturnTypeMismatch(self.fn_name()).into());
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn compile_statement(&mut self, stmt: &Stmt) -> Result<(), AssembleError> {
+        let temps = self.temps.len();
+        match *stmt {
+            // the value is discarded, `x + 1;` is only evaluated for its side effects
+            Stmt::Semi(ref e, _) => {
+                self.compile_expr(e, None)?;
+            },
+            // `if c { .. }` without semicolon, unless it is the value of the block
+            Stmt::Expr(ref e) => {
+                let found = self.compile_expr(e, Some(Ret::Void))?;
+                self.expect_type(Ret::Void, found)?;
+            },
+            Stmt::Local(ref l) => self.compile_let(l)?,
+            // `panic!(..);`, a macro followed by a semicolon is parsed as an item
+            Stmt::Item(Item::Macro(ref m)) if m.ident.is_none() => {
+                self.compile_macro(&m.mac, None)?;
+            },
+            _ => return Err(unsupported(stmt)),
+        }
+        for slot in self.temps.split_off(temps) {
+            self.frame.free(slot);
+        }
+        self.end_temporary_borrows();
+        Ok(())
+    }
+
+    /// `let pat: ty = init;`, the value is stored in a new slot and the names of the pattern
+    /// live until the end of the enclosing block. The parts of a local are copied instead.
+    fn compile_let(&mut self, local: &::syn::Local) -> Result<(), AssembleError> {
+        if local.pats.len() != 1 {
+            return Err(unsupported(local));
+        }
+        let pat = local.pats.first().unwrap().into_value();
+        let init = match local.init {
+            Some((_, ref init)) => init,
+            // `let x;` needs assignments
+            None => return Err(unsupported(local)),
+        };
+
+        let annotated = match local.ty {
+            Some((_, ref ty)) => Some(self.resolve_type(ty)?),
+            None => None,
+        };
+        let (borrows, locals) = (self.borrows.len(), self.locals.len());
+        if let Some(ty) = self.bind_local(pat, init, annotated)? {
+            // `let t = s;` of a slice of an array borrows the array while `t` is in scope
+            if self.holds_borrow(ty) {
+                self.keep_borrows(borrows, locals);
+            }
+            return Ok(());
+        }
+        let ty = match annotated {
+            Some(ty) => {
+                self.compile_expr_expect(init, ty)?;
+                ty
+            },
+            None => self.compile_expr(init, None)?,
+        };
+
+        let ty = self.infer.resolve(ty);
+        let slot = self.frame.alloc(self.size_of(ty), self.shared.types.align_of(ty));
+        self.local_slots.push(slot);
+        self.store_value(ty, Reg::Rbp, slot.disp);
+        self.bind_irrefutable(pat, ty, slot.disp)?;
+        // `let r = &mut x;` borrows `x` while `r` is in scope, like `let s = &array;`
+        if self.holds_borrow(ty) {
+            self.keep_borrows(borrows, locals);
+        }
+        Ok(())
+    }
+
+    /// Compiles an expression and checks that it has the expected type
+    fn compile_expr_expect(&mut self, expr: &Expr, expected: Ret) -> Result<(), AssembleError> {
+        let found = self.compile_expr(expr, Some(expected))?;
+        self.expect_type(expected, found)
+    }
+
+    /// Compiles an expression, leaving its value in `rax` / `xmm0`. The `expected`
+    /// type is only a hint (i.e. for the type of unsuffixed literals),
+    /// the caller has to check the returned type.
+    fn compile_expr(&mut self, expr: &Expr, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        // a local or a field of a local is loaded directly, which moves it unless it is `Copy`
+        if let Some((ty, disp, local)) = self.local_place(expr) {
+            self.use_place(expr, ty, disp, &local)?;
+            self.load_value(ty, Reg::Rbp, disp);
+            return Ok(ty);
+        }
+        let ty = match *expr {
+            Expr::Lit(ExprLit { lit: Lit::Int(ref i), .. }) => self.compile_int_literal(i, false, expected),
+            Expr::Lit(ExprLit { lit: Lit::Float(ref f), .. }) => self.compile_float_literal(f, expected),
+            Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => {
+                self.compile_data_literal(s.value().as_bytes());
+                Ok(Ret::Str)
+            },
+            Expr::Lit(ExprLit { lit: Lit::ByteStr(ref s), .. }) => {
+                self.compile_data_literal(&s.value());
+                Ok(self.shared.types.slice_type(Ret::Int(StaticIntLiteral::U8), false))
+            },
+            Expr::Lit(ExprLit { lit: Lit::Char(ref c), .. }) => {
+                // a `char` from the parser is always a valid Unicode scalar value
+                self.asm.mov_ri(Reg::Rax, u64::from(u32::from(c.value())));
+                Ok(Ret::Char)
+            },
+            Expr::Lit(ExprLit { lit: Lit::Byte(ref b), .. }) => {
+                self.asm.mov_ri(Reg::Rax, u64::from(b.value()));
+                Ok(Ret::Int(StaticIntLiteral::U8))
+            },
+            Expr::Lit(ExprLit { lit: Lit::Bool(ref b), .. }) => {
+                self.asm.mov_ri(Reg::Rax, b.value as u64);
+                Ok(Ret::Bool)
+            },
+            Expr::Paren(ref p) => self.compile_expr(&p.expr, expected),
+            Expr::Group(ref g) => self.compile_expr(&g.expr, expected),
+            Expr::Unary(ref u) => self.compile_unary(u, expected),
+            Expr::Binary(ref b) => self.compile_binary(b, expected),
+            Expr::Cast(ref c) => self.compile_cast(c),
+            Expr::If(ref i) => self.compile_if(i, expected),
+            Expr::Block(ref b) => self.compile_block(&b.block, expected),
+            Expr::Unsafe(ref u) => self.compile_unsafe(&u.block, expected),
+            Expr::Path(ref p) if self.variant_name(&p.path) == Some("None") => self.compile_none(p, expected),
+            Expr::Path(ref p) => self.compile_path(p),
+            Expr::Call(ref c) => match *c.func {
+                Expr::Path(ref p) if self.variant_name(&p.path).is_some() => self.compile_variant(c, &p.path, expected),
+                _ => self.compile_call(c),
+            },
+            Expr::Try(ref t) => self.compile_try(t),
+            Expr::IfLet(ref i) => self.compile_if_let(i, expected),
+            Expr::WhileLet(ref w) => self.compile_while_let(w),
+            Expr::ForLoop(ref f) => self.compile_for_loop(f),
+            Expr::Match(ref m) => self.compile_match(m, expected),
+            Expr::Closure(ref c) => self.compile_closure(c, expected),
+            Expr::Struct(ref s) => self.compile_struct(s),
+            Expr::Tuple(ref t) => self.compile_tuple(t, expected),
+            Expr::Array(ref a) => self.compile_array(a, expected),
+            Expr::Repeat(ref r) => self.compile_repeat(r, expected),
+            Expr::Field(ref f) => self.compile_field(f),
+            Expr::MethodCall(ref m) => self.compile_method_call(m),
+            Expr::Return(ref r) => self.compile_return(r),
+            Expr::Continue(ref c) => self.compile_continue(c, expected),
+            Expr::Break(ref b) => self.compile_break(b, expected),
+            Expr::Assign(ref a) => self.compile_assign(a),
+            Expr::AssignOp(ref a) => self.compile_assign_op(a),
+            Expr::Index(ref i) => self.compile_index(i),
+            Expr::Reference(ref r) => self.compile_reference(r, expected),
+            Expr::Macro(ref m) => self.compile_macro(&m.mac, expected),
+            _ => Err(unsupported(expr)),
+        }?;
+        Ok(self.infer.resolve(ty))
+    }
+
+    fn compile_int_literal(&mut self, lit: &LitInt, negative: bool, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+
+        let ty = match StaticIntLiteral::from_suffix(lit.suffix()) {
+            Some(ty) => ty,
+            None => {
+                let ty = match self.infer.int_literal(lit) {
+                    Some(ty) => ty,
+                    None => {
+                        let ty = match expected {
+                            Some(ty @ Ret::Int(_)) => ty,
+                            _ => self.infer.new_int(),
+                        };
+                        self.infer.set_int_literal(lit, ty);
+                        ty
+                    },
+                };
+                match ty {
+                    Ret::Int(i) => i,
+                    _ => unreachable!("integer literal with type {:?}", ty),
+                }
+            },
+        };
+
+        // the range can only be checked once the type is known
+        let value = lit.value();
+        if !self.infer.is_unknown(Ret::Int(ty)) && try_match_u64_value(value, negative, &ty).is_err() {
+            let sign = if negative { "-" } else { "" };
+            return Err(self.literal_out_of_range(format!("{}{}", sign, lit.into_token_stream()), Ret::Int(ty)));
+        }
+
+        let bits = if negative { value.wrapping_neg() } else { value };
+        self.asm.mov_ri(Reg::Rax, bits);
+        if ty.size() == 16 {
+            // the high half of a negative literal is all ones
+            self.asm.mov_ri(Reg::Rdx, if negative && value != 0 { u64::MAX } else { 0 });
+        }
+        Ok(Ret::Int(ty))
+    }
+
+    fn compile_float_literal(&mut self, lit: &LitFloat, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let ty = match lit.suffix() {
+            FloatSuffix::F32 => Ret::Float(StaticFloatLiteral::F32),
+            FloatSuffix::F64 => Ret::Float(StaticFloatLiteral::F64),
+            FloatSuffix::None => match self.infer.float_literal(lit) {
+                Some(ty) => ty,
+                None => {
+                    let ty = match expected {
+                        Some(ty @ Ret::Float(_)) => ty,
+                        _ => self.infer.new_float(),
+                    };
+                    self.infer.set_float_literal(lit, ty);
+                    ty
+                },
+            },
+        };
+
+        let value = lit.value();
+        let bits = match ty {
+            Ret::Float(StaticFloatLiteral::F32) => {
+                if value.is_finite() && (value as f32).is_infinite() {
+                    return Err(self.literal_out_of_range(lit.into_token_stream().to_string(), ty));
+                }
+                u64::from((value as f32).to_bits())
+            },
+            _ => value.to_bits(),
+        };
+
+        self.asm.mov_ri(Reg::Rax, bits);
+        self.asm.movq_xr(Xmm::Xmm0, Reg::Rax);
+        Ok(ty)
+    }
+
+    fn literal_out_of_range(&self, literal: String, ty: Ret) -> AssembleError {
+        AssembleFunctionError::LiteralOutOfRange { function: self.fn_name(), literal, ty }.into()
+    }
+
+    /// Places a string literal in the read-only data and loads its address and length
+    fn compile_data_literal(&mut self, data: &[u8]) {
+        let offset = self.shared.rodata.intern(data);
+        self.asm.lea_rodata(Reg::Rax, offset);
+        self.asm.mov_ri(Reg::Rdx, data.len() as u64);
+    }
+
+    fn compile_unary(&mut self, u: &ExprUnary, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        match u.op {
+            UnOp::Neg(_) => {
+                // fold negative literals, so that i.e. `-128i8` fits
+                if let Expr::Lit(ExprLit { lit: Lit::Int(ref i), .. }) = *u.expr {
+                    return self.compile_int_literal(i, true, expected);
+                }
+                let ty = self.compile_expr(&u.expr, expected)?;
+                match ty {
+                    // the type of an unsuffixed literal is only known in the second pass
+                    Ret::Int(StaticIntLiteral::UnknownSize(_)) => { },
+                    Ret::Int(i) if i.is_signed() => { },
+                    Ret::Float(f) => {
+                        // flip the sign bit
+                        let sign = if f.is_double() { 1 << 63 } else { 1 << 31 };
+                        self.asm.mov_ri(Reg::Rax, sign);
+                        self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+                        self.asm.xorps(Xmm::Xmm0, Xmm::Xmm1);
+                        return Ok(ty);
+                    },
+                    Ret::Vec(_) | Ret::Quat => {
+                        self.compile_vec_neg();
+                        return Ok(ty);
+                    },
+                    Ret::Simd(simd) => return self.compile_simd_unary(u, simd),
+                    Ret::Fixed => {
+                        self.check_negation(StaticIntLiteral::I64, u);
+                        self.asm.neg(Reg::Rax);
+                        return Ok(ty);
+                    },
+                    Ret::Struct(_) if self.implements("Neg", ty) => return self.compile_operator_call("Neg", ty, None),
+                    Ret::Struct(_) => return Err(self.missing_impl("Neg", ty)),
+                    _ => return Err(unsupported(u)),
+                }
+                if wide::is_wide(self.infer.resolve(ty)) {
+                    self.negate_wide(u);
+                    return Ok(ty);
+                }
+                if let Ret::Int(i) = self.infer.resolve(ty) {
+                    self.check_negation(i, u);
+                }
+                self.asm.neg(Reg::Rax);
+                self.normalize(ty);
+                Ok(ty)
+            },
+            UnOp::Not(_) => {
+                let ty = self.compile_expr(&u.expr, expected)?;
+                match ty {
+                    Ret::Int(_) => {
+                        self.asm.not(Reg::Rax);
+                        if wide::is_wide(ty) {
+                            self.asm.not(Reg::Rdx);
+                        }
+                        self.normalize(ty);
+                        Ok(ty)
+                    },
+                    Ret::Bool => {
+                        self.asm.alu_ri(AluOp::Xor, Reg::Rax, 1);
+                        Ok(ty)
+                    },
+                    Ret::Simd(simd) => self.compile_simd_unary(u, simd),
+                    _ => Err(unsupported(u)),
+                }
+            },
+            // `*name` of a `ref` binding or of a reference
+            UnOp::Deref(_) => match *u.expr {
+                Expr::Path(ref p) if p.qself.is_none() && self.is_ref_binding(&p.path) => self.compile_path(p),
+                ref e => match self.compile_expr(e, None)? {
+                    reference @ Ret::Ref(_) => self.compile_deref(u, reference),
+                    _ => Err(unsupported(u)),
+                },
+            },
+        }
+    }
+
+    fn compile_binary(&mut self, b: &ExprBinary, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        match b.op {
+            BinOp::And(_) | BinOp::Or(_) => self.compile_logical(b),
+            BinOp::Eq(_) | BinOp::Ne(_) | BinOp::Lt(_) | BinOp::Le(_) | BinOp::Gt(_) | BinOp::Ge(_) => {
+                self.compile_comparison(b)
+            },
+            _ => self.compile_arithmetic(b, expected),
+        }
+    }
+
+    fn compile_comparison(&mut self, b: &ExprBinary) -> Result<Ret, AssembleError> {
+        // the left side determines the type of an unsuffixed literal on the right side
+        let ty = self.compile_expr(&b.left, None)?;
+        match ty {
+            Ret::Int(_) | Ret::Char | Ret::Bool | Ret::Float(_) | Ret::Fixed | Ret::FnPtr(_) => { },
+            _ => return Err(unsupported(b)),
+        }
+        let slot = self.spill(ty);
+        self.compile_expr_expect(&b.right, ty)?;
+        let ty = self.infer.resolve(ty);
+        self.compare_with_slot(&b.op, ty, slot);
+        self.frame.free(slot);
+        Ok(Ret::Bool)
+    }
+
+    /// Compares the value in the slot (left side) with the value in `rax` / `xmm0` (right side),
+    /// the result is in `rax`
+    fn compare_with_slot(&mut self, op: &BinOp, ty: Ret, slot: Slot) {
+        if wide::is_wide(ty) {
+            self.compare_wide(op, ty.is_signed(), slot);
+            return;
+        }
+        if let Ret::Float(f) = ty {
+            self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+            self.load_value(ty, Reg::Rbp, slot.disp);
+            self.compare_floats(op, f.is_double());
+            return;
+        }
+
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.load_value(ty, Reg::Rbp, slot.disp);
+
+        let (signed_cond, unsigned_cond) = match *op {
+            BinOp::Eq(_) => (Cond::Equal, Cond::Equal),
+            BinOp::Ne(_) => (Cond::NotEqual, Cond::NotEqual),
+            BinOp::Lt(_) => (Cond::Less, Cond::Below),
+            BinOp::Le(_) => (Cond::LessEqual, Cond::BelowEqual),
+            BinOp::Gt(_) => (Cond::Greater, Cond::Above),
+            _ => (Cond::GreaterEqual, Cond::AboveEqual),
+        };
+
+        // both sides are normalized, so comparing all 64 bits is correct for every size
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+        self.asm.setcc(if ty.is_signed() { signed_cond } else { unsigned_cond }, Reg::Rax);
+    }
+
+    /// Compares `xmm0` (left side) with `xmm1` (right side). `ucomis` sets the flags like an
+    /// unsigned comparison, NaN sets ZF, PF and CF, so that every comparison with NaN is false.
+    fn compare_floats(&mut self, op: &BinOp, double: bool) {
+        match *op {
+            BinOp::Gt(_) | BinOp::Ge(_) => self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, double),
+            _ => self.asm.ucomis(Xmm::Xmm1, Xmm::Xmm0, double),
+        }
+        match *op {
+            BinOp::Gt(_) | BinOp::Lt(_) => self.asm.setcc(Cond::Above, Reg::Rax),
+            BinOp::Ge(_) | BinOp::Le(_) => self.asm.setcc(Cond::AboveEqual, Reg::Rax),
+            BinOp::Eq(_) => {
+                self.asm.setcc(Cond::Equal, Reg::Rax);
+                self.asm.setcc(Cond::NoParity, Reg::Rcx);
+                self.asm.alu_rr(AluOp::And, Reg::Rax, Reg::Rcx);
+            },
+            _ => {
+                self.asm.setcc(Cond::NotEqual, Reg::Rax);
+                self.asm.setcc(Cond::Parity, Reg::Rcx);
+                self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rcx);
+            },
+        }
+    }
+
+    /// `+ - * / % & | ^ << >>`. Integer arithmetic wraps around, unless overflow is checked.
+    fn compile_arithmetic(&mut self, b: &ExprBinary, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let ty = self.compile_expr(&b.left, expected)?;
+        if let Some(trait_name) = operators::binary_trait(&b.op).filter(|t| self.implements(t, ty)) {
+            return self.compile_operator_call(trait_name, ty, Some(&b.right));
+        }
+        if let (Ret::Struct(_), Some(trait_name)) = (ty, operators::operator_trait(&b.op)) {
+            return Err(self.missing_impl(trait_name, ty));
+        }
+        let slot = self.spill(ty);
+        let right = self.compile_right_operand(&b.op, ty, &b.right)?;
+        self.arithmetic_op(b, b, ty, right, slot)
+    }
+
+    /// Compiles the right side of an arithmetic operator whose left side has the type `ty`
+    fn compile_right_operand(&mut self, op: &BinOp, ty: Ret, right: &Expr) -> Result<Ret, AssembleError> {
+        match ty {
+            // the shift amount can have any integer type
+            _ if matches!(*op, BinOp::Shl(_) | BinOp::Shr(_)) => self.compile_expr(right, None),
+            // the right side is a vector, a quaternion, a matrix or an f32 scalar
+            Ret::Vec(_) | Ret::Quat | Ret::Mat(_) => self.compile_expr(right, Some(Ret::Float(StaticFloatLiteral::F32))),
+            Ret::Simd(_) => {
+                self.compile_expr_expect(right, ty)?;
+                Ok(ty)
+            },
+            _ => self.compile_expr(right, Some(ty)),
+        }
+    }
+
+    /// Like rustc in a debug build, traps if the amount of a shift in `rax` / `rdx:rax` is
+    /// negative or not less than the width of the shifted type
+    fn check_shift_amount<T: ToTokens>(&mut self, ty: StaticIntLiteral, amount: StaticIntLiteral, node: &T) {
+        let error = ScriptError::Overflow(self.location(node));
+        if amount.size() == 16 {
+            self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+            self.trap_if(Cond::NotEqual, error.clone());
+        }
+        self.asm.alu_ri(AluOp::Cmp, Reg::Rax, ty.size() * 8);
+        self.trap_if(Cond::AboveEqual, error);
+    }
+
+    /// Applies the operator of `b` to the left side, which was spilled to `slot`, and the right
+    /// side of type `right` in `rax` / `xmm0`. Frees the slot. Traps report the location of `node`.
+    fn arithmetic_op<T: ToTokens>(&mut self, b: &ExprBinary, node: &T, ty: Ret, right: Ret, slot: Slot) -> Result<Ret, AssembleError> {
+        let is_shift = matches!(b.op, BinOp::Shl(_) | BinOp::Shr(_));
+        let amount_ty = match ty {
+            _ if is_shift => right,
+            Ret::Mat(mat) => return self.compile_mat_arithmetic(b, mat, right, slot),
+            Ret::Quat => return self.compile_quat_arithmetic(b, right, slot),
+            Ret::Vec(_) => return self.compile_vec_arithmetic(b, ty, right, slot),
+            Ret::Simd(simd) => return self.compile_simd_arithmetic(b, simd, slot),
+            _ => {
+                if let Ret::Vec(_) = right {
+                    return self.compile_vec_arithmetic(b, ty, right, slot);
+                }
+                self.expect_type(ty, right)?;
+                ty
+            },
+        };
+        let ty = self.infer.resolve(ty);
+        if let (true, Ret::Int(i), Ret::Int(amount)) = (is_shift, ty, self.infer.resolve(amount_ty)) {
+            if self.checks_overflow() {
+                self.check_shift_amount(i, amount, node);
+            }
+        }
+
+        match ty {
+            Ret::Float(f) => {
+                let op = match b.op {
+                    BinOp::Add(_) => FloatOp::Add,
+                    BinOp::Sub(_) => FloatOp::Sub,
+                    BinOp::Mul(_) => FloatOp::Mul,
+                    BinOp::Div(_) => FloatOp::Div,
+                    _ => return Err(unsupported(b)),
+                };
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.load_value(ty, Reg::Rbp, slot.disp);
+                self.asm.float_op(op, Xmm::Xmm0, Xmm::Xmm1, f.is_double());
+            },
+            Ret::Int(_) if is_shift && !matches!(amount_ty, Ret::Int(_)) => return Err(unsupported(b)),
+            Ret::Int(i) if i.size() == 16 => self.wide_arithmetic(b, node, i, slot)?,
+            Ret::Fixed => self.compile_fixed_arithmetic(b, node, slot)?,
+            Ret::Int(i) => {
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.load_value(ty, Reg::Rbp, slot.disp);
+                let signed = i.is_signed();
+                match b.op {
+                    BinOp::Add(_) | BinOp::Sub(_) | BinOp::Mul(_) if self.checks_overflow() => {
+                        let op = match b.op {
+                            BinOp::Add(_) => IntOp::Add,
+                            BinOp::Sub(_) => IntOp::Sub,
+                            _ => IntOp::Mul,
+                        };
+                        let overflow = self.overflowing_int_op(op, i);
+                        let error = ScriptError::Overflow(self.location(node));
+                        self.trap_if(overflow, error);
+                    },
+                    BinOp::Add(_) => self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx),
+                    BinOp::Sub(_) => self.asm.alu_rr(AluOp::Sub, Reg::Rax, Reg::Rcx),
+                    BinOp::Mul(_) => self.asm.imul_rr(Reg::Rax, Reg::Rcx),
+                    BinOp::BitAnd(_) => self.asm.alu_rr(AluOp::And, Reg::Rax, Reg::Rcx),
+                    BinOp::BitOr(_) => self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rcx),
+                    BinOp::BitXor(_) => self.asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::Rcx),
+                    BinOp::Div(_) | BinOp::Rem(_) => {
+                        self.check_divisor(i, node);
+                        if signed {
+                            self.asm.cqo();
+                        } else {
+                            self.asm.alu_rr(AluOp::Xor, Reg::Rdx, Reg::Rdx);
+                        }
+                        self.asm.div(Reg::Rcx, signed);
+                        if let BinOp::Rem(_) = b.op {
+                            self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+                        }
+                    },
+                    BinOp::Shl(_) | BinOp::Shr(_) => {
+                        // like `wrapping_shl`, the amount is masked to the width of the type
+                        self.asm.alu_ri(AluOp::And, Reg::Rcx, i.size() * 8 - 1);
+                        let op = match b.op {
+                            BinOp::Shl(_) => ShiftOp::Shl,
+                            _ if signed => ShiftOp::Sar,
+                            _ => ShiftOp::Shr,
+                        };
+                        self.asm.shift_cl(op, Reg::Rax);
+                    },
+                    _ => return Err(unsupported(b)),
+                }
+                self.normalize(ty);
+            },
+            Ret::Bool => {
+                let op = match b.op {
+                    BinOp::BitAnd(_) => AluOp::And,
+                    BinOp::BitOr(_) => AluOp::Or,
+                    BinOp::BitXor(_) => AluOp::Xor,
+                    _ => return Err(unsupported(b)),
+                };
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.load_value(ty, Reg::Rbp, slot.disp);
+                self.asm.alu_rr(op, Reg::Rax, Reg::Rcx);
+            },
+            _ => return Err(unsupported(b)),
+        }
+
+        self.frame.free(slot);
+        Ok(ty)
+    }
+
+    /// `&&` and `||`, the right side is only evaluated if the left side does not decide the result
+    fn compile_logical(&mut self, b: &ExprBinary) -> Result<Ret, AssembleError> {
+        let end = self.asm.new_label();
+        self.compile_expr_expect(&b.left, Ret::Bool)?;
+        self.asm.test_rr(Reg::Rax, Reg::Rax);
+        match b.op {
+            BinOp::And(_) => self.asm.jcc(Cond::Equal, end),
+            _ => self.asm.jcc(Cond::NotEqual, end),
+        }
+        self.compile_expr_expect(&b.right, Ret::Bool)?;
+        self.asm.bind(end);
+        Ok(Ret::Bool)
+    }
+
+    /// `expr as ty` between numbers, `char`, `bool`, `u8 as char` and function pointers to integers, with the semantics of Rust:
+    /// narrowing truncates, widening sign-extends if the source is signed and
+    /// float to integer conversions saturate
+    fn compile_cast(&mut self, c: &ExprCast) -> Result<Ret, AssembleError> {
+        let to = self.resolve_value_type(&c.ty)?;
+        let from = self.compile_expr(&c.expr, None)?;
+
+        let valid = match (from, to) {
+            _ if from == to => true,
+            (Ret::Int(_), Ret::Int(_)) | (Ret::Char, Ret::Int(_)) | (Ret::Bool, Ret::Int(_)) => true,
+            (Ret::FnPtr(_), Ret::Int(_)) => true,
+            (Ret::Int(StaticIntLiteral::U8), Ret::Char) => true,
+            // the type of an unsuffixed literal is only known in the second pass
+            (Ret::Int(StaticIntLiteral::UnknownSize(_)), Ret::Char) => true,
+            (Ret::Int(_), Ret::Float(_)) | (Ret::Float(_), Ret::Int(_)) | (Ret::Float(_), Ret::Float(_)) => true,
+            _ => false,
+        };
+
+        if !valid {
+            return Err(AssembleFunctionError::InvalidCast { function: self.fn_name(), from, to }.into());
+        }
+
+        match (from, to) {
+            (Ret::Float(f), Ret::Float(t)) => if f.is_double() != t.is_double() {
+                self.asm.cvt_float(Xmm::Xmm0, Xmm::Xmm0, t.is_double());
+            },
+            (Ret::Int(i), Ret::Float(t)) if i.size() == 16 => self.wide_to_float(i.is_signed(), t.is_double()),
+            (Ret::Float(f), Ret::Int(t)) if t.size() == 16 => self.float_to_wide(t.is_signed(), f.is_double()),
+            (Ret::Int(i), Ret::Float(t)) => self.int_to_float(i, t),
+            (Ret::Float(f), Ret::Int(t)) => self.float_to_int(f, t),
+            _ if wide::is_wide(to) && !wide::is_wide(from) => self.widen(from.is_signed()),
+            _ => self.normalize(to),
+        }
+        Ok(to)
+    }
+
+    /// Converts the integer in `rax` to a float in `xmm0`
+    fn int_to_float(&mut self, from: StaticIntLiteral, to: StaticFloatLiteral) {
+        let double = to.is_double();
+        if !matches!(from, StaticIntLiteral::U64 | StaticIntLiteral::Usize) {
+            // all other types are normalized to a value that is also a valid i64
+            self.asm.cvt_int_to_float(Xmm::Xmm0, Reg::Rax, double);
+            return;
+        }
+
+        let large = self.asm.new_label();
+        let done = self.asm.new_label();
+        self.asm.test_rr(Reg::Rax, Reg::Rax);
+        self.asm.jcc(Cond::Sign, large);
+        self.asm.cvt_int_to_float(Xmm::Xmm0, Reg::Rax, double);
+        self.asm.jmp(done);
+        // halve the value (keeping the lowest bit, so that it rounds correctly), convert and double it
+        self.asm.bind(large);
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 1);
+        self.asm.alu_ri(AluOp::And, Reg::Rax, 1);
+        self.asm.alu_rr(AluOp::Or, Reg::Rcx, Reg::Rax);
+        self.asm.cvt_int_to_float(Xmm::Xmm0, Reg::Rcx, double);
+        self.asm.float_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm0, double);
+        self.asm.bind(done);
+    }
+
+    /// Converts the float in `xmm0` to an integer in `rax`. Like in Rust, NaN becomes 0
+    /// and values outside of the range of the type become its minimum or maximum.
+    fn float_to_int(&mut self, from: StaticFloatLiteral, to: StaticIntLiteral) {
+        if !from.is_double() {
+            self.asm.cvt_float(Xmm::Xmm0, Xmm::Xmm0, true);
+        }
+
+        let bits = to.size() * 8;
+        // the bounds are powers of two, so they are exact as f64
+        let (lower, upper, min, max) = if to.is_signed() {
+            let half = 2f64.powi(bits - 1);
+            (-half, half, (-1i64 << (bits - 1)) as u64, (1u64 << (bits - 1)) - 1)
+        } else {
+            (0.0, 2f64.powi(bits), 0, u64::MAX >> (64 - bits))
+        };
+
+        let done = self.asm.new_label();
+        self.asm.mov_ri(Reg::Rax, 0);
+        self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm0, true);
+        self.asm.jcc(Cond::Parity, done);
+
+        self.asm.mov_ri(Reg::Rax, min);
+        self.asm.mov_ri(Reg::Rcx, lower.to_bits());
+        self.asm.movq_xr(Xmm::Xmm1, Reg::Rcx);
+        self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, true);
+        self.asm.jcc(Cond::Below, done);
+
+        self.asm.mov_ri(Reg::Rax, max);
+        self.asm.mov_ri(Reg::Rcx, upper.to_bits());
+        self.asm.movq_xr(Xmm::Xmm1, Reg::Rcx);
+        self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, true);
+        self.asm.jcc(Cond::AboveEqual, done);
+
+        if matches!(to, StaticIntLiteral::U64 | StaticIntLiteral::Usize) {
+            // cvttsd2si only converts to i64, values from 2^63 on are converted with the top bit cleared
+            let small = self.asm.new_label();
+            self.asm.mov_ri(Reg::Rcx, 2f64.powi(63).to_bits());
+            self.asm.movq_xr(Xmm::Xmm1, Reg::Rcx);
+            self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, true);
+            self.asm.jcc(Cond::Below, small);
+            self.asm.float_op(FloatOp::Sub, Xmm::Xmm0, Xmm::Xmm1, true);
+            self.asm.cvt_float_to_int(Reg::Rax, Xmm::Xmm0, true);
+            self.asm.mov_ri(Reg::Rcx, 1 << 63);
+            self.asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::Rcx);
+            self.asm.jmp(done);
+            self.asm.bind(small);
+        }
+
+        self.asm.cvt_float_to_int(Reg::Rax, Xmm::Xmm0, true);
+        self.asm.bind(done);
+        self.normalize(Ret::Int(to));
+    }
+
+    fn compile_if(&mut self, i: &ExprIf, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let else_label = self.asm.new_label();
+        let end = self.asm.new_label();
+
+        self.compile_expr_expect(&i.cond, Ret::Bool)?;
+        self.asm.test_rr(Reg::Rax, Reg::Rax);
+        self.asm.jcc(Cond::Equal, else_label);
+        let mut branches = self.start_branches();
+
+        let else_branch = match i.else_branch {
+            Some((_, ref e)) => e,
+            None => {
+                // without an `else`, the block can't produce a value
+                self.compile_block_expect(&i.then_branch, Ret::Void)?;
+                self.end_branch(&mut branches);
+                self.end_branch(&mut branches);
+                self.end_branches(branches);
+                self.asm.bind(else_label);
+                self.asm.bind(end);
+                return Ok(Ret::Void);
+            },
+        };
+
+        let ty = self.compile_block(&i.then_branch, expected)?;
+        self.end_branch(&mut branches);
+        self.asm.jmp(end);
+        self.asm.bind(else_label);
+        let found = self.compile_else_branch(else_branch, ty)?;
+        self.end_branch(&mut branches);
+        self.end_branches(branches);
+        self.expect_type(ty, found)?;
+        self.asm.bind(end);
+        Ok(self.infer.resolve(ty))
+    }
+
+    /// Compiles the statements of a block, the value of the block is the value of
+    /// a trailing expression without semicolon, otherwise `()`
+    fn compile_block(&mut self, block: &Block, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let scope = self.enter_scope();
+        let ty = match block.stmts.split_last() {
+            None => Ret::Void,
+            Some((last, rest)) => {
+                for stmt in rest {
+                    self.compile_statement(stmt)?;
+                }
+                match *last {
+                    Stmt::Expr(ref e) => self.compile_expr(e, expected)?,
+                    _ => {
+                        self.compile_statement(last)?;
+                        Ret::Void
+                    },
+                }
+            },
+        };
+        self.exit_scope(scope, ty)?;
+        Ok(ty)
+    }
+
+    /// `unsafe { .. }`, a block that can use `static mut` items
+    fn compile_unsafe(&mut self, block: &Block, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let in_unsafe = mem::replace(&mut self.in_unsafe, true);
+        let ty = self.compile_block(block, expected);
+        self.in_unsafe = in_unsafe;
+        ty
+    }
+
+    /// Checks that a `static mut` is only used in an `unsafe` block
+    fn check_static_use(&self, path: &ExprPath, mutable: bool) -> Result<(), AssembleError> {
+        if mutable && !self.in_unsafe {
+            let name = path.path.clone().into_token_stream().to_string();
+            return Err(AssembleFunctionError::UseOfMutableStatic { function: self.fn_name(), name }.into());
+        }
+        Ok(())
+    }
+
+    /// Starts a block or match arm, the locals declared in it are visible until `exit_scope`
+    fn enter_scope(&self) -> Scope {
+        Scope { locals: self.locals.len(), slots: self.local_slots.len() }
+    }
+
+    /// Removes the locals of the scope and frees their slots for the following statements.
+    /// A value in memory that is the result of the scope is moved to a temporary first. No
+    /// reference may refer to the freed slots.
+    fn exit_scope(&mut self, scope: Scope, result: Ret) -> Result<(), AssembleError> {
+        self.end_borrows(scope.locals);
+        let slots = self.local_slots.split_off(scope.slots);
+        self.check_dangling(&scope, &slots, result)?;
+        self.locals.truncate(scope.locals);
+        self.moved.retain(|m| !slots.iter().any(|slot| m.overlaps(slot.disp, slot.size)));
+        let result = self.infer.resolve(result);
+        if !slots.is_empty() && is_memory_value(result) {
+            let temp = self.alloc_temp(result);
+            self.store_value(result, Reg::Rbp, temp.disp);
+            self.asm.lea(Reg::Rax, Reg::Rbp, temp.disp);
+        }
+        for slot in slots {
+            self.frame.free(slot);
+        }
+        Ok(())
+    }
+
+    fn compile_block_expect(&mut self, block: &Block, expected: Ret) -> Result<(), AssembleError> {
+        let found = self.compile_block(block, Some(expected))?;
+        self.expect_type(expected, found)
+    }
+
+    /// Sign- or zero-extends the value in `rax` according to its type
+    fn normalize(&mut self, ty: Ret) {
+        if !matches!(ty, Ret::Int(_) | Ret::Char | Ret::Bool) {
+            return;
+        }
+        let size = ty.size();
+        if size > 0 && size < 8 {
+            self.asm.extend(Reg::Rax, Reg::Rax, size as u8, ty.is_signed());
+        }
+    }
+
+    fn find_local(&self, name: &str) -> Option<&Local> {
+        self.locals.iter().rev().find(|l| l.name == name)
+    }
+
+    fn compile_path(&mut self, p: &ExprPath) -> Result<Ret, AssembleError> {
+        if let Some((i, name)) = int_type_item(p) {
+            return self.compile_int_constant(i, &name);
+        }
+        if let Some((ty, name)) = builtin_type_item(p) {
+            return match ty {
+                Ret::Vec(vec) => self.compile_vec_constant(vec, &name),
+                Ret::Mat(mat) => self.compile_mat_constant(mat, &name),
+                Ret::Simd(_) => Err(self.unknown_method(ty, &name)),
+                Ret::Fixed => self.compile_fixed_constant(&name),
+                _ => self.compile_quat_constant(&name),
+            };
+        }
+        if p.qself.is_none() && p.path.leading_colon.is_none() && p.path.segments.len() == 1 {
+            let name = p.path.segments[0].ident.to_string();
+            if let Some(local) = self.find_local(&name).cloned() {
+                self.load_value(local.ty, Reg::Rbp, local.slot.disp);
+                return Ok(local.ty);
+            }
+            if self.captures.contains(&name) {
+                return Err(AssembleFunctionError::CapturingClosure { function: self.fn_name(), name }.into());
+            }
+        }
+
+        let program = self.program;
+        match program.modules.resolve_path(self.source.module, &p.path, Namespace::Value)? {
+            Def::Fn(label) => {
+                self.asm.lea_fn(Reg::Rax, label);
+                Ok(self.shared.types.fn_type(program.signatures[&label].clone()))
+            },
+            Def::HostFn(index) => {
+                let host_fn = &program.host_functions[index];
+                self.asm.mov_ri(Reg::Rax, host_fn.address as u64);
+                Ok(self.shared.types.fn_type(host_fn.signature.clone()))
+            },
+            Def::Static(index) => {
+                let s = &program.statics[index];
+                self.check_static_use(p, s.mutable)?;
+                self.asm.lea_rodata(Reg::Rcx, s.offset);
+                self.load_value(s.ty, Reg::Rcx, 0);
+                Ok(s.ty)
+            },
+            Def::Ctor(id) if self.shared.types.struct_def(id).kind == StructKind::Unit => {
+                self.check_fields_visible(id)?;
+                let ty = Ret::Struct(id);
+                let slot = self.alloc_temp(ty);
+                self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                Ok(ty)
+            },
+            _ => Err(unsupported(p)),
+        }
+    }
+
+    /// Whether the path names a local of this function or of an enclosing function
+    fn is_local_path(&self, path: &Path) -> bool {
+        if path.leading_colon.is_some() || path.segments.len() != 1 {
+            return false;
+        }
+        let name = path.segments[0].ident.to_string();
+        self.find_local(&name).is_some() || self.captures.contains(&name)
+    }
+
+    fn compile_call(&mut self, c: &ExprCall) -> Result<Ret, AssembleError> {
+        if let Expr::Path(ref p) = *c.func {
+            if let Some((ty, name)) = builtin_type_item(p) {
+                let args = c.args.iter().collect::<Vec<_>>();
+                return match ty {
+                    Ret::Vec(vec) => self.compile_vec_constructor(vec, &name, &args),
+                    Ret::Mat(mat) => self.compile_mat_constructor(mat, &name, &args),
+                    Ret::Simd(simd) => self.compile_simd_constructor(simd, &name, &args),
+                    Ret::Fixed => self.compile_fixed_constructor(&name, &args),
+                    _ => self.compile_quat_constructor(&name, &args),
+                };
+            }
+        }
+
+        let program = self.program;
+        let (signature, target) = match *c.func {
+            Expr::Path(ref p) if p.qself.is_none() && !self.is_local_path(&p.path) => {
+                match program.modules.resolve_path(self.source.module, &p.path, Namespace::Value)? {
+                    Def::Fn(label) => (program.signatures[&label].clone(), CallTarget::Script(label)),
+                    Def::HostFn(index) => {
+                        let host_fn = &program.host_functions[index];
+                        (host_fn.signature.clone(), CallTarget::Host(host_fn.address))
+                    },
+                    Def::Ctor(id) => return self.compile_tuple_struct(id, c),
+                    _ => return Err(AssembleFunctionError::NotAFunction(path_to_string(&p.path)).into()),
+                }
+            },
+            ref callee => {
+                // the callee is evaluated before the arguments
+                let id = match self.compile_expr(callee, None)? {
+                    ty @ Ret::FnPtr(_) if self.infer.is_placeholder(ty) => {
+                        let callee = callee.into_token_stream().to_string();
+                        return Err(AssembleFunctionError::TypeAnnotationNeeded(callee).into());
+                    },
+                    Ret::FnPtr(id) => id,
+                    _ => return Err(AssembleFunctionError::NotAFunction(callee.into_token_stream().to_string()).into()),
+                };
+                let slot = self.spill(Ret::FnPtr(id));
+                (self.shared.types.signature(id).clone(), CallTarget::Indirect(slot))
+            },
+        };
+
+        if signature.arguments.len() != c.args.len() {
+            return Err(AssembleFunctionError::WrongArgumentCount {
+                function: c.func.clone().into_token_stream().to_string(),
+                expected: signature.arguments.len(),
+                found: c.args.len(),
+            }.into());
+        }
+
+        // evaluate all arguments first, since evaluating an argument may clobber argument registers
+        let mut temporaries = Vec::with_capacity(c.args.len());
+        for (arg, ty) in c.args.iter().zip(signature.arguments.iter()) {
+            self.compile_expr_expect(arg, *ty)?;
+            temporaries.push(self.store_argument(*ty));
+        }
+        Ok(self.call_with_arguments(&signature, target, temporaries))
+    }
+
+    /// Stores the value of an argument in a new slot until the call
+    fn store_argument(&mut self, ty: Ret) -> Slot {
+        let ty = self.infer.resolve(ty);
+        let slot = self.frame.alloc(self.eightbytes_size(ty), self.slot_align(ty));
+        if is_memory_value(ty) {
+            self.store_value(ty, Reg::Rbp, slot.disp);
+        } else {
+            for (i, part) in value_parts(&classify(&self.shared.types, ty)).iter().enumerate() {
+                self.store_part(*part, Reg::Rbp, slot.disp + 8 * i as i32, register_size(ty));
+            }
+        }
+        slot
+    }
+
+    /// Passes the arguments in the slots from `store_argument` and calls the function, frees the slots
+    fn call_with_arguments(&mut self, signature: &FnSignature, target: CallTarget, temporaries: Vec<Slot>) -> Ret {
+        let return_type = signature.return_type;
+        let hidden_return = returns_in_memory(&self.shared.types, return_type);
+        let (locations, stack_size) = assign_arguments(&self.shared.types, &signature.arguments, hidden_return);
+        for ((slot, parts), ty) in temporaries.iter().zip(locations.iter()).zip(signature.arguments.iter()) {
+            if is_passed_by_pointer(*ty) {
+                // the callee gets a pointer to the copy
+                match parts[0] {
+                    ArgPart::Register(reg) => self.asm.lea(reg, Reg::Rbp, slot.disp),
+                    ArgPart::Stack(offset) => {
+                        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                        self.asm.store(Reg::Rsp, offset, Reg::Rax, 8);
+                    },
+                    ArgPart::Sse(_) => unreachable!("pointers are passed in integer registers"),
+                }
+                continue;
+            }
+            for (i, part) in parts.iter().enumerate() {
+                let disp = slot.disp + 8 * i as i32;
+                match *part {
+                    ArgPart::Register(_) | ArgPart::Sse(_) => self.load_part(*part, Reg::Rbp, disp, register_size(*ty), false),
+                    ArgPart::Stack(offset) => {
+                        self.asm.load(Reg::Rax, Reg::Rbp, disp, 8, false);
+                        self.asm.store(Reg::Rsp, offset, Reg::Rax, 8);
+                    },
+                }
+            }
+        }
+        self.outgoing_args_size = self.outgoing_args_size.max(stack_size);
+
+        for slot in temporaries {
+            self.frame.free(slot);
+        }
+
+        // a struct or matrix result is stored in a temporary, either by the callee or after the call
+        let result = if is_memory_value(return_type) {
+            Some(self.alloc_temp(return_type))
+        } else {
+            None
+        };
+        if let (true, Some(slot)) = (hidden_return, result) {
+            self.asm.lea(INT_ARG_REGS[0], Reg::Rbp, slot.disp);
+        }
+
+        match target {
+            CallTarget::Script(label) => self.asm.call_fn(label),
+            CallTarget::Host(address) => {
+                self.asm.mov_ri(Reg::Rax, address as u64);
+                self.asm.call_r(Reg::Rax);
+            },
+            CallTarget::Indirect(slot) => {
+                // r11 is neither used for arguments nor preserved by the callee
+                self.asm.load(Reg::R11, Reg::Rbp, slot.disp, 8, false);
+                self.asm.call_r(Reg::R11);
+                self.frame.free(slot);
+            },
+        }
+
+        // native code only defines the low bits of small integers, a function pointer may point to native code
+        if let CallTarget::Script(_) = target { } else {
+            self.normalize(return_type);
+        }
+
+        if let Some(slot) = result {
+            if !hidden_return {
+                let classes = classify(&self.shared.types, return_type);
+                for (i, part) in value_parts(&classes).iter().enumerate() {
+                    self.store_part(*part, Reg::Rbp, slot.disp + 8 * i as i32, 8);
+                }
+            }
+            self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        }
+
+        return_type
+    }
+
+    /// `|x| x * 2`, compiled as a separate function when it is encountered first.
+    /// The closure can't use the locals of the surrounding function, so its address
+    /// is all that is needed to call it.
+    fn compile_closure(&mut self, c: &ExprClosure, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let key = c as *const ExprClosure as usize;
+        let index = match self.shared.closure_index.get(&key) {
+            Some(index) => *index,
+            None => {
+                if c.movability.is_some() {
+                    return Err(unsupported(c));
+                }
+
+                // argument types that are not annotated are taken from the expected function pointer type,
+                // or from the parameter that the closure is passed to later
+                let expected = match expected.or_else(|| self.infer.closure(key)).map(|ty| self.infer.resolve(ty)) {
+                    Some(Ret::FnPtr(id)) if self.shared.types.signature(id).arguments.len() == c.inputs.len() => {
+                        Some(self.shared.types.signature(id).clone())
+                    },
+                    _ => None,
+                };
+                let mut arguments = Vec::with_capacity(c.inputs.len());
+                for (i, arg) in c.inputs.iter().enumerate() {
+                    let ty = match *arg {
+                        FnArg::Captured(ref a) => self.resolve_type(&a.ty)?,
+                        FnArg::Inferred(_) => match expected {
+                            Some(ref signature) => signature.arguments[i],
+                            None if self.infer.closure(key).is_none() => {
+                                // the first pass, whose code is discarded, continues with a placeholder type
+                                let placeholder = self.shared.types.placeholder_fn_type();
+                                self.infer.set_closure_placeholder(key, placeholder);
+                                self.asm.mov_ri(Reg::Rax, 0);
+                                return Ok(Ret::FnPtr(placeholder));
+                            },
+                            None => {
+                                let closure = c.clone().into_token_stream().to_string();
+                                return Err(AssembleFunctionError::TypeAnnotationNeeded(closure).into());
+                            },
+                        },
+                        _ => return Err(unsupported(arg)),
+                    };
+                    arguments.push(ty);
+                }
+                let return_type = match c.output {
+                    ReturnType::Type(_, ref t) => Some(self.resolve_value_type(t)?),
+                    ReturnType::Default => expected.map(|signature| signature.return_type),
+                };
+
+                let source = FnSource {
+                    name: format!("{}::{{closure}}", self.source.name),
+                    module: self.source.module,
+                    arguments: c.inputs.iter().collect(),
+                    body: FnBody::Expr(&c.body),
+                    in_unsafe: self.in_unsafe,
+                };
+                let mut captures = self.captures.to_vec();
+                captures.extend(self.locals.iter().map(|l| l.name.clone()));
+
+                let (code, return_type) = compile_function(self.program, self.shared, &source, &arguments,
+                                                           return_type, &captures)?;
+                let ty = self.shared.types.fn_type(FnSignature { arguments, return_type });
+                self.shared.closures.push(Closure { label: new_global_label(), ty, code });
+                let index = self.shared.closures.len() - 1;
+                self.shared.closure_index.insert(key, index);
+                index
+            },
+        };
+
+        let Closure { label, ty, .. } = self.shared.closures[index];
+        self.asm.lea_fn(Reg::Rax, label);
+        Ok(ty)
+    }
+
+    fn struct_field(&self, id: StructId, name: &str) -> Result<Field, AssembleError> {
+        let def = self.shared.types.struct_def(id);
+        let field = def.field(name).cloned().ok_or_else(|| AssembleFunctionError::UnknownField {
+            function: self.fn_name(),
+            ty: def.name.clone(),
+            field: name.into(),
+        })?;
+        if !self.program.modules.is_visible(field.vis, self.source.module) {
+            return Err(AssembleError::PrivateItem(format!("{}::{}", def.name, name)));
+        }
+        Ok(field)
+    }
+
+    /// A tuple or unit struct can only be constructed where all of its fields are visible
+    fn check_fields_visible(&self, id: StructId) -> Result<(), AssembleError> {
+        for field in &self.shared.types.struct_def(id).fields {
+            self.struct_field(id, &field.name)?;
+        }
+        Ok(())
+    }
+
+    /// `Hit { dmg: 5, crit }`, the struct is built in a temporary slot
+    fn compile_struct(&mut self, s: &ExprStruct) -> Result<Ret, AssembleError> {
+        let modules = &self.program.modules;
+        let id = match modules.resolve_path(self.source.module, &s.path, Namespace::Type) {
+            Ok(Def::Struct(id)) => id,
+            Ok(_) => return Err(AssembleFunctionError::NotAStruct(path_to_string(&s.path)).into()),
+            // i.e. a function
+            Err(AssembleError::UnresolvedPath(_)) if modules.resolve_path(self.source.module, &s.path, Namespace::Value).is_ok() => {
+                return Err(AssembleFunctionError::NotAStruct(path_to_string(&s.path)).into());
+            },
+            Err(e) => return Err(e),
+        };
+        // functional update syntax, `..base`
+        if s.rest.is_some() {
+            return Err(unsupported(s));
+        }
+
+        let ty = Ret::Struct(id);
+        let slot = self.alloc_temp(ty);
+        let mut initialized = Vec::new();
+        for field_value in s.fields.iter() {
+            let name = member_name(&field_value.member);
+            if initialized.contains(&name) {
+                return Err(unsupported(field_value));
+            }
+            let field = self.struct_field(id, &name)?;
+            self.compile_expr_expect(&field_value.expr, field.ty)?;
+            self.store_value(field.ty, Reg::Rbp, slot.disp + field.offset);
+            initialized.push(name);
+        }
+
+        let def = self.shared.types.struct_def(id);
+        if let Some(field) = def.fields.iter().find(|f| !initialized.contains(&f.name)) {
+            return Err(AssembleFunctionError::MissingField {
+                function: self.fn_name(),
+                ty: def.name.clone(),
+                field: field.name.clone(),
+            }.into());
+        }
+
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(ty)
+    }
+
+    /// `Money(5)`, calling the constructor of a tuple struct
+    fn compile_tuple_struct(&mut self, id: StructId, c: &ExprCall) -> Result<Ret, AssembleError> {
+        let def = self.shared.types.struct_def(id).clone();
+        if def.kind != StructKind::Tuple {
+            return Err(AssembleFunctionError::NotAFunction(def.name).into());
+        }
+        self.check_fields_visible(id)?;
+        if def.fields.len() != c.args.len() {
+            return Err(AssembleFunctionError::WrongArgumentCount {
+                function: def.name,
+                expected: def.fields.len(),
+                found: c.args.len(),
+            }.into());
+        }
+
+        let ty = Ret::Struct(id);
+        let slot = self.alloc_temp(ty);
+        for (arg, field) in c.args.iter().zip(def.fields.iter()) {
+            self.compile_expr_expect(arg, field.ty)?;
+            self.store_value(field.ty, Reg::Rbp, slot.disp + field.offset);
+        }
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(ty)
+    }
+
+    /// `(a, b)`, the elements are evaluated from left to right and copied into a temporary slot.
+    /// `()` is the unit value.
+    fn compile_tuple(&mut self, t: &ExprTuple, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let expected_elems = match expected.map(|ty| self.infer.resolve(ty)) {
+            Some(Ret::Struct(id)) => self.shared.types.tuple_elements(id).map(|elems| elems.to_vec()),
+            _ => None,
+        };
+        let expected_elems = expected_elems.filter(|elems| elems.len() == t.elems.len());
+
+        let mut elems = Vec::new();
+        for (i, elem) in t.elems.iter().enumerate() {
+            let ty = self.compile_expr(elem, expected_elems.as_ref().map(|elems| elems[i]))?;
+            if let Some(ref expected) = expected_elems {
+                self.expect_type(expected[i], ty)?;
+            }
+            let ty = self.infer.resolve(ty);
+            elems.push((ty, self.spill(ty)));
+        }
+        if elems.is_empty() {
+            return Ok(Ret::Void);
+        }
+
+        let ty = self.shared.types.tuple_type(elems.iter().map(|&(ty, _)| ty).collect());
+        let id = match ty {
+            Ret::Struct(id) => id,
+            _ => unreachable!("tuples are structs"),
+        };
+        let fields = self.shared.types.struct_def(id).fields.clone();
+        let result = self.alloc_temp(ty);
+        for ((elem, slot), field) in elems.into_iter().zip(fields) {
+            self.load_value(elem, Reg::Rbp, slot.disp);
+            self.store_value(elem, Reg::Rbp, result.disp + field.offset);
+            self.frame.free(slot);
+        }
+        self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+        Ok(ty)
+    }
+
+    /// `hit.dmg`, `money.0`, `velocity.x` and `transform.w_axis`
+    fn compile_field(&mut self, f: &ExprField) -> Result<Ret, AssembleError> {
+        let name = member_name(&f.member);
+        let (base, through_reference) = match self.compile_expr(&f.base, None)? {
+            // the address of a struct is its value, the field is loaded through it
+            Ret::Ref(id) if matches!(self.shared.types.reference(id).elem, Ret::Struct(_)) => (self.shared.types.reference(id).elem, true),
+            reference @ Ret::Ref(_) => (self.compile_deref(&f.base, reference)?, false),
+            base => (base, false),
+        };
+        let id = match base {
+            Ret::Struct(id) => id,
+            Ret::Vec(vec) => return self.compile_swizzle(vec, &name),
+            Ret::Quat => return self.compile_quat_field(&name),
+            Ret::Mat(mat) => return self.compile_mat_field(mat, &name),
+            _ => return Err(unsupported(f)),
+        };
+        let field = self.struct_field(id, &name)?;
+        if through_reference && !self.shared.types.is_copy(field.ty) {
+            let expression = self.location(f).expression;
+            return Err(AssembleFunctionError::MoveOutOfReference { function: self.fn_name(), expression }.into());
+        }
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.load_value(field.ty, Reg::Rcx, field.offset);
+        Ok(field.ty)
+    }
+
+    /// `receiver.method(args)`, only numbers, slices, options, results and the built-in math types have methods
+    fn compile_method_call(&mut self, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        if iter::is_iterator(&m.receiver) {
+            return self.compile_iterator_method(m);
+        }
+        if m.turbofish.is_some() {
+            return Err(unsupported(m));
+        }
+        let borrows = borrow::borrows_receiver(&m.method.to_string());
+        let receiver = if borrows {
+            self.compile_borrowed(&m.receiver)?
+        } else {
+            self.compile_expr(&m.receiver, None)?
+        };
+        // the method is called on the value that a reference refers to
+        let receiver = match self.infer.resolve(receiver) {
+            reference @ Ret::Ref(_) if borrows => self.load_referenced(reference),
+            reference @ Ret::Ref(_) => self.compile_deref(&m.receiver, reference)?,
+            receiver => receiver,
+        };
+        // the methods of an array are those of a slice of its elements
+        let receiver = self.array_as_slice(receiver, false).unwrap_or(receiver);
+        if m.method == "clone" && m.args.is_empty() && self.shared.types.is_clone(receiver) {
+            return Ok(self.compile_clone(receiver));
+        }
+        match receiver {
+            Ret::Int(i) => self.compile_int_method(i, m),
+            Ret::Float(f) => self.compile_float_method(f, m),
+            Ret::Vec(vec) => self.compile_vec_method(vec, m),
+            Ret::Quat => self.compile_quat_method(m),
+            Ret::Mat(mat) => self.compile_mat_method(mat, m),
+            Ret::Simd(simd) => self.compile_simd_method(simd, m),
+            Ret::Fixed => self.compile_fixed_method(m),
+            Ret::Slice(id) => self.compile_slice_method(id, m),
+            Ret::Option(id) => self.compile_option_method(id, m),
+            Ret::Result(id) => self.compile_result_method(id, m),
+            ty => Err(AssembleFunctionError::UnknownMethod { function: self.fn_name(), ty, method: m.method.to_string() }.into()),
+        }
+    }
+
+    fn compile_return(&mut self, r: &ExprReturn) -> Result<Ret, AssembleError> {
+        if self.in_predicate {
+            return Err(unsupported(r));
+        }
+        match r.expr {
+            Some(ref e) => self.compile_return_value(e)?,
+            None => self.expect_return_type(Ret::Void)?,
+        }
+        self.asm.jmp(self.epilogue);
+        self.diverges = true;
+        Ok(Ret::Void)
+    }
+
+    /// Starts the body of a loop, `continue` jumps to `next` and `break` to `end`
+    fn enter_loop(&mut self, next: Label, end: Label) {
+        self.loops.push(Loop { next, end, moved: self.moved.clone(), broken: Vec::new() });
+    }
+
+    /// Ends a loop after `check_loop_moves`, the values that were moved before a `break` stay moved
+    fn exit_loop(&mut self) {
+        let exited = self.loops.pop().expect("not in a loop");
+        for m in exited.broken {
+            if !self.moved.contains(&m) {
+                self.moved.push(m);
+            }
+        }
+    }
+
+    /// `continue` in a `for` or `while let` loop, which can't have a label
+    fn compile_continue(&mut self, c: &ExprContinue, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let (next, moved) = match self.loops.last() {
+            Some(l) if c.label.is_none() => (l.next, l.moved.clone()),
+            _ => return Err(unsupported(c)),
+        };
+        self.check_loop_moves(&moved)?;
+        self.asm.jmp(next);
+        self.diverges = true;
+        Ok(expected.unwrap_or(Ret::Void))
+    }
+
+    /// `break` in a `for` or `while let` loop, which can't have a label or a value
+    fn compile_break(&mut self, b: &ExprBreak, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let moved = self.moved.clone();
+        let l = match self.loops.last_mut() {
+            Some(l) if b.label.is_none() && b.expr.is_none() => l,
+            _ => return Err(unsupported(b)),
+        };
+        for m in moved {
+            if !l.broken.contains(&m) {
+                l.broken.push(m);
+            }
+        }
+        let end = l.end;
+        self.asm.jmp(end);
+        self.diverges = true;
+        Ok(expected.unwrap_or(Ret::Void))
+    }
+}
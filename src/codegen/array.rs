@@ -0,0 +1,168 @@
+//! Arrays, `[1, 2, 3]`, `[0.0; 16]` and `[T; N]`.
+//!
+//! An array is an anonymous struct whose fields are its elements (see `types.rs`), so it is
+//! moved, copied, passed and returned like one and `rax` holds its address. It is indexed and
+//! iterated like a slice of its elements with a constant length, and `&array` or `&mut array` is
+//! such a slice, which borrows the array like a reference.
+
+use quote::ToTokens;
+use syn::{Expr, ExprArray, ExprLit, ExprReference, ExprRepeat, Lit};
+use assembler::{AluOp, Cond, Reg};
+use compiler::{AssembleError, AssembleFunctionError, Ret};
+use resolve::{Def, Namespace};
+use super::{FnCompiler, unsupported};
+
+impl<'a> FnCompiler<'a> {
+
+    /// The element type and the length, if `ty` is an array
+    pub(super) fn array_of(&self, ty: Ret) -> Option<(Ret, usize)> {
+        match ty {
+            Ret::Struct(id) => self.shared.types.array(id),
+            _ => None,
+        }
+    }
+
+    fn array_type<T: ToTokens>(&mut self, node: &T, elem: Ret, len: usize) -> Result<Ret, AssembleError> {
+        self.shared.types.array_type(elem, len).ok_or_else(|| {
+            AssembleFunctionError::UnsupportedType(node.into_token_stream().to_string()).into()
+        })
+    }
+
+    /// Turns the array in `rax`, or a reference to one, into a slice of its elements in `rax` /
+    /// `rdx`. Returns the type of the slice, or `None` if `ty` is no array.
+    pub(super) fn array_as_slice(&mut self, ty: Ret, mutable: bool) -> Option<Ret> {
+        let (array, mutable) = match ty {
+            Ret::Ref(id) => (self.shared.types.reference(id).elem, self.shared.types.reference(id).mutable),
+            _ => (ty, mutable),
+        };
+        let (elem, len) = self.array_of(array)?;
+        self.asm.mov_ri(Reg::Rdx, len as u64);
+        Some(self.shared.types.slice_type(elem, mutable))
+    }
+
+    /// `[a, b, c]`, the element type comes from the expected type or the first element
+    pub(super) fn compile_array(&mut self, a: &ExprArray, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let mut elem = expected.and_then(|ty| {
+            let ty = self.infer.resolve(ty);
+            self.array_of(ty)
+        }).map(|(elem, _)| elem);
+        if a.elems.is_empty() && elem.is_none() {
+            return Err(AssembleFunctionError::TypeAnnotationNeeded(a.into_token_stream().to_string()).into());
+        }
+        let mut result = None;
+        for (i, value) in a.elems.iter().enumerate() {
+            let found = self.compile_expr(value, elem)?;
+            match elem {
+                Some(elem) => self.expect_type(elem, found)?,
+                None => elem = Some(found),
+            }
+            let ty = self.infer.resolve(elem.unwrap());
+            // the array is allocated once the type of its elements is known
+            let slot = match result {
+                Some(slot) => slot,
+                None => {
+                    let array = self.array_type(a, ty, a.elems.len())?;
+                    let slot = self.alloc_temp(array);
+                    result = Some(slot);
+                    slot
+                },
+            };
+            self.store_value(ty, Reg::Rbp, slot.disp + i as i32 * self.size_of(ty));
+        }
+        let elem = self.infer.resolve(elem.unwrap());
+        let array = self.array_type(a, elem, a.elems.len())?;
+        let slot = match result {
+            Some(slot) => slot,
+            None => self.alloc_temp(array),
+        };
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(array)
+    }
+
+    /// `[value; N]`, which copies a value that is `Copy` into every element
+    pub(super) fn compile_repeat(&mut self, r: &ExprRepeat, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let len = match *r.len {
+            Expr::Lit(ExprLit { lit: Lit::Int(ref len), .. }) => len.value() as usize,
+            _ => return Err(unsupported(r)),
+        };
+        let hint = expected.and_then(|ty| {
+            let ty = self.infer.resolve(ty);
+            self.array_of(ty)
+        }).map(|(elem, _)| elem);
+        let elem = self.compile_expr(&r.expr, hint)?;
+        let elem = self.infer.resolve(elem);
+        if !self.shared.types.is_copy(elem) {
+            let expression = self.location(&r.expr).expression;
+            return Err(AssembleFunctionError::UseOfMovedValue { function: self.fn_name(), expression }.into());
+        }
+        let array = self.array_type(r, elem, len)?;
+        let value = self.spill(elem);
+        let result = self.alloc_temp(array);
+        let size = self.size_of(elem);
+        if len > 0 && size > 0 {
+            // `r8` counts the elements that are left, `r9` points to the next one
+            let top = self.asm.new_label();
+            self.asm.mov_ri(Reg::R8, len as u64);
+            self.asm.lea(Reg::R9, Reg::Rbp, result.disp);
+            self.asm.bind(top);
+            self.copy_memory(Reg::R9, 0, Reg::Rbp, value.disp, size);
+            self.asm.alu_ri(AluOp::Add, Reg::R9, size);
+            self.asm.alu_ri(AluOp::Sub, Reg::R8, 1);
+            self.asm.jcc(Cond::NotEqual, top);
+        }
+        self.frame.free(value);
+        self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+        Ok(array)
+    }
+
+    /// Whether the expression is the path of a static
+    pub(super) fn is_static(&self, expr: &Expr) -> bool {
+        self.static_of(expr).is_some()
+    }
+
+    fn static_of(&self, expr: &Expr) -> Option<usize> {
+        match *expr {
+            Expr::Path(ref p) if !self.is_local_path(&p.path) => {
+                match self.program.modules.resolve_path(self.source.module, &p.path, Namespace::Value) {
+                    Ok(Def::Static(index)) => Some(index),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// `&value` of something else than a place: a static array is borrowed where it is, any
+    /// other array is copied to a slot that lives until the end of the block
+    pub(super) fn compile_array_reference(&mut self, r: &ExprReference, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let mutable = r.mutability.is_some();
+        if let Some(index) = self.static_of(&r.expr) {
+            if mutable && !self.program.statics[index].mutable {
+                return Err(AssembleFunctionError::BorrowOfImmutable {
+                    function: self.fn_name(),
+                    place: r.into_token_stream().to_string(),
+                }.into());
+            }
+            let found = self.compile_expr(&r.expr, None)?;
+            return self.array_as_slice(found, mutable).ok_or_else(|| unsupported(r));
+        }
+        let hint = match expected.map(|ty| self.infer.resolve(ty)) {
+            Some(Ret::Slice(id)) => {
+                let elem = self.shared.types.slice(id).elem;
+                self.shared.types.array_type(elem, 0)
+            },
+            _ => None,
+        };
+        let found = self.compile_expr(&r.expr, hint)?;
+        let found = self.infer.resolve(found);
+        if self.array_of(found).is_none() {
+            return Err(unsupported(r));
+        }
+        let slot = self.frame.alloc(self.size_of(found), self.slot_align(found));
+        self.local_slots.push(slot);
+        self.store_value(found, Reg::Rbp, slot.disp);
+        self.borrow_temporary(slot, mutable);
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(self.array_as_slice(found, mutable).unwrap())
+    }
+}
@@ -0,0 +1,383 @@
+//! Assignments, `place = value` and `place += value`, and indexing and sub-slices of slices.
+//!
+//! The assigned place is a local, a field or lane of a place, an element of a slice or array, `*name` or
+//! `name.field` of a `ref mut` binding or a `&mut` reference or a `static mut`. Like the places of `pattern.rs`, a
+//! local is accessed relative to `rbp` and a slot holds the address of anything else. A compound
+//! assignment to an integer that can't overflow is a single instruction with a memory operand,
+//! everything else is loaded, computed like the binary operator and stored back. A local has to
+//! be `mut` and assigning all of it makes a moved local usable again.
+
+use syn::{BinOp, Expr, ExprAssign, ExprAssignOp, ExprBinary, ExprIndex, ExprRange, ExprReference, Path, RangeLimits, UnOp};
+use assembler::{AluOp, Cond, Reg};
+use compiler::{AssembleError, AssembleFunctionError, Ret, ScriptError, StaticFloatLiteral, StaticIntLiteral};
+use resolve::{Def, Namespace};
+use super::{FnCompiler, member_name, operators, unsupported};
+use super::pattern::{Base, Place};
+use super::vector::COMPONENTS;
+
+/// The operator that a compound assignment applies, `+` for `+=`
+fn binary_operator(op: &BinOp) -> Option<BinOp> {
+    Some(match *op {
+        BinOp::AddEq(_) => BinOp::Add(Default::default()),
+        BinOp::SubEq(_) => BinOp::Sub(Default::default()),
+        BinOp::MulEq(_) => BinOp::Mul(Default::default()),
+        BinOp::DivEq(_) => BinOp::Div(Default::default()),
+        BinOp::RemEq(_) => BinOp::Rem(Default::default()),
+        BinOp::BitAndEq(_) => BinOp::BitAnd(Default::default()),
+        BinOp::BitOrEq(_) => BinOp::BitOr(Default::default()),
+        BinOp::BitXorEq(_) => BinOp::BitXor(Default::default()),
+        BinOp::ShlEq(_) => BinOp::Shl(Default::default()),
+        BinOp::ShrEq(_) => BinOp::Shr(Default::default()),
+        _ => return None,
+    })
+}
+
+/// The instruction that applies `op` to a value in memory, if it can't overflow
+fn memory_operation(op: &BinOp, ty: Ret, checks_overflow: bool) -> Option<AluOp> {
+    match (ty, op) {
+        // both halves of a 128-bit integer are computed in registers
+        (Ret::Int(i), _) if i.size() == 16 => None,
+        (Ret::Int(_), &BinOp::Add(_)) if !checks_overflow => Some(AluOp::Add),
+        (Ret::Int(_), &BinOp::Sub(_)) if !checks_overflow => Some(AluOp::Sub),
+        (Ret::Int(_), &BinOp::BitAnd(_)) | (Ret::Bool, &BinOp::BitAnd(_)) => Some(AluOp::And),
+        (Ret::Int(_), &BinOp::BitOr(_)) | (Ret::Bool, &BinOp::BitOr(_)) => Some(AluOp::Or),
+        (Ret::Int(_), &BinOp::BitXor(_)) | (Ret::Bool, &BinOp::BitXor(_)) => Some(AluOp::Xor),
+        _ => None,
+    }
+}
+
+impl<'a> FnCompiler<'a> {
+
+    fn assign_to_immutable(&self, place: &Expr) -> AssembleError {
+        AssembleFunctionError::AssignToImmutable { function: self.fn_name(), place: self.location(place).expression }.into()
+    }
+
+    /// `place = value`
+    pub(super) fn compile_assign(&mut self, a: &ExprAssign) -> Result<Ret, AssembleError> {
+        let (ty, place) = self.compile_assignee(&a.left)?;
+        let borrows = self.borrows.len();
+        self.compile_expr_expect(&a.right, ty)?;
+        let ty = self.infer.resolve(ty);
+        if let Some(offset) = place.frame_offset() {
+            self.reinitialize(&a.left, offset, self.size_of(ty))?;
+        }
+        // `r = &mut x;` borrows `x` while `r` is in scope
+        if self.holds_borrow(ty) {
+            if let Some(local) = self.locals.iter().rposition(|l| place.frame_offset() == Some(l.slot.disp)) {
+                self.keep_borrows(borrows, local);
+            }
+        }
+        // `rcx` is needed to copy a struct
+        let (base, disp) = self.place_address(place, Reg::R11);
+        self.store_value(ty, base, disp);
+        Ok(Ret::Void)
+    }
+
+    /// `place += value`, with the same types and overflow checks as `place = place + value`
+    pub(super) fn compile_assign_op(&mut self, a: &ExprAssignOp) -> Result<Ret, AssembleError> {
+        let op = binary_operator(&a.op).ok_or_else(|| unsupported(a))?;
+        let (ty, place) = self.compile_assignee(&a.left)?;
+        let ty = self.infer.resolve(ty);
+        if let (Ret::Struct(_), Some(trait_name)) = (ty, operators::operator_trait(&op)) {
+            return Err(self.missing_impl(&format!("{}Assign", trait_name), ty));
+        }
+        if let Some(offset) = place.frame_offset() {
+            self.check_not_borrowed(&a.left, offset, self.size_of(ty))?;
+            self.check_not_moved(&a.left, offset, self.size_of(ty))?;
+        }
+
+        if let Some(operation) = memory_operation(&op, ty, self.checks_overflow()) {
+            self.compile_expr_expect(&a.right, ty)?;
+            let (base, disp) = self.place_address(place, Reg::Rcx);
+            self.asm.alu_mr(operation, base, disp, Reg::Rax, ty.size() as u8);
+            return Ok(Ret::Void);
+        }
+
+        // the right side is evaluated first, it may change the place
+        let right = self.compile_right_operand(&op, ty, &a.right)?;
+        let right = self.infer.resolve(right);
+        let right_slot = self.spill(right);
+        let (base, disp) = self.place_address(place, Reg::Rcx);
+        self.load_value(ty, base, disp);
+        let slot = self.spill(ty);
+        self.load_value(right, Reg::Rbp, right_slot.disp);
+
+        // only used for the operator and the location of errors
+        let binary = ExprBinary { attrs: Vec::new(), left: a.left.clone(), op, right: a.right.clone() };
+        let found = self.arithmetic_op(&binary, &binary, ty, right, slot)?;
+        self.frame.free(right_slot);
+        self.expect_type(ty, found)?;
+        let (base, disp) = self.place_address(place, Reg::R11);
+        self.store_value(ty, base, disp);
+        Ok(Ret::Void)
+    }
+
+    /// The type and place of the left side of an assignment. Evaluates the indices and stores
+    /// the address in a slot that is freed at the end of the statement, unless it is a local.
+    fn compile_assignee(&mut self, expr: &Expr) -> Result<(Ret, Place), AssembleError> {
+        match *expr {
+            Expr::Paren(ref p) => self.compile_assignee(&p.expr),
+            Expr::Path(ref p) if p.qself.is_none() && self.is_local_path(&p.path) => {
+                let name = p.path.segments[0].ident.to_string();
+                let local = match self.find_local(&name) {
+                    Some(local) => local.clone(),
+                    None => return Err(AssembleFunctionError::CapturingClosure { function: self.fn_name(), name }.into()),
+                };
+                // a `ref` binding is only changed through `*name`
+                if local.by_ref {
+                    return Err(unsupported(expr));
+                }
+                if !local.mutable {
+                    return Err(self.assign_to_immutable(expr));
+                }
+                Ok((self.infer.resolve(local.ty), Place::new(Base::Frame, local.slot.disp)))
+            },
+            Expr::Path(ref p) if p.qself.is_none() => {
+                let program = self.program;
+                match program.modules.resolve_path(self.source.module, &p.path, Namespace::Value)? {
+                    Def::Static(index) if program.statics[index].mutable => {
+                        let s = &program.statics[index];
+                        self.check_static_use(p, true)?;
+                        self.asm.lea_rodata(Reg::Rax, s.offset);
+                        Ok((s.ty, self.address_place()))
+                    },
+                    Def::Static(_) => Err(self.assign_to_immutable(expr)),
+                    _ => Err(unsupported(expr)),
+                }
+            },
+            Expr::Unary(ref u) if matches!(u.op, UnOp::Deref(_)) => match *u.expr {
+                Expr::Path(ref p) if p.qself.is_none() && self.is_ref_binding(&p.path) => self.referenced_place(expr, &p.path),
+                ref reference => self.place_behind(expr, reference),
+            },
+            Expr::Field(ref f) => {
+                let (ty, place) = match *f.base {
+                    // a field is assigned through a `ref mut` binding, `enemy.hp -= 1`
+                    Expr::Path(ref p) if p.qself.is_none() && self.is_ref_binding(&p.path) => self.referenced_place(expr, &p.path)?,
+                    // a field is assigned through a `&mut` reference, `state.timer -= dt`
+                    ref base if matches!(self.local_place(base), Some((Ret::Ref(_), _, _))) => self.place_behind(expr, base)?,
+                    ref base => self.compile_assignee(base)?,
+                };
+                let name = member_name(&f.member);
+                let lanes = match ty {
+                    Ret::Struct(id) => {
+                        let field = self.struct_field(id, &name)?;
+                        return Ok((field.ty, place.at(field.offset)));
+                    },
+                    Ret::Vec(vec) => vec.lanes(),
+                    Ret::Quat => 4,
+                    _ => return Err(unsupported(f)),
+                };
+                // a single lane of a vector or quaternion, `position.y`
+                match COMPONENTS[..lanes].iter().position(|c| name == c.to_string()) {
+                    Some(lane) => Ok((Ret::Float(StaticFloatLiteral::F32), place.at(4 * lane as i32))),
+                    None => Err(AssembleFunctionError::UnknownField {
+                        function: self.fn_name(),
+                        ty: self.shared.types.type_name(ty),
+                        field: name,
+                    }.into()),
+                }
+            },
+            // an element of an array in a place, `grid[y][x] = tile`
+            Expr::Index(ref i) if self.array_place(&i.expr).is_some() => {
+                let (ty, place) = self.compile_assignee(&i.expr)?;
+                // the index is only known at run time, so no part of the array may be borrowed
+                if let Some(offset) = place.frame_offset() {
+                    self.check_not_borrowed(&i.expr, offset, self.size_of(ty))?;
+                    self.check_not_moved(&i.expr, offset, self.size_of(ty))?;
+                }
+                let (base, disp) = self.place_address(place, Reg::Rcx);
+                self.asm.lea(Reg::Rax, base, disp);
+                let slice = self.array_as_slice(ty, true).expect("not an array");
+                let (elem, _) = self.element_address(i, slice)?;
+                Ok((elem, self.address_place()))
+            },
+            Expr::Index(ref i) => {
+                let (elem, mutable) = self.compile_element_address(i)?;
+                if !mutable {
+                    return Err(self.assign_to_immutable(expr));
+                }
+                Ok((elem, self.address_place()))
+            },
+            _ => Err(unsupported(expr)),
+        }
+    }
+
+    /// The type of an array in a local, a static, behind a reference or in another array,
+    /// whose elements are assigned in place
+    fn array_place(&mut self, expr: &Expr) -> Option<Ret> {
+        let ty = match *expr {
+            Expr::Paren(ref p) => return self.array_place(&p.expr),
+            Expr::Index(ref i) => self.array_place(&i.expr).and_then(|array| self.array_of(array)).map(|(elem, _)| elem)?,
+            Expr::Path(ref p) if self.is_static(expr) => {
+                let program = self.program;
+                match program.modules.resolve_path(self.source.module, &p.path, Namespace::Value) {
+                    Ok(Def::Static(index)) => program.statics[index].ty,
+                    _ => return None,
+                }
+            },
+            _ => match self.local_place(expr) {
+                Some((_, _, ref local)) if local.by_ref => return None,
+                Some((ty, _, _)) => ty,
+                None => self.place_behind_reference(expr)?.0,
+            },
+        };
+        self.array_of(ty).map(|_| ty)
+    }
+
+    /// The place that the `ref mut` binding `path` refers to, `expr` is the assigned place
+    fn referenced_place(&mut self, expr: &Expr, path: &Path) -> Result<(Ret, Place), AssembleError> {
+        let local = self.find_local(&path.segments[0].ident.to_string()).cloned().unwrap();
+        if local.detached {
+            return Err(unsupported(expr));
+        }
+        // `ref x` instead of `ref mut x`
+        if !local.mutable {
+            return Err(self.assign_to_immutable(expr));
+        }
+        Ok((self.infer.resolve(local.ty), Place::new(Base::Frame, local.slot.disp)))
+    }
+
+    /// The value that a `&mut` reference refers to, `*r = value`
+    fn place_behind(&mut self, expr: &Expr, reference: &Expr) -> Result<(Ret, Place), AssembleError> {
+        let found = self.compile_expr(reference, None)?;
+        match self.infer.resolve(found) {
+            Ret::Ref(id) if self.shared.types.reference(id).mutable => Ok((self.shared.types.reference(id).elem, self.address_place())),
+            Ret::Ref(_) => Err(self.assign_to_immutable(expr)),
+            _ => Err(unsupported(expr)),
+        }
+    }
+
+    /// A place at the address in `rax`, which is stored in a slot until the end of the statement
+    fn address_place(&mut self) -> Place {
+        let slot = self.frame.alloc(8, 8);
+        self.temps.push(slot);
+        self.asm.store(Reg::Rbp, slot.disp, Reg::Rax, 8);
+        Place::new(Base::Address(slot), 0)
+    }
+
+    /// `slice[index]`, the script stops if the index is out of bounds. A struct with an `Index`
+    /// impl is only borrowed.
+    pub(super) fn compile_index(&mut self, i: &ExprIndex) -> Result<Ret, AssembleError> {
+        let found = self.compile_borrowed(&i.expr)?;
+        if self.implements("Index", found) {
+            return self.compile_operator_call("Index", found, Some(&i.index));
+        }
+        if let (Ret::Struct(_), None) = (found, self.array_of(found)) {
+            return Err(self.missing_impl("Index", found));
+        }
+        let (elem, _) = self.element_address(i, found)?;
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.load_value(elem, Reg::Rcx, 0);
+        Ok(elem)
+    }
+
+    fn compile_element_address(&mut self, i: &ExprIndex) -> Result<(Ret, bool), AssembleError> {
+        self.check_elements_not_borrowed(&i.expr)?;
+        let found = self.compile_expr(&i.expr, None)?;
+        // an `Index` impl only gives shared access to the element
+        if let Ret::Struct(_) = found {
+            return Err(self.missing_impl("IndexMut", found));
+        }
+        self.element_address(i, found)
+    }
+
+    /// Compiles an index or a bound of a range. Any integer type up to 64 bits can be used, a
+    /// negative one is out of bounds.
+    fn compile_slice_index(&mut self, index: &Expr) -> Result<(), AssembleError> {
+        let found = self.compile_expr(index, Some(Ret::Int(StaticIntLiteral::Usize)))?;
+        if !matches!(found, Ret::Int(i) if i.size() <= 8) {
+            self.expect_type(Ret::Int(StaticIntLiteral::Usize), found)?;
+        }
+        Ok(())
+    }
+
+    /// `&xs[start..end]`, `&mut xs[start..]`, `&xs[..=last]` or `&xs[..]` of a slice or array, the
+    /// script stops unless `start <= end <= len`. Like `&array`, it borrows an array in a local.
+    pub(super) fn compile_subslice(&mut self, r: &ExprReference, i: &ExprIndex, range: &ExprRange) -> Result<Ret, AssembleError> {
+        let mutable = r.mutability.is_some();
+        let found = match self.local_place(&i.expr) {
+            Some((ty, _, _)) if matches!(self.infer.resolve(ty), Ret::Slice(_)) => self.compile_expr(&i.expr, None)?,
+            _ => self.compile_reference(&ExprReference { expr: i.expr.clone(), ..r.clone() }, None)?,
+        };
+        let found = self.infer.resolve(found);
+        let slice = match found {
+            Ret::Slice(id) => self.shared.types.slice(id),
+            _ => return Err(unsupported(r)),
+        };
+        if mutable && !slice.mutable {
+            return Err(AssembleFunctionError::BorrowOfImmutable {
+                function: self.fn_name(),
+                place: self.location(r).expression,
+            }.into());
+        }
+        let source = self.spill(found);
+        let error = ScriptError::IndexOutOfBounds(self.location(i));
+
+        // the end is at most the length, or less than it if the range is inclusive
+        match range.to {
+            Some(ref to) => {
+                self.compile_slice_index(to)?;
+                self.asm.load(Reg::Rcx, Reg::Rbp, source.disp + 8, 8, false);
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                match range.limits {
+                    RangeLimits::HalfOpen(_) => self.trap_if(Cond::Above, error.clone()),
+                    RangeLimits::Closed(_) => {
+                        self.trap_if(Cond::AboveEqual, error.clone());
+                        self.asm.alu_ri(AluOp::Add, Reg::Rax, 1);
+                    },
+                }
+            },
+            None => self.asm.load(Reg::Rax, Reg::Rbp, source.disp + 8, 8, false),
+        }
+        let end = self.spill(Ret::Int(StaticIntLiteral::Usize));
+        match range.from {
+            Some(ref from) => self.compile_slice_index(from)?,
+            None => self.asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::Rax),
+        }
+        self.asm.load(Reg::Rcx, Reg::Rbp, end.disp, 8, false);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+        self.trap_if(Cond::Above, error);
+
+        // `rdx` is the length `end - start` and `rax` the address of the element at `start`
+        self.asm.mov_rr(Reg::Rdx, Reg::Rcx);
+        self.asm.alu_rr(AluOp::Sub, Reg::Rdx, Reg::Rax);
+        let size = self.size_of(slice.elem);
+        if size != 1 {
+            self.asm.mov_ri(Reg::Rcx, size as u64);
+            self.asm.imul_rr(Reg::Rax, Reg::Rcx);
+        }
+        self.asm.load(Reg::Rcx, Reg::Rbp, source.disp, 8, false);
+        self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+        self.frame.free(end);
+        self.frame.free(source);
+        Ok(self.shared.types.slice_type(slice.elem, mutable))
+    }
+
+    /// Computes the address of an element of the slice or array in `rax`, after checking the
+    /// index. Returns the type of the element and whether the slice is mutable.
+    fn element_address(&mut self, i: &ExprIndex, found: Ret) -> Result<(Ret, bool), AssembleError> {
+        let found = self.infer.resolve(found);
+        let found = self.array_as_slice(found, false).unwrap_or(found);
+        let slice = match found {
+            Ret::Slice(id) => self.shared.types.slice(id),
+            _ => return Err(unsupported(i)),
+        };
+        let slot = self.spill(found);
+        self.compile_slice_index(&i.index)?;
+
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp + 8, 8, false);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+        let error = ScriptError::IndexOutOfBounds(self.location(i));
+        self.trap_if(Cond::AboveEqual, error);
+
+        let size = self.size_of(slice.elem);
+        if size != 1 {
+            self.asm.mov_ri(Reg::Rcx, size as u64);
+            self.asm.imul_rr(Reg::Rax, Reg::Rcx);
+        }
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp, 8, false);
+        self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+        self.frame.free(slot);
+        Ok((slice.elem, slice.mutable))
+    }
+}
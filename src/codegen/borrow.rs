@@ -0,0 +1,471 @@
+//! Lexical checks of mutability, borrows and moves, a subset of the rules of rustc.
+//!
+//! Only locals and their fields are tracked, by the bytes of the frame that they occupy. A value
+//! that is not `Copy` is moved out of a local when it is used by value, and that part of the local
+//! can't be used until it is assigned again. The moves of the branches of an `if` or `match` add
+//! up, unless a branch returns. A `ref mut` binding needs a `mut` local and can't overlap another
+//! `ref` or `ref mut` binding that is in scope.
+//!
+//! A `&` or `&mut` reference borrows a local or a field of a local until the end of the statement,
+//! or while the local that it is assigned to is in scope. A `&mut` reference needs a `mut` local
+//! and can't overlap any other borrow, and the value can't be used otherwise in the statement that
+//! passes the reference, i.e. `swap(&mut a, a)`. Parameters that are references point to the
+//! values of the caller, which the function can't move.
+//!
+//! A loop over `xs.iter()` or `xs.iter_mut()` borrows the elements of the slice `xs` until its
+//! end. While `iter_mut` borrows them, `xs` can't be used in the body or by another iterator of
+//! the chain, and while `iter` does, its elements can't be assigned.
+
+use std::mem;
+use quote::ToTokens;
+use syn::{Expr, ExprReference, PatIdent, UnOp};
+use assembler::Reg;
+use compiler::{AssembleError, AssembleFunctionError, Ret};
+use super::{FnCompiler, Local, Scope, Slot, is_memory_value, member_name, unsupported};
+use super::pattern::Place;
+
+/// Methods that take `&self`, calling them doesn't move the receiver
+pub(super) fn borrows_receiver(method: &str) -> bool {
+    matches!(method, "clone" | "is_some" | "is_none" | "is_ok" | "is_err" | "len" | "is_empty")
+}
+
+/// Bytes of the frame at `rbp + disp` whose value was moved out of a local
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Moved {
+    disp: i32,
+    size: i32,
+    /// The moved expression or binding, for errors
+    expression: String,
+}
+
+impl Moved {
+    pub(super) fn overlaps(&self, disp: i32, size: i32) -> bool {
+        self.disp < disp + size && disp < self.disp + self.size
+    }
+}
+
+/// How long a borrow lasts
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Lifetime {
+    /// Until the end of the statement
+    Statement,
+    /// While the local with the index is in scope
+    Local(usize),
+    /// Until the end of the loop over the elements of a slice
+    Loop,
+}
+
+/// Bytes of the frame at `rbp + disp` that a `&` or `&mut` reference refers to, or bytes at
+/// `disp` after the address in another reference or slice
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Borrow {
+    /// The slot of the reference that the place is reborrowed from, i.e. `&mut r.field`, or of
+    /// the slice whose elements a loop iterates over
+    through: Option<i32>,
+    disp: i32,
+    size: i32,
+    mutable: bool,
+    lifetime: Lifetime,
+}
+
+impl Borrow {
+    /// Whether the borrow overlaps the bytes of the frame at `rbp + disp`
+    fn overlaps(&self, disp: i32, size: i32) -> bool {
+        self.through.is_none() && self.overlaps_place(None, disp, size)
+    }
+
+    /// Whether the borrow refers to the bytes at `rbp + disp`, or is a loop over a slice in them
+    fn refers_to(&self, disp: i32, size: i32) -> bool {
+        match self.lifetime {
+            Lifetime::Statement | Lifetime::Local(_) => self.overlaps(disp, size),
+            // a loop over an array borrows the array itself
+            Lifetime::Loop if self.through.is_none() => self.overlaps_place(None, disp, size),
+            Lifetime::Loop => self.iterates(disp, size),
+        }
+    }
+
+    /// Whether the borrow is a loop over the elements of a slice in the bytes at `rbp + disp`
+    fn iterates(&self, disp: i32, size: i32) -> bool {
+        self.lifetime == Lifetime::Loop && self.through.is_some_and(|slice| disp <= slice && slice < disp + size)
+    }
+
+    fn overlaps_place(&self, through: Option<i32>, disp: i32, size: i32) -> bool {
+        self.through == through && self.disp < disp + size && disp < self.disp + self.size
+    }
+}
+
+/// The moves before the branches of an `if` or `match`, and the moves of the branches that
+/// were compiled so far
+pub(super) struct Branches {
+    start: Vec<Moved>,
+    merged: Vec<Moved>,
+    /// None of the branches so far reaches the end of the `if` or `match`
+    diverges: bool,
+}
+
+impl<'a> FnCompiler<'a> {
+
+    fn use_of_moved_value(&self, expression: String) -> AssembleError {
+        AssembleFunctionError::UseOfMovedValue { function: self.fn_name(), expression }.into()
+    }
+
+    /// Reports a use of `node`, which is at `rbp + disp`, if a part of it was moved or is
+    /// mutably borrowed
+    pub(super) fn check_not_moved<T: ToTokens>(&self, node: &T, disp: i32, size: i32) -> Result<(), AssembleError> {
+        if self.moved.iter().any(|m| m.overlaps(disp, size)) {
+            return Err(self.use_of_moved_value(self.location(node).expression));
+        }
+        if self.borrows.iter().any(|b| b.mutable && b.refers_to(disp, size)) {
+            return Err(self.use_of_borrowed_value(node));
+        }
+        Ok(())
+    }
+
+    fn use_of_borrowed_value<T: ToTokens>(&self, node: &T) -> AssembleError {
+        let expression = self.location(node).expression;
+        AssembleFunctionError::UseOfBorrowedValue { function: self.fn_name(), expression }.into()
+    }
+
+    /// Reports an assignment to `node`, which is at `rbp + disp`, if any reference refers to a
+    /// part of it
+    pub(super) fn check_not_borrowed<T: ToTokens>(&self, node: &T, disp: i32, size: i32) -> Result<(), AssembleError> {
+        if self.borrows.iter().any(|b| b.refers_to(disp, size)) {
+            return Err(self.use_of_borrowed_value(node));
+        }
+        Ok(())
+    }
+
+    /// Uses the value of a local or a field of a local, which moves it unless it is `Copy`.
+    /// The value of a `ref` binding is only borrowed.
+    pub(super) fn use_place(&mut self, expr: &Expr, ty: Ret, disp: i32, local: &Local) -> Result<(), AssembleError> {
+        let size = self.size_of(ty);
+        self.check_not_moved(expr, disp, size)?;
+        if !local.by_ref && !self.shared.types.is_copy(ty) {
+            self.check_not_borrowed(expr, disp, size)?;
+            self.moved.push(Moved { disp, size, expression: self.location(expr).expression });
+        }
+        if self.holds_borrow(ty) {
+            let containing = self.locals.iter().rposition(|l| {
+                !l.by_ref && l.slot.disp <= disp && disp < l.slot.disp + l.slot.size
+            });
+            if let Some(index) = containing {
+                self.forward_borrows(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds a part of a local by value, which moves it unless it is `Copy`
+    pub(super) fn move_part(&mut self, p: &PatIdent, ty: Ret, disp: i32) -> Result<(), AssembleError> {
+        if !self.shared.types.is_copy(ty) {
+            let size = self.size_of(ty);
+            self.check_not_borrowed(p, disp, size)?;
+            self.moved.push(Moved { disp, size, expression: p.ident.to_string() });
+        }
+        Ok(())
+    }
+
+    /// The place at `rbp + disp` is assigned a new value, which can be used even if it was moved.
+    /// It can't be a part of a moved value.
+    pub(super) fn reinitialize(&mut self, place: &Expr, disp: i32, size: i32) -> Result<(), AssembleError> {
+        self.check_not_borrowed(place, disp, size)?;
+        self.moved.retain(|m| !(disp <= m.disp && m.disp + m.size <= disp + size));
+        self.check_not_moved(place, disp, size)
+    }
+
+    /// Checks a `ref` or `ref mut` binding of the value at `place`
+    pub(super) fn check_borrow(&mut self, p: &PatIdent, ty: Ret, place: Place) -> Result<(), AssembleError> {
+        // the binding of a part of a temporary is a copy, see `bind`
+        let disp = match place.frame_offset() {
+            Some(disp) => disp,
+            None => return Ok(()),
+        };
+        let size = self.size_of(ty);
+        self.check_not_moved(p, disp, size)?;
+        let mutable = p.mutability.is_some() && p.by_ref.is_some();
+        if mutable && !place.is_mutable() {
+            return Err(AssembleFunctionError::BorrowOfImmutable {
+                function: self.fn_name(),
+                place: p.into_token_stream().to_string(),
+            }.into());
+        }
+        self.check_overlapping_borrow(p.ident.to_string(), None, disp, size, mutable)
+    }
+
+    /// Checks that a new borrow of `rbp + disp` (or of a place through the reference in the slot
+    /// `through`) doesn't overlap a binding or reference that conflicts with it
+    fn check_overlapping_borrow(&self, name: String, through: Option<i32>, disp: i32, size: i32, mutable: bool)
+    -> Result<(), AssembleError>
+    {
+        let overlapping = through.is_none() && self.locals.iter().any(|l| {
+            l.by_ref && !l.detached && (mutable || l.mutable)
+                && l.slot.disp < disp + size && disp < l.slot.disp + l.slot.size
+        }) || self.borrows.iter().any(|b| (mutable || b.mutable) && b.overlaps_place(through, disp, size));
+        if overlapping {
+            return Err(AssembleFunctionError::OverlappingBorrow { function: self.fn_name(), name }.into());
+        }
+        Ok(())
+    }
+
+    /// `&place` or `&mut place` of a local, a field of a local or a place behind another
+    /// reference (`&mut *r` or `&mut r.field`), the address of the value. A reference to an
+    /// array is a slice of its elements.
+    pub(super) fn compile_reference(&mut self, r: &ExprReference, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        if let Expr::Index(ref i) = *r.expr {
+            if let Expr::Range(ref range) = *i.index {
+                return self.compile_subslice(r, i, range);
+            }
+        }
+        let (ty, through, disp, mutable_place) = match self.local_place(&r.expr) {
+            // a `ref` binding is not a reference to its value
+            Some((_, _, ref local)) if local.by_ref => return Err(unsupported(r)),
+            Some((ty, disp, local)) => (ty, None, disp, local.mutable),
+            None => match self.place_behind_reference(&r.expr) {
+                Some((ty, reference, offset, mutable)) => (ty, Some(reference), offset, mutable),
+                None => return self.compile_array_reference(r, expected),
+            },
+        };
+        let size = self.size_of(ty);
+        let mutable = r.mutability.is_some();
+        if mutable && !mutable_place {
+            return Err(AssembleFunctionError::BorrowOfImmutable {
+                function: self.fn_name(),
+                place: r.into_token_stream().to_string(),
+            }.into());
+        }
+        self.check_overlapping_borrow(self.location(&r.expr).expression, through, disp, size, mutable)?;
+        match through {
+            Some(reference) => {
+                self.asm.load(Reg::Rax, Reg::Rbp, reference, 8, false);
+                self.asm.lea(Reg::Rax, Reg::Rax, disp);
+            },
+            None => {
+                self.check_not_moved(&r.expr, disp, size)?;
+                self.asm.lea(Reg::Rax, Reg::Rbp, disp);
+            },
+        }
+        self.borrows.push(Borrow { through, disp, size, mutable, lifetime: Lifetime::Statement });
+        match self.array_as_slice(ty, mutable) {
+            Some(slice) => Ok(slice),
+            None => Ok(self.shared.types.ref_type(ty, mutable)),
+        }
+    }
+
+    /// Borrows the slot of a temporary array until the end of the statement, `&[1, 2, 3]`
+    pub(super) fn borrow_temporary(&mut self, slot: Slot, mutable: bool) {
+        self.borrows.push(Borrow { through: None, disp: slot.disp, size: slot.size, mutable, lifetime: Lifetime::Statement });
+    }
+
+    /// Whether a value of the type can hold a borrow of a local, a reference or a slice of an array
+    pub(super) fn holds_borrow(&self, ty: Ret) -> bool {
+        self.shared.types.contains_ref(ty) || self.shared.types.contains_slice(ty)
+    }
+
+    /// The borrows of the local with the index `local` also last until the end of the statement
+    /// that copies its value, so that `{ let s = &array; s }` can't outlive `array`
+    pub(super) fn forward_borrows(&mut self, local: usize) {
+        let forwarded: Vec<Borrow> = self.borrows.iter()
+            .filter(|b| b.lifetime == Lifetime::Local(local))
+            .map(|b| Borrow { lifetime: Lifetime::Statement, ..b.clone() })
+            .collect();
+        self.borrows.extend(forwarded);
+    }
+
+    /// Reports a value that contains a reference or slice to a local, which is returned from
+    /// the function
+    pub(super) fn check_returned_borrows(&self, ty: Ret) -> Result<(), AssembleError> {
+        if self.holds_borrow(ty) && self.borrows.iter().any(|b| b.through.is_none() && b.lifetime == Lifetime::Statement) {
+            return Err(AssembleFunctionError::DanglingReference { function: self.fn_name(), name: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    /// The type of `*r`, `r.field` or `(*r).field.x`, the slot of the reference `r`, the offset
+    /// from its address and whether the reference is `&mut`
+    pub(super) fn place_behind_reference(&mut self, expr: &Expr) -> Option<(Ret, i32, i32, bool)> {
+        let reference = |compiler: &mut Self, expr: &Expr| match compiler.local_place(expr) {
+            Some((Ret::Ref(id), disp, ref local)) if !local.by_ref => {
+                let reference = compiler.shared.types.reference(id);
+                Some((reference.elem, disp, 0, reference.mutable))
+            },
+            _ => None,
+        };
+        match *expr {
+            Expr::Unary(ref u) if matches!(u.op, UnOp::Deref(_)) => reference(self, &u.expr),
+            Expr::Field(ref f) => {
+                let (ty, slot, offset, mutable) = reference(self, &f.base).or_else(|| self.place_behind_reference(&f.base))?;
+                let field = match ty {
+                    Ret::Struct(id) => self.struct_field(id, &member_name(&f.member)).ok()?,
+                    _ => return None,
+                };
+                Some((field.ty, slot, offset + field.offset, mutable))
+            },
+            Expr::Paren(ref p) => self.place_behind_reference(&p.expr),
+            _ => None,
+        }
+    }
+
+    /// The borrows since the first `borrows` last while the local with the index `local` is in scope
+    pub(super) fn keep_borrows(&mut self, borrows: usize, local: usize) {
+        for borrow in &mut self.borrows[borrows..] {
+            if borrow.lifetime == Lifetime::Statement {
+                borrow.lifetime = Lifetime::Local(local);
+            }
+        }
+    }
+
+    /// Ends the borrows of the current statement
+    pub(super) fn end_temporary_borrows(&mut self) {
+        self.borrows.retain(|b| b.lifetime != Lifetime::Statement);
+    }
+
+    /// Ends the borrows of the locals that go out of scope, the first `locals` stay
+    pub(super) fn end_borrows(&mut self, locals: usize) {
+        self.borrows.retain(|b| !matches!(b.lifetime, Lifetime::Local(l) if l >= locals));
+    }
+
+    /// Checks that no reference outlives the slots of a scope that ends: neither a borrow of a
+    /// local outside of the scope, nor a temporary borrow in the `result` of the scope
+    pub(super) fn check_dangling(&mut self, scope: &Scope, slots: &[Slot], result: Ret) -> Result<(), AssembleError> {
+        let result = self.infer.resolve(result);
+        let returns_ref = self.holds_borrow(result);
+        let dangling = self.borrows.iter().find(|b| {
+            (returns_ref || b.lifetime != Lifetime::Statement) && slots.iter().any(|s| b.overlaps(s.disp, s.size))
+        });
+        match dangling {
+            Some(borrow) => {
+                let name = self.locals[scope.locals..].iter()
+                    .find(|l| !l.by_ref && borrow.overlaps(l.slot.disp, l.slot.size))
+                    .map_or_else(String::new, |l| l.name.clone());
+                Err(AssembleFunctionError::DanglingReference { function: self.fn_name(), name }.into())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that a loop can borrow the elements of the slice `expr` at `rbp + disp`, which
+    /// another iterator of the chain or an enclosing loop may borrow already
+    pub(super) fn check_elements_borrow(&self, expr: &Expr, disp: i32, mutable: bool) -> Result<(), AssembleError> {
+        self.check_overlapping_borrow(self.location(expr).expression, Some(disp), 0, i32::MAX, mutable)
+    }
+
+    /// Checks that a loop can borrow the array `expr` at `rbp + disp`
+    pub(super) fn check_array_borrow(&self, expr: &Expr, disp: i32, size: i32, mutable: bool) -> Result<(), AssembleError> {
+        self.check_overlapping_borrow(self.location(expr).expression, None, disp, size, mutable)
+    }
+
+    /// Borrows the array at `rbp + disp` until `end_loop_borrows`
+    pub(super) fn borrow_array(&mut self, disp: i32, size: i32, mutable: bool) {
+        self.borrows.push(Borrow { through: None, disp, size, mutable, lifetime: Lifetime::Loop });
+    }
+
+    /// Borrows the elements of the slice at `rbp + disp` until `end_loop_borrows`
+    pub(super) fn borrow_elements(&mut self, disp: i32, mutable: bool) {
+        self.borrows.push(Borrow { through: Some(disp), disp: 0, size: i32::MAX, mutable, lifetime: Lifetime::Loop });
+    }
+
+    /// The borrows of locals since the first `borrows` last until `end_loop_borrows`
+    pub(super) fn extend_to_loop(&mut self, borrows: usize) {
+        for borrow in &mut self.borrows[borrows..] {
+            if borrow.through.is_none() && borrow.lifetime == Lifetime::Statement {
+                borrow.lifetime = Lifetime::Loop;
+            }
+        }
+    }
+
+    /// Ends the borrows since the first `borrows` at the end of a loop
+    pub(super) fn end_loop_borrows(&mut self, borrows: usize) {
+        self.borrows.truncate(borrows);
+    }
+
+    /// Reports an assignment to an element of the slice `expr` while a loop iterates over it
+    pub(super) fn check_elements_not_borrowed(&mut self, expr: &Expr) -> Result<(), AssembleError> {
+        if let Some((ty, disp, _)) = self.local_place(expr) {
+            let size = self.size_of(ty);
+            if self.borrows.iter().any(|b| b.iterates(disp, size)) {
+                return Err(self.use_of_borrowed_value(expr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the value that the reference in `rax` refers to
+    pub(super) fn load_referenced(&mut self, reference: Ret) -> Ret {
+        let elem = match reference {
+            Ret::Ref(id) => self.shared.types.reference(id).elem,
+            _ => unreachable!("not a reference"),
+        };
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.load_value(elem, Reg::Rcx, 0);
+        elem
+    }
+
+    /// Uses the value that the reference in `rax` refers to, which can't be moved out of it
+    pub(super) fn compile_deref<T: ToTokens>(&mut self, node: &T, reference: Ret) -> Result<Ret, AssembleError> {
+        let elem = self.load_referenced(reference);
+        if !self.shared.types.is_copy(elem) {
+            let expression = self.location(node).expression;
+            return Err(AssembleFunctionError::MoveOutOfReference { function: self.fn_name(), expression }.into());
+        }
+        Ok(elem)
+    }
+
+    /// Starts the branches of an `if` or `match`, each of them is ended with `end_branch`
+    pub(super) fn start_branches(&mut self) -> Branches {
+        Branches { start: self.moved.clone(), merged: Vec::new(), diverges: true }
+    }
+
+    /// Ends a branch, the next one starts with the moves before the first one
+    pub(super) fn end_branch(&mut self, branches: &mut Branches) {
+        let moved = mem::replace(&mut self.moved, branches.start.clone());
+        if !mem::replace(&mut self.diverges, false) {
+            for m in moved {
+                if !branches.merged.contains(&m) {
+                    branches.merged.push(m);
+                }
+            }
+            branches.diverges = false;
+        }
+    }
+
+    /// After all branches, a value is moved if a branch that doesn't return moved it
+    pub(super) fn end_branches(&mut self, branches: Branches) {
+        if !branches.diverges {
+            self.moved = branches.merged;
+        }
+        self.diverges = branches.diverges;
+    }
+
+    /// Reports a value that the body of a loop moves, unless it was moved before the loop
+    pub(super) fn check_loop_moves(&mut self, before: &[Moved]) -> Result<(), AssembleError> {
+        if !mem::replace(&mut self.diverges, false) {
+            if let Some(m) = self.moved.iter().find(|m| !before.contains(m)) {
+                return Err(self.use_of_moved_value(m.expression.clone()));
+            }
+        }
+        self.moved = before.to_vec();
+        Ok(())
+    }
+
+    /// Compiles an expression whose value is only borrowed, i.e. the receiver of `is_some`
+    pub(super) fn compile_borrowed(&mut self, expr: &Expr) -> Result<Ret, AssembleError> {
+        match self.local_place(expr) {
+            Some((ty, disp, _)) => {
+                self.check_not_moved(expr, disp, self.size_of(ty))?;
+                self.load_value(ty, Reg::Rbp, disp);
+                Ok(ty)
+            },
+            None => self.compile_expr(expr, None),
+        }
+    }
+
+    /// `value.clone()` of a type with `#[derive(Clone)]`, a value in memory is copied to a temporary
+    pub(super) fn compile_clone(&mut self, ty: Ret) -> Ret {
+        if is_memory_value(ty) {
+            let slot = self.alloc_temp(ty);
+            self.store_value(ty, Reg::Rbp, slot.disp);
+            self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        }
+        ty
+    }
+}
@@ -0,0 +1,210 @@
+//! `Fixed`, the 32.32 fixed-point numbers of `fixed.rs`, held in `rax` like their `i64` bits.
+//!
+//! Addition, subtraction, negation and comparisons are those of `i64`. A product is the 128-bit
+//! product of the bits, rounded and shifted right by 32. Division, `sqrt`, `sin` and `cos` call the
+//! functions of the host type, so that both compute the same bits. Overflow is checked like for
+//! integers. `Fixed::from_num` only converts literals, which is done while compiling.
+
+use quote::ToTokens;
+use syn::{BinOp, Expr, ExprBinary, ExprLit, ExprMethodCall, Lit, UnOp};
+use assembler::{AluOp, Cond, FloatOp, Reg, ShiftOp, Xmm};
+use compiler::{AssembleError, Ret, ScriptError, StaticFloatLiteral, StaticIntLiteral};
+use fixed::{self, Fixed};
+use super::{FnCompiler, Slot, unsupported};
+
+const I32: Ret = Ret::Int(StaticIntLiteral::I32);
+const I64: Ret = Ret::Int(StaticIntLiteral::I64);
+
+extern "sysv64" fn fixed_quotient(a: i64, b: i64) -> i128 {
+    fixed::quotient(a, b)
+}
+
+extern "sysv64" fn fixed_sqrt(bits: i64) -> i64 {
+    fixed::sqrt(bits)
+}
+
+extern "sysv64" fn fixed_sin(bits: i64, quarters: i64) -> i64 {
+    fixed::sin(bits, quarters)
+}
+
+/// The value of an integer or float literal, which may be negated
+fn literal_value(expr: &Expr) -> Option<f64> {
+    match *expr {
+        Expr::Lit(ExprLit { lit: Lit::Int(ref i), .. }) => Some(i.value() as f64),
+        Expr::Lit(ExprLit { lit: Lit::Float(ref f), .. }) => Some(f.value()),
+        Expr::Unary(ref u) if matches!(u.op, UnOp::Neg(_)) => literal_value(&u.expr).map(|value| -value),
+        Expr::Paren(ref p) => literal_value(&p.expr),
+        _ => None,
+    }
+}
+
+/// The value of `Fixed::ZERO`, `ONE`, `MIN`, `MAX` or `PI`
+pub(super) fn fixed_constant(name: &str) -> Option<Fixed> {
+    match name {
+        "ZERO" => Some(Fixed::ZERO),
+        "ONE" => Some(Fixed::ONE),
+        "MIN" => Some(Fixed::MIN),
+        "MAX" => Some(Fixed::MAX),
+        "PI" => Some(Fixed::PI),
+        _ => None,
+    }
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// `Fixed::ZERO`, `ONE`, `MIN`, `MAX` and `PI`
+    pub(super) fn compile_fixed_constant(&mut self, name: &str) -> Result<Ret, AssembleError> {
+        let value = match fixed_constant(name) {
+            Some(value) => value,
+            None => return Err(self.unknown_method(Ret::Fixed, name)),
+        };
+        self.asm.mov_ri(Reg::Rax, value.to_bits() as u64);
+        Ok(Ret::Fixed)
+    }
+
+    /// `Fixed::from_num(1.5)`, `Fixed::from_int(i32)` and `Fixed::from_bits(i64)`
+    pub(super) fn compile_fixed_constructor(&mut self, name: &str, args: &[&Expr]) -> Result<Ret, AssembleError> {
+        if !matches!(name, "from_num" | "from_int" | "from_bits") {
+            return Err(self.unknown_method(Ret::Fixed, name));
+        }
+        self.expect_argument_count(format!("Fixed::{}", name), 1, args.len())?;
+        match name {
+            "from_num" => {
+                let value = literal_value(args[0]).ok_or_else(|| unsupported(args[0]))?;
+                // the integer part has 32 bits
+                if !(-2f64.powi(31)..2f64.powi(31)).contains(&value) {
+                    return Err(self.literal_out_of_range(args[0].into_token_stream().to_string(), Ret::Fixed));
+                }
+                self.asm.mov_ri(Reg::Rax, Fixed::from_num(value).to_bits() as u64);
+            },
+            "from_int" => {
+                self.compile_expr_expect(args[0], I32)?;
+                self.asm.shift_ri(ShiftOp::Shl, Reg::Rax, 32);
+            },
+            _ => self.compile_expr_expect(args[0], I64)?,
+        }
+        Ok(Ret::Fixed)
+    }
+
+    /// Applies the operator of `b` to the left side, which was spilled to `slot`, and the right side in `rax`
+    pub(super) fn compile_fixed_arithmetic<T: ToTokens>(&mut self, b: &ExprBinary, node: &T, slot: Slot) -> Result<(), AssembleError> {
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.load_value(Ret::Fixed, Reg::Rbp, slot.disp);
+        let checks_overflow = self.checks_overflow();
+        match b.op {
+            BinOp::Add(_) | BinOp::Sub(_) => {
+                let op = if let BinOp::Add(_) = b.op { AluOp::Add } else { AluOp::Sub };
+                self.asm.alu_rr(op, Reg::Rax, Reg::Rcx);
+                if checks_overflow {
+                    let error = ScriptError::Overflow(self.location(node));
+                    self.trap_if(Cond::Overflow, error);
+                }
+            },
+            BinOp::Mul(_) => {
+                self.asm.mul_sized(Reg::Rcx, 8, true);
+                // round like `fixed::product`, the bias is 2^31 or 2^31 - 1 for a negative product
+                self.asm.mov_rr(Reg::Rcx, Reg::Rdx);
+                self.asm.shift_ri(ShiftOp::Sar, Reg::Rcx, 63);
+                self.asm.mov_ri(Reg::R8, 1 << 31);
+                self.asm.alu_rr(AluOp::Add, Reg::Rcx, Reg::R8);
+                self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.asm.alu_ri(AluOp::Adc, Reg::Rdx, 0);
+                if checks_overflow {
+                    // the bits 32 to 95 of `rdx:rax` are the result, the ones above have to be its sign
+                    self.asm.extend(Reg::Rcx, Reg::Rdx, 4, true);
+                    self.asm.alu_rr(AluOp::Cmp, Reg::Rcx, Reg::Rdx);
+                    let error = ScriptError::Overflow(self.location(node));
+                    self.trap_if(Cond::NotEqual, error);
+                }
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rax, 32);
+                self.asm.shift_ri(ShiftOp::Shl, Reg::Rdx, 32);
+                self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rdx);
+            },
+            BinOp::Div(_) => {
+                self.asm.test_rr(Reg::Rcx, Reg::Rcx);
+                let error = ScriptError::DivideByZero(self.location(node));
+                self.trap_if(Cond::Equal, error);
+                self.asm.mov_rr(Reg::Rdi, Reg::Rax);
+                self.asm.mov_rr(Reg::Rsi, Reg::Rcx);
+                self.call_address(fixed_quotient as *const u8 as usize);
+                if checks_overflow {
+                    // the 128-bit quotient fits if `rdx` is the sign of `rax`
+                    self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                    self.asm.shift_ri(ShiftOp::Sar, Reg::Rcx, 63);
+                    self.asm.alu_rr(AluOp::Cmp, Reg::Rcx, Reg::Rdx);
+                    let error = ScriptError::Overflow(self.location(node));
+                    self.trap_if(Cond::NotEqual, error);
+                }
+            },
+            _ => return Err(unsupported(b)),
+        }
+        Ok(())
+    }
+
+    /// The conversions to integers and floats, `abs`, `min`, `max`, `sqrt`, `sin` and `cos`
+    pub(super) fn compile_fixed_method(&mut self, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let method = m.method.to_string();
+        let argument_types = match &*method {
+            "to_bits" | "to_int" | "round_to_int" | "to_f32" | "to_f64" | "abs" | "sqrt" | "sin" | "cos" => vec![],
+            "min" | "max" => vec![Ret::Fixed],
+            _ => return Err(self.unknown_method(Ret::Fixed, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_types.len(), m.args.len())?;
+        let args: Vec<_> = m.args.iter().zip(argument_types).collect();
+        self.method_operands(Ret::Fixed, &args)?;
+
+        match &*method {
+            "to_bits" => return Ok(I64),
+            "to_int" => {
+                // rounds towards zero, a negative value is biased by 2^32 - 1
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.shift_ri(ShiftOp::Sar, Reg::Rcx, 63);
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 32);
+                self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.asm.shift_ri(ShiftOp::Sar, Reg::Rax, 32);
+                return Ok(I32);
+            },
+            "round_to_int" => {
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.shift_ri(ShiftOp::Sar, Reg::Rcx, 63);
+                self.asm.mov_ri(Reg::R8, 1 << 31);
+                self.asm.alu_rr(AluOp::Add, Reg::Rcx, Reg::R8);
+                self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.asm.shift_ri(ShiftOp::Sar, Reg::Rax, 32);
+                return Ok(I32);
+            },
+            "to_f32" | "to_f64" => {
+                // scaling by a power of two is exact, so this rounds once like the host type
+                let double = method == "to_f64";
+                self.asm.cvt_int_to_float(Xmm::Xmm0, Reg::Rax, double);
+                let scale = if double { 2f64.powi(-32).to_bits() } else { u64::from(2f32.powi(-32).to_bits()) };
+                self.asm.mov_ri(Reg::Rax, scale);
+                self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+                self.asm.float_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1, double);
+                let ty = if double { StaticFloatLiteral::F64 } else { StaticFloatLiteral::F32 };
+                return Ok(Ret::Float(ty));
+            },
+            "abs" => {
+                self.check_negation(StaticIntLiteral::I64, m);
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.neg(Reg::Rax);
+                self.asm.cmov(Cond::Sign, Reg::Rax, Reg::Rcx);
+            },
+            "min" | "max" => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.cmov(if method == "min" { Cond::Greater } else { Cond::Less }, Reg::Rax, Reg::Rcx);
+            },
+            "sqrt" => {
+                self.asm.mov_rr(Reg::Rdi, Reg::Rax);
+                self.call_address(fixed_sqrt as *const u8 as usize);
+            },
+            _ => {
+                // the cosine is the sine a quarter turn later
+                self.asm.mov_rr(Reg::Rdi, Reg::Rax);
+                self.asm.mov_ri(Reg::Rsi, (method == "cos") as u64);
+                self.call_address(fixed_sin as *const u8 as usize);
+            },
+        }
+        Ok(Ret::Fixed)
+    }
+}
@@ -0,0 +1,589 @@
+//! Methods of the primitive number types, `x.sqrt()`, `a.min(b)` or `n.pow(3)`.
+//!
+//! Most of them compile to a few inline instructions. The overflow of `checked_add`,
+//! `saturating_sub` and the like is detected with the flags of an operation of the
+//! width of the type. Rounding uses `roundss` if
+//! the CPU has SSE4.1 and otherwise a conversion to an integer and back, counting
+//! bits uses `popcnt` / `lzcnt` / `tzcnt` or `bsr` / `bsf` and a bit twiddling sum. The
+//! transcendental functions (`sin`, `atan2`, `powf`, ..) call the implementation
+//! of the Rust standard library that is linked into the host, so that a script
+//! computes exactly the same values as the engine does.
+
+use quote::ToTokens;
+use syn::{Expr, ExprMethodCall, ExprPath};
+use assembler::{AluOp, Cond, FloatCmp, FloatOp, Reg, RoundMode, ShiftOp, Xmm, SSE_ARG_REGS};
+use compiler::{AssembleError, Ret, ScriptError, StaticFloatLiteral, StaticIntLiteral};
+use super::FnCompiler;
+
+/// Integer registers that hold the receiver and the arguments of a method
+const INT_OPERANDS: [Reg; 3] = [Reg::Rax, Reg::Rcx, Reg::Rdx];
+
+/// An integer operation that can overflow
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum IntOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+/// Splits `i32::MAX` into the integer type and the name of the constant
+pub(super) fn int_type_item(p: &ExprPath) -> Option<(StaticIntLiteral, String)> {
+    let path = &p.path;
+    if p.qself.is_some() || path.leading_colon.is_some() || path.segments.len() != 2 {
+        return None;
+    }
+    let ty = StaticIntLiteral::from_name(&path.segments[0].ident.to_string())?;
+    Some((ty, path.segments[1].ident.to_string()))
+}
+
+/// The operation of `wrapping_add`, `checked_mul`, ..
+pub(super) fn int_op(method: &str) -> Option<IntOp> {
+    if method.ends_with("_add") {
+        Some(IntOp::Add)
+    } else if method.ends_with("_sub") {
+        Some(IntOp::Sub)
+    } else if method.ends_with("_mul") {
+        Some(IntOp::Mul)
+    } else {
+        None
+    }
+}
+
+/// The largest value of the type and the smallest one (normalized)
+fn int_bounds(i: StaticIntLiteral) -> (u64, u64) {
+    let bits = 8 * i.size() as u32;
+    if i.is_signed() {
+        let max = u64::MAX >> (65 - bits);
+        (max, !max)
+    } else {
+        (u64::MAX >> (64 - bits), 0)
+    }
+}
+
+macro_rules! math_functions {
+    ($lookup:ident, $args:tt [$($name:ident),*]) => {
+        /// Address of the standard library implementation of the method, `f32` or `f64`
+        fn $lookup(name: &str, double: bool) -> Option<usize> {
+            $(
+                if name == stringify!($name) {
+                    math_functions!(@address $name, double, $args);
+                }
+            )*
+            None
+        }
+    };
+    (@address $name:ident, $double:ident, ($($arg:ident),*)) => {
+        extern "sysv64" fn single($($arg: f32),*) -> f32 {
+            math_functions!(@call $name, $($arg),*)
+        }
+        extern "sysv64" fn double_precision($($arg: f64),*) -> f64 {
+            math_functions!(@call $name, $($arg),*)
+        }
+        return Some(if $double { double_precision as *const u8 as usize } else { single as *const u8 as usize });
+    };
+    (@call $name:ident, $x:ident) => { $x.$name() };
+    (@call $name:ident, $x:ident, $y:ident) => { $x.$name($y) };
+}
+
+math_functions!(unary_function, (x) [sin, cos, tan, asin, acos, atan, exp, exp2, ln, log2, log10, cbrt]);
+math_functions!(binary_function, (x, y) [atan2, powf, hypot]);
+
+extern "sysv64" fn powi_single(x: f32, n: i32) -> f32 {
+    x.powi(n)
+}
+
+extern "sysv64" fn powi_double(x: f64, n: i32) -> f64 {
+    x.powi(n)
+}
+
+fn sign_bit(double: bool) -> u64 {
+    if double { 1 << 63 } else { 1 << 31 }
+}
+
+/// All bits of the float except for its sign
+fn magnitude_bits(double: bool) -> u64 {
+    if double { !(1 << 63) } else { 0x7FFF_FFFF }
+}
+
+/// The smallest magnitude from which on every float is an integer
+fn first_integer_only(double: bool) -> f64 {
+    if double { 4_503_599_627_370_496.0 } else { 8_388_608.0 }
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// Calls the standard library implementation of a function with one float argument in xmm0
+    pub(super) fn call_math(&mut self, name: &str, double: bool) {
+        let address = unary_function(name, double).expect("not a math function");
+        self.call_address(address);
+    }
+
+    pub(super) fn call_address(&mut self, address: usize) {
+        self.asm.mov_ri(Reg::Rax, address as u64);
+        self.asm.call_r(Reg::Rax);
+    }
+
+    /// Loads the bits of a float into the lowest lane of `xmm`, using `rax`
+    fn float_bits(&mut self, xmm: Xmm, bits: u64) {
+        self.asm.mov_ri(Reg::Rax, bits);
+        self.asm.movq_xr(xmm, Reg::Rax);
+    }
+
+    fn float_constant(&mut self, xmm: Xmm, value: f64, double: bool) {
+        let bits = if double { value.to_bits() } else { u64::from((value as f32).to_bits()) };
+        self.float_bits(xmm, bits);
+    }
+
+    /// Evaluates the arguments of a method whose receiver was just computed. Afterwards the
+    /// receiver is in `rax` / `xmm0` and the arguments follow in `rcx`, `rdx` / `xmm1`, `xmm2`.
+    pub(super) fn method_operands(&mut self, receiver: Ret, args: &[(&Expr, Ret)]) -> Result<(), AssembleError> {
+        let mut operands = vec![(receiver, self.spill(receiver))];
+        for &(arg, ty) in args {
+            self.compile_expr_expect(arg, ty)?;
+            let ty = self.infer.resolve(ty);
+            operands.push((ty, self.spill(ty)));
+        }
+        for (i, (ty, slot)) in operands.into_iter().enumerate().rev() {
+            self.load_value(ty, Reg::Rbp, slot.disp);
+            self.frame.free(slot);
+            match ty {
+                Ret::Float(_) if i > 0 => self.asm.movaps(SSE_ARG_REGS[i], Xmm::Xmm0),
+                Ret::Float(_) => { },
+                _ if i > 0 => self.asm.mov_rr(INT_OPERANDS[i], Reg::Rax),
+                _ => { },
+            }
+        }
+        Ok(())
+    }
+
+    /// `sqrt`, `abs`, `min`, `max`, `clamp`, `floor`, `ceil`, `round`, `trunc`, `lerp`, `powi`
+    /// and the transcendental functions of `f32` / `f64`, the receiver is in xmm0
+    pub(super) fn compile_float_method(&mut self, f: StaticFloatLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let ty = Ret::Float(f);
+        let double = f.is_double();
+        let method = m.method.to_string();
+        let argument_types = match &*method {
+            "sqrt" | "abs" | "floor" | "ceil" | "round" | "trunc" => vec![],
+            "min" | "max" => vec![ty],
+            "clamp" | "lerp" => vec![ty, ty],
+            "powi" => vec![Ret::Int(StaticIntLiteral::I32)],
+            _ if unary_function(&method, double).is_some() => vec![],
+            _ if binary_function(&method, double).is_some() => vec![ty],
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_types.len(), m.args.len())?;
+        let args: Vec<_> = m.args.iter().zip(argument_types).collect();
+        self.method_operands(ty, &args)?;
+
+        match &*method {
+            "sqrt" => self.asm.float_op(FloatOp::Sqrt, Xmm::Xmm0, Xmm::Xmm0, double),
+            "abs" => {
+                self.float_bits(Xmm::Xmm1, magnitude_bits(double));
+                self.asm.andps(Xmm::Xmm0, Xmm::Xmm1);
+            },
+            "min" | "max" => self.float_min_max(method == "max", double),
+            "clamp" => {
+                // `if x < min { min } else if x > max { max } else { x }`, NaN stays NaN
+                self.asm.float_op(FloatOp::Max, Xmm::Xmm1, Xmm::Xmm0, double);
+                self.asm.float_op(FloatOp::Min, Xmm::Xmm2, Xmm::Xmm1, double);
+                self.asm.movaps(Xmm::Xmm0, Xmm::Xmm2);
+            },
+            "lerp" => {
+                // x + (end - x) * t
+                self.asm.float_op(FloatOp::Sub, Xmm::Xmm1, Xmm::Xmm0, double);
+                self.asm.float_op(FloatOp::Mul, Xmm::Xmm1, Xmm::Xmm2, double);
+                self.asm.float_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm1, double);
+            },
+            "floor" | "ceil" | "round" | "trunc" => self.round_float(&method, double),
+            "powi" => {
+                self.asm.mov_rr(Reg::Rdi, Reg::Rcx);
+                let powi = if double { powi_double as *const u8 } else { powi_single as *const u8 };
+                self.call_address(powi as usize);
+            },
+            _ => {
+                let address = unary_function(&method, double).or_else(|| binary_function(&method, double));
+                self.call_address(address.expect("not a math function"));
+            },
+        }
+        Ok(ty)
+    }
+
+    /// `xmm0.min(xmm1)` or `xmm0.max(xmm1)` into xmm0, using xmm2
+    pub(super) fn float_min_max(&mut self, max: bool, double: bool) {
+        // `minss` returns the second operand if either is NaN, but like the standard
+        // library, the result is only NaN if both are
+        let op = if max { FloatOp::Max } else { FloatOp::Min };
+        self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+        self.asm.float_op(op, Xmm::Xmm2, Xmm::Xmm1, double);
+        self.asm.cmp_float(FloatCmp::Unordered, Xmm::Xmm1, Xmm::Xmm1, double);
+        self.asm.andps(Xmm::Xmm0, Xmm::Xmm1);
+        self.asm.andnps(Xmm::Xmm1, Xmm::Xmm2);
+        self.asm.orps(Xmm::Xmm0, Xmm::Xmm1);
+    }
+
+    /// `floor`, `ceil`, `round` (half away from zero) and `trunc` of xmm0
+    fn round_float(&mut self, method: &str, double: bool) {
+        let mode = match method {
+            "floor" => RoundMode::Floor,
+            "ceil" => RoundMode::Ceil,
+            _ => RoundMode::Trunc,
+        };
+        let sse41 = self.program.features.sse41;
+        if sse41 && method != "round" {
+            self.asm.round_float(mode, Xmm::Xmm0, Xmm::Xmm0, double);
+            return;
+        }
+
+        // xmm1 = trunc(x)
+        if sse41 {
+            self.asm.round_float(RoundMode::Trunc, Xmm::Xmm1, Xmm::Xmm0, double);
+        } else {
+            // floats that are too large for an i64 are integers already, so are NaN and infinity
+            self.asm.cvt_float_to_int(Reg::Rax, Xmm::Xmm0, double);
+            self.asm.cvt_int_to_float(Xmm::Xmm1, Reg::Rax, double);
+            self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+            self.float_bits(Xmm::Xmm3, magnitude_bits(double));
+            self.asm.andps(Xmm::Xmm2, Xmm::Xmm3);
+            self.float_constant(Xmm::Xmm3, first_integer_only(double), double);
+            self.asm.cmp_float(FloatCmp::Lt, Xmm::Xmm2, Xmm::Xmm3, double);
+            self.asm.andps(Xmm::Xmm1, Xmm::Xmm2);
+            self.asm.andnps(Xmm::Xmm2, Xmm::Xmm0);
+            self.asm.orps(Xmm::Xmm1, Xmm::Xmm2);
+        }
+
+        // xmm2 = 1.0 or 0.0, xmm1 += xmm2 or xmm1 -= xmm2
+        self.float_constant(Xmm::Xmm3, 1.0, double);
+        match method {
+            "floor" => {
+                self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+                self.asm.cmp_float(FloatCmp::Lt, Xmm::Xmm2, Xmm::Xmm1, double);
+                self.asm.andps(Xmm::Xmm2, Xmm::Xmm3);
+                self.asm.float_op(FloatOp::Sub, Xmm::Xmm1, Xmm::Xmm2, double);
+            },
+            "ceil" => {
+                self.asm.movaps(Xmm::Xmm2, Xmm::Xmm1);
+                self.asm.cmp_float(FloatCmp::Lt, Xmm::Xmm2, Xmm::Xmm0, double);
+                self.asm.andps(Xmm::Xmm2, Xmm::Xmm3);
+                self.asm.float_op(FloatOp::Add, Xmm::Xmm1, Xmm::Xmm2, double);
+            },
+            "round" => {
+                // add copysign(1.0, x) if |x - trunc(x)| >= 0.5, the difference is exact
+                self.float_bits(Xmm::Xmm4, sign_bit(double));
+                self.asm.andps(Xmm::Xmm4, Xmm::Xmm0);
+                self.asm.orps(Xmm::Xmm3, Xmm::Xmm4);
+                self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+                self.asm.float_op(FloatOp::Sub, Xmm::Xmm2, Xmm::Xmm1, double);
+                self.float_bits(Xmm::Xmm4, magnitude_bits(double));
+                self.asm.andps(Xmm::Xmm2, Xmm::Xmm4);
+                self.float_constant(Xmm::Xmm4, 0.5, double);
+                self.asm.cmp_float(FloatCmp::NotLt, Xmm::Xmm2, Xmm::Xmm4, double);
+                self.asm.andps(Xmm::Xmm2, Xmm::Xmm3);
+                self.asm.float_op(FloatOp::Add, Xmm::Xmm1, Xmm::Xmm2, double);
+            },
+            _ => { },
+        }
+
+        // the result has the sign of x, also if it is zero
+        self.float_bits(Xmm::Xmm2, sign_bit(double));
+        self.asm.andps(Xmm::Xmm2, Xmm::Xmm0);
+        self.asm.orps(Xmm::Xmm1, Xmm::Xmm2);
+        self.asm.movaps(Xmm::Xmm0, Xmm::Xmm1);
+    }
+
+    /// `i32::MIN` or `u64::MAX`
+    pub(super) fn compile_int_constant(&mut self, i: StaticIntLiteral, name: &str) -> Result<Ret, AssembleError> {
+        let value = match i.limit(name) {
+            Some(value) => value,
+            None => return Err(self.unknown_method(Ret::Int(i), name)),
+        };
+        self.asm.mov_ri(Reg::Rax, value as u64);
+        if i.size() == 16 {
+            self.asm.mov_ri(Reg::Rdx, (value >> 64) as u64);
+        }
+        Ok(Ret::Int(i))
+    }
+
+    /// `abs`, `min`, `max`, `clamp`, `pow`, the bit manipulation methods and the wrapping,
+    /// saturating, checked and overflowing arithmetic of an integer in `rax`. Like the other
+    /// integer arithmetic, `abs` and `pow` wrap around on overflow unless overflow is checked.
+    pub(super) fn compile_int_method(&mut self, i: StaticIntLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        if i.size() == 16 {
+            return self.compile_wide_method(i, m);
+        }
+        let ty = Ret::Int(i);
+        // an integer of unknown type may still become signed
+        let signed = i.is_signed() || matches!(i, StaticIntLiteral::UnknownSize(_));
+        let method = m.method.to_string();
+        let argument_types = match &*method {
+            "abs" if signed => vec![],
+            "min" | "max" => vec![ty],
+            "clamp" => vec![ty, ty],
+            "pow" | "rotate_left" | "rotate_right" => vec![Ret::Int(StaticIntLiteral::U32)],
+            "count_ones" | "leading_zeros" | "trailing_zeros" | "swap_bytes" => vec![],
+            "wrapping_add" | "wrapping_sub" | "wrapping_mul" | "saturating_add" | "saturating_sub" |
+            "checked_add" | "checked_sub" | "checked_mul" | "overflowing_add" | "overflowing_sub" | "overflowing_mul" => vec![ty],
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_types.len(), m.args.len())?;
+        let args: Vec<_> = m.args.iter().zip(argument_types).collect();
+        self.method_operands(ty, &args)?;
+
+        let (less, greater) = if i.is_signed() { (Cond::Less, Cond::Greater) } else { (Cond::Below, Cond::Above) };
+        match &*method {
+            "abs" => {
+                self.check_negation(i, m);
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.neg(Reg::Rax);
+                self.asm.cmov(Cond::Sign, Reg::Rax, Reg::Rcx);
+            },
+            "min" => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.cmov(greater, Reg::Rax, Reg::Rcx);
+            },
+            "max" => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.cmov(less, Reg::Rax, Reg::Rcx);
+            },
+            "clamp" => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.cmov(less, Reg::Rax, Reg::Rcx);
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rdx);
+                self.asm.cmov(greater, Reg::Rax, Reg::Rdx);
+            },
+            "count_ones" | "leading_zeros" | "trailing_zeros" => {
+                self.count_bits(&method, i.size() as u8);
+                return Ok(Ret::Int(StaticIntLiteral::U32));
+            },
+            "swap_bytes" => {
+                self.asm.bswap(Reg::Rax);
+                if i.size() < 8 {
+                    self.asm.shift_ri(ShiftOp::Shr, Reg::Rax, 64 - 8 * i.size() as u8);
+                }
+            },
+            "rotate_left" | "rotate_right" => {
+                // the amount is in cl, the CPU takes it modulo the width of the type
+                let op = if method == "rotate_left" { ShiftOp::Rol } else { ShiftOp::Ror };
+                self.asm.shift_cl_sized(op, Reg::Rax, i.size() as u8);
+            },
+            "wrapping_add" => self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx),
+            "wrapping_sub" => self.asm.alu_rr(AluOp::Sub, Reg::Rax, Reg::Rcx),
+            "wrapping_mul" => self.asm.imul_rr(Reg::Rax, Reg::Rcx),
+            "saturating_add" | "saturating_sub" => self.saturating_int_op(&method, i),
+            _ if method.starts_with("checked_") => {
+                let op = int_op(&method).expect("not an arithmetic method");
+                let overflow = self.overflowing_int_op(op, i);
+                // the value of `None` is whatever the operation wrapped around to
+                let option = self.shared.types.option_type(ty);
+                let offset = match option {
+                    Ret::Option(id) => self.shared.types.option_value_offset(id),
+                    _ => unreachable!("not an option"),
+                };
+                let slot = self.alloc_temp(option);
+                self.asm.setcc(overflow.negate(), Reg::Rcx);
+                self.asm.store(Reg::Rbp, slot.disp, Reg::Rcx, 1);
+                self.asm.store(Reg::Rbp, slot.disp + offset, Reg::Rax, i.size() as u8);
+                self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                return Ok(option);
+            },
+            _ if method.starts_with("overflowing_") => {
+                let op = int_op(&method).expect("not an arithmetic method");
+                let overflow = self.overflowing_int_op(op, i);
+                let tuple = self.shared.types.tuple_type(vec![ty, Ret::Bool]);
+                let slot = self.alloc_temp(tuple);
+                self.asm.setcc(overflow, Reg::Rcx);
+                self.asm.store(Reg::Rbp, slot.disp, Reg::Rax, i.size() as u8);
+                self.asm.store(Reg::Rbp, slot.disp + i.size(), Reg::Rcx, 1);
+                self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                return Ok(tuple);
+            },
+            _ if self.checks_overflow() => self.checked_pow(i, m),
+            _ => {
+                // square and multiply, rdx holds the result
+                let (top, skip, end) = (self.asm.new_label(), self.asm.new_label(), self.asm.new_label());
+                self.asm.mov_ri(Reg::Rdx, 1);
+                self.asm.bind(top);
+                self.asm.test_rr(Reg::Rcx, Reg::Rcx);
+                self.asm.jcc(Cond::Equal, end);
+                // the lowest bit of the exponent is shifted into the carry flag
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 1);
+                self.asm.jcc(Cond::AboveEqual, skip);
+                self.asm.imul_rr(Reg::Rdx, Reg::Rax);
+                self.asm.bind(skip);
+                self.asm.imul_rr(Reg::Rax, Reg::Rax);
+                self.asm.jmp(top);
+                self.asm.bind(end);
+                self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+            },
+        }
+        self.normalize(ty);
+        Ok(ty)
+    }
+
+    /// `rax = rax op rcx` for integers of type `i`, normalized. Returns the condition under which
+    /// the flags signal an overflow. A multiplication overwrites `rdx`.
+    pub(super) fn overflowing_int_op(&mut self, op: IntOp, i: StaticIntLiteral) -> Cond {
+        let size = i.size() as u8;
+        let signed = i.is_signed();
+        let overflow = match op {
+            IntOp::Add | IntOp::Sub => {
+                let alu = if op == IntOp::Add { AluOp::Add } else { AluOp::Sub };
+                self.asm.alu_rr_sized(alu, Reg::Rax, Reg::Rcx, size);
+                if signed { Cond::Overflow } else { Cond::Below }
+            },
+            IntOp::Mul => {
+                self.asm.mul_sized(Reg::Rcx, size, signed);
+                Cond::Overflow
+            },
+        };
+        // neither `movsx` nor `movzx` change the flags
+        self.asm.extend(Reg::Rax, Reg::Rax, size, signed);
+        overflow
+    }
+
+    /// Traps if the signed integer in `rax` is the smallest value of its type, which has no
+    /// positive counterpart. Does nothing if overflow isn't checked.
+    pub(super) fn check_negation<T: ToTokens>(&mut self, i: StaticIntLiteral, node: &T) {
+        // unsigned integers (and literals whose type is still unknown) aren't checked
+        if !self.checks_overflow() || !i.is_signed() {
+            return;
+        }
+        let (_, min) = int_bounds(i);
+        self.asm.mov_ri(Reg::Rcx, min);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+        let error = ScriptError::Overflow(self.location(node));
+        self.trap_if(Cond::Equal, error);
+    }
+
+    /// Traps before `rax / rcx` (or `%`) if the divisor is zero or if the quotient of a signed
+    /// division (the smallest value divided by -1) doesn't fit. Unlike overflow, this is always checked.
+    pub(super) fn check_divisor<T: ToTokens>(&mut self, i: StaticIntLiteral, node: &T) {
+        self.asm.test_rr(Reg::Rcx, Reg::Rcx);
+        let error = ScriptError::DivideByZero(self.location(node));
+        self.trap_if(Cond::Equal, error);
+        if !i.is_signed() {
+            return;
+        }
+        let valid = self.asm.new_label();
+        self.asm.alu_ri(AluOp::Cmp, Reg::Rcx, -1);
+        self.asm.jcc(Cond::NotEqual, valid);
+        let (_, min) = int_bounds(i);
+        self.asm.mov_ri(Reg::Rdx, min);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rdx);
+        let error = ScriptError::Overflow(self.location(node));
+        self.trap_if(Cond::Equal, error);
+        self.asm.bind(valid);
+    }
+
+    /// `pow` of `rax` and the exponent in `rcx`, trapping if any product that is needed
+    /// overflows. rsi holds the result, r8 the base and rdi the remaining exponent.
+    fn checked_pow(&mut self, i: StaticIntLiteral, m: &ExprMethodCall) {
+        let (top, skip, end) = (self.asm.new_label(), self.asm.new_label(), self.asm.new_label());
+        self.asm.mov_rr(Reg::R8, Reg::Rax);
+        self.asm.mov_rr(Reg::Rdi, Reg::Rcx);
+        self.asm.mov_ri(Reg::Rsi, 1);
+        self.asm.bind(top);
+        self.asm.test_rr(Reg::Rdi, Reg::Rdi);
+        self.asm.jcc(Cond::Equal, end);
+        self.asm.shift_ri(ShiftOp::Shr, Reg::Rdi, 1);
+        self.asm.jcc(Cond::AboveEqual, skip);
+        self.asm.mov_rr(Reg::Rax, Reg::Rsi);
+        self.asm.mov_rr(Reg::Rcx, Reg::R8);
+        let overflow = self.overflowing_int_op(IntOp::Mul, i);
+        self.trap_if(overflow, ScriptError::Overflow(self.location(m)));
+        self.asm.mov_rr(Reg::Rsi, Reg::Rax);
+        self.asm.bind(skip);
+        // the base is only squared if a higher bit of the exponent is set
+        self.asm.test_rr(Reg::Rdi, Reg::Rdi);
+        self.asm.jcc(Cond::Equal, end);
+        self.asm.mov_rr(Reg::Rax, Reg::R8);
+        self.asm.mov_rr(Reg::Rcx, Reg::R8);
+        let overflow = self.overflowing_int_op(IntOp::Mul, i);
+        self.trap_if(overflow, ScriptError::Overflow(self.location(m)));
+        self.asm.mov_rr(Reg::R8, Reg::Rax);
+        self.asm.jmp(top);
+        self.asm.bind(end);
+        self.asm.mov_rr(Reg::Rax, Reg::Rsi);
+    }
+
+    /// `saturating_add` / `saturating_sub` of `rax` and `rcx`. The bound is chosen before the
+    /// operation, then replaces the result if it overflowed.
+    fn saturating_int_op(&mut self, method: &str, i: StaticIntLiteral) {
+        let (max, min) = int_bounds(i);
+        let op = int_op(method).expect("not an arithmetic method");
+        if i.is_signed() {
+            // a sum overflows towards the sign of rcx, a difference towards the opposite sign
+            self.asm.mov_rr(Reg::Rdx, Reg::Rcx);
+            self.asm.shift_ri(ShiftOp::Sar, Reg::Rdx, 63);
+            self.asm.mov_ri(Reg::Rsi, if op == IntOp::Add { max } else { min });
+            self.asm.alu_rr(AluOp::Xor, Reg::Rdx, Reg::Rsi);
+        } else {
+            self.asm.mov_ri(Reg::Rdx, if op == IntOp::Add { max } else { min });
+        }
+        let overflow = self.overflowing_int_op(op, i);
+        self.asm.cmov(overflow, Reg::Rax, Reg::Rdx);
+    }
+
+    /// `count_ones`, `leading_zeros` and `trailing_zeros` of the lowest `size` bytes of `rax`,
+    /// with `popcnt`, `lzcnt` and `tzcnt` if the CPU has them
+    fn count_bits(&mut self, method: &str, size: u8) {
+        let features = self.program.features;
+        let bits = 8 * size;
+        self.asm.extend(Reg::Rax, Reg::Rax, size, false);
+        match method {
+            "count_ones" if features.popcnt => self.asm.popcnt(Reg::Rax, Reg::Rax),
+            "count_ones" => {
+                // sums of 2, 4 and 8 bits, then the bytes are added up by the multiplication
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 1);
+                self.asm.mov_ri(Reg::Rdx, 0x5555_5555_5555_5555);
+                self.asm.alu_rr(AluOp::And, Reg::Rcx, Reg::Rdx);
+                self.asm.alu_rr(AluOp::Sub, Reg::Rax, Reg::Rcx);
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 2);
+                self.asm.mov_ri(Reg::Rdx, 0x3333_3333_3333_3333);
+                self.asm.alu_rr(AluOp::And, Reg::Rcx, Reg::Rdx);
+                self.asm.alu_rr(AluOp::And, Reg::Rax, Reg::Rdx);
+                self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rcx, 4);
+                self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.asm.mov_ri(Reg::Rdx, 0x0F0F_0F0F_0F0F_0F0F);
+                self.asm.alu_rr(AluOp::And, Reg::Rax, Reg::Rdx);
+                self.asm.mov_ri(Reg::Rdx, 0x0101_0101_0101_0101);
+                self.asm.imul_rr(Reg::Rax, Reg::Rdx);
+                self.asm.shift_ri(ShiftOp::Shr, Reg::Rax, 56);
+            },
+            "leading_zeros" => {
+                if features.lzcnt {
+                    self.asm.lzcnt(Reg::Rax, Reg::Rax);
+                } else {
+                    // 63 - index of the highest set bit, which is -1 for zero
+                    self.asm.bsr(Reg::Rcx, Reg::Rax);
+                    self.asm.mov_ri(Reg::Rdx, -1i64 as u64);
+                    self.asm.cmov(Cond::Equal, Reg::Rcx, Reg::Rdx);
+                    self.asm.mov_ri(Reg::Rax, 63);
+                    self.asm.alu_rr(AluOp::Sub, Reg::Rax, Reg::Rcx);
+                }
+                // the 64-bit count includes the zeros above the type
+                if bits < 64 {
+                    self.asm.alu_ri(AluOp::Sub, Reg::Rax, 64 - i32::from(bits));
+                }
+            },
+            _ => {
+                // a bit above the type limits the count of zero to the width of the type
+                if bits < 64 {
+                    self.asm.mov_ri(Reg::Rcx, 1 << bits);
+                    self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rcx);
+                }
+                if features.bmi1 {
+                    self.asm.tzcnt(Reg::Rax, Reg::Rax);
+                } else {
+                    self.asm.bsf(Reg::Rcx, Reg::Rax);
+                    self.asm.mov_ri(Reg::Rdx, 64);
+                    self.asm.cmov(Cond::Equal, Reg::Rcx, Reg::Rdx);
+                    self.asm.mov_rr(Reg::Rax, Reg::Rcx);
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! Iterators over slices, `for (i, enemy) in enemies.iter().enumerate()` and `xs.iter().sum()`.
+//!
+//! There are no iterator values, a chain of `iter`, `iter_mut`, `enumerate`, `zip` and `rev` is
+//! lowered to an indexed loop. Every element and every count of `enumerate` in the item has a
+//! stack slot with its current index, which the loop steps up, or down after `rev`, which starts
+//! it at the other end. The loop runs as often as the shortest zipped slice is long. The elements
+//! of `iter` are bound like the elements of a slice pattern, copies that can be dereferenced. An
+//! element of `iter_mut` is bound to a `&mut T` reference to the element, so it is changed in
+//! place. An array is iterated like a slice of its elements. A slice or array in a local is
+//! borrowed by the loop, see `borrow.rs`. `any` and `all` inline their closure into the loop, so
+//! it can use the locals of the function.
+
+use std::mem;
+use syn::{BinOp, Expr, ExprBinary, ExprForLoop, ExprMethodCall, ExprReference, FnArg, GenericMethodArgument, Pat, ReturnType};
+use assembler::{AluOp, Cond, Label, Reg};
+use compiler::{AssembleError, AssembleFunctionError, Ret, StaticIntLiteral};
+use super::{FnCompiler, Slot, unsupported};
+use super::pattern::{Base, Place};
+
+/// The methods that make an iterator out of a slice or another iterator
+const ADAPTERS: [&str; 5] = ["iter", "iter_mut", "enumerate", "zip", "rev"];
+
+/// Whether `expr` is an iterator chain, like `xs.iter().rev()`
+pub(super) fn is_iterator(expr: &Expr) -> bool {
+    match *expr {
+        Expr::MethodCall(ref m) => ADAPTERS.iter().any(|a| m.method == a),
+        Expr::Paren(ref p) => is_iterator(&p.expr),
+        _ => false,
+    }
+}
+
+/// A slot with the index of the current element or count
+#[derive(Debug, Copy, Clone)]
+struct Counter {
+    slot: Slot,
+    /// Counts down, after `rev`
+    down: bool,
+}
+
+/// The parts of the items of an iterator
+#[derive(Debug)]
+enum Item {
+    /// An element of the slice that is stored in `slice`, which is a copy of an array if the
+    /// elements are bound `by_value`
+    Element { slice: Slot, elem: Ret, mutable: bool, by_value: bool, index: Counter },
+    /// The count of `enumerate`
+    Count(Counter),
+    /// The items of `enumerate` and `zip`
+    Pair(Box<Item>, Box<Item>),
+}
+
+impl Item {
+    fn counters(&mut self) -> Vec<&mut Counter> {
+        match *self {
+            Item::Element { ref mut index, .. } => vec![index],
+            Item::Count(ref mut counter) => vec![counter],
+            Item::Pair(ref mut first, ref mut second) => {
+                let mut counters = first.counters();
+                counters.extend(second.counters());
+                counters
+            },
+        }
+    }
+}
+
+struct Chain {
+    item: Item,
+    /// The number of items that are left
+    len: Slot,
+}
+
+impl<'a> FnCompiler<'a> {
+
+    fn not_iterable(&self, expr: &Expr) -> AssembleError {
+        AssembleFunctionError::NotIterable { function: self.fn_name(), expression: self.location(expr).expression }.into()
+    }
+
+    /// Allocates a counter that starts at 0
+    fn new_counter(&mut self, slots: &mut Vec<Slot>) -> Counter {
+        let slot = self.frame.alloc(8, 8);
+        slots.push(slot);
+        self.asm.alu_rr(AluOp::Xor, Reg::Rcx, Reg::Rcx);
+        self.asm.store(Reg::Rbp, slot.disp, Reg::Rcx, 8);
+        Counter { slot, down: false }
+    }
+
+    /// Evaluates the slices of an iterator chain and sets up its counters. The slots are freed by
+    /// the caller after the loop.
+    fn compile_chain(&mut self, expr: &Expr, slots: &mut Vec<Slot>) -> Result<Chain, AssembleError> {
+        let m = match *expr {
+            Expr::Paren(ref p) => return self.compile_chain(&p.expr, slots),
+            Expr::MethodCall(ref m) if is_iterator(expr) => m,
+            // a slice is iterated like with `iter`, or `iter_mut` if it is a `&mut [T]`
+            _ => return self.compile_source(expr, None, slots),
+        };
+        let method = m.method.to_string();
+        if m.turbofish.is_some() {
+            return Err(unsupported(m));
+        }
+        self.expect_argument_count(method.clone(), if method == "zip" { 1 } else { 0 }, m.args.len())?;
+        match &*method {
+            "iter" | "iter_mut" => self.compile_source(&m.receiver, Some(method == "iter_mut"), slots),
+            "enumerate" => {
+                let inner = self.compile_chain(&m.receiver, slots)?;
+                let count = self.new_counter(slots);
+                Ok(Chain { item: Item::Pair(Box::new(Item::Count(count)), Box::new(inner.item)), len: inner.len })
+            },
+            "zip" => {
+                let first = self.compile_chain(&m.receiver, slots)?;
+                let second = self.compile_chain(&m.args[0], slots)?;
+                // as many items as the shorter one has
+                let len = self.frame.alloc(8, 8);
+                slots.push(len);
+                self.asm.load(Reg::Rax, Reg::Rbp, first.len.disp, 8, false);
+                self.asm.load(Reg::Rcx, Reg::Rbp, second.len.disp, 8, false);
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.cmov(Cond::Above, Reg::Rax, Reg::Rcx);
+                self.asm.store(Reg::Rbp, len.disp, Reg::Rax, 8);
+                Ok(Chain { item: Item::Pair(Box::new(first.item), Box::new(second.item)), len })
+            },
+            _ => {
+                let mut chain = self.compile_chain(&m.receiver, slots)?;
+                // the counters start at the last item and step in the other direction
+                self.asm.load(Reg::Rax, Reg::Rbp, chain.len.disp, 8, false);
+                self.asm.alu_ri(AluOp::Sub, Reg::Rax, 1);
+                for counter in chain.item.counters() {
+                    self.asm.load(Reg::Rcx, Reg::Rbp, counter.slot.disp, 8, false);
+                    self.asm.alu_rr(if counter.down { AluOp::Sub } else { AluOp::Add }, Reg::Rcx, Reg::Rax);
+                    self.asm.store(Reg::Rbp, counter.slot.disp, Reg::Rcx, 8);
+                    counter.down = !counter.down;
+                }
+                Ok(chain)
+            },
+        }
+    }
+
+    /// The elements of a slice or array, `mutable` is `None` if it depends on the type of the
+    /// slice or if the array is iterated by value
+    fn compile_source(&mut self, expr: &Expr, mutable: Option<bool>, slots: &mut Vec<Slot>) -> Result<Chain, AssembleError> {
+        match *expr {
+            // `for x in &mut array` is `for x in array.iter_mut()`, `&mut xs[1..]` is a slice
+            Expr::Reference(ref r) if mutable.is_none() && !matches!(*r.expr, Expr::Index(_)) => {
+                return self.compile_source(&r.expr, Some(r.mutability.is_some()), slots);
+            },
+            Expr::Paren(ref p) => return self.compile_source(&p.expr, mutable, slots),
+            _ => {},
+        }
+        // a slice in a local is borrowed until the end of the loop, like an array in a local
+        // unless the loop iterates over a copy of it
+        let mut array = None;
+        let borrows = self.borrows.len();
+        let borrowed = match self.local_place(expr) {
+            Some((ty, disp, local)) => match self.infer.resolve(ty) {
+                Ret::Slice(id) => {
+                    let mutable = mutable.unwrap_or(self.shared.types.slice(id).mutable);
+                    self.check_elements_borrow(expr, disp, mutable)?;
+                    Some(disp)
+                },
+                ty if self.array_of(ty).is_some() && !local.by_ref && mutable.is_some() => {
+                    let size = self.size_of(ty);
+                    self.check_array_borrow(expr, disp, size, mutable == Some(true))?;
+                    array = Some((disp, size, local.mutable));
+                    None
+                },
+                _ => None,
+            },
+            None => None,
+        };
+        let found = match (array, mutable) {
+            (Some(_), _) => self.compile_borrowed(expr)?,
+            // `STATIC.iter_mut()` borrows a `static mut` array where it is
+            (None, Some(mutable)) if self.is_static(expr) => self.compile_array_reference(&ExprReference {
+                attrs: Vec::new(),
+                and_token: Default::default(),
+                mutability: if mutable { Some(Default::default()) } else { None },
+                expr: Box::new(expr.clone()),
+            }, None)?,
+            (None, _) => self.compile_expr(expr, None)?,
+        };
+        // `for x in &mut array[1..]` borrows the array until the end of the loop
+        self.extend_to_loop(borrows);
+        let found = self.infer.resolve(found);
+        // `for x in array` binds the elements of a copy by value
+        let by_value = mutable.is_none() && self.array_of(found).is_some();
+        if by_value {
+            let copy = self.frame.alloc(self.size_of(found), self.slot_align(found));
+            slots.push(copy);
+            self.store_value(found, Reg::Rbp, copy.disp);
+            self.asm.lea(Reg::Rax, Reg::Rbp, copy.disp);
+        }
+        let found = self.array_as_slice(found, array.is_some_and(|(_, _, mutable)| mutable)).unwrap_or(found);
+        let slice = match found {
+            Ret::Slice(id) => self.shared.types.slice(id),
+            _ => return Err(self.not_iterable(expr)),
+        };
+        let mutable = mutable.unwrap_or(slice.mutable);
+        if mutable && !slice.mutable {
+            return Err(AssembleFunctionError::BorrowOfImmutable {
+                function: self.fn_name(),
+                place: self.location(expr).expression,
+            }.into());
+        }
+        if let Some(disp) = borrowed {
+            self.borrow_elements(disp, mutable);
+        }
+        if let Some((disp, size, _)) = array {
+            self.borrow_array(disp, size, mutable);
+        }
+        let source = self.frame.alloc(16, 8);
+        slots.push(source);
+        self.store_value(found, Reg::Rbp, source.disp);
+        let index = self.new_counter(slots);
+        let len = Slot { disp: source.disp + 8, size: 8 };
+        Ok(Chain { item: Item::Element { slice: source, elem: slice.elem, mutable, by_value, index }, len })
+    }
+
+    /// Compiles `body` for every item, it can continue with the next item by jumping to the first
+    /// label or leave the loop by jumping to the second one
+    fn compile_loop<F>(&mut self, chain: &mut Chain, mut body: F) -> Result<(), AssembleError>
+    where F: FnMut(&mut Self, &Item, Label, Label) -> Result<(), AssembleError>
+    {
+        let (top, next, end) = (self.asm.new_label(), self.asm.new_label(), self.asm.new_label());
+        let moved = self.moved.clone();
+        self.asm.bind(top);
+        self.asm.load(Reg::Rax, Reg::Rbp, chain.len.disp, 8, false);
+        self.asm.test_rr(Reg::Rax, Reg::Rax);
+        self.asm.jcc(Cond::Equal, end);
+        self.asm.alu_ri(AluOp::Sub, Reg::Rax, 1);
+        self.asm.store(Reg::Rbp, chain.len.disp, Reg::Rax, 8);
+
+        body(self, &chain.item, next, end)?;
+        self.check_loop_moves(&moved)?;
+        self.asm.bind(next);
+
+        for counter in chain.item.counters() {
+            self.asm.load(Reg::Rax, Reg::Rbp, counter.slot.disp, 8, false);
+            self.asm.alu_ri(if counter.down { AluOp::Sub } else { AluOp::Add }, Reg::Rax, 1);
+            self.asm.store(Reg::Rbp, counter.slot.disp, Reg::Rax, 8);
+        }
+        self.asm.jmp(top);
+        self.asm.bind(end);
+        Ok(())
+    }
+
+    /// Stores the address of the current element in a new slot of the scope
+    fn current_element(&mut self, slice: Slot, elem: Ret, index: Counter) -> Slot {
+        self.asm.load(Reg::Rax, Reg::Rbp, index.slot.disp, 8, false);
+        let size = self.size_of(elem);
+        if size != 1 {
+            self.asm.mov_ri(Reg::Rcx, size as u64);
+            self.asm.imul_rr(Reg::Rax, Reg::Rcx);
+        }
+        self.asm.load(Reg::Rcx, Reg::Rbp, slice.disp, 8, false);
+        self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx);
+        let address = self.frame.alloc(8, 8);
+        self.local_slots.push(address);
+        self.asm.store(Reg::Rbp, address.disp, Reg::Rax, 8);
+        address
+    }
+
+    /// Binds the names of the pattern to the parts of the current item, `(i, (a, b))` for
+    /// `xs.iter().zip(ys).enumerate()`
+    fn bind_item(&mut self, pat: &Pat, item: &Item) -> Result<(), AssembleError> {
+        match *item {
+            Item::Pair(ref first, ref second) => match *pat {
+                Pat::Tuple(ref t) if t.dot2_token.is_none() && t.front.len() == 2 => {
+                    self.bind_item(&t.front[0], first)?;
+                    self.bind_item(&t.front[1], second)
+                },
+                Pat::Wild(_) => Ok(()),
+                _ => Err(unsupported(pat)),
+            },
+            Item::Count(counter) => {
+                let slot = self.frame.alloc(8, 8);
+                self.local_slots.push(slot);
+                self.asm.load(Reg::Rax, Reg::Rbp, counter.slot.disp, 8, false);
+                self.asm.store(Reg::Rbp, slot.disp, Reg::Rax, 8);
+                self.bind_irrefutable(pat, Ret::Int(StaticIntLiteral::Usize), slot.disp)
+            },
+            Item::Element { slice, elem, by_value: true, index, .. } => {
+                let address = self.current_element(slice, elem, index);
+                self.bind_irrefutable_at(pat, elem, Place::new(Base::Address(address), 0))
+            },
+            Item::Element { slice, elem, mutable: false, index, .. } => {
+                let address = self.current_element(slice, elem, index);
+                self.bind_irrefutable_at(pat, elem, Place::element(address))
+            },
+            Item::Element { slice, elem, mutable: true, index, .. } => {
+                // `*e += 1` and `e.hp -= 1` change the element through the reference
+                let address = self.current_element(slice, elem, index);
+                let reference = self.shared.types.ref_type(elem, true);
+                self.bind_irrefutable(pat, reference, address.disp)
+            },
+        }
+    }
+
+    /// Binds the item and compiles `body` in a new scope
+    fn compile_item<F>(&mut self, pat: &Pat, item: &Item, body: F) -> Result<(), AssembleError>
+    where F: FnOnce(&mut Self) -> Result<(), AssembleError>
+    {
+        let scope = self.enter_scope();
+        self.bind_item(pat, item)?;
+        body(self)?;
+        self.exit_scope(scope, Ret::Void)?;
+        Ok(())
+    }
+
+    /// `for pat in iterator { .. }`
+    pub(super) fn compile_for_loop(&mut self, f: &ExprForLoop) -> Result<Ret, AssembleError> {
+        if f.label.is_some() {
+            return Err(unsupported(f));
+        }
+        let mut slots = Vec::new();
+        let borrows = self.borrows.len();
+        let mut chain = self.compile_chain(&f.expr, &mut slots)?;
+        self.compile_loop(&mut chain, |this, item, next, end| {
+            this.enter_loop(next, end);
+            this.compile_item(&f.pat, item, |this| this.compile_block_expect(&f.body, Ret::Void))
+        })?;
+        self.exit_loop();
+        self.end_loop_borrows(borrows);
+        for slot in slots {
+            self.frame.free(slot);
+        }
+        Ok(Ret::Void)
+    }
+
+    /// `sum`, `min`, `max`, `any` and `all` of an iterator chain
+    pub(super) fn compile_iterator_method(&mut self, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let method = m.method.to_string();
+        match &*method {
+            "sum" | "min" | "max" => self.expect_argument_count(method.clone(), 0, m.args.len())?,
+            "any" | "all" => self.expect_argument_count(method.clone(), 1, m.args.len())?,
+            _ => return Err(unsupported(m)),
+        }
+        let mut slots = Vec::new();
+        let borrows = self.borrows.len();
+        let mut chain = self.compile_chain(&m.receiver, &mut slots)?;
+        let ty = match &*method {
+            "any" | "all" => self.compile_predicate(m, &mut chain, &mut slots)?,
+            _ => self.compile_fold(m, &method, &mut chain, &mut slots)?,
+        };
+        self.end_loop_borrows(borrows);
+        for slot in slots {
+            self.frame.free(slot);
+        }
+        Ok(ty)
+    }
+
+    /// `sum`, `min` and `max` of the elements of a slice
+    fn compile_fold(&mut self, m: &ExprMethodCall, method: &str, chain: &mut Chain, slots: &mut Vec<Slot>)
+    -> Result<Ret, AssembleError>
+    {
+        let elem = match chain.item {
+            Item::Element { elem, .. } => elem,
+            _ => return Err(unsupported(m)),
+        };
+        let valid = match (method, elem) {
+            ("sum", Ret::Int(_)) | ("sum", Ret::Float(_)) | (_, Ret::Fixed) => true,
+            (_, Ret::Int(i)) if i.size() == 16 => false,
+            (_, Ret::Int(_)) | (_, Ret::Char) => method != "sum",
+            _ => false,
+        };
+        if !valid {
+            return Err(self.unknown_method(elem, method));
+        }
+        // `sum::<u32>()`
+        if let Some(ref turbofish) = m.turbofish {
+            match (method, turbofish.args.first().map(|a| a.into_value())) {
+                ("sum", Some(GenericMethodArgument::Type(ty))) if turbofish.args.len() == 1 => {
+                    let ty = self.resolve_type(ty)?;
+                    self.expect_type(ty, elem)?;
+                },
+                _ => return Err(unsupported(m)),
+            }
+        }
+
+        // the sum or the smallest or largest element so far
+        let result = self.frame.alloc(self.eightbytes_size(elem), 8);
+        let found = self.frame.alloc(8, 8);
+        slots.push(result);
+        slots.push(found);
+        self.asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::Rax);
+        for eightbyte in 0..result.size / 8 {
+            self.asm.store(Reg::Rbp, result.disp + 8 * eightbyte, Reg::Rax, 8);
+        }
+        self.asm.store(Reg::Rbp, found.disp, Reg::Rax, 8);
+        // only used for the operator, overflows are reported at the `sum()` call
+        let binary = ExprBinary { attrs: Vec::new(), left: m.receiver.clone(), op: BinOp::Add(Default::default()), right: m.receiver.clone() };
+
+        self.compile_loop(chain, |this, item, _, _| {
+            let (slice, index) = match *item {
+                Item::Element { slice, index, .. } => (slice, index),
+                _ => unreachable!("not an element"),
+            };
+            let scope = this.enter_scope();
+            let address = this.current_element(slice, elem, index);
+            if method == "sum" {
+                this.load_value(elem, Reg::Rbp, result.disp);
+                let left = this.spill(elem);
+                this.asm.load(Reg::Rcx, Reg::Rbp, address.disp, 8, false);
+                this.load_value(elem, Reg::Rcx, 0);
+                this.arithmetic_op(&binary, m, elem, elem, left)?;
+                this.store_value(elem, Reg::Rbp, result.disp);
+            } else {
+                // like in std, `max` returns the last and `min` the first of equal elements
+                let (keep, replace) = (this.asm.new_label(), this.asm.new_label());
+                this.asm.load(Reg::Rcx, Reg::Rbp, address.disp, 8, false);
+                this.load_value(elem, Reg::Rcx, 0);
+                this.asm.load(Reg::Rcx, Reg::Rbp, found.disp, 8, false);
+                this.asm.test_rr(Reg::Rcx, Reg::Rcx);
+                this.asm.jcc(Cond::Equal, replace);
+                this.asm.load(Reg::Rcx, Reg::Rbp, result.disp, 8, false);
+                this.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                let cond = match (method == "max", elem.is_signed()) {
+                    (true, true) => Cond::Less,
+                    (true, false) => Cond::Below,
+                    (false, true) => Cond::GreaterEqual,
+                    (false, false) => Cond::AboveEqual,
+                };
+                this.asm.jcc(cond, keep);
+                this.asm.bind(replace);
+                this.asm.store(Reg::Rbp, result.disp, Reg::Rax, 8);
+                this.asm.mov_ri(Reg::Rcx, 1);
+                this.asm.store(Reg::Rbp, found.disp, Reg::Rcx, 8);
+                this.asm.bind(keep);
+            }
+            this.exit_scope(scope, Ret::Void)?;
+            Ok(())
+        })?;
+
+        if method == "sum" {
+            self.load_value(elem, Reg::Rbp, result.disp);
+            return Ok(elem);
+        }
+        // `None` for an empty iterator
+        let ty = self.shared.types.option_type(elem);
+        let offset = match ty {
+            Ret::Option(id) => self.shared.types.option_value_offset(id),
+            _ => unreachable!("not an option"),
+        };
+        let option = self.alloc_variant(ty, false);
+        self.asm.load(Reg::Rax, Reg::Rbp, found.disp, 1, false);
+        self.asm.store(Reg::Rbp, option, Reg::Rax, 1);
+        self.load_value(elem, Reg::Rbp, result.disp);
+        self.store_value(elem, Reg::Rbp, option + offset);
+        self.asm.lea(Reg::Rax, Reg::Rbp, option);
+        Ok(ty)
+    }
+
+    /// `any(|x| ..)` and `all(|x| ..)`, which stop at the first item that decides the result
+    fn compile_predicate(&mut self, m: &ExprMethodCall, chain: &mut Chain, slots: &mut Vec<Slot>) -> Result<Ret, AssembleError> {
+        let c = match m.args[0] {
+            Expr::Closure(ref c) if c.inputs.len() == 1 && c.movability.is_none() && matches!(c.output, ReturnType::Default) => c,
+            ref arg => return Err(unsupported(arg)),
+        };
+        let pat = match c.inputs[0] {
+            FnArg::Inferred(ref pat) => pat,
+            ref arg => return Err(unsupported(arg)),
+        };
+        let any = m.method == "any";
+        let result = self.frame.alloc(8, 8);
+        slots.push(result);
+        self.asm.mov_ri(Reg::Rax, !any as u64);
+        self.asm.store(Reg::Rbp, result.disp, Reg::Rax, 8);
+
+        self.compile_loop(chain, |this, item, _, end| {
+            this.compile_item(pat, item, |this| {
+                // the closure can't return or leave the loops around it
+                let inside = mem::replace(&mut this.in_predicate, true);
+                let loops = mem::take(&mut this.loops);
+                this.compile_expr_expect(&c.body, Ret::Bool)?;
+                this.in_predicate = inside;
+                this.loops = loops;
+                let next = this.asm.new_label();
+                this.asm.test_rr(Reg::Rax, Reg::Rax);
+                this.asm.jcc(if any { Cond::Equal } else { Cond::NotEqual }, next);
+                this.asm.mov_ri(Reg::Rax, any as u64);
+                this.asm.store(Reg::Rbp, result.disp, Reg::Rax, 8);
+                this.asm.jmp(end);
+                this.asm.bind(next);
+                Ok(())
+            })
+        })?;
+        self.asm.load(Reg::Rax, Reg::Rbp, result.disp, 8, false);
+        Ok(Ret::Bool)
+    }
+}
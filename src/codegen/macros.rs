@@ -0,0 +1,255 @@
+//! `panic!`, `unreachable!`, `assert!`, `assert_eq!`, `assert_ne!` and their `debug_` variants,
+//! and the logging macros `print!`, `println!`, `eprint!`, `eprintln!` and `log!`.
+//!
+//! A failing macro copies the values of its message to the trap state and traps, the host
+//! formats the message when `JitMemory::call` returns. Like in Rust, the values of the
+//! message are only evaluated if the assertion fails. In `CompileMode::Release` the `debug_`
+//! variants are still type checked, but the code is jumped over. A logging macro copies its
+//! values to the stack and calls `logger::write_message`.
+
+use std::rc::Rc;
+use quote::ToTokens;
+use syn::{BinOp, Expr, ExprLit, Lit, Macro};
+use syn::punctuated::Punctuated;
+use syn::synom::Parser;
+use syn::token::Comma;
+use assembler::{Cond, Reg};
+use compiler::{AssembleError, AssembleFunctionError, CompileMode, Ret, Trap, MAX_TRAP_ARGUMENTS, TRAP_ARGUMENTS};
+use format::{FormatString, ARGUMENT_SIZE, is_formattable};
+use logger::{self, LogMessage, LogTarget};
+use super::{FnCompiler, Slot};
+
+/// The name of the macro, `panic` for `panic!(..)`
+fn macro_name(mac: &Macro) -> String {
+    mac.path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>().join("::")
+}
+
+/// Whether the macro never returns, so that a function can end with it
+pub(super) fn is_diverging(mac: &Macro) -> bool {
+    matches!(&*macro_name(mac), "panic" | "unreachable")
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// A macro in statement or expression position. The code after a macro that never returns
+    /// is unreachable, so it has whatever type the context expects.
+    pub(super) fn compile_macro(&mut self, mac: &Macro, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let name = macro_name(mac);
+        // like `if cfg!(debug_assertions) { .. }` in Rust
+        let skip = match self.program.mode {
+            CompileMode::Release if name.starts_with("debug_assert") => {
+                let skip = self.asm.new_label();
+                self.asm.jmp(skip);
+                Some(skip)
+            },
+            _ => None,
+        };
+        let result = match &*name {
+            "panic" | "unreachable" => {
+                let arguments = self.macro_arguments(mac)?;
+                let mut message = FormatString::new();
+                let mut values = Vec::new();
+                match (&*name, arguments.is_empty()) {
+                    ("panic", true) => message.push_str("explicit panic"),
+                    ("panic", false) => message = self.compile_message(&arguments, &mut values)?,
+                    (_, true) => message.push_str("internal error: entered unreachable code"),
+                    (_, false) => {
+                        message.push_str("internal error: entered unreachable code: ");
+                        message.append(self.compile_message(&arguments, &mut values)?);
+                    },
+                }
+                self.compile_panic(mac, message, values);
+                self.diverges = true;
+                Ok(expected.unwrap_or(Ret::Void))
+            },
+            "assert" | "debug_assert" => {
+                let arguments = self.macro_arguments(mac)?;
+                let (condition, message) = match arguments.split_first() {
+                    Some(split) => split,
+                    None => return Err(self.invalid_format(format!("`{}!` needs a condition", name))),
+                };
+                self.compile_expr_expect(condition, Ret::Bool)?;
+                let passed = self.asm.new_label();
+                self.asm.test_rr(Reg::Rax, Reg::Rax);
+                self.asm.jcc(Cond::NotEqual, passed);
+                let mut values = Vec::new();
+                let message = if message.is_empty() {
+                    let mut text = FormatString::new();
+                    text.push_str(&format!("assertion failed: {}", condition.into_token_stream()));
+                    text
+                } else {
+                    self.compile_message(message, &mut values)?
+                };
+                self.compile_panic(mac, message, values);
+                self.asm.bind(passed);
+                Ok(Ret::Void)
+            },
+            "assert_eq" | "assert_ne" | "debug_assert_eq" | "debug_assert_ne" => {
+                let arguments = self.macro_arguments(mac)?;
+                if arguments.len() < 2 {
+                    return Err(self.invalid_format(format!("`{}!` needs two values", name)));
+                }
+                let equal = name.ends_with("_eq");
+                let ty = self.compile_expr(&arguments[0], None)?;
+                if !matches!(ty, Ret::Int(_) | Ret::Float(_) | Ret::Fixed | Ret::Bool | Ret::Char) {
+                    return Err(AssembleFunctionError::UnsupportedType(self.shared.types.type_name(ty)).into());
+                }
+                let left = self.spill(ty);
+                self.compile_expr_expect(&arguments[1], ty)?;
+                let ty = self.infer.resolve(ty);
+                let right = self.spill(ty);
+                let op = if equal { BinOp::Eq(Default::default()) } else { BinOp::Ne(Default::default()) };
+                self.compare_with_slot(&op, ty, left);
+                let passed = self.asm.new_label();
+                self.asm.test_rr(Reg::Rax, Reg::Rax);
+                self.asm.jcc(Cond::NotEqual, passed);
+
+                let mut values = vec![(ty, left), (ty, right)];
+                let mut message = FormatString::new();
+                message.push_str(if equal { "assertion `left == right` failed" } else { "assertion `left != right` failed" });
+                if arguments.len() > 2 {
+                    message.push_str(": ");
+                    message.append(self.compile_message(&arguments[2..], &mut values)?);
+                }
+                message.push_str("\n  left: ");
+                message.push_argument(0, true, None);
+                message.push_str("\n right: ");
+                message.push_argument(1, true, None);
+                self.compile_panic(mac, message, values);
+                self.asm.bind(passed);
+                Ok(Ret::Void)
+            },
+            "print" | "println" | "eprint" | "eprintln" | "log" => {
+                let arguments = self.macro_arguments(mac)?;
+                let target = match &*name {
+                    "print" | "println" => LogTarget::Stdout,
+                    "eprint" | "eprintln" => LogTarget::Stderr,
+                    _ => LogTarget::Log,
+                };
+                let newline = !matches!(&*name, "print" | "eprint");
+                let mut values = Vec::new();
+                let format = match arguments.is_empty() {
+                    // only a line break
+                    true if newline && target != LogTarget::Log => FormatString::new(),
+                    true => return Err(self.invalid_format(format!("`{}!` needs a format string", name))),
+                    false => self.compile_message(&arguments, &mut values)?,
+                };
+                self.compile_log(LogMessage { target, newline, format }, values);
+                Ok(Ret::Void)
+            },
+            _ => Err(AssembleFunctionError::UnknownMacro { function: self.fn_name(), name }.into()),
+        };
+        if let Some(skip) = skip {
+            self.asm.bind(skip);
+        }
+        result
+    }
+
+    /// The comma separated arguments, parsed only once for both passes
+    fn macro_arguments(&mut self, mac: &Macro) -> Result<Rc<Vec<Expr>>, AssembleError> {
+        let key = mac as *const Macro as usize;
+        if let Some(arguments) = self.shared.macro_arguments.get(&key) {
+            return Ok(arguments.clone());
+        }
+        let parser = Punctuated::<Expr, Comma>::parse_terminated;
+        let arguments = parser.parse2(mac.tts.clone())
+            .map_err(|e| self.invalid_format(format!("`{}!`: {}", macro_name(mac), e)))?;
+        let arguments = Rc::new(arguments.into_iter().collect::<Vec<_>>());
+        self.shared.macro_arguments.insert(key, arguments.clone());
+        Ok(arguments)
+    }
+
+    /// A format string literal followed by its arguments. The values are spilled and appended
+    /// to `values`, the indices of the placeholders start after the values already there.
+    fn compile_message(&mut self, arguments: &[Expr], values: &mut Vec<(Ret, Slot)>) -> Result<FormatString, AssembleError> {
+        let template = match arguments[0] {
+            Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => s.value(),
+            ref e => return Err(self.invalid_format(format!("`{}` is not a string literal", e.into_token_stream()))),
+        };
+        let first = values.len();
+        let message = FormatString::parse(&template, first).map_err(|e| self.invalid_format(e))?;
+        let count = arguments.len() - 1;
+        if message.argument_count() > first + count {
+            return Err(self.invalid_format(format!("{:?} needs more than {} arguments", template, count)));
+        }
+        if let Some(unused) = (first..first + count).find(|i| !message.uses_argument(*i)) {
+            let unused = &arguments[unused - first + 1];
+            return Err(self.invalid_format(format!("`{}` is never used by {:?}", unused.into_token_stream(), template)));
+        }
+        if first + count > MAX_TRAP_ARGUMENTS {
+            return Err(self.invalid_format(format!("a message can contain at most {} values", MAX_TRAP_ARGUMENTS)));
+        }
+        for argument in &arguments[1..] {
+            let ty = self.compile_expr(argument, None)?;
+            let ty = self.infer.resolve(ty);
+            if !is_formattable(ty) {
+                return Err(self.invalid_format(format!("`{}` of type {} can't be formatted",
+                                                       argument.into_token_stream(), self.shared.types.type_name(ty))));
+            }
+            let slot = self.spill(ty);
+            values.push((ty, slot));
+        }
+        Ok(message)
+    }
+
+    /// Copies the values of a message to the buffer at `[base + disp]` and frees their slots
+    fn copy_message_values(&mut self, base: Reg, disp: i32, values: Vec<(Ret, Slot)>) {
+        for (i, &(ty, slot)) in values.iter().enumerate() {
+            let disp = disp + i as i32 * ARGUMENT_SIZE;
+            // a `&str` is pointer and length, a vector its lanes, a 128-bit integer both halves,
+            // everything else the (normalized) bits of the value
+            let (size, words) = match ty {
+                Ret::Str => (8, 2),
+                Ret::Int(i) if i.size() == 16 => (8, 2),
+                Ret::Vec(v) => (8, v.size() / 8),
+                _ => (ty.size() as u8, 1),
+            };
+            self.asm.load(Reg::Rax, Reg::Rbp, slot.disp, size, ty.is_signed());
+            self.asm.store(base, disp, Reg::Rax, 8);
+            if words == 2 {
+                self.asm.load(Reg::Rax, Reg::Rbp, slot.disp + 8, 8, false);
+                self.asm.store(base, disp + 8, Reg::Rax, 8);
+            }
+        }
+        for (_, slot) in values {
+            self.frame.free(slot);
+        }
+    }
+
+    /// Copies the values of the message to the trap state and traps
+    fn compile_panic(&mut self, mac: &Macro, mut message: FormatString, values: Vec<(Ret, Slot)>) {
+        let buffer = self.program.trap_state + TRAP_ARGUMENTS;
+        self.asm.lea_rodata(Reg::Rcx, buffer);
+        message.arguments = values.iter().map(|&(ty, _)| ty).collect();
+        self.copy_message_values(Reg::Rcx, 0, values);
+        let line = mac.path.segments.first().map(|s| s.value().ident.span().start().line).unwrap_or(0);
+        let trap = Trap::Panic { message, file: self.program.file_name.clone(), line: line as u32 };
+        self.asm.trap(trap, self.program.trap_handler);
+    }
+
+    /// Copies the values of the message to the stack and passes them to the logger
+    fn compile_log(&mut self, mut message: LogMessage, values: Vec<(Ret, Slot)>) {
+        let context = match self.program.log_context {
+            Some(context) => context,
+            None => {
+                for (_, slot) in values {
+                    self.frame.free(slot);
+                }
+                return;
+            },
+        };
+        message.format.arguments = values.iter().map(|&(ty, _)| ty).collect();
+        let buffer = self.frame.alloc(values.len() as i32 * ARGUMENT_SIZE, 16);
+        self.copy_message_values(Reg::Rbp, buffer.disp, values);
+        self.asm.lea_rodata(Reg::Rdi, context);
+        self.asm.load(Reg::Rdi, Reg::Rdi, 0, 8, false);
+        self.asm.mov_message(message);
+        self.asm.lea(Reg::Rdx, Reg::Rbp, buffer.disp);
+        self.call_address(logger::write_message as *const u8 as usize);
+        self.frame.free(buffer);
+    }
+
+    fn invalid_format(&self, message: String) -> AssembleError {
+        AssembleFunctionError::InvalidFormat { function: self.fn_name(), message }.into()
+    }
+}
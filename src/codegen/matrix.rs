@@ -0,0 +1,690 @@
+//! Built-in `Mat3`, `Mat4` and `Quat` types.
+//!
+//! Matrices are column-major and every column is a `Vec3` / `Vec4` in 16 bytes, so
+//! that a column is loaded with a single instruction. Like structs, matrices live in
+//! stack slots and `rax` holds their address; they are passed to other functions as
+//! a pointer to a copy and returned through a hidden pointer. A `Quat` `(x, y, z, w)`
+//! is held in an xmm register like a `Vec4`.
+//!
+//! Everything except `slerp` compiles to straight-line code. Products of matrices and
+//! vectors use packed instructions (with AVX broadcasts if the CPU has them), `inverse`
+//! and `determinant` evaluate their formulas with scalar instructions, in the same order
+//! as the usual Rust math libraries, so the results are identical to theirs.
+
+use std::ops;
+use syn::{BinOp, Expr, ExprBinary, ExprMethodCall};
+use assembler::{Cond, FloatOp, Reg, Xmm, SSE_ARG_REGS};
+use compiler::{AssembleError, AssembleFunctionError, Ret, StaticMatLiteral, StaticVecLiteral};
+use super::{FnCompiler, Slot, unsupported};
+use super::vector::{F32, shuffle};
+
+const QUAT: Ret = Ret::Quat;
+const VEC3: Ret = Ret::Vec(StaticVecLiteral::Vec3);
+
+/// Above this cosine of the angle between two rotations, `slerp` interpolates linearly
+const SLERP_LINEAR_THRESHOLD: f32 = 0.9995;
+
+/// The public fields of a matrix, its columns
+const AXES: [&str; 4] = ["x_axis", "y_axis", "z_axis", "w_axis"];
+
+/// Offset of the element in `column` and `row` of the matrix at `matrix`
+fn element(matrix: i32, column: usize, row: usize) -> i32 {
+    matrix + 16 * column as i32 + 4 * row as i32
+}
+
+/// An `f32` expression over values in the stack frame, compiled to scalar instructions
+#[derive(Debug, Clone)]
+enum Scalar {
+    /// `[rbp + disp]`
+    At(i32),
+    Const(f32),
+    Neg(Box<Scalar>),
+    Op(FloatOp, Box<Scalar>, Box<Scalar>),
+}
+
+fn at(disp: i32) -> Scalar {
+    Scalar::At(disp)
+}
+
+macro_rules! scalar_op {
+    ($trait_name:ident, $method:ident, $op:expr) => {
+        impl ops::$trait_name for Scalar {
+            type Output = Scalar;
+            fn $method(self, rhs: Scalar) -> Scalar {
+                Scalar::Op($op, Box::new(self), Box::new(rhs))
+            }
+        }
+    };
+}
+
+scalar_op!(Add, add, FloatOp::Add);
+scalar_op!(Sub, sub, FloatOp::Sub);
+scalar_op!(Mul, mul, FloatOp::Mul);
+scalar_op!(Div, div, FloatOp::Div);
+
+impl ops::Neg for Scalar {
+    type Output = Scalar;
+    fn neg(self) -> Scalar {
+        Scalar::Neg(Box::new(self))
+    }
+}
+
+/// The lanes of `a.cross(b)` for the `Vec3`s at `a` and `b`
+fn cross_lanes(a: i32, b: i32) -> Vec<Scalar> {
+    let lane = |v: i32, i: usize| at(v + 4 * (i % 3) as i32);
+    (0..3).map(|i| lane(a, i + 1) * lane(b, i + 2) - lane(b, i + 1) * lane(a, i + 2)).collect()
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// Evaluates `e` into the xmm register `depth`, the registers above it are scratch registers
+    fn emit_scalar(&mut self, e: &Scalar, depth: usize) {
+        let dst = SSE_ARG_REGS[depth];
+        match *e {
+            Scalar::At(disp) => self.asm.load_float(dst, Reg::Rbp, disp, false),
+            Scalar::Const(value) => {
+                self.asm.mov_ri(Reg::Rax, u64::from(value.to_bits()));
+                self.asm.movq_xr(dst, Reg::Rax);
+            },
+            Scalar::Neg(ref a) => {
+                self.emit_scalar(a, depth);
+                self.asm.mov_ri(Reg::Rax, 1 << 31);
+                self.asm.movq_xr(SSE_ARG_REGS[depth + 1], Reg::Rax);
+                self.asm.xorps(dst, SSE_ARG_REGS[depth + 1]);
+            },
+            Scalar::Op(op, ref a, ref b) => {
+                self.emit_scalar(a, depth);
+                self.emit_scalar(b, depth + 1);
+                self.asm.float_op(op, dst, SSE_ARG_REGS[depth + 1], false);
+            },
+        }
+    }
+
+    fn store_scalar(&mut self, disp: i32, e: Scalar) {
+        self.emit_scalar(&e, 0);
+        self.asm.store_float(Reg::Rbp, disp, Xmm::Xmm0, false);
+    }
+
+    /// Stores the lanes to `[rbp + disp]`, `[rbp + disp + 4]` ..
+    fn store_lanes(&mut self, disp: i32, lanes: Vec<Scalar>) {
+        for (i, lane) in lanes.into_iter().enumerate() {
+            self.store_scalar(disp + 4 * i as i32, lane);
+        }
+    }
+
+    /// Loads the `f32` at `[rbp + disp]` into every lane of `dst`
+    fn broadcast(&mut self, dst: Xmm, disp: i32) {
+        if self.program.features.avx {
+            self.asm.vbroadcastss(dst, Reg::Rbp, disp);
+        } else {
+            self.asm.load_float(dst, Reg::Rbp, disp, false);
+            self.asm.shufps(dst, dst, 0);
+        }
+    }
+
+    /// `x_axis * v.x + y_axis * v.y + ..` into xmm0, for the first `columns` columns of the
+    /// matrix and the vector at `vector`. With `translate`, the next column is added as well.
+    fn transform(&mut self, matrix: i32, columns: usize, vector: i32, translate: bool) {
+        for i in 0..columns {
+            self.broadcast(Xmm::Xmm1, vector + 4 * i as i32);
+            self.asm.load_packed(Xmm::Xmm2, Reg::Rbp, matrix + 16 * i as i32);
+            self.asm.packed_op(FloatOp::Mul, Xmm::Xmm2, Xmm::Xmm1);
+            if i == 0 {
+                self.asm.movaps(Xmm::Xmm0, Xmm::Xmm2);
+            } else {
+                self.asm.packed_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm2);
+            }
+        }
+        if translate {
+            self.asm.load_packed(Xmm::Xmm2, Reg::Rbp, matrix + 16 * columns as i32);
+            self.asm.packed_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm2);
+        }
+    }
+
+    /// Sets all elements of the matrix to zero, except for the diagonal
+    fn store_diagonal(&mut self, mat: StaticMatLiteral, matrix: i32, diagonal: f32) {
+        self.asm.xorps(Xmm::Xmm0, Xmm::Xmm0);
+        for column in 0..mat.columns() {
+            self.asm.store_packed(Reg::Rbp, matrix + 16 * column as i32, Xmm::Xmm0);
+        }
+        self.asm.mov_ri(Reg::Rax, u64::from(diagonal.to_bits()));
+        for i in 0..mat.columns() {
+            self.asm.store(Reg::Rbp, element(matrix, i, i), Reg::Rax, 4);
+        }
+    }
+
+    /// Writes the rotation of the (normalized) quaternion at `quat` to the first three columns of `matrix`
+    fn quat_to_axes(&mut self, quat: i32, matrix: i32) {
+        let scratch = self.frame.alloc(12 * 4, 4);
+        let t = |i: i32| scratch.disp + 4 * i;
+        let (x, y, z, w) = (quat, quat + 4, quat + 8, quat + 12);
+        let (x2, y2, z2) = (t(0), t(1), t(2));
+        self.store_scalar(x2, at(x) + at(x));
+        self.store_scalar(y2, at(y) + at(y));
+        self.store_scalar(z2, at(z) + at(z));
+        let products = [(x, x2), (x, y2), (x, z2), (y, y2), (y, z2), (z, z2), (w, x2), (w, y2), (w, z2)];
+        for (i, &(a, b)) in products.iter().enumerate() {
+            self.store_scalar(t(3 + i as i32), at(a) * at(b));
+        }
+        let [xx, xy, xz, yy, yz, zz, wx, wy, wz] = [t(3), t(4), t(5), t(6), t(7), t(8), t(9), t(10), t(11)].map(at);
+        let one = || Scalar::Const(1.0);
+        let zero = || Scalar::Const(0.0);
+
+        self.store_lanes(matrix, vec![
+            one() - (yy.clone() + zz.clone()), xy.clone() + wz.clone(), xz.clone() - wy.clone(), zero(),
+        ]);
+        self.store_lanes(matrix + 16, vec![
+            xy - wz, one() - (xx.clone() + zz), yz.clone() + wx.clone(), zero(),
+        ]);
+        self.store_lanes(matrix + 32, vec![
+            xz + wy, yz - wx, one() - (xx + yy), zero(),
+        ]);
+        self.frame.free(scratch);
+    }
+
+    /// The determinant of the matrix at `matrix`, into xmm0
+    fn determinant(&mut self, mat: StaticMatLiteral, matrix: i32) {
+        let m = |column: usize, row: usize| at(element(matrix, column, row));
+        match mat {
+            StaticMatLiteral::Mat3 => {
+                // z_axis.dot(x_axis.cross(y_axis))
+                let cross = self.frame.alloc(12, 4);
+                self.store_lanes(cross.disp, cross_lanes(matrix, matrix + 16));
+                let c = |i: i32| at(cross.disp + 4 * i);
+                self.emit_scalar(&(m(2, 0) * c(0) + m(2, 1) * c(1) + m(2, 2) * c(2)), 0);
+                self.frame.free(cross);
+            },
+            StaticMatLiteral::Mat4 => {
+                // determinants of the 2x2 minors in the lower right
+                let minors = self.frame.alloc(6 * 4, 4);
+                let pairs = [(2, 3), (1, 3), (1, 2), (0, 3), (0, 2), (0, 1)];
+                for (k, &(i, j)) in pairs.iter().enumerate() {
+                    self.store_scalar(minors.disp + 4 * k as i32, m(2, i) * m(3, j) - m(2, j) * m(3, i));
+                }
+                let a = |k: i32| at(minors.disp + 4 * k);
+                let (a2323, a1323, a1223, a0323, a0223, a0123) = (a(0), a(1), a(2), a(3), a(4), a(5));
+                let det = m(0, 0) * (m(1, 1) * a2323.clone() - m(1, 2) * a1323.clone() + m(1, 3) * a1223.clone())
+                    - m(0, 1) * (m(1, 0) * a2323 - m(1, 2) * a0323.clone() + m(1, 3) * a0223.clone())
+                    + m(0, 2) * (m(1, 0) * a1323 - m(1, 1) * a0323 + m(1, 3) * a0123.clone())
+                    - m(0, 3) * (m(1, 0) * a1223 - m(1, 1) * a0223 + m(1, 2) * a0123);
+                self.emit_scalar(&det, 0);
+                self.frame.free(minors);
+            },
+        }
+    }
+
+    /// Writes the inverse of the matrix at `matrix` to `result`. The inverse of a singular
+    /// matrix contains infinities or NaN, like in Rust.
+    fn inverse(&mut self, mat: StaticMatLiteral, matrix: i32, result: i32) {
+        let m = |column: usize, row: usize| at(element(matrix, column, row));
+        let scratch = self.frame.alloc(19 * 4, 4);
+        let rcp_det = scratch.disp;
+
+        match mat {
+            StaticMatLiteral::Mat3 => {
+                // the rows of the inverse are the cross products of the columns, divided by the determinant
+                let rows = [(1, 2), (2, 0), (0, 1)];
+                for (i, &(a, b)) in rows.iter().enumerate() {
+                    self.store_lanes(scratch.disp + 4 + 12 * i as i32, cross_lanes(matrix + 16 * a, matrix + 16 * b));
+                }
+                let row = |i: usize, lane: usize| at(scratch.disp + 4 + 12 * i as i32 + 4 * lane as i32);
+                self.store_scalar(rcp_det, m(2, 0) * row(2, 0) + m(2, 1) * row(2, 1) + m(2, 2) * row(2, 2));
+                self.store_scalar(rcp_det, Scalar::Const(1.0) / at(rcp_det));
+                for column in 0..3 {
+                    let lanes = (0..3).map(|r| row(r, column) * at(rcp_det)).collect();
+                    self.store_lanes(result + 16 * column as i32, lanes);
+                }
+            },
+            StaticMatLiteral::Mat4 => {
+                // 2x2 subdeterminants of the last three columns, in groups of three
+                let coefficients = [
+                    ((2, 2), (3, 3), (3, 2), (2, 3)), ((1, 2), (3, 3), (3, 2), (1, 3)), ((1, 2), (2, 3), (2, 2), (1, 3)),
+                    ((2, 1), (3, 3), (3, 1), (2, 3)), ((1, 1), (3, 3), (3, 1), (1, 3)), ((1, 1), (2, 3), (2, 1), (1, 3)),
+                    ((2, 1), (3, 2), (3, 1), (2, 2)), ((1, 1), (3, 2), (3, 1), (1, 2)), ((1, 1), (2, 2), (2, 1), (1, 2)),
+                    ((2, 0), (3, 3), (3, 0), (2, 3)), ((1, 0), (3, 3), (3, 0), (1, 3)), ((1, 0), (2, 3), (2, 0), (1, 3)),
+                    ((2, 0), (3, 2), (3, 0), (2, 2)), ((1, 0), (3, 2), (3, 0), (1, 2)), ((1, 0), (2, 2), (2, 0), (1, 2)),
+                    ((2, 0), (3, 1), (3, 0), (2, 1)), ((1, 0), (3, 1), (3, 0), (1, 1)), ((1, 0), (2, 1), (2, 0), (1, 1)),
+                ];
+                // the first slot holds the reciprocal of the determinant
+                let coefficient = |i: usize| scratch.disp + 4 + 4 * i as i32;
+                for (i, &(a, b, c, d)) in coefficients.iter().enumerate() {
+                    self.store_scalar(coefficient(i), m(a.0, a.1) * m(b.0, b.1) - m(c.0, c.1) * m(d.0, d.1));
+                }
+
+                // lane k of factor g, the first coefficient of each group is used twice
+                let factor = |g: usize, k: usize| at(coefficient(3 * g + [0, 0, 1, 2][k]));
+                // lane k of the vector that is built from column `i` of the first two columns
+                let vector = |i: usize, k: usize| if k == 0 { m(1, i) } else { m(0, i) };
+                let terms = [[(1, 0), (2, 1), (3, 2)], [(0, 0), (2, 3), (3, 4)], [(0, 1), (1, 3), (3, 5)], [(0, 2), (1, 4), (2, 5)]];
+                for (column, t) in terms.iter().enumerate() {
+                    let lanes = (0..4).map(|k| {
+                        let lane = vector(t[0].0, k) * factor(t[0].1, k) - vector(t[1].0, k) * factor(t[1].1, k)
+                            + vector(t[2].0, k) * factor(t[2].1, k);
+                        if (column + k) % 2 == 1 { -lane } else { lane }
+                    }).collect();
+                    self.store_lanes(result + 16 * column as i32, lanes);
+                }
+
+                let inv = |column: usize| at(element(result, column, 0));
+                self.store_scalar(rcp_det, m(0, 0) * inv(0) + m(0, 1) * inv(1) + m(0, 2) * inv(2) + m(0, 3) * inv(3));
+                self.store_scalar(rcp_det, Scalar::Const(1.0) / at(rcp_det));
+                self.broadcast(Xmm::Xmm1, rcp_det);
+                for column in 0..4 {
+                    self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, result + 16 * column);
+                    self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+                    self.asm.store_packed(Reg::Rbp, result + 16 * column, Xmm::Xmm0);
+                }
+            },
+        }
+        self.frame.free(scratch);
+    }
+
+    /// `Mat4::IDENTITY` and `Mat4::ZERO`
+    pub(super) fn compile_mat_constant(&mut self, mat: StaticMatLiteral, name: &str) -> Result<Ret, AssembleError> {
+        let ty = Ret::Mat(mat);
+        let diagonal = match name {
+            "IDENTITY" => 1.0,
+            "ZERO" => 0.0,
+            _ => return Err(self.unknown_method(ty, name)),
+        };
+        let slot = self.alloc_temp(ty);
+        self.store_diagonal(mat, slot.disp, diagonal);
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(ty)
+    }
+
+    /// `Mat4::from_cols(x, y, z, w)`, `from_quat(rotation)`, `from_translation(v)`,
+    /// `from_scale(v)` and `from_rotation_translation(rotation, v)`.
+    /// `Mat3` only has `from_cols` and `from_quat`.
+    pub(super) fn compile_mat_constructor(&mut self, mat: StaticMatLiteral, name: &str, args: &[&Expr])
+    -> Result<Ret, AssembleError>
+    {
+        let ty = Ret::Mat(mat);
+        let is_mat4 = mat == StaticMatLiteral::Mat4;
+        let argument_count = match name {
+            "from_cols" => mat.columns(),
+            "from_quat" => 1,
+            "from_translation" | "from_scale" if is_mat4 => 1,
+            "from_rotation_translation" if is_mat4 => 2,
+            _ => return Err(self.unknown_method(ty, name)),
+        };
+        self.expect_argument_count(format!("{:?}::{}", mat, name), argument_count, args.len())?;
+
+        let slot = self.alloc_temp(ty);
+        let w_axis = slot.disp + 48;
+        match name {
+            "from_cols" => for (i, arg) in args.iter().enumerate() {
+                self.compile_expr_expect(arg, Ret::Vec(mat.column()))?;
+                self.asm.store_packed(Reg::Rbp, slot.disp + 16 * i as i32, Xmm::Xmm0);
+            },
+            "from_quat" | "from_rotation_translation" => {
+                self.compile_expr_expect(args[0], QUAT)?;
+                let quat = self.spill(QUAT);
+                self.quat_to_axes(quat.disp, slot.disp);
+                self.frame.free(quat);
+                if is_mat4 {
+                    if name == "from_quat" {
+                        self.asm.xorps(Xmm::Xmm0, Xmm::Xmm0);
+                    } else {
+                        self.compile_expr_expect(args[1], VEC3)?;
+                    }
+                    self.asm.store_packed(Reg::Rbp, w_axis, Xmm::Xmm0);
+                    self.store_scalar(w_axis + 12, Scalar::Const(1.0));
+                }
+            },
+            "from_translation" => {
+                self.store_diagonal(mat, slot.disp, 1.0);
+                self.compile_expr_expect(args[0], VEC3)?;
+                self.asm.store_packed(Reg::Rbp, w_axis, Xmm::Xmm0);
+                self.store_scalar(w_axis + 12, Scalar::Const(1.0));
+            },
+            _ => {
+                self.store_diagonal(mat, slot.disp, 1.0);
+                self.compile_expr_expect(args[0], VEC3)?;
+                let scale = self.spill(VEC3);
+                for i in 0..3 {
+                    self.store_scalar(element(slot.disp, i, i), at(scale.disp + 4 * i as i32));
+                }
+                self.frame.free(scale);
+            },
+        }
+        self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        Ok(ty)
+    }
+
+    /// `m.x_axis` .. `m.w_axis`, the address of the matrix is in `rax`
+    pub(super) fn compile_mat_field(&mut self, mat: StaticMatLiteral, name: &str) -> Result<Ret, AssembleError> {
+        match AXES[..mat.columns()].iter().position(|axis| *axis == name) {
+            Some(column) => {
+                self.asm.load_packed(Xmm::Xmm0, Reg::Rax, 16 * column as i32);
+                Ok(Ret::Vec(mat.column()))
+            },
+            None => Err(AssembleFunctionError::UnknownField {
+                function: self.fn_name(),
+                ty: format!("{:?}", mat),
+                field: name.into(),
+            }.into()),
+        }
+    }
+
+    /// `m * n` and `m * v`, the matrix was spilled to `slot`, the right side is in `rax` / xmm0
+    pub(super) fn compile_mat_arithmetic(&mut self, b: &ExprBinary, mat: StaticMatLiteral, right: Ret, slot: Slot)
+    -> Result<Ret, AssembleError>
+    {
+        let ty = Ret::Mat(mat);
+        let column = Ret::Vec(mat.column());
+        if let BinOp::Mul(_) = b.op { } else {
+            self.expect_type(ty, right)?;
+            return Err(unsupported(b));
+        }
+
+        let result = if let Ret::Mat(_) = right {
+            self.expect_type(ty, right)?;
+            // every column of the result is the left matrix times a column of the right one
+            let other = self.spill(ty);
+            let result = self.alloc_temp(ty);
+            for i in 0..mat.columns() as i32 {
+                self.transform(slot.disp, mat.columns(), other.disp + 16 * i, false);
+                self.asm.store_packed(Reg::Rbp, result.disp + 16 * i, Xmm::Xmm0);
+            }
+            self.frame.free(other);
+            self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+            ty
+        } else {
+            self.expect_type(column, right)?;
+            let vector = self.spill(column);
+            self.transform(slot.disp, mat.columns(), vector.disp, false);
+            self.frame.free(vector);
+            column
+        };
+        self.frame.free(slot);
+        Ok(result)
+    }
+
+    /// `transpose`, `inverse`, `determinant` and, for `Mat4`, `transform_point3` and
+    /// `transform_vector3` (which ignore the bottom row). The address of the matrix is in `rax`.
+    pub(super) fn compile_mat_method(&mut self, mat: StaticMatLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let ty = Ret::Mat(mat);
+        let method = m.method.to_string();
+        let argument_count = match &*method {
+            "transpose" | "inverse" | "determinant" => 0,
+            "transform_point3" | "transform_vector3" if mat == StaticMatLiteral::Mat4 => 1,
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_count, m.args.len())?;
+
+        let matrix = self.spill(ty);
+        let columns = mat.columns();
+        let result = match &*method {
+            "transpose" => {
+                let result = self.alloc_temp(ty);
+                for column in 0..columns {
+                    for row in 0..columns {
+                        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, element(matrix.disp, row, column), false);
+                        self.asm.store_float(Reg::Rbp, element(result.disp, column, row), Xmm::Xmm0, false);
+                    }
+                }
+                self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+                ty
+            },
+            "inverse" => {
+                let result = self.alloc_temp(ty);
+                self.inverse(mat, matrix.disp, result.disp);
+                self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+                ty
+            },
+            "determinant" => {
+                self.determinant(mat, matrix.disp);
+                F32
+            },
+            _ => {
+                self.compile_expr_expect(&m.args[0], VEC3)?;
+                let vector = self.spill(VEC3);
+                self.transform(matrix.disp, 3, vector.disp, method == "transform_point3");
+                self.frame.free(vector);
+                VEC3
+            },
+        };
+        self.frame.free(matrix);
+        Ok(result)
+    }
+
+    /// `Quat::IDENTITY`
+    pub(super) fn compile_quat_constant(&mut self, name: &str) -> Result<Ret, AssembleError> {
+        if name != "IDENTITY" {
+            return Err(self.unknown_method(QUAT, name));
+        }
+        // (0, 0, 0, 1)
+        self.asm.mov_ri(Reg::Rax, u64::from(1f32.to_bits()));
+        self.asm.movq_xr(Xmm::Xmm0, Reg::Rax);
+        self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, shuffle([1, 1, 1, 0]));
+        Ok(QUAT)
+    }
+
+    /// Stores `sin(angle / 2)` and `cos(angle / 2)` of the angle in xmm0 to `[rbp + disp]` and `[rbp + disp + 4]`
+    fn half_angle_sin_cos(&mut self, disp: i32) {
+        self.asm.store_float(Reg::Rbp, disp + 4, Xmm::Xmm0, false);
+        self.store_scalar(disp + 4, at(disp + 4) * Scalar::Const(0.5));
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, disp + 4, false);
+        self.call_math("sin", false);
+        self.asm.store_float(Reg::Rbp, disp, Xmm::Xmm0, false);
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, disp + 4, false);
+        self.call_math("cos", false);
+        self.asm.store_float(Reg::Rbp, disp + 4, Xmm::Xmm0, false);
+    }
+
+    /// `Quat::from_xyzw(x, y, z, w)`, `from_axis_angle(axis, angle)` (the axis has to be
+    /// normalized) and `from_rotation_x(angle)` / `_y` / `_z`, angles are in radians
+    pub(super) fn compile_quat_constructor(&mut self, name: &str, args: &[&Expr]) -> Result<Ret, AssembleError> {
+        let argument_count = match name {
+            "from_xyzw" => 4,
+            "from_axis_angle" => 2,
+            "from_rotation_x" | "from_rotation_y" | "from_rotation_z" => 1,
+            _ => return Err(self.unknown_method(QUAT, name)),
+        };
+        self.expect_argument_count(format!("Quat::{}", name), argument_count, args.len())?;
+        if name == "from_xyzw" {
+            self.compile_lanes(args)?;
+            return Ok(QUAT);
+        }
+
+        let axis = if name == "from_axis_angle" {
+            self.compile_expr_expect(args[0], VEC3)?;
+            Some(self.spill(VEC3))
+        } else {
+            None
+        };
+        self.compile_expr_expect(args[args.len() - 1], F32)?;
+        let sin_cos = self.frame.alloc(8, 4);
+        self.half_angle_sin_cos(sin_cos.disp);
+        let (s, c) = (at(sin_cos.disp), at(sin_cos.disp + 4));
+
+        let lanes = match axis {
+            Some(axis) => (0..3).map(|i| at(axis.disp + 4 * i) * s.clone()).chain(Some(c)).collect(),
+            None => {
+                let mut lanes = vec![Scalar::Const(0.0), Scalar::Const(0.0), Scalar::Const(0.0), c];
+                let lane = match name.as_bytes()[name.len() - 1] {
+                    b'x' => 0,
+                    b'y' => 1,
+                    _ => 2,
+                };
+                lanes[lane] = s;
+                lanes
+            },
+        };
+        let slot = self.frame.alloc(16, 16);
+        self.store_lanes(slot.disp, lanes);
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, slot.disp);
+        self.frame.free(slot);
+        self.frame.free(sin_cos);
+        if let Some(axis) = axis {
+            self.frame.free(axis);
+        }
+        Ok(QUAT)
+    }
+
+    /// `q.x` .. `q.w`, the quaternion is in xmm0
+    pub(super) fn compile_quat_field(&mut self, name: &str) -> Result<Ret, AssembleError> {
+        let lane = match name {
+            "x" => 0,
+            "y" => 1,
+            "z" => 2,
+            "w" => 3,
+            _ => return Err(AssembleFunctionError::UnknownField {
+                function: self.fn_name(),
+                ty: "Quat".into(),
+                field: name.into(),
+            }.into()),
+        };
+        if lane != 0 {
+            self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, lane * 0x55);
+        }
+        Ok(F32)
+    }
+
+    /// `q * r` (rotating by `r`, then by `q`) and `q * v` (rotating a `Vec3`),
+    /// `q` was spilled to `slot`, the right side is in xmm0
+    pub(super) fn compile_quat_arithmetic(&mut self, b: &ExprBinary, right: Ret, slot: Slot) -> Result<Ret, AssembleError> {
+        if let BinOp::Mul(_) = b.op { } else {
+            self.expect_type(QUAT, right)?;
+            return Err(unsupported(b));
+        }
+        let q = |i: i32| at(slot.disp + 4 * i);
+        let result = self.frame.alloc(16, 16);
+
+        let ty = if right == VEC3 {
+            let vector = self.spill(VEC3);
+            let v = |i: i32| at(vector.disp + 4 * i);
+            // v * (w * w - b.dot(b)) + b * (v.dot(b) * 2) + b.cross(v) * (w * 2), where b = q.xyz
+            let scratch = self.frame.alloc(6 * 4, 4);
+            let t = |i: i32| scratch.disp + 4 * i;
+            self.store_scalar(t(0), q(3) * q(3) - (q(0) * q(0) + q(1) * q(1) + q(2) * q(2)));
+            self.store_scalar(t(1), (v(0) * q(0) + v(1) * q(1) + v(2) * q(2)) * Scalar::Const(2.0));
+            self.store_scalar(t(2), q(3) * Scalar::Const(2.0));
+            self.store_lanes(t(3), cross_lanes(slot.disp, vector.disp));
+            let lanes = (0..3).map(|i| v(i) * at(t(0)) + q(i) * at(t(1)) + at(t(3 + i)) * at(t(2))).collect();
+            self.store_lanes(result.disp, lanes);
+            self.frame.free(scratch);
+            self.frame.free(vector);
+            VEC3
+        } else {
+            self.expect_type(QUAT, right)?;
+            let other = self.spill(QUAT);
+            let r = |i: i32| at(other.disp + 4 * i);
+            let (x0, y0, z0, w0) = (q(0), q(1), q(2), q(3));
+            let (x1, y1, z1, w1) = (r(0), r(1), r(2), r(3));
+            self.store_lanes(result.disp, vec![
+                w0.clone() * x1.clone() + x0.clone() * w1.clone() + y0.clone() * z1.clone() - z0.clone() * y1.clone(),
+                w0.clone() * y1.clone() - x0.clone() * z1.clone() + y0.clone() * w1.clone() + z0.clone() * x1.clone(),
+                w0.clone() * z1.clone() + x0.clone() * y1.clone() - y0.clone() * x1.clone() + z0.clone() * w1.clone(),
+                w0 * w1 - x0 * x1 - y0 * y1 - z0 * z1,
+            ]);
+            self.frame.free(other);
+            QUAT
+        };
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, result.disp);
+        self.frame.free(result);
+        self.frame.free(slot);
+        Ok(ty)
+    }
+
+    /// `inverse` / `conjugate` (the same for a normalized quaternion), `slerp`,
+    /// and the methods of `Vec4` except for swizzles. The quaternion is in xmm0.
+    pub(super) fn compile_quat_method(&mut self, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let method = m.method.to_string();
+        match &*method {
+            "inverse" | "conjugate" => {
+                self.expect_argument_count(method.clone(), 0, m.args.len())?;
+                // flip the sign bits of x, y and z
+                self.asm.mov_ri(Reg::Rax, 0x8000_0000_8000_0000);
+                self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+                self.asm.shufps(Xmm::Xmm1, Xmm::Xmm1, shuffle([0, 1, 0, 2]));
+                self.asm.xorps(Xmm::Xmm0, Xmm::Xmm1);
+                Ok(QUAT)
+            },
+            "slerp" => {
+                self.expect_argument_count(method.clone(), 2, m.args.len())?;
+                self.compile_slerp(&m.args[0], &m.args[1])
+            },
+            _ => self.compile_lane_method(QUAT, 4, m),
+        }
+    }
+
+    /// `q.slerp(end, s)`: takes the shorter arc, nearly identical rotations are
+    /// interpolated linearly and normalized
+    fn compile_slerp(&mut self, end: &Expr, s: &Expr) -> Result<Ret, AssembleError> {
+        let start = self.spill(QUAT);
+        self.compile_expr_expect(end, QUAT)?;
+        let end = self.spill(QUAT);
+        self.compile_expr_expect(s, F32)?;
+        let scratch = self.frame.alloc(5 * 4, 4);
+        let (s, dot, theta, scale1, scale2) = (scratch.disp, scratch.disp + 4, scratch.disp + 8, scratch.disp + 12, scratch.disp + 16);
+        self.asm.store_float(Reg::Rbp, s, Xmm::Xmm0, false);
+
+        let q = |i: i32| at(start.disp + 4 * i);
+        let e = |i: i32| at(end.disp + 4 * i);
+        self.store_scalar(dot, q(0) * e(0) + q(1) * e(1) + q(2) * e(2) + q(3) * e(3));
+
+        let shorter = self.asm.new_label();
+        let linear = self.asm.new_label();
+        let done = self.asm.new_label();
+
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, dot, false);
+        self.asm.xorps(Xmm::Xmm1, Xmm::Xmm1);
+        self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, false);
+        self.asm.jcc(Cond::AboveEqual, shorter);
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, end.disp);
+        self.compile_vec_neg();
+        self.asm.store_packed(Reg::Rbp, end.disp, Xmm::Xmm0);
+        self.store_scalar(dot, -at(dot));
+        self.asm.bind(shorter);
+
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, dot, false);
+        self.asm.mov_ri(Reg::Rax, u64::from(SLERP_LINEAR_THRESHOLD.to_bits()));
+        self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+        self.asm.ucomis(Xmm::Xmm0, Xmm::Xmm1, false);
+        self.asm.jcc(Cond::Above, linear);
+
+        // (start * sin(theta * (1 - s)) + end * sin(theta * s)) * (1 / sin(theta))
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, dot, false);
+        self.call_math("acos", false);
+        self.asm.store_float(Reg::Rbp, theta, Xmm::Xmm0, false);
+        self.emit_scalar(&(at(theta) * (Scalar::Const(1.0) - at(s))), 0);
+        self.call_math("sin", false);
+        self.asm.store_float(Reg::Rbp, scale1, Xmm::Xmm0, false);
+        self.emit_scalar(&(at(theta) * at(s)), 0);
+        self.call_math("sin", false);
+        self.asm.store_float(Reg::Rbp, scale2, Xmm::Xmm0, false);
+        self.asm.load_float(Xmm::Xmm0, Reg::Rbp, theta, false);
+        self.call_math("sin", false);
+        self.asm.store_float(Reg::Rbp, theta, Xmm::Xmm0, false);
+        self.store_scalar(theta, Scalar::Const(1.0) / at(theta));
+
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, start.disp);
+        self.broadcast(Xmm::Xmm1, scale1);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+        self.asm.load_packed(Xmm::Xmm2, Reg::Rbp, end.disp);
+        self.broadcast(Xmm::Xmm3, scale2);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm2, Xmm::Xmm3);
+        self.asm.packed_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm2);
+        self.broadcast(Xmm::Xmm1, theta);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+        self.asm.jmp(done);
+
+        // start + (end - start) * s
+        self.asm.bind(linear);
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, end.disp);
+        self.asm.load_packed(Xmm::Xmm1, Reg::Rbp, start.disp);
+        self.asm.packed_op(FloatOp::Sub, Xmm::Xmm0, Xmm::Xmm1);
+        self.broadcast(Xmm::Xmm2, s);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm2);
+        self.asm.packed_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm1);
+        self.normalize_lanes(QUAT, 4);
+        self.asm.bind(done);
+
+        self.frame.free(scratch);
+        self.frame.free(end);
+        self.frame.free(start);
+        Ok(QUAT)
+    }
+}
@@ -0,0 +1,84 @@
+//! Operators on structs, which call the method of an `impl Add for Money` or another operator trait
+
+use syn::{BinOp, Expr};
+use compiler::{AssembleError, AssembleFunctionError, GlobalLabel, Ret};
+use super::{CallTarget, FnCompiler};
+
+/// The trait of a binary operator that scripts can implement
+pub(super) fn binary_trait(op: &BinOp) -> Option<&'static str> {
+    Some(match *op {
+        BinOp::Add(_) => "Add",
+        BinOp::Sub(_) => "Sub",
+        BinOp::Mul(_) => "Mul",
+        BinOp::Div(_) => "Div",
+        BinOp::Rem(_) => "Rem",
+        _ => return None,
+    })
+}
+
+/// The trait of any binary operator, for the error if a struct doesn't implement it
+pub(super) fn operator_trait(op: &BinOp) -> Option<&'static str> {
+    binary_trait(op).or(match *op {
+        BinOp::BitAnd(_) => Some("BitAnd"),
+        BinOp::BitOr(_) => Some("BitOr"),
+        BinOp::BitXor(_) => Some("BitXor"),
+        BinOp::Shl(_) => Some("Shl"),
+        BinOp::Shr(_) => Some("Shr"),
+        _ => None,
+    })
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// The functions of the impls of the trait for `ty`, one for every type of the right side
+    fn operator_impls(&self, trait_name: &str, ty: Ret) -> Vec<GlobalLabel> {
+        self.program.operators.iter().filter(|o| o.trait_name == trait_name && o.ty == ty).map(|o| o.label).collect()
+    }
+
+    pub(super) fn implements(&self, trait_name: &str, ty: Ret) -> bool {
+        self.program.operators.iter().any(|o| o.trait_name == trait_name && o.ty == ty)
+    }
+
+    pub(super) fn missing_impl(&self, trait_name: &str, ty: Ret) -> AssembleError {
+        AssembleFunctionError::MissingTraitImpl { function: self.fn_name(), ty, trait_name: trait_name.into() }.into()
+    }
+
+    /// Calls the method of the trait with the value in `rax` as `self` and `right` as the other
+    /// operand, if the trait has one. The impl is chosen by the type of `right`.
+    pub(super) fn compile_operator_call(&mut self, trait_name: &str, ty: Ret, right: Option<&Expr>) -> Result<Ret, AssembleError> {
+        let impls = self.operator_impls(trait_name, ty);
+        let program = self.program;
+        let left = self.store_argument(ty);
+        let right = match right {
+            Some(right) => right,
+            None => {
+                let label = impls[0];
+                return Ok(self.call_with_arguments(&program.signatures[&label], CallTarget::Script(label), vec![left]));
+            },
+        };
+
+        // an unsuffixed literal takes the type of the only impl
+        let hint = match impls.len() {
+            1 => Some(program.signatures[&impls[0]].arguments[1]),
+            _ => None,
+        };
+        let found = self.compile_expr(right, hint)?;
+        let mut chosen = None;
+        for label in &impls {
+            if self.unify(program.signatures[label].arguments[1], found) {
+                chosen = Some(*label);
+                break;
+            }
+        }
+        let label = match chosen {
+            Some(label) => label,
+            None => return Err(AssembleFunctionError::TypeMismatch {
+                function: self.fn_name(),
+                expected: program.signatures[&impls[0]].arguments[1],
+                found: self.infer.resolve(found),
+            }.into()),
+        };
+        let right = self.store_argument(found);
+        Ok(self.call_with_arguments(&program.signatures[&label], CallTarget::Script(label), vec![left, right]))
+    }
+}
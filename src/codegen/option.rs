@@ -0,0 +1,207 @@
+//! The built-in `Option<T>` and `Result<T, E>`.
+//!
+//! An option is laid out like `#[repr(C)] struct { is_some: bool, value: T }` and a result
+//! like `#[repr(C, u8)] enum { Err(E), Ok(T) }`, so the host can declare matching types.
+//! Like a struct, they live in a stack slot and `rax` holds their address. The value of a
+//! `None` is unspecified. `None`, `Ok(..)` and `Err(..)` take their type from the context.
+
+use quote::ToTokens;
+use syn::{ExprCall, ExprMethodCall, ExprPath, ExprTry, Path};
+use assembler::{AluOp, Cond, Reg};
+use compiler::{AssembleError, AssembleFunctionError, Ret};
+use resolve::Namespace;
+use types::{OptionId, ResultId};
+use super::{FnCompiler, unsupported};
+
+impl<'a> FnCompiler<'a> {
+
+    /// `Some`, `None`, `Ok` or `Err`, unless the name refers to a local or an item of the script
+    pub(super) fn variant_name(&self, path: &Path) -> Option<&'static str> {
+        if path.leading_colon.is_some() || path.segments.len() != 1 || !path.segments[0].arguments.is_empty() {
+            return None;
+        }
+        let name = ["Some", "None", "Ok", "Err"].iter().find(|n| path.segments[0].ident == **n)?;
+        if self.find_local(name).is_some() || self.program.modules.resolve_path(self.source.module, path, Namespace::Value).is_ok() {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Allocates a temporary option or result and sets its flag, using `rcx`
+    pub(super) fn alloc_variant(&mut self, ty: Ret, flag: bool) -> i32 {
+        let slot = self.alloc_temp(ty);
+        self.asm.mov_ri(Reg::Rcx, flag as u64);
+        self.asm.store(Reg::Rbp, slot.disp, Reg::Rcx, 1);
+        slot.disp
+    }
+
+    /// `None`, of the option type that the context expects
+    pub(super) fn compile_none(&mut self, p: &ExprPath, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let ty = match expected.map(|ty| self.infer.resolve(ty)) {
+            Some(ty @ Ret::Option(_)) => ty,
+            _ => return Err(AssembleFunctionError::TypeAnnotationNeeded(p.into_token_stream().to_string()).into()),
+        };
+        let disp = self.alloc_variant(ty, false);
+        self.asm.lea(Reg::Rax, Reg::Rbp, disp);
+        Ok(ty)
+    }
+
+    /// `Some(value)`, `Ok(value)` or `Err(error)`
+    pub(super) fn compile_variant(&mut self, c: &ExprCall, path: &Path, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let name = self.variant_name(path).expect("not a variant");
+        if name == "None" {
+            return Err(AssembleFunctionError::NotAFunction(name.to_string()).into());
+        }
+        if c.args.len() != 1 {
+            return Err(AssembleFunctionError::WrongArgumentCount { function: name.to_string(), expected: 1, found: c.args.len() }.into());
+        }
+        let arg = &c.args[0];
+        let expected = expected.map(|ty| self.infer.resolve(ty));
+        let (ty, value, offset) = match (name, expected) {
+            ("Some", Some(ty @ Ret::Option(id))) => {
+                let value = self.shared.types.option(id);
+                self.compile_expr_expect(arg, value)?;
+                (ty, value, self.shared.types.option_value_offset(id))
+            },
+            ("Some", _) => {
+                let value = self.compile_expr(arg, None)?;
+                let value = self.infer.resolve(value);
+                let ty = self.shared.types.option_type(value);
+                let offset = match ty {
+                    Ret::Option(id) => self.shared.types.option_value_offset(id),
+                    _ => unreachable!("not an option"),
+                };
+                (ty, value, offset)
+            },
+            (_, Some(ty @ Ret::Result(id))) => {
+                let (ok, err) = self.shared.types.result(id);
+                let value = if name == "Ok" { ok } else { err };
+                self.compile_expr_expect(arg, value)?;
+                (ty, value, self.shared.types.result_payload_offset(id))
+            },
+            _ => return Err(AssembleFunctionError::TypeAnnotationNeeded(c.into_token_stream().to_string()).into()),
+        };
+        let value = self.infer.resolve(value);
+        let disp = self.alloc_variant(ty, name != "Err");
+        self.store_value(value, Reg::Rbp, disp + offset);
+        self.asm.lea(Reg::Rax, Reg::Rbp, disp);
+        Ok(ty)
+    }
+
+    /// `value?`, returns `None` or the error from the function, otherwise unwraps the value.
+    /// The error type has to be the same, there are no `From` conversions.
+    pub(super) fn compile_try(&mut self, t: &ExprTry) -> Result<Ret, AssembleError> {
+        if self.in_predicate {
+            return Err(unsupported(t));
+        }
+        let ty = self.compile_expr(&t.expr, None)?;
+        let ty = self.infer.resolve(ty);
+        let return_type = self.return_type.map(|ty| self.infer.resolve(ty)).unwrap_or(Ret::Void);
+        let (value, offset, error) = match (ty, return_type) {
+            (Ret::Option(id), Ret::Option(_)) => {
+                (self.shared.types.option(id), self.shared.types.option_value_offset(id), None)
+            },
+            (Ret::Result(id), Ret::Result(returned)) => {
+                let (ok, err) = self.shared.types.result(id);
+                let (_, returned_err) = self.shared.types.result(returned);
+                if !self.unify(returned_err, err) {
+                    return Err(AssembleFunctionError::TryErrorMismatch {
+                        function: self.fn_name(),
+                        expression: t.into_token_stream().to_string(),
+                        expected: self.infer.resolve(returned_err),
+                        found: self.infer.resolve(err),
+                    }.into());
+                }
+                let offset = self.shared.types.result_payload_offset(id);
+                let returned_offset = self.shared.types.result_payload_offset(returned);
+                (ok, offset, Some((err, returned_offset)))
+            },
+            _ => return Err(AssembleFunctionError::InvalidTry {
+                function: self.fn_name(),
+                expression: t.into_token_stream().to_string(),
+                return_type: self.shared.types.type_name(return_type),
+            }.into()),
+        };
+
+        let operand = self.frame.alloc(8, 8);
+        self.asm.store(Reg::Rbp, operand.disp, Reg::Rax, 8);
+        let present = self.asm.new_label();
+        self.asm.load(Reg::Rdx, Reg::Rax, 0, 1, false);
+        self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+        self.asm.jcc(Cond::NotEqual, present);
+
+        let returned = self.alloc_variant(return_type, false);
+        if let Some((err, returned_offset)) = error {
+            self.asm.load(Reg::Rcx, Reg::Rbp, operand.disp, 8, false);
+            self.load_value(err, Reg::Rcx, offset);
+            self.store_value(err, Reg::Rbp, returned + returned_offset);
+        }
+        self.asm.lea(Reg::Rax, Reg::Rbp, returned);
+        self.move_return_value(return_type);
+        self.asm.jmp(self.epilogue);
+
+        self.asm.bind(present);
+        self.asm.load(Reg::Rcx, Reg::Rbp, operand.disp, 8, false);
+        self.frame.free(operand);
+        self.load_value(value, Reg::Rcx, offset);
+        Ok(value)
+    }
+
+    /// `is_some`, `is_none` and `unwrap_or` of the option at the address in `rax`
+    pub(super) fn compile_option_method(&mut self, id: OptionId, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let value = self.shared.types.option(id);
+        let offset = self.shared.types.option_value_offset(id);
+        self.compile_flag_method(Ret::Option(id), m, ("is_some", "is_none"), value, offset)
+    }
+
+    /// `is_ok`, `is_err` and `unwrap_or` of the result at the address in `rax`
+    pub(super) fn compile_result_method(&mut self, id: ResultId, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let (ok, _) = self.shared.types.result(id);
+        let offset = self.shared.types.result_payload_offset(id);
+        self.compile_flag_method(Ret::Result(id), m, ("is_ok", "is_err"), ok, offset)
+    }
+
+    /// The methods of a value with a flag in its first byte, `tests` are the names of the methods
+    /// that return the flag and its negation. `unwrap_or` returns the `value` at `offset` if the
+    /// flag is set.
+    fn compile_flag_method(&mut self, ty: Ret, m: &ExprMethodCall, tests: (&str, &str), value: Ret, offset: i32)
+    -> Result<Ret, AssembleError>
+    {
+        let method = m.method.to_string();
+        let argument_count = match &*method {
+            _ if method == tests.0 || method == tests.1 => 0,
+            "unwrap_or" => 1,
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_count, m.args.len())?;
+
+        if method != "unwrap_or" {
+            self.asm.load(Reg::Rax, Reg::Rax, 0, 1, false);
+            if method == tests.1 {
+                self.asm.alu_ri(AluOp::Xor, Reg::Rax, 1);
+            }
+            return Ok(Ret::Bool);
+        }
+
+        let option = self.frame.alloc(8, 8);
+        self.asm.store(Reg::Rbp, option.disp, Reg::Rax, 8);
+        self.compile_expr_expect(&m.args[0], value)?;
+        let value = self.infer.resolve(value);
+        // a struct value has to stay valid after this expression
+        let default = self.alloc_temp(value);
+        self.store_value(value, Reg::Rbp, default.disp);
+
+        let (none, end) = (self.asm.new_label(), self.asm.new_label());
+        self.asm.load(Reg::Rcx, Reg::Rbp, option.disp, 8, false);
+        self.frame.free(option);
+        self.asm.load(Reg::Rdx, Reg::Rcx, 0, 1, false);
+        self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+        self.asm.jcc(Cond::Equal, none);
+        self.load_value(value, Reg::Rcx, offset);
+        self.asm.jmp(end);
+        self.asm.bind(none);
+        self.load_value(value, Reg::Rbp, default.disp);
+        self.asm.bind(end);
+        Ok(value)
+    }
+}
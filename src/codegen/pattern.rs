@@ -0,0 +1,1045 @@
+//! Patterns of `let`, function parameters, `match`, `if let` and `while let`.
+//!
+//! All of them are lowered by `compile_pattern`: the tests of the pattern jump to a label if the
+//! value doesn't match, a binding copies (a part of) the value into a new local, which is visible
+//! until the end of the scope. A local that is matched stays where it is, any other value is
+//! kept in memory and a stack slot holds its address. There are no reference types, a `ref` or
+//! `ref mut` binding is a local that shares its slot with a part of the matched local, the
+//! bindings of slice elements are copies that can be dereferenced like `ref` bindings and `&x`
+//! binds an element by value.
+//!
+//! The arms of a `match` have to cover every value and the patterns of `let` and parameters have
+//! to match every value. Like in rustc, this is checked on the variants of options, results and
+//! `bool`s, the fields of structs and tuples, the elements of arrays, the lengths of slices and the
+//! values of integers, `char`s and `Fixed` numbers: the literals, constants (`i32::MAX`,
+//! `Fixed::ZERO`) and ranges split the values of the type into intervals, each of which has to
+//! be covered.
+
+use quote::ToTokens;
+use syn::{Expr, ExprIfLet, ExprLit, ExprMatch, ExprPath, ExprWhileLet, Lit, Pat, PatIdent, PatPath, PatRange, PatSlice, PatTuple, Path, RangeLimits, UnOp};
+use syn::punctuated::Punctuated;
+use syn::token::Or;
+use assembler::{AluOp, Cond, Label, Reg};
+use compiler::{AssembleError, AssembleFunctionError, Ret, StaticIntLiteral};
+use fixed::Fixed;
+use resolve::{Def, Namespace, path_to_string};
+use types::{Field, StructKind, TypeTable};
+use super::{FnCompiler, Local, Slot, is_memory_value, member_name, unsupported};
+use super::fixed::fixed_constant;
+use super::intrinsics::int_type_item;
+use super::vector::builtin_type_item;
+use super::wide::is_wide;
+
+/// Where the matched (or assigned) value is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum Base {
+    /// A slot that only holds the matched value, i.e. the value of a `let` or a parameter.
+    /// Bindings refer to it instead of copying the value.
+    Owned,
+    /// A local, at `rbp + offset`. `ref` bindings refer to it.
+    Frame,
+    /// The slot holds the address of the value
+    Address(Slot),
+}
+
+/// Where a part of the matched value is: `offset` bytes after the start of `base`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) struct Place {
+    base: Base,
+    offset: i32,
+    /// The value is an element of a slice, its bindings are references like with `ref`
+    by_ref: bool,
+    /// `ref mut` bindings can change the value, it is not a part of an immutable local
+    mutable: bool,
+    /// The value is a part of a local that is bound with `ref`, binding it by value doesn't move it
+    borrowed: bool,
+}
+
+impl Place {
+    pub(super) fn new(base: Base, offset: i32) -> Place {
+        Place { base, offset, by_ref: false, mutable: true, borrowed: false }
+    }
+
+    /// The place of a local or a field of a local, see `FnCompiler::local_place`
+    fn local(offset: i32, local: &Local) -> Place {
+        Place { mutable: local.mutable, borrowed: local.by_ref, ..Place::new(Base::Frame, offset) }
+    }
+
+    /// An element of a slice whose address is in the slot, its bindings are references
+    pub(super) fn element(address: Slot) -> Place {
+        Place { by_ref: true, ..Place::new(Base::Address(address), 0) }
+    }
+
+    pub(super) fn is_mutable(&self) -> bool {
+        self.mutable
+    }
+
+    /// The offset from `rbp`, if the place is a local
+    pub(super) fn frame_offset(&self) -> Option<i32> {
+        match self.base {
+            Base::Frame => Some(self.offset),
+            _ => None,
+        }
+    }
+
+    pub(super) fn at(self, offset: i32) -> Place {
+        Place { offset: self.offset + offset, ..self }
+    }
+}
+
+/// The values that a pattern matches, for checking that patterns cover every value
+#[derive(Debug, Clone, PartialEq)]
+enum Cover {
+    /// A wildcard or a binding, matches every value
+    Any,
+    /// A variant and the values of its fields. The variants of a type are numbered like the flag
+    /// of an option or result (`None`, `Some` and `Err`, `Ok`), `false` and `true` and the single
+    /// variant of a struct or tuple.
+    Variant(usize, Vec<Cover>),
+    /// The elements of a slice, `back` is `None` if the pattern has no `..`, i.e. a fixed length
+    Slice { front: Vec<Cover>, back: Option<Vec<Cover>> },
+    /// The integers or `char`s from the first to the second value, see `int_values`
+    Range(u128, u128),
+    /// Some values of a type that can't be enumerated
+    Partial,
+}
+
+/// The variants of a type that can be enumerated, with the types of their fields
+fn variants(types: &TypeTable, ty: Ret) -> Option<Vec<Vec<Ret>>> {
+    match ty {
+        Ret::Bool => Some(vec![vec![], vec![]]),
+        Ret::Option(id) => Some(vec![vec![], vec![types.option(id)]]),
+        Ret::Result(id) => {
+            let (ok, err) = types.result(id);
+            Some(vec![vec![err], vec![ok]])
+        },
+        Ret::Struct(id) => Some(vec![types.struct_def(id).fields.iter().map(|f| f.ty).collect()]),
+        _ => None,
+    }
+}
+
+/// The values of an integer type or `char`, biased like `biased` so that they are ordered like
+/// unsigned integers. `char`s skip the surrogates.
+fn int_values(ty: Ret) -> Option<Vec<(u128, u128)>> {
+    match ty {
+        Ret::Int(StaticIntLiteral::UnknownSize(_)) => None,
+        Ret::Int(i) => Some(vec![(0, u128::MAX >> (128 - i.size() * 8))]),
+        Ret::Char => Some(vec![(0, 0xd7ff), (0xe000, 0x10_ffff)]),
+        Ret::Fixed => Some(vec![(0, u128::from(u64::MAX))]),
+        _ => None,
+    }
+}
+
+/// Flips the sign bit of a signed integer, so that `MIN` becomes 0 and `MAX` the largest value
+fn biased(ty: Ret, value: u128) -> u128 {
+    match ty {
+        Ret::Int(i) => {
+            let bits = i.size() as u32 * 8;
+            let sign = if i.is_signed() { 1 << (bits - 1) } else { 0 };
+            (value ^ sign) & (u128::MAX >> (128 - bits))
+        },
+        // the bits of an `i64`
+        Ret::Fixed => (value ^ 1 << 63) & u128::from(u64::MAX),
+        _ => value,
+    }
+}
+
+/// The value of a literal pattern, sign-extended to 128 bits
+fn literal_value(expr: &Expr) -> Option<u128> {
+    match *expr {
+        Expr::Lit(ExprLit { lit: Lit::Int(ref i), .. }) => Some(u128::from(i.value())),
+        Expr::Lit(ExprLit { lit: Lit::Byte(ref b), .. }) => Some(u128::from(b.value())),
+        Expr::Lit(ExprLit { lit: Lit::Char(ref c), .. }) => Some(u128::from(u32::from(c.value()))),
+        Expr::Unary(ref u) if matches!(u.op, UnOp::Neg(_)) => literal_value(&u.expr).map(u128::wrapping_neg),
+        Expr::Path(ref p) => match int_type_item(p) {
+            Some((i, name)) => i.limit(&name),
+            None => fixed_item(p).map(|value| value.to_bits() as u128),
+        },
+        _ => None,
+    }
+}
+
+/// `Fixed::ZERO` and the other constants of `Fixed`
+fn fixed_item(p: &ExprPath) -> Option<Fixed> {
+    match builtin_type_item(p) {
+        Some((Ret::Fixed, name)) => fixed_constant(&name),
+        _ => None,
+    }
+}
+
+/// A constant like `i32::MAX` is parsed as a path pattern, it is compared like a literal
+fn path_expr(p: &PatPath) -> Expr {
+    Expr::Path(ExprPath { attrs: Vec::new(), qself: p.qself.clone(), path: p.path.clone() })
+}
+
+/// Checks that every combination of values of `tys` matches one of the rows. This is the
+/// usefulness algorithm of rustc, specialized to a row of wildcards.
+fn is_exhaustive(types: &TypeTable, rows: Vec<Vec<Cover>>, tys: &[Ret]) -> bool {
+    let (ty, rest) = match tys.split_first() {
+        Some(split) => split,
+        None => return !rows.is_empty(),
+    };
+    if let Ret::Slice(id) = *ty {
+        if rows.iter().any(|row| matches!(row[0], Cover::Slice { .. })) {
+            return is_slice_exhaustive(types, types.slice(id).elem, &rows, rest);
+        }
+    }
+    if let Some(values) = int_values(*ty) {
+        if rows.iter().any(|row| matches!(row[0], Cover::Range(..))) {
+            return is_range_exhaustive(types, &values, &rows, rest);
+        }
+    }
+    let variants = variants(types, *ty).filter(|variants| {
+        (0..variants.len()).all(|v| rows.iter().any(|row| matches!(row[0], Cover::Variant(w, _) if w == v)))
+    });
+    match variants {
+        // every variant is mentioned, each of them has to be covered
+        Some(variants) => variants.into_iter().enumerate().all(|(v, fields)| {
+            let rows = rows.iter().filter_map(|row| match row[0] {
+                Cover::Variant(w, ref covers) if w == v => Some(covers.iter().chain(&row[1..]).cloned().collect()),
+                Cover::Any => Some(vec![Cover::Any; fields.len()].into_iter().chain(row[1..].iter().cloned()).collect()),
+                _ => None,
+            }).collect();
+            let tys = fields.into_iter().chain(rest.iter().cloned()).collect::<Vec<_>>();
+            is_exhaustive(types, rows, &tys)
+        }),
+        // a value that no row mentions is only covered by the wildcards
+        None => {
+            let rows = rows.into_iter().filter(|row| row[0] == Cover::Any).map(|row| row[1..].to_vec()).collect();
+            is_exhaustive(types, rows, rest)
+        },
+    }
+}
+
+/// Checks every length of the slices in the first column. The lengths above the longest pattern
+/// are only matched by patterns with `..`, which match all of them like the next length.
+fn is_slice_exhaustive(types: &TypeTable, elem: Ret, rows: &[Vec<Cover>], rest: &[Ret]) -> bool {
+    let longest = rows.iter().map(|row| match row[0] {
+        Cover::Slice { ref front, ref back } => front.len() + back.as_ref().map_or(0, Vec::len),
+        _ => 0,
+    }).max().unwrap_or(0);
+    (0..=longest + 1).all(|len| {
+        let rows = rows.iter().filter_map(|row| {
+            let elements = match row[0] {
+                Cover::Any => vec![Cover::Any; len],
+                Cover::Slice { ref front, back: None } if front.len() == len => front.clone(),
+                Cover::Slice { ref front, back: Some(ref back) } if front.len() + back.len() <= len => {
+                    let middle = vec![Cover::Any; len - front.len() - back.len()];
+                    front.iter().cloned().chain(middle).chain(back.iter().cloned()).collect()
+                },
+                _ => return None,
+            };
+            Some(elements.into_iter().chain(row[1..].iter().cloned()).collect())
+        }).collect();
+        let tys = vec![elem; len].into_iter().chain(rest.iter().cloned()).collect::<Vec<_>>();
+        is_exhaustive(types, rows, &tys)
+    })
+}
+
+/// Checks the intervals between the starts and ends of the ranges in the first column. Every
+/// range contains either all values of such an interval or none, so it is enough to look at the
+/// first value of each interval.
+fn is_range_exhaustive(types: &TypeTable, values: &[(u128, u128)], rows: &[Vec<Cover>], rest: &[Ret]) -> bool {
+    let mut starts: Vec<u128> = values.iter().map(|&(min, _)| min).collect();
+    for row in rows {
+        if let Cover::Range(lo, hi) = row[0] {
+            starts.push(lo);
+            starts.extend(hi.checked_add(1));
+        }
+    }
+    starts.sort();
+    starts.dedup();
+    values.iter().all(|&(min, max)| starts.iter().filter(|&&start| min <= start && start <= max).all(|&start| {
+        let rows = rows.iter().filter(|row| match row[0] {
+            Cover::Any => true,
+            Cover::Range(lo, hi) => lo <= start && start <= hi,
+            _ => false,
+        }).map(|row| row[1..].to_vec()).collect();
+        is_exhaustive(types, rows, rest)
+    }))
+}
+
+/// Checks the number of patterns in `(a, b, ..)` against the number of fields
+fn element_count_matches(elements: &PatTuple, count: usize) -> bool {
+    let patterns = elements.front.len() + elements.back.len();
+    if elements.dot2_token.is_some() { patterns <= count } else { patterns == count }
+}
+
+/// A literal, or a negated literal, as allowed in a pattern
+fn is_literal(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Lit(_) => true,
+        Expr::Unary(ref u) => matches!(u.op, UnOp::Neg(_)) && matches!(*u.expr, Expr::Lit(_)),
+        // `i32::MIN` or `Fixed::ZERO`
+        Expr::Path(ref p) => int_type_item(p).is_some() || fixed_item(p).is_some(),
+        _ => false,
+    }
+}
+
+impl<'a> FnCompiler<'a> {
+
+    fn invalid_pattern<T: ToTokens>(&self, pat: &T, ty: Ret) -> AssembleError {
+        AssembleFunctionError::InvalidPattern {
+            function: self.fn_name(),
+            pattern: pat.into_token_stream().to_string(),
+            ty: self.shared.types.type_name(ty),
+        }.into()
+    }
+
+    /// The register and displacement to access a place with, loads the address into `rcx`
+    fn place_operand(&mut self, place: Place) -> (Reg, i32) {
+        self.place_address(place, Reg::Rcx)
+    }
+
+    /// Like `place_operand`, but loads the address into `reg`
+    pub(super) fn place_address(&mut self, place: Place, reg: Reg) -> (Reg, i32) {
+        match place.base {
+            Base::Owned | Base::Frame => (Reg::Rbp, place.offset),
+            Base::Address(slot) => {
+                self.asm.load(reg, Reg::Rbp, slot.disp, 8, false);
+                (reg, place.offset)
+            },
+        }
+    }
+
+    /// Stores the address of a place in a new slot, so that bindings copy the value
+    fn detach(&mut self, place: Place) -> (Place, Option<Slot>) {
+        if let Base::Address(_) = place.base {
+            return (place, None);
+        }
+        let address = self.frame.alloc(8, 8);
+        self.asm.lea(Reg::Rax, Reg::Rbp, place.offset);
+        self.asm.store(Reg::Rbp, address.disp, Reg::Rax, 8);
+        (Place { base: Base::Address(address), offset: 0, ..place }, Some(address))
+    }
+
+    /// Stores the address of the value in `rax` / `rdx` / `xmm0` in a new slot
+    fn scrutinee_address(&mut self, ty: Ret) -> Slot {
+        if !is_memory_value(ty) {
+            let value = self.alloc_temp(ty);
+            self.store_value(ty, Reg::Rbp, value.disp);
+            self.asm.lea(Reg::Rax, Reg::Rbp, value.disp);
+        }
+        let address = self.frame.alloc(8, 8);
+        self.asm.store(Reg::Rbp, address.disp, Reg::Rax, 8);
+        address
+    }
+
+    /// The type and slot of a local or of a field of a local, i.e. `hit.pos`, and the local
+    pub(super) fn local_place(&mut self, expr: &Expr) -> Option<(Ret, i32, Local)> {
+        match *expr {
+            Expr::Path(ref p) if p.qself.is_none() && p.path.leading_colon.is_none() && p.path.segments.len() == 1 => {
+                let local = self.find_local(&p.path.segments[0].ident.to_string())?.clone();
+                Some((self.infer.resolve(local.ty), local.slot.disp, local))
+            },
+            Expr::Field(ref f) => match self.local_place(&f.base)? {
+                (Ret::Struct(id), disp, local) => {
+                    let field = self.struct_field(id, &member_name(&f.member)).ok()?;
+                    Some((field.ty, disp + field.offset, local))
+                },
+                _ => None,
+            },
+            Expr::Paren(ref p) => self.local_place(&p.expr),
+            _ => None,
+        }
+    }
+
+    /// Evaluates the matched value. Returns its type, its place and the slot that holds its address,
+    /// unless it is a local.
+    fn compile_scrutinee(&mut self, expr: &Expr) -> Result<(Ret, Place, Option<Slot>), AssembleError> {
+        if let Some((ty, disp, local)) = self.local_place(expr) {
+            self.check_not_moved(expr, disp, self.size_of(ty))?;
+            return Ok((ty, Place::local(disp, &local), None));
+        }
+        let ty = self.compile_expr(expr, None)?;
+        let ty = self.infer.resolve(ty);
+        let address = self.scrutinee_address(ty);
+        Ok((ty, Place::new(Base::Address(address), 0), Some(address)))
+    }
+
+    /// Whether `path` is a local that was bound with `ref` or `ref mut`, which can be dereferenced
+    pub(super) fn is_ref_binding(&self, path: &Path) -> bool {
+        path.leading_colon.is_none() && path.segments.len() == 1
+            && self.find_local(&path.segments[0].ident.to_string()).is_some_and(|l| l.by_ref)
+    }
+
+    /// Matches `pat` against the value of type `ty` at `place`. Jumps to `mismatch` if it
+    /// doesn't match, otherwise binds the names of the pattern.
+    fn compile_pattern(&mut self, pat: &Pat, ty: Ret, place: Place, mismatch: Label) -> Result<(), AssembleError> {
+        let ty = self.infer.resolve(ty);
+        match *pat {
+            Pat::Wild(_) => Ok(()),
+            // `None` is parsed like a binding
+            Pat::Ident(ref p) if p.subpat.is_none() && p.by_ref.is_none() && p.mutability.is_none()
+                && self.variant_name(&Path::from(p.ident.clone())) == Some("None") => {
+                self.compile_variant_pattern(pat, "None", None, ty, place, mismatch)
+            },
+            Pat::Ident(ref p) => {
+                if let Some((_, ref subpat)) = p.subpat {
+                    self.compile_pattern(subpat, ty, place, mismatch)?;
+                }
+                self.bind(p, ty, place)
+            },
+            Pat::Path(ref p) if p.qself.is_none() && self.variant_name(&p.path) == Some("None") => {
+                self.compile_variant_pattern(pat, "None", None, ty, place, mismatch)
+            },
+            Pat::TupleStruct(ref p) => {
+                let name = match self.variant_name(&p.path) {
+                    Some("None") => return Err(unsupported(pat)),
+                    Some(name) => name,
+                    None => {
+                        let fields = self.tuple_struct_fields(pat, &p.path, ty)?;
+                        return self.compile_elements(pat, &p.pat, &fields, ty, place, mismatch);
+                    },
+                };
+                // `Some(x)`, or `Some(..)` which matches any value
+                let inner = match (p.pat.front.len(), p.pat.dot2_token.is_some(), p.pat.back.len()) {
+                    (1, false, 0) => Some(p.pat.front.first().unwrap().into_value()),
+                    (0, true, 0) => None,
+                    _ => return Err(self.invalid_pattern(pat, ty)),
+                };
+                self.compile_variant_pattern(pat, name, inner, ty, place, mismatch)
+            },
+            Pat::Tuple(ref t) => {
+                let fields = match ty {
+                    Ret::Struct(id) if self.shared.types.tuple_elements(id).is_some() => self.shared.types.struct_def(id).fields.clone(),
+                    _ => return Err(self.invalid_pattern(pat, ty)),
+                };
+                self.compile_elements(pat, t, &fields, ty, place, mismatch)
+            },
+            Pat::Struct(ref s) => {
+                let id = match self.program.modules.resolve_path(self.source.module, &s.path, Namespace::Type)? {
+                    Def::Struct(id) if ty == Ret::Struct(id) => id,
+                    Def::Struct(_) => return Err(self.invalid_pattern(pat, ty)),
+                    _ => return Err(AssembleFunctionError::NotAStruct(path_to_string(&s.path)).into()),
+                };
+                let mut matched = Vec::new();
+                for field_pat in s.fields.iter() {
+                    let name = member_name(&field_pat.member);
+                    if matched.contains(&name) {
+                        return Err(unsupported(field_pat));
+                    }
+                    let field = self.struct_field(id, &name)?;
+                    self.compile_pattern(&field_pat.pat, field.ty, place.at(field.offset), mismatch)?;
+                    matched.push(name);
+                }
+                let def = self.shared.types.struct_def(id);
+                match def.fields.iter().find(|f| !matched.contains(&f.name)) {
+                    Some(field) if s.dot2_token.is_none() => Err(AssembleFunctionError::MissingField {
+                        function: self.fn_name(),
+                        ty: def.name.clone(),
+                        field: field.name.clone(),
+                    }.into()),
+                    _ => Ok(()),
+                }
+            },
+            Pat::Lit(ref l) if is_literal(&l.expr) => self.compile_literal_pattern(pat, &l.expr, ty, place, mismatch),
+            Pat::Path(ref p) if is_literal(&path_expr(p)) => self.compile_literal_pattern(pat, &path_expr(p), ty, place, mismatch),
+            Pat::Range(ref r) if is_literal(&r.lo) && is_literal(&r.hi) && is_wide(ty) => {
+                self.compile_wide_range(r, ty, place, mismatch)
+            },
+            Pat::Range(ref r) if is_literal(&r.lo) && is_literal(&r.hi) => {
+                if !matches!(ty, Ret::Int(i) if i.size() <= 8) && !matches!(ty, Ret::Char | Ret::Fixed) {
+                    return Err(self.invalid_pattern(pat, ty));
+                }
+                let signed = ty.is_signed();
+                self.compare_with_place(&r.lo, ty, place)?;
+                self.asm.jcc(if signed { Cond::Less } else { Cond::Below }, mismatch);
+                self.compare_with_place(&r.hi, ty, place)?;
+                let above = match (r.limits, signed) {
+                    (RangeLimits::Closed(_), true) => Cond::Greater,
+                    (RangeLimits::Closed(_), false) => Cond::Above,
+                    (RangeLimits::HalfOpen(_), true) => Cond::GreaterEqual,
+                    (RangeLimits::HalfOpen(_), false) => Cond::AboveEqual,
+                };
+                self.asm.jcc(above, mismatch);
+                Ok(())
+            },
+            Pat::Slice(ref s) => self.compile_slice_pattern(pat, s, ty, place, mismatch),
+            // `&x` binds an element of a slice by value
+            Pat::Ref(ref r) if place.by_ref => self.compile_pattern(&r.pat, ty, Place { by_ref: false, ..place }, mismatch),
+            _ => Err(unsupported(pat)),
+        }
+    }
+
+    /// Binds the name of an identifier pattern to the value at `place`
+    fn bind(&mut self, p: &PatIdent, ty: Ret, place: Place) -> Result<(), AssembleError> {
+        let by_ref = p.by_ref.is_some() || place.by_ref;
+        if by_ref {
+            self.check_borrow(p, ty, place)?;
+        } else if !place.borrowed {
+            if let Some(disp) = place.frame_offset() {
+                self.move_part(p, ty, disp)?;
+            }
+        }
+        let slot = match place.base {
+            Base::Owned => Slot { disp: place.offset, size: self.size_of(ty) },
+            Base::Frame if by_ref => Slot { disp: place.offset, size: self.size_of(ty) },
+            // a temporary can be copied, but there is nothing to change through `ref mut`
+            Base::Address(_) if p.by_ref.is_some() && p.mutability.is_some() => return Err(unsupported(p)),
+            _ => {
+                let slot = self.frame.alloc(self.size_of(ty), self.shared.types.align_of(ty));
+                let (base, disp) = self.place_operand(place);
+                self.load_value(ty, base, disp);
+                self.store_value(ty, Reg::Rbp, slot.disp);
+                self.local_slots.push(slot);
+                slot
+            },
+        };
+        // a `ref` binding of a part of a temporary and a slice element are copies
+        let detached = by_ref && matches!(place.base, Base::Address(_));
+        let mutable = p.mutability.is_some();
+        self.locals.push(Local { name: p.ident.to_string(), ty, slot, by_ref, detached, mutable });
+        Ok(())
+    }
+
+    /// Compiles a literal pattern or the bound of a range and compares the value at `place` with it
+    fn compile_literal_pattern(&mut self, pat: &Pat, literal: &Expr, ty: Ret, place: Place, mismatch: Label) -> Result<(), AssembleError> {
+        if !matches!(ty, Ret::Int(_) | Ret::Char | Ret::Bool | Ret::Fixed) {
+            return Err(self.invalid_pattern(pat, ty));
+        }
+        self.compare_with_place(literal, ty, place)?;
+        self.asm.jcc(Cond::NotEqual, mismatch);
+        Ok(())
+    }
+
+    fn compare_with_place(&mut self, literal: &Expr, ty: Ret, place: Place) -> Result<(), AssembleError> {
+        self.compile_expr_expect(literal, ty)?;
+        if is_wide(ty) {
+            // only sets the zero flag
+            self.asm.mov_rr(Reg::R8, Reg::Rax);
+            self.asm.mov_rr(Reg::R9, Reg::Rdx);
+            let (base, disp) = self.place_operand(place);
+            self.load_value(ty, base, disp);
+            self.asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::R8);
+            self.asm.alu_rr(AluOp::Xor, Reg::Rdx, Reg::R9);
+            self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rdx);
+            return Ok(());
+        }
+        self.asm.mov_rr(Reg::Rdx, Reg::Rax);
+        let (base, disp) = self.place_operand(place);
+        self.load_value(ty, base, disp);
+        // both sides are normalized
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rdx);
+        Ok(())
+    }
+
+    /// `lo..=hi` or `lo..hi` of an `i128` or `u128`, the value matches if `value - lo` is at most
+    /// (or less than) `hi - lo` when both are compared as unsigned integers
+    fn compile_wide_range(&mut self, r: &PatRange, ty: Ret, place: Place, mismatch: Label) -> Result<(), AssembleError> {
+        self.compile_expr_expect(&r.lo, ty)?;
+        let lo = self.spill(ty);
+        self.compile_expr_expect(&r.hi, ty)?;
+        // `r8` / `r9` is the width of the range
+        self.asm.mov_rr(Reg::R8, Reg::Rax);
+        self.asm.mov_rr(Reg::R9, Reg::Rdx);
+        self.asm.load(Reg::Rax, Reg::Rbp, lo.disp, 8, false);
+        self.asm.alu_rr(AluOp::Sub, Reg::R8, Reg::Rax);
+        self.asm.load(Reg::Rax, Reg::Rbp, lo.disp + 8, 8, false);
+        self.asm.alu_rr(AluOp::Sbb, Reg::R9, Reg::Rax);
+        let (base, disp) = self.place_operand(place);
+        self.load_value(ty, base, disp);
+        self.asm.load(Reg::Rcx, Reg::Rbp, lo.disp, 8, false);
+        self.asm.alu_rr(AluOp::Sub, Reg::Rax, Reg::Rcx);
+        self.asm.load(Reg::Rcx, Reg::Rbp, lo.disp + 8, 8, false);
+        self.asm.alu_rr(AluOp::Sbb, Reg::Rdx, Reg::Rcx);
+        self.frame.free(lo);
+
+        let matched = self.asm.new_label();
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rdx, Reg::R9);
+        self.asm.jcc(Cond::Above, mismatch);
+        self.asm.jcc(Cond::Below, matched);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::R8);
+        let above = match r.limits {
+            RangeLimits::Closed(_) => Cond::Above,
+            RangeLimits::HalfOpen(_) => Cond::AboveEqual,
+        };
+        self.asm.jcc(above, mismatch);
+        self.asm.bind(matched);
+        Ok(())
+    }
+
+    /// The fields of the tuple struct in `Money(x)`, which has to be the type of the value
+    fn tuple_struct_fields(&self, pat: &Pat, path: &Path, ty: Ret) -> Result<Vec<Field>, AssembleError> {
+        let id = match self.program.modules.resolve_path(self.source.module, path, Namespace::Value)? {
+            Def::Ctor(id) if self.shared.types.struct_def(id).kind == StructKind::Tuple => id,
+            _ => return Err(AssembleFunctionError::NotAStruct(path_to_string(path)).into()),
+        };
+        if ty != Ret::Struct(id) {
+            return Err(self.invalid_pattern(pat, ty));
+        }
+        self.check_fields_visible(id)?;
+        Ok(self.shared.types.struct_def(id).fields.clone())
+    }
+
+    /// Matches the patterns of a tuple or tuple struct with the fields, `..` skips fields in the middle
+    fn compile_elements(&mut self, pat: &Pat, elements: &PatTuple, fields: &[Field], ty: Ret, place: Place, mismatch: Label)
+    -> Result<(), AssembleError>
+    {
+        if !element_count_matches(elements, fields.len()) {
+            return Err(self.invalid_pattern(pat, ty));
+        }
+        let back = &fields[fields.len() - elements.back.len()..];
+        for (element, field) in elements.front.iter().zip(fields).chain(elements.back.iter().zip(back)) {
+            self.compile_pattern(element, field.ty, place.at(field.offset), mismatch)?;
+        }
+        Ok(())
+    }
+
+    /// Tests the flag of an option or result, then matches the value with `inner`
+    fn compile_variant_pattern(&mut self, pat: &Pat, name: &str, inner: Option<&Pat>, ty: Ret, place: Place, mismatch: Label)
+    -> Result<(), AssembleError>
+    {
+        let (value, value_offset) = match (name, ty) {
+            ("Some", Ret::Option(id)) | ("None", Ret::Option(id)) => {
+                (self.shared.types.option(id), self.shared.types.option_value_offset(id))
+            },
+            ("Ok", Ret::Result(id)) | ("Err", Ret::Result(id)) => {
+                let (ok, err) = self.shared.types.result(id);
+                (if name == "Ok" { ok } else { err }, self.shared.types.result_payload_offset(id))
+            },
+            _ => return Err(self.invalid_pattern(pat, ty)),
+        };
+        let (base, disp) = self.place_operand(place);
+        self.asm.load(Reg::Rdx, base, disp, 1, false);
+        self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+        let flag_set = name == "Some" || name == "Ok";
+        self.asm.jcc(if flag_set { Cond::Equal } else { Cond::NotEqual }, mismatch);
+        match inner {
+            Some(inner) => self.compile_pattern(inner, value, place.at(value_offset), mismatch),
+            None => Ok(()),
+        }
+    }
+
+    /// `[first, .., last]` or `[a, b, rest..]`, tests the length of the slice and matches the elements
+    fn compile_slice_pattern(&mut self, pat: &Pat, s: &PatSlice, ty: Ret, place: Place, mismatch: Label)
+    -> Result<(), AssembleError>
+    {
+        let id = match ty {
+            Ret::Slice(id) => id,
+            _ => return self.compile_array_pattern(pat, s, ty, place, mismatch),
+        };
+        let elem = self.shared.types.slice(id).elem;
+        let size = self.size_of(elem);
+        let (front, back) = (s.front.len() as i32, s.back.len() as i32);
+        let rest = s.dot2_token.is_some();
+
+        let (base, disp) = self.place_operand(place);
+        self.asm.load(Reg::Rax, base, disp + 8, 8, false);
+        self.asm.alu_ri(AluOp::Cmp, Reg::Rax, front + back);
+        self.asm.jcc(if rest { Cond::Below } else { Cond::NotEqual }, mismatch);
+
+        // the elements are matched through a copy of the pointer
+        let pointer = self.frame.alloc(8, 8);
+        let (base, disp) = self.place_operand(place);
+        self.asm.load(Reg::Rax, base, disp, 8, false);
+        self.asm.store(Reg::Rbp, pointer.disp, Reg::Rax, 8);
+        let elements = Place::element(pointer);
+        for (i, element) in s.front.iter().enumerate() {
+            self.compile_pattern(element, elem, elements.at(i as i32 * size), mismatch)?;
+        }
+
+        // `rest..` is the slice between the front and back elements
+        let middle = match s.middle {
+            Some(ref middle) => match **middle {
+                Pat::Ident(ref p) if p.subpat.is_none() && p.by_ref.is_none() => Some(p),
+                Pat::Wild(_) => None,
+                ref p => return Err(unsupported(p)),
+            },
+            None => None,
+        };
+        if let Some(p) = middle {
+            let (base, disp) = self.place_operand(place);
+            self.asm.load(Reg::Rdx, base, disp + 8, 8, false);
+            self.asm.alu_ri(AluOp::Sub, Reg::Rdx, front + back);
+            self.asm.load(Reg::Rax, Reg::Rbp, pointer.disp, 8, false);
+            self.asm.alu_ri(AluOp::Add, Reg::Rax, front * size);
+            let slot = self.frame.alloc(16, 8);
+            self.store_value(ty, Reg::Rbp, slot.disp);
+            self.local_slots.push(slot);
+            let mutable = p.mutability.is_some();
+            self.locals.push(Local { name: p.ident.to_string(), ty, slot, by_ref: false, detached: false, mutable });
+        }
+
+        if back > 0 {
+            // the back elements start at `pointer + (len - back) * size`
+            let (base, disp) = self.place_operand(place);
+            self.asm.load(Reg::Rax, base, disp + 8, 8, false);
+            self.asm.alu_ri(AluOp::Sub, Reg::Rax, back);
+            self.asm.mov_ri(Reg::Rdx, size as u64);
+            self.asm.imul_rr(Reg::Rax, Reg::Rdx);
+            self.asm.load(Reg::Rdx, Reg::Rbp, pointer.disp, 8, false);
+            self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rdx);
+            self.asm.store(Reg::Rbp, pointer.disp, Reg::Rax, 8);
+            for (i, element) in s.back.iter().enumerate() {
+                self.compile_pattern(element, elem, elements.at(i as i32 * size), mismatch)?;
+            }
+        }
+        self.frame.free(pointer);
+        Ok(())
+    }
+
+    /// `[a, b, c]` or `[first, rest.., last]` of an array, whose length is known. The elements are
+    /// matched like the fields of a tuple and `rest` is bound to an array of the elements between.
+    fn compile_array_pattern(&mut self, pat: &Pat, s: &PatSlice, ty: Ret, place: Place, mismatch: Label)
+    -> Result<(), AssembleError>
+    {
+        let (elem, len) = match self.array_of(ty) {
+            Some(array) => array,
+            None => return Err(self.invalid_pattern(pat, ty)),
+        };
+        let (front, back) = (s.front.len(), s.back.len());
+        if front + back > len || (s.dot2_token.is_none() && front != len) {
+            return Err(self.invalid_pattern(pat, ty));
+        }
+        let size = self.size_of(elem);
+        for (i, element) in s.front.iter().enumerate() {
+            self.compile_pattern(element, elem, place.at(i as i32 * size), mismatch)?;
+        }
+        if let Some(ref middle) = s.middle {
+            let rest = self.shared.types.array_type(elem, len - front - back).expect("smaller than the array");
+            self.compile_pattern(middle, rest, place.at(front as i32 * size), mismatch)?;
+        }
+        for (i, element) in s.back.iter().enumerate() {
+            self.compile_pattern(element, elem, place.at((len - back + i) as i32 * size), mismatch)?;
+        }
+        Ok(())
+    }
+
+    /// Matches one of the alternatives of `a | b`, jumps to `mismatch` if none matches. Every
+    /// alternative has to bind the same names to values of the same types, the values are copied
+    /// to the locals of the first alternative.
+    fn compile_alternatives(&mut self, pats: &Punctuated<Pat, Or>, ty: Ret, place: Place, mismatch: Label)
+    -> Result<(), AssembleError>
+    {
+        if pats.len() == 1 {
+            return self.compile_pattern(pats.first().unwrap().into_value(), ty, place, mismatch);
+        }
+        let (place, address) = self.detach(place);
+        let scope = self.locals.len();
+        let matched = self.asm.new_label();
+        let mut bindings: Option<Vec<Local>> = None;
+        for (i, pat) in pats.iter().enumerate() {
+            let last = i + 1 == pats.len();
+            let next = if last { mismatch } else { self.asm.new_label() };
+            self.compile_pattern(pat, ty, place, next)?;
+            let bound = self.locals.split_off(scope);
+            match bindings {
+                Some(ref first) => {
+                    let missing = first.iter().find(|l| !bound.iter().any(|b| b.name == l.name))
+                        .or_else(|| bound.iter().find(|b| !first.iter().any(|l| l.name == b.name)));
+                    if let Some(local) = missing {
+                        return Err(AssembleFunctionError::InconsistentBinding {
+                            function: self.fn_name(),
+                            pattern: pats.into_token_stream().to_string(),
+                            name: local.name.clone(),
+                        }.into());
+                    }
+                    for local in &bound {
+                        let target = first.iter().find(|l| l.name == local.name).unwrap().clone();
+                        self.expect_type(target.ty, local.ty)?;
+                        self.load_value(local.ty, Reg::Rbp, local.slot.disp);
+                        self.store_value(local.ty, Reg::Rbp, target.slot.disp);
+                    }
+                },
+                None => bindings = Some(bound),
+            }
+            if !last {
+                self.asm.jmp(matched);
+                self.asm.bind(next);
+            }
+        }
+        self.asm.bind(matched);
+        self.locals.extend(bindings.unwrap_or_default());
+        if let Some(address) = address {
+            self.frame.free(address);
+        }
+        Ok(())
+    }
+
+    /// The values that a pattern matches, which compiled for a value of type `ty`
+    fn cover(&mut self, pat: &Pat, ty: Ret) -> Cover {
+        let ty = self.infer.resolve(ty);
+        match *pat {
+            Pat::Ident(ref p) if p.subpat.is_none() && p.by_ref.is_none() && p.mutability.is_none()
+                && self.variant_name(&Path::from(p.ident.clone())) == Some("None") => Cover::Variant(0, vec![]),
+            Pat::Ident(ref p) => match p.subpat {
+                Some((_, ref subpat)) => self.cover(subpat, ty),
+                None => Cover::Any,
+            },
+            Pat::Path(ref p) if is_literal(&path_expr(p)) => {
+                let value = path_expr(p);
+                self.cover_range(&value, &value, false, ty)
+            },
+            // `None`
+            Pat::Path(_) => Cover::Variant(0, vec![]),
+            Pat::TupleStruct(ref p) if self.variant_name(&p.path).is_some() => {
+                let (variant, value) = match ty {
+                    Ret::Option(id) => (1, self.shared.types.option(id)),
+                    Ret::Result(id) if p.path.segments[0].ident == "Ok" => (1, self.shared.types.result(id).0),
+                    Ret::Result(id) => (0, self.shared.types.result(id).1),
+                    _ => unreachable!("not an option or result"),
+                };
+                let inner = match p.pat.front.first() {
+                    Some(inner) => self.cover(inner.value(), value),
+                    None => Cover::Any,
+                };
+                Cover::Variant(variant, vec![inner])
+            },
+            Pat::TupleStruct(ref p) => self.cover_elements(&p.pat, ty),
+            Pat::Tuple(ref t) => self.cover_elements(t, ty),
+            Pat::Struct(ref s) => {
+                let fields = match ty {
+                    Ret::Struct(id) => self.shared.types.struct_def(id).fields.clone(),
+                    _ => unreachable!("not a struct"),
+                };
+                let covers = fields.iter().map(|field| {
+                    match s.fields.iter().find(|f| member_name(&f.member) == field.name) {
+                        Some(f) => self.cover(&f.pat, field.ty),
+                        None => Cover::Any,
+                    }
+                }).collect();
+                Cover::Variant(0, covers)
+            },
+            Pat::Lit(ref l) => match *l.expr {
+                Expr::Lit(ExprLit { lit: Lit::Bool(ref b), .. }) => Cover::Variant(b.value as usize, vec![]),
+                _ => self.cover_range(&l.expr, &l.expr, false, ty),
+            },
+            Pat::Range(ref r) => self.cover_range(&r.lo, &r.hi, matches!(r.limits, RangeLimits::HalfOpen(_)), ty),
+            // the elements of an array are like the fields of a tuple
+            Pat::Slice(ref s) if self.array_of(ty).is_some() => {
+                let (elem, len) = self.array_of(ty).unwrap();
+                let mut covers = vec![Cover::Any; len];
+                for (i, p) in s.front.iter().enumerate() {
+                    covers[i] = self.cover(p, elem);
+                }
+                for (i, p) in s.back.iter().enumerate() {
+                    covers[len - s.back.len() + i] = self.cover(p, elem);
+                }
+                Cover::Variant(0, covers)
+            },
+            Pat::Slice(ref s) => {
+                let elem = match ty {
+                    Ret::Slice(id) => self.shared.types.slice(id).elem,
+                    _ => unreachable!("not a slice"),
+                };
+                let front = s.front.iter().map(|p| self.cover(p, elem)).collect();
+                let back = s.back.iter().map(|p| self.cover(p, elem)).collect();
+                Cover::Slice { front, back: s.dot2_token.map(|_| back) }
+            },
+            Pat::Ref(ref r) => self.cover(&r.pat, ty),
+            Pat::Wild(_) => Cover::Any,
+            _ => Cover::Partial,
+        }
+    }
+
+    /// The values from `lo` to `hi`, or to the value before `hi` for a half-open range
+    fn cover_range(&mut self, lo: &Expr, hi: &Expr, half_open: bool, ty: Ret) -> Cover {
+        // checked once the type is known
+        if self.infer.is_unknown(ty) {
+            return Cover::Any;
+        }
+        let lo = literal_value(lo).map(|lo| biased(ty, lo));
+        let hi = literal_value(hi).map(|hi| biased(ty, hi));
+        match (lo, hi) {
+            (Some(lo), Some(hi)) if half_open && lo < hi => Cover::Range(lo, hi - 1),
+            (Some(lo), Some(hi)) if !half_open && lo <= hi => Cover::Range(lo, hi),
+            // an empty range
+            _ => Cover::Partial,
+        }
+    }
+
+    fn cover_elements(&mut self, elements: &PatTuple, ty: Ret) -> Cover {
+        let fields = match ty {
+            Ret::Struct(id) => self.shared.types.struct_def(id).fields.clone(),
+            _ => unreachable!("not a tuple"),
+        };
+        let mut covers = vec![Cover::Any; fields.len()];
+        let back = fields.len() - elements.back.len();
+        for (i, element) in elements.front.iter().enumerate() {
+            covers[i] = self.cover(element, fields[i].ty);
+        }
+        for (i, element) in elements.back.iter().enumerate() {
+            covers[back + i] = self.cover(element, fields[back + i].ty);
+        }
+        Cover::Variant(0, covers)
+    }
+
+    /// Binds the names of the pattern of a `let` or a parameter to the value in the slot
+    /// at `rbp + disp`, the pattern has to match every value
+    pub(super) fn bind_irrefutable(&mut self, pat: &Pat, ty: Ret, disp: i32) -> Result<(), AssembleError> {
+        self.bind_irrefutable_at(pat, ty, Place::new(Base::Owned, disp))
+    }
+
+    /// `let pat = name;` or `let pat = name.field;`, the bindings copy the parts of the local
+    /// and `ref` bindings refer to them. Returns the type, or `None` if `init` is not a local.
+    pub(super) fn bind_local(&mut self, pat: &Pat, init: &Expr, annotated: Option<Ret>) -> Result<Option<Ret>, AssembleError> {
+        let (ty, disp, local) = match self.local_place(init) {
+            Some(place) => place,
+            None => return Ok(None),
+        };
+        if let Some(expected) = annotated {
+            self.expect_type(expected, ty)?;
+        }
+        self.check_not_moved(init, disp, self.size_of(ty))?;
+        if self.holds_borrow(ty) {
+            if let Some(index) = self.locals.iter().rposition(|l| l.slot.disp == local.slot.disp && !l.by_ref) {
+                self.forward_borrows(index);
+            }
+        }
+        self.bind_irrefutable_at(pat, ty, Place::local(disp, &local))?;
+        Ok(Some(ty))
+    }
+
+    pub(super) fn bind_irrefutable_at(&mut self, pat: &Pat, ty: Ret, place: Place) -> Result<(), AssembleError> {
+        let ty = self.infer.resolve(ty);
+        let mismatch = self.asm.new_label();
+        self.compile_pattern(pat, ty, place, mismatch)?;
+        let cover = self.cover(pat, ty);
+        if !is_exhaustive(&self.shared.types, vec![vec![cover]], &[ty]) {
+            return Err(AssembleFunctionError::RefutablePattern {
+                function: self.fn_name(),
+                pattern: pat.into_token_stream().to_string(),
+            }.into());
+        }
+        self.asm.bind(mismatch);
+        Ok(())
+    }
+
+    /// `match value { pat if guard => body, .. }`, the arms are tried in order and have to cover every value
+    pub(super) fn compile_match(&mut self, m: &ExprMatch, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        if m.arms.is_empty() {
+            return Err(unsupported(m));
+        }
+        let (ty, place, address) = self.compile_scrutinee(&m.expr)?;
+        let end = self.asm.new_label();
+        let mut result: Option<Ret> = None;
+        let mut rows = Vec::new();
+        let mut branches = self.start_branches();
+        for arm in &m.arms {
+            let next = self.asm.new_label();
+            let scope = self.enter_scope();
+            self.compile_alternatives(&arm.pats, ty, place, next)?;
+            match arm.guard {
+                Some((_, ref guard)) => {
+                    self.compile_expr_expect(guard, Ret::Bool)?;
+                    self.asm.test_rr(Reg::Rax, Reg::Rax);
+                    self.asm.jcc(Cond::Equal, next);
+                },
+                // an arm with a guard doesn't cover anything
+                None => for pat in arm.pats.iter() {
+                    let cover = self.cover(pat, ty);
+                    rows.push(vec![cover]);
+                },
+            }
+            let found = match result {
+                Some(result) => {
+                    let found = self.compile_arm_body(&arm.body, Some(result))?;
+                    self.expect_type(result, found)?;
+                    result
+                },
+                None => self.compile_arm_body(&arm.body, expected)?,
+            };
+            self.exit_scope(scope, found)?;
+            self.end_branch(&mut branches);
+            result = Some(found);
+            self.asm.jmp(end);
+            self.asm.bind(next);
+        }
+        self.end_branches(branches);
+        if let Some(address) = address {
+            self.frame.free(address);
+        }
+        if !is_exhaustive(&self.shared.types, rows, &[ty]) {
+            return Err(AssembleFunctionError::NonExhaustiveMatch {
+                function: self.fn_name(),
+                expression: m.expr.clone().into_token_stream().to_string(),
+            }.into());
+        }
+        self.asm.bind(end);
+        Ok(self.infer.resolve(result.unwrap()))
+    }
+
+    /// The body of a match arm, an expression or a block
+    fn compile_arm_body(&mut self, body: &Expr, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        match *body {
+            Expr::Block(ref b) => self.compile_block(&b.block, expected),
+            ref e => self.compile_expr(e, expected),
+        }
+    }
+
+    /// `if let pat = expr { .. } else { .. }`, the names bound by the pattern are only visible in
+    /// the first block
+    pub(super) fn compile_if_let(&mut self, i: &ExprIfLet, expected: Option<Ret>) -> Result<Ret, AssembleError> {
+        let else_label = self.asm.new_label();
+        let end = self.asm.new_label();
+
+        let (ty, place, address) = self.compile_scrutinee(&i.expr)?;
+        let mut branches = self.start_branches();
+        let scope = self.enter_scope();
+        self.compile_alternatives(&i.pats, ty, place, else_label)?;
+        if let Some(address) = address {
+            self.frame.free(address);
+        }
+
+        let else_branch = match i.else_branch {
+            Some((_, ref e)) => e,
+            None => {
+                self.compile_block_expect(&i.then_branch, Ret::Void)?;
+                self.exit_scope(scope, Ret::Void)?;
+                self.end_branch(&mut branches);
+                self.end_branch(&mut branches);
+                self.end_branches(branches);
+                self.asm.bind(else_label);
+                self.asm.bind(end);
+                return Ok(Ret::Void);
+            },
+        };
+
+        let ty = self.compile_block(&i.then_branch, expected)?;
+        self.exit_scope(scope, ty)?;
+        self.end_branch(&mut branches);
+        self.asm.jmp(end);
+        self.asm.bind(else_label);
+        let found = self.compile_else_branch(else_branch, ty)?;
+        self.end_branch(&mut branches);
+        self.end_branches(branches);
+        self.expect_type(ty, found)?;
+        self.asm.bind(end);
+        Ok(self.infer.resolve(ty))
+    }
+
+    /// The `else` of an `if` or `if let`: a block or another `if`
+    pub(super) fn compile_else_branch(&mut self, else_branch: &Expr, ty: Ret) -> Result<Ret, AssembleError> {
+        match *else_branch {
+            Expr::Block(ref b) => self.compile_block(&b.block, Some(ty)),
+            Expr::If(ref i) => self.compile_if(i, Some(ty)),
+            Expr::IfLet(ref i) => self.compile_if_let(i, Some(ty)),
+            ref e => Err(unsupported(e)),
+        }
+    }
+
+    /// `while let pat = expr { .. }`, evaluates `expr` again before every iteration
+    pub(super) fn compile_while_let(&mut self, w: &ExprWhileLet) -> Result<Ret, AssembleError> {
+        if w.label.is_some() {
+            return Err(unsupported(w));
+        }
+        let (top, end) = (self.asm.new_label(), self.asm.new_label());
+        let moved = self.moved.clone();
+        self.asm.bind(top);
+        self.enter_loop(top, end);
+        let (ty, place, address) = self.compile_scrutinee(&w.expr)?;
+        let scope = self.enter_scope();
+        self.compile_alternatives(&w.pats, ty, place, end)?;
+        if let Some(address) = address {
+            self.frame.free(address);
+        }
+        self.compile_block_expect(&w.body, Ret::Void)?;
+        self.exit_scope(scope, Ret::Void)?;
+        self.check_loop_moves(&moved)?;
+        self.exit_loop();
+        self.asm.jmp(top);
+        self.asm.bind(end);
+        Ok(Ret::Void)
+    }
+}
@@ -0,0 +1,442 @@
+//! Portable SIMD types `f32x4`, `f32x8`, `i32x4`, `i32x8` and their masks `mask32x4` /
+//! `mask32x8`, with the operators and methods of `std::simd`, and the `len` of slices.
+//!
+//! The four lane types are held in an xmm register like a `Vec4`. The eight lane types
+//! live in stack slots like matrices (`rax` holds their address) and are passed by pointer.
+//! A lanewise operation on eight lanes is a single AVX instruction if the CPU supports it
+//! (AVX2 for integer arithmetic and comparisons), otherwise the SSE instruction is applied
+//! to both halves. A mask lane is `0` (false) or `-1` (true), like the result of `cmpps`.
+
+use syn::{BinOp, Expr, ExprBinary, ExprMethodCall, ExprUnary, UnOp};
+use assembler::{AluOp, Cond, FloatCmp, FloatOp, PackedOp, Reg, ShiftOp, Xmm, SSE_ARG_REGS};
+use compiler::{AssembleError, AssembleFunctionError, Ret, ScriptError, StaticIntLiteral, StaticSimdLiteral};
+use types::SliceId;
+use super::{FnCompiler, Slot, unsupported};
+use super::vector::F32;
+
+const I32: Ret = Ret::Int(StaticIntLiteral::I32);
+
+/// The bits of a true mask lane and the sign bit of an `f32`
+const ALL_ONES: u32 = 0xFFFF_FFFF;
+const SIGN: u32 = 0x8000_0000;
+
+/// `pshufd` immediate that moves lanes 0 and 2 to lanes 0 and 1
+const EVEN_LANES: u8 = 0b00_00_10_00;
+
+/// The instruction of a comparison method, whether its operands are swapped and
+/// whether the result is inverted (SSE only has `==` and `>` for integers)
+fn comparison(simd: StaticSimdLiteral, method: &str) -> Option<(PackedOp, bool, bool)> {
+    let cmp = if simd.element() == F32 {
+        match method {
+            "simd_eq" => (PackedOp::Cmp(FloatCmp::Eq), false, false),
+            "simd_ne" => (PackedOp::Cmp(FloatCmp::NotEq), false, false),
+            "simd_lt" => (PackedOp::Cmp(FloatCmp::Lt), false, false),
+            "simd_le" => (PackedOp::Cmp(FloatCmp::Le), false, false),
+            "simd_gt" => (PackedOp::Cmp(FloatCmp::Lt), true, false),
+            "simd_ge" => (PackedOp::Cmp(FloatCmp::Le), true, false),
+            _ => return None,
+        }
+    } else {
+        match method {
+            "simd_eq" => (PackedOp::EqInt, false, false),
+            "simd_ne" => (PackedOp::EqInt, false, true),
+            "simd_lt" => (PackedOp::GtInt, true, false),
+            "simd_le" => (PackedOp::GtInt, false, true),
+            "simd_gt" => (PackedOp::GtInt, false, false),
+            "simd_ge" => (PackedOp::GtInt, true, true),
+            _ => return None,
+        }
+    };
+    Some(cmp)
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// Whether an operation on `simd` is a single AVX instruction, `integer` operations need AVX2
+    fn simd_uses_avx(&self, simd: StaticSimdLiteral, integer: bool) -> bool {
+        let features = &self.program.features;
+        simd.is_wide() && features.avx && (!integer || features.avx2)
+    }
+
+    /// `dst = dst op src`, on ymm registers if `avx`
+    fn lanes_op(&mut self, op: PackedOp, dst: Xmm, src: Xmm, avx: bool) {
+        if avx {
+            self.asm.vpacked(op, dst, dst, src);
+        } else {
+            self.asm.packed(op, dst, src);
+        }
+    }
+
+    /// Loads the vectors at `[rbp + operands[i]]` into xmm0, xmm1 .. and runs `body`, which
+    /// returns the register that holds the result. Eight lanes are either processed at once
+    /// in ymm registers (if `avx`) or as two halves. Afterwards a four lane result is in xmm0
+    /// and the address of an eight lane result in `rax`.
+    fn simd_lanewise<F>(&mut self, simd: StaticSimdLiteral, avx: bool, operands: &[i32], body: F)
+    where F: Fn(&mut FnCompiler<'a>, bool) -> Xmm
+    {
+        if !simd.is_wide() {
+            for (i, &disp) in operands.iter().enumerate() {
+                self.asm.load_packed(SSE_ARG_REGS[i], Reg::Rbp, disp);
+            }
+            let result = body(self, false);
+            if result != Xmm::Xmm0 {
+                self.asm.movaps(Xmm::Xmm0, result);
+            }
+            return;
+        }
+
+        let result = self.alloc_temp(Ret::Simd(simd));
+        if avx {
+            for (i, &disp) in operands.iter().enumerate() {
+                self.asm.vload_packed(SSE_ARG_REGS[i], Reg::Rbp, disp);
+            }
+            let register = body(self, true);
+            self.asm.vstore_packed(Reg::Rbp, result.disp, register);
+            self.asm.vzeroupper();
+        } else {
+            for half in 0..2 {
+                let offset = 16 * half;
+                for (i, &disp) in operands.iter().enumerate() {
+                    self.asm.load_packed(SSE_ARG_REGS[i], Reg::Rbp, disp + offset);
+                }
+                let register = body(self, false);
+                self.asm.store_packed(Reg::Rbp, result.disp + offset, register);
+            }
+        }
+        self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+    }
+
+    /// `xmm0 * xmm1` for `i32` lanes, using xmm2. Without SSE4.1 there is no `pmulld`, the
+    /// products of the even and the odd lanes are computed with `pmuludq` and interleaved
+    /// (the low 32 bits of a product are the same for signed numbers).
+    fn multiply_int_lanes(&mut self, avx: bool) -> Xmm {
+        if avx || self.program.features.sse41 {
+            self.lanes_op(PackedOp::MulInt, Xmm::Xmm0, Xmm::Xmm1, avx);
+            return Xmm::Xmm0;
+        }
+        self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+        self.asm.pmuludq(Xmm::Xmm2, Xmm::Xmm1);
+        self.asm.psrlq(Xmm::Xmm0, 32);
+        self.asm.psrlq(Xmm::Xmm1, 32);
+        self.asm.pmuludq(Xmm::Xmm0, Xmm::Xmm1);
+        self.asm.pshufd(Xmm::Xmm2, Xmm::Xmm2, EVEN_LANES);
+        self.asm.pshufd(Xmm::Xmm0, Xmm::Xmm0, EVEN_LANES);
+        self.asm.punpckldq(Xmm::Xmm2, Xmm::Xmm0);
+        Xmm::Xmm2
+    }
+
+    /// A new stack slot with `bits` in every lane of a `simd` vector, using `rax`
+    fn simd_constant(&mut self, simd: StaticSimdLiteral, bits: u32) -> Slot {
+        let slot = self.frame.alloc(simd.size(), 16);
+        self.asm.mov_ri(Reg::Rax, u64::from(bits));
+        for lane in 0..simd.lanes() as i32 {
+            self.asm.store(Reg::Rbp, slot.disp + 4 * lane, Reg::Rax, 4);
+        }
+        slot
+    }
+
+    /// Moves the bits of a lane value that was just computed into `eax`
+    fn lane_bits(&mut self, simd: StaticSimdLiteral) {
+        match simd.element() {
+            Ret::Float(_) => self.asm.movq_rx(Reg::Rax, Xmm::Xmm0),
+            // `true` is 1, a true lane is -1
+            Ret::Bool => self.asm.neg(Reg::Rax),
+            _ => { },
+        }
+    }
+
+    /// Evaluates the slice argument of `from_slice` / `copy_to_slice` into `rax` / `rdx`.
+    /// Like in the standard library, a slice with less than one element per lane is a bug,
+    /// the script stops with `ScriptError::IndexOutOfBounds`.
+    fn compile_lane_slice(&mut self, simd: StaticSimdLiteral, arg: &Expr, mutable: bool) -> Result<(), AssembleError> {
+        let found = self.compile_expr(arg, None)?;
+        let compatible = match self.infer.resolve(found) {
+            Ret::Slice(id) => {
+                let slice = self.shared.types.slice(id);
+                slice.elem == simd.element() && (slice.mutable || !mutable)
+            },
+            _ => false,
+        };
+        if !compatible {
+            let expected = self.shared.types.slice_type(simd.element(), mutable);
+            self.expect_type(expected, found)?;
+        }
+
+        self.asm.alu_ri(AluOp::Cmp, Reg::Rdx, simd.lanes() as i32);
+        let error = ScriptError::IndexOutOfBounds(self.location(arg));
+        self.trap_if(Cond::Below, error);
+        Ok(())
+    }
+
+    /// `f32x8::splat(x)`, `f32x8::from_array([..])` and `f32x8::from_slice(s)`,
+    /// masks are created with `splat` and `from_array`
+    pub(super) fn compile_simd_constructor(&mut self, simd: StaticSimdLiteral, name: &str, args: &[&Expr])
+    -> Result<Ret, AssembleError>
+    {
+        let ty = Ret::Simd(simd);
+        match name {
+            "splat" | "from_array" => { },
+            "from_slice" if !simd.is_mask() => { },
+            _ => return Err(self.unknown_method(ty, name)),
+        }
+        let function = format!("{:?}::{}", simd, name);
+        self.expect_argument_count(function.clone(), 1, args.len())?;
+
+        if name == "from_slice" {
+            self.compile_lane_slice(simd, args[0], false)?;
+            if simd.is_wide() {
+                let result = self.alloc_temp(ty);
+                self.copy_memory(Reg::Rbp, result.disp, Reg::Rax, 0, simd.size());
+                self.asm.lea(Reg::Rax, Reg::Rbp, result.disp);
+            } else {
+                self.asm.load_packed(Xmm::Xmm0, Reg::Rax, 0);
+            }
+            return Ok(ty);
+        }
+
+        // the lanes are assembled in memory
+        let slot = if simd.is_wide() { self.alloc_temp(ty) } else { self.frame.alloc(16, 16) };
+        if name == "splat" {
+            self.compile_expr_expect(args[0], simd.element())?;
+            self.lane_bits(simd);
+            for lane in 0..simd.lanes() as i32 {
+                self.asm.store(Reg::Rbp, slot.disp + 4 * lane, Reg::Rax, 4);
+            }
+        } else {
+            let elems = match *args[0] {
+                Expr::Array(ref array) => array.elems.iter().collect::<Vec<_>>(),
+                _ => return Err(unsupported(args[0])),
+            };
+            self.expect_argument_count(function, simd.lanes(), elems.len())?;
+            for (lane, elem) in elems.into_iter().enumerate() {
+                self.compile_expr_expect(elem, simd.element())?;
+                self.lane_bits(simd);
+                self.asm.store(Reg::Rbp, slot.disp + 4 * lane as i32, Reg::Rax, 4);
+            }
+        }
+
+        if simd.is_wide() {
+            self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+        } else {
+            self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, slot.disp);
+            self.frame.free(slot);
+        }
+        Ok(ty)
+    }
+
+    /// `+ - * /` of `f32` vectors, `+ - *` (wrapping) of `i32` vectors and `& | ^` of `i32`
+    /// vectors and masks. The left side was spilled to `slot`, the right side is in xmm0 / `rax`.
+    pub(super) fn compile_simd_arithmetic(&mut self, b: &ExprBinary, simd: StaticSimdLiteral, slot: Slot)
+    -> Result<Ret, AssembleError>
+    {
+        let ty = Ret::Simd(simd);
+        let float = simd.element() == F32;
+        let op = match b.op {
+            BinOp::Add(_) if float => PackedOp::Float(FloatOp::Add),
+            BinOp::Sub(_) if float => PackedOp::Float(FloatOp::Sub),
+            BinOp::Mul(_) if float => PackedOp::Float(FloatOp::Mul),
+            BinOp::Div(_) if float => PackedOp::Float(FloatOp::Div),
+            BinOp::Add(_) if !simd.is_mask() => PackedOp::AddInt,
+            BinOp::Sub(_) if !simd.is_mask() => PackedOp::SubInt,
+            BinOp::Mul(_) if !simd.is_mask() => PackedOp::MulInt,
+            BinOp::BitAnd(_) if !float => PackedOp::And,
+            BinOp::BitOr(_) if !float => PackedOp::Or,
+            BinOp::BitXor(_) if !float => PackedOp::Xor,
+            _ => return Err(unsupported(b)),
+        };
+
+        let right = self.spill(ty);
+        let integer = matches!(op, PackedOp::AddInt | PackedOp::SubInt | PackedOp::MulInt);
+        let avx = self.simd_uses_avx(simd, integer);
+        self.simd_lanewise(simd, avx, &[slot.disp, right.disp], |this, avx| {
+            if op == PackedOp::MulInt {
+                return this.multiply_int_lanes(avx);
+            }
+            this.lanes_op(op, Xmm::Xmm0, Xmm::Xmm1, avx);
+            Xmm::Xmm0
+        });
+        self.frame.free(right);
+        self.frame.free(slot);
+        Ok(ty)
+    }
+
+    /// `-v` of `f32` (flips the sign bits) and `i32` vectors (`0 - v`), `!v` of `i32` vectors and masks
+    pub(super) fn compile_simd_unary(&mut self, u: &ExprUnary, simd: StaticSimdLiteral) -> Result<Ret, AssembleError> {
+        let ty = Ret::Simd(simd);
+        let float = simd.element() == F32;
+        let (op, bits) = match u.op {
+            UnOp::Neg(_) if float => (PackedOp::Xor, SIGN),
+            UnOp::Neg(_) if !simd.is_mask() => (PackedOp::SubInt, 0),
+            UnOp::Not(_) if !float => (PackedOp::Xor, ALL_ONES),
+            _ => return Err(unsupported(u)),
+        };
+
+        let value = self.spill(ty);
+        let constant = self.simd_constant(simd, bits);
+        let operands = if op == PackedOp::SubInt { [constant.disp, value.disp] } else { [value.disp, constant.disp] };
+        let avx = self.simd_uses_avx(simd, op == PackedOp::SubInt);
+        self.simd_lanewise(simd, avx, &operands, |this, avx| {
+            this.lanes_op(op, Xmm::Xmm0, Xmm::Xmm1, avx);
+            Xmm::Xmm0
+        });
+        self.frame.free(constant);
+        self.frame.free(value);
+        Ok(ty)
+    }
+
+    /// Comparisons (`simd_eq`, `simd_lt`, ..), `reduce_sum`, `reduce_product`, `reduce_min`,
+    /// `reduce_max` and `copy_to_slice` of vectors, `any`, `all` and `select` of masks.
+    /// The vector is in xmm0 or its address in `rax`.
+    pub(super) fn compile_simd_method(&mut self, simd: StaticSimdLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let ty = Ret::Simd(simd);
+        let method = m.method.to_string();
+        let argument_count = match &*method {
+            "any" | "all" if simd.is_mask() => 0,
+            "select" if simd.is_mask() => 2,
+            "reduce_sum" | "reduce_product" | "reduce_min" | "reduce_max" if !simd.is_mask() => 0,
+            "copy_to_slice" if !simd.is_mask() => 1,
+            _ if !simd.is_mask() && comparison(simd, &method).is_some() => 1,
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_count, m.args.len())?;
+
+        let value = self.spill(ty);
+        let result = match &*method {
+            "any" | "all" => {
+                self.lane_sign_bits(simd, value.disp);
+                if method == "any" {
+                    self.asm.test_rr(Reg::Rax, Reg::Rax);
+                    self.asm.setcc(Cond::NotEqual, Reg::Rax);
+                } else {
+                    self.asm.alu_ri(AluOp::Cmp, Reg::Rax, (1 << simd.lanes()) - 1);
+                    self.asm.setcc(Cond::Equal, Reg::Rax);
+                }
+                Ret::Bool
+            },
+            "select" => self.compile_select(simd, value, &m.args[0], &m.args[1])?,
+            "copy_to_slice" => {
+                self.compile_lane_slice(simd, &m.args[0], true)?;
+                self.copy_memory(Reg::Rax, 0, Reg::Rbp, value.disp, simd.size());
+                Ret::Void
+            },
+            "reduce_sum" | "reduce_product" | "reduce_min" | "reduce_max" => {
+                self.reduce_lanes(simd, &method, value.disp);
+                simd.element()
+            },
+            _ => {
+                let (op, swap, invert) = comparison(simd, &method).expect("not a comparison");
+                self.compile_expr_expect(&m.args[0], ty)?;
+                let other = self.spill(ty);
+                let operands = if swap { [other.disp, value.disp] } else { [value.disp, other.disp] };
+                let avx = self.simd_uses_avx(simd, simd.element() == I32);
+                self.simd_lanewise(simd.mask(), avx, &operands, |this, avx| {
+                    this.lanes_op(op, Xmm::Xmm0, Xmm::Xmm1, avx);
+                    if invert {
+                        // xor with all ones
+                        this.lanes_op(PackedOp::EqInt, Xmm::Xmm1, Xmm::Xmm1, avx);
+                        this.lanes_op(PackedOp::Xor, Xmm::Xmm0, Xmm::Xmm1, avx);
+                    }
+                    Xmm::Xmm0
+                });
+                self.frame.free(other);
+                Ret::Simd(simd.mask())
+            },
+        };
+        self.frame.free(value);
+        Ok(result)
+    }
+
+    /// The sign bit of every lane of the vector at `[rbp + disp]`, in the low bits of `rax`
+    fn lane_sign_bits(&mut self, simd: StaticSimdLiteral, disp: i32) {
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, disp);
+        self.asm.movmskps(Reg::Rax, Xmm::Xmm0);
+        if simd.is_wide() {
+            self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, disp + 16);
+            self.asm.movmskps(Reg::Rcx, Xmm::Xmm0);
+            self.asm.shift_ri(ShiftOp::Shl, Reg::Rcx, 4);
+            self.asm.alu_rr(AluOp::Or, Reg::Rax, Reg::Rcx);
+        }
+    }
+
+    /// `mask.select(a, b)`, the lanes of `a` where the mask is true and those of `b` elsewhere.
+    /// The mask was spilled to `mask`.
+    fn compile_select(&mut self, simd: StaticSimdLiteral, mask: Slot, a: &Expr, b: &Expr) -> Result<Ret, AssembleError> {
+        let found = self.compile_expr(a, None)?;
+        let vector = match self.infer.resolve(found) {
+            Ret::Simd(vector) if !vector.is_mask() && vector.mask() == simd => vector,
+            found => {
+                let expected = if simd.is_wide() { StaticSimdLiteral::F32x8 } else { StaticSimdLiteral::F32x4 };
+                return Err(AssembleFunctionError::TypeMismatch {
+                    function: self.fn_name(),
+                    expected: Ret::Simd(expected),
+                    found,
+                }.into());
+            },
+        };
+        let ty = Ret::Simd(vector);
+        let first = self.spill(ty);
+        self.compile_expr_expect(b, ty)?;
+        let second = self.spill(ty);
+
+        // (mask & a) | (!mask & b)
+        let avx = self.simd_uses_avx(simd, false);
+        self.simd_lanewise(vector, avx, &[mask.disp, first.disp, second.disp], |this, avx| {
+            this.lanes_op(PackedOp::And, Xmm::Xmm1, Xmm::Xmm0, avx);
+            this.lanes_op(PackedOp::AndNot, Xmm::Xmm0, Xmm::Xmm2, avx);
+            this.lanes_op(PackedOp::Or, Xmm::Xmm0, Xmm::Xmm1, avx);
+            Xmm::Xmm0
+        });
+        self.frame.free(second);
+        self.frame.free(first);
+        Ok(ty)
+    }
+
+    /// Combines the lanes of the vector at `[rbp + disp]` from the first to the last, like the
+    /// standard library. Integers wrap around, `reduce_min` / `reduce_max` ignore NaN lanes.
+    fn reduce_lanes(&mut self, simd: StaticSimdLiteral, method: &str, disp: i32) {
+        if simd.element() == F32 {
+            self.asm.load_float(Xmm::Xmm0, Reg::Rbp, disp, false);
+            for lane in 1..simd.lanes() as i32 {
+                self.asm.load_float(Xmm::Xmm1, Reg::Rbp, disp + 4 * lane, false);
+                match method {
+                    "reduce_sum" => self.asm.float_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm1, false),
+                    "reduce_product" => self.asm.float_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1, false),
+                    _ => self.float_min_max(method == "reduce_max", false),
+                }
+            }
+            return;
+        }
+
+        self.asm.load(Reg::Rax, Reg::Rbp, disp, 4, true);
+        for lane in 1..simd.lanes() as i32 {
+            self.asm.load(Reg::Rcx, Reg::Rbp, disp + 4 * lane, 4, true);
+            match method {
+                "reduce_sum" => self.asm.alu_rr(AluOp::Add, Reg::Rax, Reg::Rcx),
+                "reduce_product" => self.asm.imul_rr(Reg::Rax, Reg::Rcx),
+                _ => {
+                    self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                    let replace = if method == "reduce_max" { Cond::Less } else { Cond::Greater };
+                    self.asm.cmov(replace, Reg::Rax, Reg::Rcx);
+                },
+            }
+        }
+        self.normalize(I32);
+    }
+
+    /// `len` and `is_empty` of a slice in `rax` / `rdx`
+    pub(super) fn compile_slice_method(&mut self, id: SliceId, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let method = m.method.to_string();
+        match &*method {
+            "len" | "is_empty" => self.expect_argument_count(method.clone(), 0, m.args.len())?,
+            _ => return Err(self.unknown_method(Ret::Slice(id), &method)),
+        }
+        if method == "len" {
+            self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+            Ok(Ret::Int(StaticIntLiteral::Usize))
+        } else {
+            self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+            self.asm.setcc(Cond::Equal, Reg::Rax);
+            Ok(Ret::Bool)
+        }
+    }
+}
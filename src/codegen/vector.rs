@@ -0,0 +1,274 @@
+//! Built-in `Vec2` / `Vec3` / `Vec4` types.
+//!
+//! A vector is held in a single xmm register, one `f32` per lane, so that
+//! componentwise operations are single packed instructions. Lanes beyond the
+//! length of the vector (the upper two of a `Vec2`, the fourth of a `Vec3`)
+//! have unspecified values and every operation ignores them.
+
+use syn::{BinOp, Expr, ExprBinary, ExprMethodCall, ExprPath};
+use assembler::{FloatOp, Reg, Xmm};
+use compiler::{AssembleError, AssembleFunctionError, Ret, StaticFloatLiteral, StaticVecLiteral, math_type_from_name};
+use super::{FnCompiler, Slot, unsupported};
+
+pub(super) const F32: Ret = Ret::Float(StaticFloatLiteral::F32);
+
+pub(super) const COMPONENTS: [char; 4] = ['x', 'y', 'z', 'w'];
+
+/// `shufps` immediate that keeps every lane where it is
+const IDENTITY: u8 = 0b11_10_01_00;
+/// Lanes `(y, z, x, w)` and `(z, x, y, w)`, for the cross product
+const YZX: u8 = 0b11_00_10_01;
+const ZXY: u8 = 0b11_01_00_10;
+
+/// `shufps` immediate that moves lane `lanes[i]` to lane `i`
+pub(super) fn shuffle(lanes: [u8; 4]) -> u8 {
+    lanes[0] | (lanes[1] << 2) | (lanes[2] << 4) | (lanes[3] << 6)
+}
+
+/// Splits `Vec3::new` or `Mat4::IDENTITY` into the built-in type and the name of the associated item
+pub fn builtin_type_item(p: &ExprPath) -> Option<(Ret, String)> {
+    let path = &p.path;
+    if p.qself.is_some() || path.leading_colon.is_some() || path.segments.len() != 2 {
+        return None;
+    }
+    let ty = math_type_from_name(&path.segments[0].ident.to_string())?;
+    Some((ty, path.segments[1].ident.to_string()))
+}
+
+impl<'a> FnCompiler<'a> {
+
+    pub(super) fn unknown_method(&self, ty: Ret, method: &str) -> AssembleError {
+        AssembleFunctionError::UnknownMethod { function: self.fn_name(), ty, method: method.into() }.into()
+    }
+
+    pub(super) fn expect_argument_count(&self, function: String, expected: usize, found: usize) -> Result<(), AssembleError> {
+        if expected == found {
+            Ok(())
+        } else {
+            Err(AssembleFunctionError::WrongArgumentCount { function, expected, found }.into())
+        }
+    }
+
+    /// Sets every lane of `xmm` to `value`, using `rax`
+    pub(super) fn splat_constant(&mut self, xmm: Xmm, value: f32) {
+        self.asm.mov_ri(Reg::Rax, u64::from(value.to_bits()));
+        self.asm.movq_xr(xmm, Reg::Rax);
+        self.asm.shufps(xmm, xmm, 0);
+    }
+
+    /// Adds the first `lanes` lanes of xmm0 into its lowest lane, from left to right
+    pub(super) fn horizontal_sum(&mut self, lanes: usize) {
+        for lane in 1..lanes {
+            self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+            self.asm.shufps(Xmm::Xmm1, Xmm::Xmm1, lane as u8 * 0x55);
+            self.asm.float_op(FloatOp::Add, Xmm::Xmm0, Xmm::Xmm1, false);
+        }
+    }
+
+    /// Loads one `f32` argument per lane into xmm0, unused lanes are zero
+    pub(super) fn compile_lanes(&mut self, args: &[&Expr]) -> Result<(), AssembleError> {
+        // the lanes are assembled in memory
+        let slot = self.frame.alloc(16, 16);
+        self.asm.xorps(Xmm::Xmm0, Xmm::Xmm0);
+        self.asm.store_packed(Reg::Rbp, slot.disp, Xmm::Xmm0);
+        for (i, arg) in args.iter().enumerate() {
+            self.compile_expr_expect(arg, F32)?;
+            self.asm.store_float(Reg::Rbp, slot.disp + 4 * i as i32, Xmm::Xmm0, false);
+        }
+        self.asm.load_packed(Xmm::Xmm0, Reg::Rbp, slot.disp);
+        self.frame.free(slot);
+        Ok(())
+    }
+
+    /// `Vec3::new(x, y, z)` and `Vec3::splat(v)`
+    pub(super) fn compile_vec_constructor(&mut self, vec: StaticVecLiteral, name: &str, args: &[&Expr])
+    -> Result<Ret, AssembleError>
+    {
+        let ty = Ret::Vec(vec);
+        let function = format!("{:?}::{}", vec, name);
+        match name {
+            "new" => {
+                self.expect_argument_count(function, vec.lanes(), args.len())?;
+                self.compile_lanes(args)?;
+            },
+            "splat" => {
+                self.expect_argument_count(function, 1, args.len())?;
+                self.compile_expr_expect(args[0], F32)?;
+                self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, 0);
+            },
+            _ => return Err(self.unknown_method(ty, name)),
+        }
+        Ok(ty)
+    }
+
+    /// `Vec3::ZERO` and `Vec3::ONE`
+    pub(super) fn compile_vec_constant(&mut self, vec: StaticVecLiteral, name: &str) -> Result<Ret, AssembleError> {
+        match name {
+            "ZERO" => self.asm.xorps(Xmm::Xmm0, Xmm::Xmm0),
+            "ONE" => self.splat_constant(Xmm::Xmm0, 1.0),
+            _ => return Err(self.unknown_method(Ret::Vec(vec), name)),
+        }
+        Ok(Ret::Vec(vec))
+    }
+
+    /// `v.x` and swizzles like `v.zyx`, the vector is in xmm0
+    pub(super) fn compile_swizzle(&mut self, vec: StaticVecLiteral, name: &str)
+    -> Result<Ret, AssembleError>
+    {
+        let unknown_field = || -> AssembleError {
+            AssembleFunctionError::UnknownField { function: self.fn_name(), ty: format!("{:?}", vec), field: name.into() }.into()
+        };
+
+        let mut lanes = Vec::new();
+        for c in name.chars() {
+            match COMPONENTS[..vec.lanes()].iter().position(|component| *component == c) {
+                Some(lane) => lanes.push(lane as u8),
+                None => return Err(unknown_field()),
+            }
+        }
+        let ty = match lanes.len() {
+            1 => F32,
+            n => Ret::Vec(StaticVecLiteral::with_lanes(n).ok_or_else(unknown_field)?),
+        };
+
+        let mut selected = [lanes[0]; 4];
+        selected[..lanes.len()].copy_from_slice(&lanes);
+        let imm = shuffle(selected);
+        if imm != IDENTITY && !(ty == F32 && lanes[0] == 0) {
+            self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, imm);
+        }
+        Ok(ty)
+    }
+
+    /// `-v`, flips the sign bit of every lane
+    pub(super) fn compile_vec_neg(&mut self) {
+        self.asm.mov_ri(Reg::Rax, 1 << 31);
+        self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+        self.asm.shufps(Xmm::Xmm1, Xmm::Xmm1, 0);
+        self.asm.xorps(Xmm::Xmm0, Xmm::Xmm1);
+    }
+
+    /// `+ - * /` with a vector on at least one side: componentwise between vectors of the
+    /// same type, or with an `f32` for `v * s`, `s * v` and `v / s`.
+    /// The left side was spilled to `slot`, the right side is in xmm0.
+    pub(super) fn compile_vec_arithmetic(&mut self, b: &ExprBinary, left: Ret, right: Ret, slot: Slot)
+    -> Result<Ret, AssembleError>
+    {
+        let op = match b.op {
+            BinOp::Add(_) => FloatOp::Add,
+            BinOp::Sub(_) => FloatOp::Sub,
+            BinOp::Mul(_) => FloatOp::Mul,
+            BinOp::Div(_) => FloatOp::Div,
+            _ => return Err(unsupported(b)),
+        };
+        let scalar_op = op == FloatOp::Mul || op == FloatOp::Div;
+
+        let ty = match (left, right) {
+            (Ret::Vec(_), Ret::Vec(_)) => {
+                self.expect_type(left, right)?;
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.load_value(left, Reg::Rbp, slot.disp);
+                left
+            },
+            (Ret::Vec(_), _) if scalar_op => {
+                self.expect_type(F32, right)?;
+                self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, 0);
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.load_value(left, Reg::Rbp, slot.disp);
+                left
+            },
+            (_, Ret::Vec(_)) if op == FloatOp::Mul => {
+                self.expect_type(F32, left)?;
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.load_value(F32, Reg::Rbp, slot.disp);
+                self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, 0);
+                right
+            },
+            _ => {
+                self.expect_type(left, right)?;
+                return Err(unsupported(b));
+            },
+        };
+
+        self.frame.free(slot);
+        self.asm.packed_op(op, Xmm::Xmm0, Xmm::Xmm1);
+        Ok(ty)
+    }
+
+    /// Divides the first `lanes` lanes of xmm0 by their length
+    pub(super) fn normalize_lanes(&mut self, ty: Ret, lanes: usize) {
+        let slot = self.spill(ty);
+        self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+        self.horizontal_sum(lanes);
+        self.asm.float_op(FloatOp::Sqrt, Xmm::Xmm0, Xmm::Xmm0, false);
+        // v * (1 / length)
+        self.asm.mov_ri(Reg::Rax, u64::from(1f32.to_bits()));
+        self.asm.movq_xr(Xmm::Xmm1, Reg::Rax);
+        self.asm.float_op(FloatOp::Div, Xmm::Xmm1, Xmm::Xmm0, false);
+        self.asm.shufps(Xmm::Xmm1, Xmm::Xmm1, 0);
+        self.load_value(ty, Reg::Rbp, slot.disp);
+        self.frame.free(slot);
+        self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+    }
+
+    pub(super) fn compile_vec_method(&mut self, vec: StaticVecLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        self.compile_lane_method(Ret::Vec(vec), vec.lanes(), m)
+    }
+
+    /// `dot`, `cross`, `length`, `length_squared` and `normalize` of a vector or quaternion
+    /// in xmm0. The lanes are combined in the same order as a scalar implementation would,
+    /// so the results are identical to those of the usual Rust math libraries.
+    pub(super) fn compile_lane_method(&mut self, ty: Ret, lanes: usize, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let method = m.method.to_string();
+        let argument_count = match &*method {
+            "dot" => 1,
+            "cross" if ty == Ret::Vec(StaticVecLiteral::Vec3) => 1,
+            "length" | "length_squared" | "normalize" => 0,
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_count, m.args.len())?;
+
+        match &*method {
+            "length" | "length_squared" => {
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+                self.horizontal_sum(lanes);
+                if method == "length" {
+                    self.asm.float_op(FloatOp::Sqrt, Xmm::Xmm0, Xmm::Xmm0, false);
+                }
+                Ok(F32)
+            },
+            "normalize" => {
+                self.normalize_lanes(ty, lanes);
+                Ok(ty)
+            },
+            _ => {
+                let slot = self.spill(ty);
+                self.compile_expr_expect(&m.args[0], ty)?;
+                self.asm.movaps(Xmm::Xmm1, Xmm::Xmm0);
+                self.load_value(ty, Reg::Rbp, slot.disp);
+                self.frame.free(slot);
+
+                if method == "dot" {
+                    self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+                    self.horizontal_sum(lanes);
+                    return Ok(F32);
+                }
+
+                // a.yzx * b.zxy - a.zxy * b.yzx
+                self.asm.movaps(Xmm::Xmm2, Xmm::Xmm0);
+                self.asm.shufps(Xmm::Xmm2, Xmm::Xmm2, YZX);
+                self.asm.movaps(Xmm::Xmm3, Xmm::Xmm1);
+                self.asm.shufps(Xmm::Xmm3, Xmm::Xmm3, ZXY);
+                self.asm.packed_op(FloatOp::Mul, Xmm::Xmm2, Xmm::Xmm3);
+                self.asm.shufps(Xmm::Xmm0, Xmm::Xmm0, ZXY);
+                self.asm.shufps(Xmm::Xmm1, Xmm::Xmm1, YZX);
+                self.asm.packed_op(FloatOp::Mul, Xmm::Xmm0, Xmm::Xmm1);
+                self.asm.packed_op(FloatOp::Sub, Xmm::Xmm2, Xmm::Xmm0);
+                self.asm.movaps(Xmm::Xmm0, Xmm::Xmm2);
+                Ok(ty)
+            },
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! `u128` and `i128`, which are held in `rdx:rax` like they are passed and returned.
+//!
+//! Addition, subtraction and the bitwise operators work on both halves, the carry of the low
+//! half is propagated with `adc` / `sbb`. A product combines the widening `mul` of the low halves
+//! with the cross products, which only contribute to the high half. Division, remainder, shifts,
+//! the overflow check of a product and the conversions from and to floats call the
+//! implementation of the standard library, like the transcendental float functions. So do
+//! `pow`, the rotations and counting bits, the other integer methods are inlined like the
+//! operators. A literal can't have more than 64 bits.
+
+use quote::ToTokens;
+use syn::{BinOp, ExprBinary, ExprMethodCall};
+use assembler::{AluOp, Cond, Reg, ShiftOp, Xmm};
+use compiler::{AssembleError, Ret, ScriptError, StaticIntLiteral};
+use super::{FnCompiler, Slot, unsupported};
+use super::intrinsics::{IntOp, int_op};
+
+/// Whether values of the type are held in two integer registers, like a string
+pub(super) fn is_wide(ty: Ret) -> bool {
+    match ty {
+        Ret::Int(i) => i.size() == 16,
+        _ => false,
+    }
+}
+
+extern "sysv64" fn divide_unsigned(a: u128, b: u128) -> u128 {
+    a / b
+}
+
+extern "sysv64" fn remainder_unsigned(a: u128, b: u128) -> u128 {
+    a % b
+}
+
+// the divisor is checked before, `MIN / -1` traps as well
+extern "sysv64" fn divide_signed(a: i128, b: i128) -> i128 {
+    a.wrapping_div(b)
+}
+
+extern "sysv64" fn remainder_signed(a: i128, b: i128) -> i128 {
+    a.wrapping_rem(b)
+}
+
+// like the other shifts, the amount is masked to the width of the type
+extern "sysv64" fn shift_left(a: u128, amount: u32) -> u128 {
+    a.wrapping_shl(amount)
+}
+
+extern "sysv64" fn shift_right_unsigned(a: u128, amount: u32) -> u128 {
+    a.wrapping_shr(amount)
+}
+
+extern "sysv64" fn shift_right_signed(a: i128, amount: u32) -> i128 {
+    a.wrapping_shr(amount)
+}
+
+extern "sysv64" fn product_overflows_unsigned(a: u128, b: u128) -> bool {
+    a.checked_mul(b).is_none()
+}
+
+extern "sysv64" fn product_overflows_signed(a: i128, b: i128) -> bool {
+    a.checked_mul(b).is_none()
+}
+
+// the powers wrap around the same way for signed and unsigned integers
+extern "sysv64" fn power(a: u128, exponent: u32) -> u128 {
+    a.wrapping_pow(exponent)
+}
+
+extern "sysv64" fn power_overflows_unsigned(a: u128, exponent: u32) -> bool {
+    a.checked_pow(exponent).is_none()
+}
+
+extern "sysv64" fn power_overflows_signed(a: i128, exponent: u32) -> bool {
+    a.checked_pow(exponent).is_none()
+}
+
+extern "sysv64" fn rotate_left(a: u128, amount: u32) -> u128 {
+    a.rotate_left(amount)
+}
+
+extern "sysv64" fn rotate_right(a: u128, amount: u32) -> u128 {
+    a.rotate_right(amount)
+}
+
+extern "sysv64" fn count_ones(x: u128) -> u32 {
+    x.count_ones()
+}
+
+extern "sysv64" fn leading_zeros(x: u128) -> u32 {
+    x.leading_zeros()
+}
+
+extern "sysv64" fn trailing_zeros(x: u128) -> u32 {
+    x.trailing_zeros()
+}
+
+// converting to `f64` first would round twice
+extern "sysv64" fn unsigned_to_single(x: u128) -> f32 {
+    x as f32
+}
+
+extern "sysv64" fn unsigned_to_double(x: u128) -> f64 {
+    x as f64
+}
+
+extern "sysv64" fn signed_to_single(x: i128) -> f32 {
+    x as f32
+}
+
+extern "sysv64" fn signed_to_double(x: i128) -> f64 {
+    x as f64
+}
+
+// an `f32` is converted to `f64` before, which is exact
+extern "sysv64" fn double_to_unsigned(x: f64) -> u128 {
+    x as u128
+}
+
+extern "sysv64" fn double_to_signed(x: f64) -> i128 {
+    x as i128
+}
+
+impl<'a> FnCompiler<'a> {
+
+    /// Calls a function of two 128-bit integers, the left side in the slot and the right side in `rdx:rax`
+    fn call_with_operands(&mut self, slot: Slot, address: usize) {
+        self.asm.mov_rr(Reg::Rcx, Reg::Rdx);
+        self.asm.mov_rr(Reg::Rdx, Reg::Rax);
+        self.asm.load(Reg::Rdi, Reg::Rbp, slot.disp, 8, false);
+        self.asm.load(Reg::Rsi, Reg::Rbp, slot.disp + 8, 8, false);
+        self.call_address(address);
+    }
+
+    /// Like `arithmetic_op` for 128-bit integers, the left side is in the slot and the right side in `rdx:rax`
+    pub(super) fn wide_arithmetic<T: ToTokens>(&mut self, b: &ExprBinary, node: &T, i: StaticIntLiteral, slot: Slot) -> Result<(), AssembleError> {
+        let signed = i.is_signed();
+        let (low, high) = match b.op {
+            BinOp::Add(_) => (AluOp::Add, AluOp::Adc),
+            BinOp::Sub(_) => (AluOp::Sub, AluOp::Sbb),
+            BinOp::BitAnd(_) => (AluOp::And, AluOp::And),
+            BinOp::BitOr(_) => (AluOp::Or, AluOp::Or),
+            BinOp::BitXor(_) => (AluOp::Xor, AluOp::Xor),
+            BinOp::Mul(_) => {
+                self.wide_multiplication(node, i, slot);
+                return Ok(());
+            },
+            BinOp::Div(_) | BinOp::Rem(_) => {
+                self.check_wide_divisor(signed, slot, node);
+                let address = match (b.op, signed) {
+                    (BinOp::Div(_), false) => divide_unsigned as *const u8,
+                    (BinOp::Div(_), true) => divide_signed as *const u8,
+                    (_, false) => remainder_unsigned as *const u8,
+                    (_, true) => remainder_signed as *const u8,
+                };
+                self.call_with_operands(slot, address as usize);
+                return Ok(());
+            },
+            BinOp::Shl(_) | BinOp::Shr(_) => {
+                // the amount is the third argument, only its lowest bits matter
+                let address = match (b.op, signed) {
+                    (BinOp::Shl(_), _) => shift_left as *const u8,
+                    (_, false) => shift_right_unsigned as *const u8,
+                    (_, true) => shift_right_signed as *const u8,
+                };
+                self.asm.mov_rr(Reg::Rdx, Reg::Rax);
+                self.asm.load(Reg::Rdi, Reg::Rbp, slot.disp, 8, false);
+                self.asm.load(Reg::Rsi, Reg::Rbp, slot.disp + 8, 8, false);
+                self.call_address(address as usize);
+                return Ok(());
+            },
+            _ => return Err(unsupported(b)),
+        };
+
+        self.asm.mov_rr(Reg::R8, Reg::Rax);
+        self.asm.mov_rr(Reg::R9, Reg::Rdx);
+        self.load_value(Ret::Int(i), Reg::Rbp, slot.disp);
+        self.asm.alu_rr(low, Reg::Rax, Reg::R8);
+        self.asm.alu_rr(high, Reg::Rdx, Reg::R9);
+        if self.checks_overflow() && matches!(b.op, BinOp::Add(_) | BinOp::Sub(_)) {
+            let error = ScriptError::Overflow(self.location(node));
+            self.trap_if(if signed { Cond::Overflow } else { Cond::Below }, error);
+        }
+        Ok(())
+    }
+
+    /// The product of the left side in the slot and the right side in `rdx:rax`
+    fn wide_multiplication<T: ToTokens>(&mut self, node: &T, i: StaticIntLiteral, slot: Slot) {
+        let ty = Ret::Int(i);
+        if self.checks_overflow() {
+            // the call overwrites the right side
+            let right = self.frame.alloc(16, 8);
+            self.store_value(ty, Reg::Rbp, right.disp);
+            let overflows = if i.is_signed() { product_overflows_signed as *const u8 } else { product_overflows_unsigned as *const u8 };
+            self.call_with_operands(slot, overflows as usize);
+            // only the lowest byte of a returned `bool` is defined
+            self.asm.extend(Reg::Rax, Reg::Rax, 1, false);
+            self.asm.test_rr(Reg::Rax, Reg::Rax);
+            let error = ScriptError::Overflow(self.location(node));
+            self.trap_if(Cond::NotEqual, error);
+            self.load_value(ty, Reg::Rbp, right.disp);
+            self.frame.free(right);
+        }
+        self.wrapping_product(slot);
+    }
+
+    /// `(a1 * 2^64 + a0) * (b1 * 2^64 + b0)` wraps around to `a0 * b0 + (a0 * b1 + a1 * b0) * 2^64`,
+    /// which is the same for signed and unsigned integers. The left side is in the slot.
+    fn wrapping_product(&mut self, slot: Slot) {
+        self.asm.mov_rr(Reg::R8, Reg::Rax);
+        self.asm.mov_rr(Reg::R9, Reg::Rdx);
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp, 8, false);
+        self.asm.imul_rr(Reg::Rcx, Reg::R9);
+        self.asm.load(Reg::R10, Reg::Rbp, slot.disp + 8, 8, false);
+        self.asm.imul_rr(Reg::R10, Reg::R8);
+        self.asm.alu_rr(AluOp::Add, Reg::Rcx, Reg::R10);
+        self.asm.load(Reg::Rax, Reg::Rbp, slot.disp, 8, false);
+        self.asm.mul_sized(Reg::R8, 8, false);
+        self.asm.alu_rr(AluOp::Add, Reg::Rdx, Reg::Rcx);
+    }
+
+    /// Like `check_divisor`, traps if the divisor in `rdx:rax` is zero or if the quotient of
+    /// the dividend in the slot and -1 doesn't fit
+    fn check_wide_divisor<T: ToTokens>(&mut self, signed: bool, slot: Slot, node: &T) {
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.asm.alu_rr(AluOp::Or, Reg::Rcx, Reg::Rdx);
+        let error = ScriptError::DivideByZero(self.location(node));
+        self.trap_if(Cond::Equal, error);
+        if !signed {
+            return;
+        }
+        let valid = self.asm.new_label();
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.asm.alu_rr(AluOp::And, Reg::Rcx, Reg::Rdx);
+        self.asm.alu_ri(AluOp::Cmp, Reg::Rcx, -1);
+        self.asm.jcc(Cond::NotEqual, valid);
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp + 8, 8, false);
+        self.asm.mov_ri(Reg::R8, 1 << 63);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rcx, Reg::R8);
+        self.asm.jcc(Cond::NotEqual, valid);
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp, 8, false);
+        self.asm.test_rr(Reg::Rcx, Reg::Rcx);
+        let error = ScriptError::Overflow(self.location(node));
+        self.trap_if(Cond::Equal, error);
+        self.asm.bind(valid);
+    }
+
+    /// Like `compare_with_slot` for 128-bit integers. The order is decided by the flags of
+    /// subtracting the high halves with the borrow of the low halves, so `a > b` is `b < a`.
+    pub(super) fn compare_wide(&mut self, op: &BinOp, signed: bool, slot: Slot) {
+        self.asm.load(Reg::Rcx, Reg::Rbp, slot.disp, 8, false);
+        self.asm.load(Reg::R8, Reg::Rbp, slot.disp + 8, 8, false);
+        let (less, greater_equal) = if signed { (Cond::Less, Cond::GreaterEqual) } else { (Cond::Below, Cond::AboveEqual) };
+        let cond = match *op {
+            BinOp::Eq(_) | BinOp::Ne(_) => {
+                self.asm.alu_rr(AluOp::Xor, Reg::Rcx, Reg::Rax);
+                self.asm.alu_rr(AluOp::Xor, Reg::R8, Reg::Rdx);
+                self.asm.alu_rr(AluOp::Or, Reg::Rcx, Reg::R8);
+                if let BinOp::Eq(_) = *op { Cond::Equal } else { Cond::NotEqual }
+            },
+            BinOp::Lt(_) | BinOp::Ge(_) => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rcx, Reg::Rax);
+                self.asm.alu_rr(AluOp::Sbb, Reg::R8, Reg::Rdx);
+                if let BinOp::Lt(_) = *op { less } else { greater_equal }
+            },
+            _ => {
+                self.asm.alu_rr(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+                self.asm.alu_rr(AluOp::Sbb, Reg::Rdx, Reg::R8);
+                if let BinOp::Gt(_) = *op { less } else { greater_equal }
+            },
+        };
+        self.asm.setcc(cond, Reg::Rax);
+    }
+
+    /// `-x` of an `i128`, trapping on `-i128::MIN` if overflow is checked
+    pub(super) fn negate_wide<T: ToTokens>(&mut self, node: &T) {
+        if self.checks_overflow() {
+            self.asm.mov_ri(Reg::Rcx, 1 << 63);
+            self.asm.alu_rr(AluOp::Xor, Reg::Rcx, Reg::Rdx);
+            self.asm.alu_rr(AluOp::Or, Reg::Rcx, Reg::Rax);
+            let error = ScriptError::Overflow(self.location(node));
+            self.trap_if(Cond::Equal, error);
+        }
+        // the high half is `!high + 1` if the low half is zero and `!high` otherwise
+        self.asm.neg(Reg::Rax);
+        self.asm.alu_ri(AluOp::Adc, Reg::Rdx, 0);
+        self.asm.neg(Reg::Rdx);
+    }
+
+    /// Sign- or zero-extends the integer in `rax` to `rdx:rax`
+    pub(super) fn widen(&mut self, signed: bool) {
+        if signed {
+            self.asm.cqo();
+        } else {
+            self.asm.alu_rr(AluOp::Xor, Reg::Rdx, Reg::Rdx);
+        }
+    }
+
+    /// Converts the integer in `rdx:rax` to a float in `xmm0`
+    pub(super) fn wide_to_float(&mut self, signed: bool, double: bool) {
+        let address = match (signed, double) {
+            (false, false) => unsigned_to_single as *const u8,
+            (false, true) => unsigned_to_double as *const u8,
+            (true, false) => signed_to_single as *const u8,
+            (true, true) => signed_to_double as *const u8,
+        };
+        self.asm.mov_rr(Reg::Rdi, Reg::Rax);
+        self.asm.mov_rr(Reg::Rsi, Reg::Rdx);
+        self.call_address(address as usize);
+    }
+
+    /// Converts the float in `xmm0` to an integer in `rdx:rax`, saturating like `as`
+    pub(super) fn float_to_wide(&mut self, signed: bool, double: bool) {
+        if !double {
+            self.asm.cvt_float(Xmm::Xmm0, Xmm::Xmm0, true);
+        }
+        let address = if signed { double_to_signed as *const u8 } else { double_to_unsigned as *const u8 };
+        self.call_address(address as usize);
+    }
+
+    /// The integer methods of `u128` and `i128`, like `compile_int_method`. The receiver is in `rdx:rax`.
+    pub(super) fn compile_wide_method(&mut self, i: StaticIntLiteral, m: &ExprMethodCall) -> Result<Ret, AssembleError> {
+        let ty = Ret::Int(i);
+        let signed = i.is_signed();
+        let method = m.method.to_string();
+        let argument_types = match &*method {
+            "abs" if signed => vec![],
+            "min" | "max" => vec![ty],
+            "clamp" => vec![ty, ty],
+            "pow" | "rotate_left" | "rotate_right" => vec![Ret::Int(StaticIntLiteral::U32)],
+            "count_ones" | "leading_zeros" | "trailing_zeros" | "swap_bytes" => vec![],
+            "wrapping_add" | "wrapping_sub" | "wrapping_mul" | "saturating_add" | "saturating_sub" |
+            "checked_add" | "checked_sub" | "checked_mul" | "overflowing_add" | "overflowing_sub" | "overflowing_mul" => vec![ty],
+            _ => return Err(self.unknown_method(ty, &method)),
+        };
+        self.expect_argument_count(method.clone(), argument_types.len(), m.args.len())?;
+        // the receiver and the arguments wait in slots until all of them are computed
+        let mut slots = vec![self.spill(ty)];
+        for (arg, arg_ty) in m.args.iter().zip(argument_types) {
+            self.compile_expr_expect(arg, arg_ty)?;
+            let arg_ty = self.infer.resolve(arg_ty);
+            slots.push(self.spill(arg_ty));
+        }
+        let receiver = slots[0];
+
+        let result = match &*method {
+            "abs" => {
+                let positive = self.asm.new_label();
+                self.load_value(ty, Reg::Rbp, receiver.disp);
+                self.asm.test_rr(Reg::Rdx, Reg::Rdx);
+                self.asm.jcc(Cond::NoSign, positive);
+                self.negate_wide(m);
+                self.asm.bind(positive);
+                ty
+            },
+            "min" | "max" | "clamp" => {
+                let (less, greater_equal) = if signed { (Cond::Less, Cond::GreaterEqual) } else { (Cond::Below, Cond::AboveEqual) };
+                self.load_value(ty, Reg::Rbp, receiver.disp);
+                if method != "min" {
+                    self.select_wide(less, slots[1]);
+                }
+                if method != "max" {
+                    self.select_wide(greater_equal, slots[slots.len() - 1]);
+                }
+                ty
+            },
+            "pow" | "rotate_left" | "rotate_right" => {
+                if method == "pow" && self.checks_overflow() {
+                    let overflows = if signed { power_overflows_signed as *const u8 } else { power_overflows_unsigned as *const u8 };
+                    self.call_with_amount(receiver, slots[1], overflows as usize);
+                    // only the lowest byte of a returned `bool` is defined
+                    self.asm.extend(Reg::Rax, Reg::Rax, 1, false);
+                    self.asm.test_rr(Reg::Rax, Reg::Rax);
+                    let error = ScriptError::Overflow(self.location(m));
+                    self.trap_if(Cond::NotEqual, error);
+                }
+                let address = match &*method {
+                    "pow" => power as *const u8,
+                    "rotate_left" => rotate_left as *const u8,
+                    _ => rotate_right as *const u8,
+                };
+                self.call_with_amount(receiver, slots[1], address as usize);
+                ty
+            },
+            "count_ones" | "leading_zeros" | "trailing_zeros" => {
+                let address = match &*method {
+                    "count_ones" => count_ones as *const u8,
+                    "leading_zeros" => leading_zeros as *const u8,
+                    _ => trailing_zeros as *const u8,
+                };
+                self.asm.load(Reg::Rdi, Reg::Rbp, receiver.disp, 8, false);
+                self.asm.load(Reg::Rsi, Reg::Rbp, receiver.disp + 8, 8, false);
+                self.call_address(address as usize);
+                self.asm.extend(Reg::Rax, Reg::Rax, 4, false);
+                Ret::Int(StaticIntLiteral::U32)
+            },
+            "swap_bytes" => {
+                self.load_value(ty, Reg::Rbp, receiver.disp);
+                self.asm.bswap(Reg::Rax);
+                self.asm.bswap(Reg::Rdx);
+                self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+                self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+                self.asm.mov_rr(Reg::Rdx, Reg::Rcx);
+                ty
+            },
+            "wrapping_mul" => {
+                self.load_value(ty, Reg::Rbp, slots[1].disp);
+                self.wrapping_product(receiver);
+                ty
+            },
+            "wrapping_add" | "wrapping_sub" => {
+                let op = int_op(&method).expect("not an arithmetic method");
+                self.overflowing_wide(op, signed, receiver, slots[1]);
+                ty
+            },
+            "saturating_add" | "saturating_sub" => {
+                let op = int_op(&method).expect("not an arithmetic method");
+                self.saturating_wide(op, signed, receiver, slots[1]);
+                ty
+            },
+            _ => {
+                let op = int_op(&method).expect("not an arithmetic method");
+                self.overflowing_wide(op, signed, receiver, slots[1]);
+                if method.starts_with("checked_") {
+                    // the value of `None` is whatever the operation wrapped around to
+                    let option = self.shared.types.option_type(ty);
+                    let offset = match option {
+                        Ret::Option(id) => self.shared.types.option_value_offset(id),
+                        _ => unreachable!("not an option"),
+                    };
+                    let slot = self.alloc_temp(option);
+                    self.asm.alu_ri(AluOp::Xor, Reg::R11, 1);
+                    self.asm.store(Reg::Rbp, slot.disp, Reg::R11, 1);
+                    self.store_value(ty, Reg::Rbp, slot.disp + offset);
+                    self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                    option
+                } else {
+                    let tuple = self.shared.types.tuple_type(vec![ty, Ret::Bool]);
+                    let slot = self.alloc_temp(tuple);
+                    self.store_value(ty, Reg::Rbp, slot.disp);
+                    self.asm.store(Reg::Rbp, slot.disp + 16, Reg::R11, 1);
+                    self.asm.lea(Reg::Rax, Reg::Rbp, slot.disp);
+                    tuple
+                }
+            },
+        };
+        for slot in slots {
+            self.frame.free(slot);
+        }
+        Ok(result)
+    }
+
+    /// Calls a function of a 128-bit integer in the first slot and a `u32` in the second one
+    fn call_with_amount(&mut self, value: Slot, amount: Slot, address: usize) {
+        self.asm.load(Reg::Rdi, Reg::Rbp, value.disp, 8, false);
+        self.asm.load(Reg::Rsi, Reg::Rbp, value.disp + 8, 8, false);
+        self.asm.load(Reg::Rdx, Reg::Rbp, amount.disp, 4, false);
+        self.call_address(address);
+    }
+
+    /// Replaces `rdx:rax` with the integer in the slot if the flags of subtracting that
+    /// integer from `rdx:rax` satisfy the condition
+    fn select_wide(&mut self, cond: Cond, slot: Slot) {
+        self.asm.load(Reg::R8, Reg::Rbp, slot.disp, 8, false);
+        self.asm.load(Reg::R9, Reg::Rbp, slot.disp + 8, 8, false);
+        self.asm.mov_rr(Reg::Rcx, Reg::Rax);
+        self.asm.alu_rr(AluOp::Cmp, Reg::Rcx, Reg::R8);
+        self.asm.mov_rr(Reg::R10, Reg::Rdx);
+        self.asm.alu_rr(AluOp::Sbb, Reg::R10, Reg::R9);
+        self.asm.cmov(cond, Reg::Rax, Reg::R8);
+        self.asm.cmov(cond, Reg::Rdx, Reg::R9);
+    }
+
+    /// `rdx:rax = left op right` of the integers in the slots, wrapping around. Sets `r11` to 1
+    /// if the operation overflowed and to 0 otherwise.
+    fn overflowing_wide(&mut self, op: IntOp, signed: bool, left: Slot, right: Slot) {
+        let ty = Ret::Int(if signed { StaticIntLiteral::I128 } else { StaticIntLiteral::U128 });
+        if op == IntOp::Mul {
+            let overflows = if signed { product_overflows_signed as *const u8 } else { product_overflows_unsigned as *const u8 };
+            self.load_value(ty, Reg::Rbp, right.disp);
+            self.call_with_operands(left, overflows as usize);
+            self.asm.extend(Reg::R11, Reg::Rax, 1, false);
+            self.load_value(ty, Reg::Rbp, right.disp);
+            self.wrapping_product(left);
+            return;
+        }
+        let (low, high) = if op == IntOp::Add { (AluOp::Add, AluOp::Adc) } else { (AluOp::Sub, AluOp::Sbb) };
+        self.asm.load(Reg::R8, Reg::Rbp, right.disp, 8, false);
+        self.asm.load(Reg::R9, Reg::Rbp, right.disp + 8, 8, false);
+        self.load_value(ty, Reg::Rbp, left.disp);
+        self.asm.alu_rr(low, Reg::Rax, Reg::R8);
+        self.asm.alu_rr(high, Reg::Rdx, Reg::R9);
+        self.asm.setcc(if signed { Cond::Overflow } else { Cond::Below }, Reg::R11);
+    }
+
+    /// `saturating_add` and `saturating_sub`, like `saturating_int_op`. The bound that a sum
+    /// overflows to is prepared in `r10:r11` before the flags are set.
+    fn saturating_wide(&mut self, op: IntOp, signed: bool, left: Slot, right: Slot) {
+        if signed {
+            // a sum overflows towards the sign of the right side, a difference towards the opposite sign
+            self.asm.load(Reg::R11, Reg::Rbp, right.disp + 8, 8, false);
+            self.asm.shift_ri(ShiftOp::Sar, Reg::R11, 63);
+            self.asm.mov_rr(Reg::R10, Reg::R11);
+            if op == IntOp::Add {
+                self.asm.not(Reg::R10);
+            }
+            self.asm.mov_ri(Reg::Rcx, if op == IntOp::Add { i64::MAX as u64 } else { 1 << 63 });
+            self.asm.alu_rr(AluOp::Xor, Reg::R11, Reg::Rcx);
+        } else {
+            let bound = if op == IntOp::Add { u64::MAX } else { 0 };
+            self.asm.mov_ri(Reg::R10, bound);
+            self.asm.mov_ri(Reg::R11, bound);
+        }
+        let ty = Ret::Int(if signed { StaticIntLiteral::I128 } else { StaticIntLiteral::U128 });
+        let (low, high) = if op == IntOp::Add { (AluOp::Add, AluOp::Adc) } else { (AluOp::Sub, AluOp::Sbb) };
+        self.asm.load(Reg::R8, Reg::Rbp, right.disp, 8, false);
+        self.asm.load(Reg::R9, Reg::Rbp, right.disp + 8, 8, false);
+        self.load_value(ty, Reg::Rbp, left.disp);
+        self.asm.alu_rr(low, Reg::Rax, Reg::R8);
+        self.asm.alu_rr(high, Reg::Rdx, Reg::R9);
+        let overflow = if signed { Cond::Overflow } else { Cond::Below };
+        self.asm.cmov(overflow, Reg::Rax, Reg::R10);
+        self.asm.cmov(overflow, Reg::Rdx, Reg::R11);
+    }
+}
@@ -1,8 +1,16 @@
-use std::{fmt, collections::{HashSet, BTreeMap}, sync::atomic::{AtomicUsize, Ordering}};
-use syn::{File, Stmt, Type, FnArg, Item::Fn, ReturnType,
-          ItemFn, Ident, Path, Lit, Expr, IntSuffix, ExprLit};
-
-#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+use std::{fmt, collections::BTreeMap, rc::Rc, sync::atomic::{AtomicUsize, Ordering}};
+use quote::ToTokens;
+use syn::{Expr, ExprLit, File, Stmt, Type, FnArg, Item, ItemStatic, ItemStruct, ReturnType, ItemFn, Ident, Path, ForeignItem,
+          ForeignItemFn, Fields, FloatSuffix, IntSuffix, Lit, Meta, MetaNameValue, NestedMeta, UnOp};
+use assembler::{AluOp, Assembler, CallRelocation, Cond, DataRelocation, MachineCode, Reg, Xmm};
+use codegen::{FnBody, FnSignature, FnSource, compile_function};
+use format::{FormatString, ARGUMENT_SIZE};
+use logger::{LogMessage, SharedLogger};
+use operators::{self, Operator, OperatorDecl};
+use resolve::{ModuleTree, ModuleId, Def, ROOT_MODULE};
+use types::{FnTypeId, OptionId, RefId, ResultId, SliceId, StructId, TypeTable};
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Default)]
 pub struct GlobalLabel(pub usize);
 
 impl fmt::Display for GlobalLabel {
@@ -14,71 +22,135 @@ impl fmt::Display for GlobalLabel {
 pub type FnMap = BTreeMap<GlobalLabel, Function>;
 pub type FnOffsetMap = BTreeMap<GlobalLabel, FnLocation>;
 
-const FN_PROLOGUE: [u8;4] = [
+pub const FN_PROLOGUE: [u8;4] = [
     0x55,                     // push   rbp
     0x48, 0x89, 0xE5          // mov    rbp,rsp
 ];
 
-const FN_EPILOGUE: [u8;2] = [
-    0x5D,                     // pop    rbp
+pub const FN_EPILOGUE: [u8;2] = [
+    0xC9,                     // leave  (mov rsp,rbp; pop rbp)
     0xC3                      // ret
 ];
 
-const GLOBAL_LABEL_ID: AtomicUsize = AtomicUsize::new(0);
+static GLOBAL_LABEL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a label that is unique for the whole process
+pub fn new_global_label() -> GlobalLabel {
+    GlobalLabel(GLOBAL_LABEL_ID.fetch_add(1, Ordering::SeqCst))
+}
 
 pub struct AssemblyBuf {
     pub instructions: Vec<u8>,
+    /// Offset of the code that calls the entry function and catches traps, see `JitMemory::call`.
+    /// `None` if the script has no `#[start]` function, only exports.
+    pub trap_entry: Option<usize>,
+    /// The functions marked with `#[export]`, sorted by name
+    pub exports: Vec<Export>,
+    /// Offset of the state of the trap handler: the stack pointer to return to and the trapped site
+    pub trap_state: usize,
+    /// What every place that can trap reports, indexed by the number of the site
+    pub traps: Vec<Trap>,
+    /// The logger of `CompileOptions`, the logging macros are only compiled if there is one
+    pub logger: Option<SharedLogger>,
+    /// Offset of the pointer to the `LogContext`, written when the code is loaded
+    pub log_context: usize,
+    /// Every logging macro, indexed by the number of the message
+    pub log_messages: Vec<LogMessage>,
 }
 
+/// A function marked with `#[export]`, which the host can look up and call by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    /// The name of the function, or the one given by `#[export(name = "..")]`
+    pub name: String,
+    /// Offset of the code of the function, which can be called like an `extern "sysv64"` function
+    pub offset: usize,
+    /// Offset of the code that calls the function and catches traps, see `JitMemory::call_export`
+    pub trap_entry: usize,
+    pub signature: FnSignature,
+}
+
+/// What a place in the code that can trap reports, see `JitMemory::call`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    Error(ScriptError),
+    /// A `panic!` or failed assertion, the values of the message are in the trap state
+    Panic { message: FormatString, file: String, line: u32 },
+}
+
+/// Offset of the values of a panic message in the trap state, after the stack pointer
+/// to return to and the number of the site that trapped
+pub const TRAP_ARGUMENTS: usize = 16;
+
+/// How many values a panic message can contain
+pub const MAX_TRAP_ARGUMENTS: usize = 8;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AllocationError {
     /// Instructions are too big to fit in the allocated JIT memory
     InstructionBufTooLarge,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub enum Ret {
     Str,
-    ByteStr,
-    Byte,
+    /// A Unicode scalar value, `'x'`. Byte literals (`b'x'`) are `u8`, as in Rust.
     Char,
     Int(StaticIntLiteral),
     Float(StaticFloatLiteral),
     Bool,
+    /// `Vec2` / `Vec3` / `Vec4` with `f32` lanes, held in a single xmm register
     Vec(StaticVecLiteral),
+    /// `Quat`, a rotation `(x, y, z, w)`, held in a single xmm register like a `Vec4`
+    Quat,
+    /// `Mat3` / `Mat4`, column-major. Matrices live in memory like structs.
+    Mat(StaticMatLiteral),
+    /// `f32x4`, `i32x8`, `mask32x4`, ..
+    Simd(StaticSimdLiteral),
+    /// `Fixed`, a 32.32 fixed-point number held in an integer register like its `i64` bits
+    Fixed,
+    /// `&[T]` or `&mut [T]`, a pointer and a length. Byte string literals are `&[u8]`.
+    Slice(SliceId),
+    /// `&T` or `&mut T` of a parameter or local, the address of the value
+    Ref(RefId),
+    /// A script struct or a tuple, laid out like a `#[repr(C)]` struct
+    Struct(StructId),
+    /// `Option<T>`, a flag followed by the value
+    Option(OptionId),
+    /// `Result<T, E>`, a flag followed by the value or the error
+    Result(ResultId),
+    /// `fn(u32) -> u32`, the address of a script function, a closure or a host function
+    FnPtr(FnTypeId),
+    #[default]
     Void,
 }
 
-impl Default for Ret {
-    fn default() -> Self {
-        Ret::Void
+impl Ret {
+    /// Size of the value in memory (locals, arguments), in bytes.
+    /// The size of a struct is only known to the `TypeTable`.
+    pub fn size(&self) -> i32 {
+        match *self {
+            Ret::Int(i) => i.size(),
+            Ret::Float(f) => f.size(),
+            // pointer + length
+            Ret::Str | Ret::Slice(_) => 16,
+            Ret::Char => 4,
+            Ret::Bool => 1,
+            Ret::Vec(v) => v.size(),
+            Ret::Quat => 16,
+            Ret::Mat(m) => m.size(),
+            Ret::Simd(s) => s.size(),
+            Ret::Void => 0,
+            Ret::Struct(_) | Ret::Option(_) | Ret::Result(_) => unreachable!("struct sizes are stored in the TypeTable"),
+            _ => 8,
+        }
     }
-}
 
-pub enum Instruction {
-    OneComponent(u8),
-    TwoComponent((u8, u8))
-}
-
-impl Ret {
-    pub fn get_optimal_register_return(&self) -> Option<Instruction> {
-        use self::Ret::*;
-        use self::StaticIntLiteral::*;
+    pub fn is_signed(&self) -> bool {
         match *self {
-            Int(i) => {
-                match i {
-                    // mov al [0x04]
-                    I8 | U8 => Some(Instruction::OneComponent(0xB0)),
-                    // mov ax [0x04, 0x00]
-                    I16 | U16 => Some(Instruction::TwoComponent((0x66, 0xB8))),
-                    // mov eax [0x04, 0x00, 0x00, 0x00]
-                    I32 | U32 => Some(Instruction::OneComponent(0xB8)),
-                    // movabs rax [0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
-                    I64 | U64 => Some(Instruction::TwoComponent((0x48, 0xB8))),
-                    _ => None,
-                }
-            },
-            _ => None
+            Ret::Int(i) => i.is_signed(),
+            Ret::Fixed => true,
+            _ => false,
         }
     }
 }
@@ -87,6 +159,21 @@ impl Ret {
 pub enum StaticFloatLiteral {
     F64,
     F32,
+    /// Type variable of an unsuffixed float literal during type inference
+    UnknownSize(u64),
+}
+
+impl StaticFloatLiteral {
+    pub fn size(&self) -> i32 {
+        match *self {
+            StaticFloatLiteral::F32 => 4,
+            StaticFloatLiteral::F64 | StaticFloatLiteral::UnknownSize(_) => 8,
+        }
+    }
+
+    pub fn is_double(&self) -> bool {
+        *self != StaticFloatLiteral::F32
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -99,9 +186,87 @@ pub enum StaticIntLiteral {
     U16,
     U32,
     U64,
+    I128,
+    U128,
+    Isize,
+    Usize,
+    /// Type variable of an unsuffixed integer literal during type inference
     UnknownSize(u64)
 }
 
+impl StaticIntLiteral {
+    pub fn size(&self) -> i32 {
+        use self::StaticIntLiteral::*;
+        match *self {
+            I8 | U8 => 1,
+            I16 | U16 => 2,
+            I32 | U32 => 4,
+            I64 | U64 | Isize | Usize | UnknownSize(_) => 8,
+            // held in `rdx:rax`, like they are passed and returned
+            I128 | U128 => 16,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        use self::StaticIntLiteral::*;
+        matches!(*self, I8 | I16 | I32 | I64 | I128 | Isize)
+    }
+
+    /// The type of a suffixed literal, `None` for an unsuffixed one
+    pub fn from_suffix(suffix: IntSuffix) -> Option<Self> {
+        use self::StaticIntLiteral::*;
+        match suffix {
+            IntSuffix::I8 => Some(I8),
+            IntSuffix::I16 => Some(I16),
+            IntSuffix::I32 => Some(I32),
+            IntSuffix::I64 => Some(I64),
+            IntSuffix::I128 => Some(I128),
+            IntSuffix::Isize => Some(Isize),
+            IntSuffix::U8 => Some(U8),
+            IntSuffix::U16 => Some(U16),
+            IntSuffix::U32 => Some(U32),
+            IntSuffix::U64 => Some(U64),
+            IntSuffix::U128 => Some(U128),
+            IntSuffix::Usize => Some(Usize),
+            IntSuffix::None => None,
+        }
+    }
+
+    /// `u8` .. `i128`, `usize` and `isize`
+    pub fn from_name(name: &str) -> Option<Self> {
+        use self::StaticIntLiteral::*;
+        match name {
+            "u8" => Some(U8),
+            "u16" => Some(U16),
+            "u32" => Some(U32),
+            "u64" => Some(U64),
+            "u128" => Some(U128),
+            "usize" => Some(Usize),
+            "i8" => Some(I8),
+            "i16" => Some(I16),
+            "i32" => Some(I32),
+            "i64" => Some(I64),
+            "i128" => Some(I128),
+            "isize" => Some(Isize),
+            _ => None,
+        }
+    }
+
+    /// `MIN` and `MAX`, sign-extended to 128 bits
+    pub fn limit(&self, name: &str) -> Option<u128> {
+        let bits = self.size() as u32 * 8;
+        let max = u128::MAX >> (128 - bits + self.is_signed() as u32);
+        match name {
+            "MIN" if self.is_signed() => Some(!max),
+            "MIN" => Some(0),
+            "MAX" => Some(max),
+            _ => None,
+        }
+    }
+}
+
+/// Built-in vector types. `Vec2` is laid out and passed like `#[repr(C)] struct { x: f32, y: f32 }`,
+/// `Vec3` and `Vec4` like `__m128` (the fourth lane of a `Vec3` is unspecified).
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum StaticVecLiteral {
     Vec2,
@@ -109,9 +274,162 @@ pub enum StaticVecLiteral {
     Vec4,
 }
 
+impl StaticVecLiteral {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Vec2" => Some(StaticVecLiteral::Vec2),
+            "Vec3" => Some(StaticVecLiteral::Vec3),
+            "Vec4" => Some(StaticVecLiteral::Vec4),
+            _ => None,
+        }
+    }
+
+    /// Number of `f32` components
+    pub fn lanes(&self) -> usize {
+        match *self {
+            StaticVecLiteral::Vec2 => 2,
+            StaticVecLiteral::Vec3 => 3,
+            StaticVecLiteral::Vec4 => 4,
+        }
+    }
+
+    pub fn size(&self) -> i32 {
+        match *self {
+            StaticVecLiteral::Vec2 => 8,
+            StaticVecLiteral::Vec3 | StaticVecLiteral::Vec4 => 16,
+        }
+    }
+
+    pub fn align(&self) -> i32 {
+        match *self {
+            StaticVecLiteral::Vec2 => 4,
+            StaticVecLiteral::Vec3 | StaticVecLiteral::Vec4 => 16,
+        }
+    }
+
+    /// The vector type with the given number of lanes
+    pub fn with_lanes(lanes: usize) -> Option<Self> {
+        match lanes {
+            2 => Some(StaticVecLiteral::Vec2),
+            3 => Some(StaticVecLiteral::Vec3),
+            4 => Some(StaticVecLiteral::Vec4),
+            _ => None,
+        }
+    }
+}
+
+/// Built-in matrix types. The columns are `Vec3` / `Vec4`, so a `Mat4` is laid out like
+/// `[[f32; 4]; 4]` and a `Mat3` like `[__m128; 3]`, both 16-byte aligned.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StaticMatLiteral {
+    Mat3,
+    Mat4,
+}
+
+impl StaticMatLiteral {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Mat3" => Some(StaticMatLiteral::Mat3),
+            "Mat4" => Some(StaticMatLiteral::Mat4),
+            _ => None,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        match *self {
+            StaticMatLiteral::Mat3 => 3,
+            StaticMatLiteral::Mat4 => 4,
+        }
+    }
+
+    /// The type of a column (and of the vectors that the matrix transforms)
+    pub fn column(&self) -> StaticVecLiteral {
+        match *self {
+            StaticMatLiteral::Mat3 => StaticVecLiteral::Vec3,
+            StaticMatLiteral::Mat4 => StaticVecLiteral::Vec4,
+        }
+    }
+
+    pub fn size(&self) -> i32 {
+        self.columns() as i32 * 16
+    }
+}
+
+/// Portable SIMD types with the names of `std::simd`. The 4-lane types are passed like
+/// `__m128` / `__m128i`, the 8-lane types like matrices, as a pointer to `[f32; 8]` / `[i32; 8]`.
+/// A mask has an `i32` per lane that is either 0 or -1.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StaticSimdLiteral {
+    F32x4,
+    F32x8,
+    I32x4,
+    I32x8,
+    Mask32x4,
+    Mask32x8,
+}
+
+impl StaticSimdLiteral {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32x4" => Some(StaticSimdLiteral::F32x4),
+            "f32x8" => Some(StaticSimdLiteral::F32x8),
+            "i32x4" => Some(StaticSimdLiteral::I32x4),
+            "i32x8" => Some(StaticSimdLiteral::I32x8),
+            "mask32x4" => Some(StaticSimdLiteral::Mask32x4),
+            "mask32x8" => Some(StaticSimdLiteral::Mask32x8),
+            _ => None,
+        }
+    }
+
+    pub fn lanes(&self) -> usize {
+        if self.is_wide() { 8 } else { 4 }
+    }
+
+    /// Whether the vector needs two SSE registers or one AVX register
+    pub fn is_wide(&self) -> bool {
+        matches!(*self, StaticSimdLiteral::F32x8 | StaticSimdLiteral::I32x8 | StaticSimdLiteral::Mask32x8)
+    }
+
+    pub fn size(&self) -> i32 {
+        self.lanes() as i32 * 4
+    }
+
+    /// The type of a lane, `bool` for masks
+    pub fn element(&self) -> Ret {
+        match *self {
+            StaticSimdLiteral::F32x4 | StaticSimdLiteral::F32x8 => Ret::Float(StaticFloatLiteral::F32),
+            StaticSimdLiteral::I32x4 | StaticSimdLiteral::I32x8 => Ret::Int(StaticIntLiteral::I32),
+            StaticSimdLiteral::Mask32x4 | StaticSimdLiteral::Mask32x8 => Ret::Bool,
+        }
+    }
+
+    pub fn is_mask(&self) -> bool {
+        self.element() == Ret::Bool
+    }
+
+    /// The result of comparing two vectors of this type
+    pub fn mask(&self) -> Self {
+        if self.is_wide() { StaticSimdLiteral::Mask32x8 } else { StaticSimdLiteral::Mask32x4 }
+    }
+}
+
+/// `Vec2` .. `Vec4`, `Quat`, `Mat3`, `Mat4` and the SIMD types
+pub fn math_type_from_name(name: &str) -> Option<Ret> {
+    match name {
+        "Quat" => return Some(Ret::Quat),
+        "Fixed" => return Some(Ret::Fixed),
+        _ => { },
+    }
+    StaticVecLiteral::from_name(name).map(Ret::Vec)
+        .or_else(|| StaticMatLiteral::from_name(name).map(Ret::Mat))
+        .or_else(|| StaticSimdLiteral::from_name(name).map(Ret::Simd))
+}
+
 #[derive(Debug, Hash, Clone, Eq, PartialEq)]
 pub struct Function {
     pub name: FnName,
+    /// The module the function is declared in, paths in the body are resolved relative to it
+    pub module: ModuleId,
     pub arguments: Vec<FnArg>,
     pub statements: Vec<Stmt>,
     pub return_type: Option<Type>,
@@ -134,82 +452,661 @@ impl fmt::Display for FnName {
     }
 }
 
+/// Native functions that scripts can call. A script declares them in an
+/// `extern "C" { fn play_sound(name: &str); }` block, the host registers
+/// their addresses under the same name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HostFunctions {
+    addresses: BTreeMap<String, usize>,
+}
+
+impl HostFunctions {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a native function. It has to use the System V calling convention
+    /// (`extern "sysv64" fn`) and match the signature declared in the script.
+    pub fn insert(&mut self, name: &str, address: *const u8) {
+        self.addresses.insert(name.into(), address as usize);
+    }
+
+    pub fn get(&self, name: &str) -> Option<*const u8> {
+        self.addresses.get(name).map(|address| *address as *const u8)
+    }
+}
+
+/// Instruction set extensions that the generated code may use. The default are the
+/// extensions of the CPU that the compiler runs on, since the code is executed there.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CpuFeatures {
+    /// `roundss` for `floor`, `ceil`, `round` and `trunc`, `pmulld` for `i32x4`
+    pub sse41: bool,
+    /// `vbroadcastss` for products of matrices and vectors, 256-bit registers for `f32x8`
+    pub avx: bool,
+    /// 256-bit registers for `i32x8`
+    pub avx2: bool,
+    /// `popcnt` for `count_ones`
+    pub popcnt: bool,
+    /// `lzcnt` for `leading_zeros`
+    pub lzcnt: bool,
+    /// `tzcnt` for `trailing_zeros`
+    pub bmi1: bool,
+}
+
+impl CpuFeatures {
+    /// Only SSE2, which every x86-64 CPU supports
+    pub fn baseline() -> Self {
+        CpuFeatures { sse41: false, avx: false, avx2: false, popcnt: false, lzcnt: false, bmi1: false }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        CpuFeatures {
+            sse41: is_x86_feature_detected!("sse4.1"),
+            avx: is_x86_feature_detected!("avx"),
+            avx2: is_x86_feature_detected!("avx2"),
+            popcnt: is_x86_feature_detected!("popcnt"),
+            lzcnt: is_x86_feature_detected!("lzcnt"),
+            bmi1: is_x86_feature_detected!("bmi1"),
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect() -> Self {
+        Self::baseline()
+    }
+}
+
+impl Default for CpuFeatures {
+    fn default() -> Self {
+        Self::detect()
+    }
+}
+
+/// Whether the script is compiled like `rustc` compiles a debug or a release build
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CompileMode {
+    /// Integer overflow of `+`, `-`, `*`, negation, `abs` and `pow` raises `ScriptError::Overflow`
+    Debug,
+    /// Integer arithmetic wraps around
+    #[default]
+    Release,
+}
+
+/// Settings that apply to a whole script
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompileOptions {
+    pub host_functions: HostFunctions,
+    pub features: CpuFeatures,
+    pub mode: CompileMode,
+    /// The name of the script in the location of a panic, `<script>` if it is empty
+    pub file_name: String,
+    /// Receives the messages of `println!` and the other logging macros, which are
+    /// compiled to nothing without a logger (their arguments are still evaluated)
+    pub logger: Option<SharedLogger>,
+}
+
+/// A native function declared in an `extern` block of the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostFunction {
+    pub name: String,
+    pub address: usize,
+    pub signature: FnSignature,
+}
+
+/// String and byte string literals. Identical literals share their storage,
+/// the data is placed after the code of all functions.
+#[derive(Debug, Default)]
+pub struct ReadOnlyData {
+    pub bytes: Vec<u8>,
+    offsets: BTreeMap<Vec<u8>, usize>,
+}
+
+impl ReadOnlyData {
+    /// Appends zeroed, 8-byte aligned bytes that are never shared with a literal. The runtime
+    /// writes to them, which is possible since the JIT memory is writable.
+    pub fn reserve(&mut self, len: usize) -> usize {
+        while !self.bytes.len().is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        let offset = self.bytes.len();
+        self.bytes.resize(offset + len, 0);
+        offset
+    }
+
+    /// Returns the offset of the data, adding it if it isn't present yet
+    pub fn intern(&mut self, data: &[u8]) -> usize {
+        if let Some(offset) = self.offsets.get(data) {
+            return *offset;
+        }
+        let offset = self.bytes.len();
+        self.bytes.extend_from_slice(data);
+        self.offsets.insert(data.to_vec(), offset);
+        offset
+    }
+}
+
+/// Everything that is known about a script after all items were collected
+#[derive(Debug, Default)]
+pub struct Program {
+    pub modules: ModuleTree,
+    pub functions: FnMap,
+    pub signatures: BTreeMap<GlobalLabel, FnSignature>,
+    pub host_functions: Vec<HostFunction>,
+    /// Indexed like `Def::Static`
+    pub statics: Vec<Static>,
+    pub features: CpuFeatures,
+    pub mode: CompileMode,
+    pub file_name: String,
+    /// Where a trapping site jumps to, see `FnCompiler::trap_if`
+    pub trap_handler: GlobalLabel,
+    /// Offset of the state of the trap handler in the read-only data
+    pub trap_state: usize,
+    /// Offset of the pointer to the `LogContext` in the read-only data, if there is a logger
+    pub log_context: Option<usize>,
+    /// The operator traits that are implemented for structs
+    pub operators: Vec<Operator>,
+}
+
+/// A `static` or `static mut`, which lives in the read-only data. The JIT memory is writable,
+/// so assignments to a `static mut` persist until the `JitMemory` is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Static {
+    pub ty: Ret,
+    /// Offset of the value in the read-only data
+    pub offset: usize,
+    pub mutable: bool,
+}
+
+/// Declarations whose types can only be resolved once all items are collected
+#[derive(Debug, Default)]
+struct Declarations {
+    entry_fn: Option<GlobalLabel>,
+    /// The exported name of every function with `#[export]`
+    exports: Vec<(String, GlobalLabel)>,
+    /// Indexed by `StructId`
+    structs: Vec<ItemStruct>,
+    /// Indexed like `Program::host_functions`
+    host_functions: Vec<(ForeignItemFn, ModuleId, usize)>,
+    /// Indexed like `Program::statics`
+    statics: Vec<(ItemStatic, ModuleId)>,
+    operators: Vec<OperatorDecl>,
+}
+
+/// A closure, compiled as a separate function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub label: GlobalLabel,
+    pub ty: Ret,
+    pub code: MachineCode,
+}
+
+/// State that is shared by all functions of a script while they are compiled
+#[derive(Debug, Default)]
+pub struct SharedData {
+    pub rodata: ReadOnlyData,
+    pub types: TypeTable,
+    pub closures: Vec<Closure>,
+    /// Index into `closures`, keyed by the address of the closure expression
+    /// (each function is compiled twice, but every closure only once)
+    pub closure_index: BTreeMap<usize, usize>,
+    /// The parsed arguments of a macro, keyed by the address of the macro. They are only parsed
+    /// once, so that the literals among them have the same address in both passes.
+    pub macro_arguments: BTreeMap<usize, Rc<Vec<Expr>>>,
+}
+
 pub fn compile(ast: File)
 -> Result<AssemblyBuf, AssembleError>
 {
-    let mut entry_fn: Option<GlobalLabel> = None;
-    let mut module_functions = BTreeMap::<GlobalLabel, Function>::new();
+    compile_with_options(ast, &CompileOptions::default())
+}
 
-    {
-        let mut module_functions_set = HashSet::<FnName>::new();
-
-        for item in ast.items {
-            match item {
-                Fn(f) => {
-                    let fn_name = FnName(f.ident.clone());
-                    let fn_label = GlobalLabel(GLOBAL_LABEL_ID.load(Ordering::SeqCst));
-                    if module_functions_set.contains(&fn_name) {
-                        return Err(AssembleError::FunctionDeclaredMultipleTimes(fn_name.to_string()));
-                    } else {
-                        module_functions_set.insert(fn_name.clone());
-                    }
+pub fn compile_with_options(ast: File, options: &CompileOptions)
+-> Result<AssemblyBuf, AssembleError>
+{
+    let mut declarations = Declarations::default();
+    let mut shared = SharedData::default();
+    let trap_state = shared.rodata.reserve(TRAP_ARGUMENTS + MAX_TRAP_ARGUMENTS * ARGUMENT_SIZE as usize);
+    let log_context = options.logger.as_ref().map(|_| shared.rodata.reserve(8));
+    let file_name = if options.file_name.is_empty() { "<script>" } else { &options.file_name };
+    let mut program = Program {
+        features: options.features,
+        mode: options.mode,
+        file_name: file_name.to_string(),
+        trap_handler: new_global_label(),
+        trap_state,
+        log_context,
+        ..Program::default()
+    };
 
-                    let return_type: Option<Type> = match f.decl.output {
-                        ReturnType::Default => None,
-                        ReturnType::Type(_, ref t) => Some((*(*t)).clone())
-                    };
+    collect_items(&ast.items, ROOT_MODULE, options, &mut program, &mut shared.types, &mut declarations)?;
+    program.modules.resolve_imports()?;
+    shared.types.layout_structs(&program.modules, &declarations.structs)?;
 
-                    let statements = f.block.stmts.clone();
-                    let arguments = f.decl.inputs.iter().cloned().collect();
+    if declarations.entry_fn.is_none() && declarations.exports.is_empty() {
+        return Err(AssembleError::NoEntryFunction);
+    }
 
-                    let result_fn = Function {
-                        name: fn_name,
-                        arguments: arguments,
-                        statements: statements,
-                        return_type: return_type,
-                        memory_location: None,
-                    };
-                    module_functions.insert(fn_label, result_fn);
-                    if is_start_label(&f) {
-                        if entry_fn.is_some() {
-                            return Err(AssembleError::MultipleEntryPoints);
-                        } else {
-                            entry_fn = Some(fn_label);
-                        }
-                    }
-                    GLOBAL_LABEL_ID.fetch_add(1, Ordering::SeqCst);
-                },
-                _ => { }
-            }
+    for (label, mod_fn) in program.functions.iter() {
+        let signature = FnSignature::new(&program.modules, &mut shared.types, mod_fn.module,
+                                         &mod_fn.arguments, mod_fn.return_type.as_ref())?;
+        program.signatures.insert(*label, signature);
+    }
+
+    for operator in declarations.operators.drain(..) {
+        let ty = shared.types.resolve_value(&program.modules, operator.module, &operator.self_ty)?;
+        let name = || format!("impl {} for {}", operator.trait_name, operator.self_ty.clone().into_token_stream());
+        if !matches!(ty, Ret::Struct(_)) {
+            return Err(AssembleError::InvalidImpl(name()));
+        }
+        // `impl Mul<f32> for Angle` and `impl Mul for Angle` differ in the type of the right side
+        let right = program.signatures[&operator.label].arguments.get(1).cloned();
+        if program.operators.iter().any(|o| {
+            o.trait_name == operator.trait_name && o.ty == ty && program.signatures[&o.label].arguments.get(1) == right.as_ref()
+        }) {
+            return Err(AssembleError::ItemDeclaredMultipleTimes(name()));
         }
+        program.operators.push(Operator { trait_name: operator.trait_name, ty, label: operator.label });
     }
 
-    let entry_function = entry_fn.ok_or(AssembleError::NoEntryFunction)?;
+    for (s, module) in declarations.statics.drain(..) {
+        let ty = shared.types.resolve_value(&program.modules, module, &s.ty)?;
+        let value = static_value(&s, &s.expr, ty, &shared.types)?;
+        let offset = shared.rodata.reserve(value.len());
+        shared.rodata.bytes[offset..offset + value.len()].copy_from_slice(&value);
+        program.statics.push(Static { ty, offset, mutable: s.mutability.is_some() });
+    }
+
+    for (f, module, address) in declarations.host_functions.drain(..) {
+        let return_type = match f.decl.output {
+            ReturnType::Default => None,
+            ReturnType::Type(_, ref t) => Some(&**t),
+        };
+        let signature = FnSignature::new(&program.modules, &mut shared.types, module, &f.decl.inputs, return_type)?;
+        program.host_functions.push(HostFunction { name: f.ident.to_string(), address, signature });
+    }
 
     let mut fn_offset_map = FnOffsetMap::new();
 
-    for (label, mod_fn) in module_functions.iter() {
+    for (label, mod_fn) in program.functions.iter() {
         fn_offset_map.insert(*label, FnLocation::UnresolvedFnName(mod_fn.name.clone()));
     }
 
-    let assembly = assemble_function(entry_function, &mut module_functions, &mut fn_offset_map)?;
+    // the entry function has to be at offset 0, since that is where `JitMemory::run` jumps to
+    let mut link_order = declarations.entry_fn.into_iter().collect::<Vec<_>>();
+    link_order.extend(program.functions.keys().cloned().filter(|label| Some(*label) != declarations.entry_fn));
+
+    let mut instructions = Vec::<u8>::new();
+    let mut relocations = Vec::<CallRelocation>::new();
+    let mut data_relocations = Vec::<DataRelocation>::new();
+    let mut traps = Vec::<Trap>::new();
+    let mut log_messages = Vec::<LogMessage>::new();
+    let trap_entry = new_global_label();
+    let export_entries = declarations.exports.iter().map(|_| new_global_label()).collect::<Vec<_>>();
+
+    {
+        let mut link = |label: GlobalLabel, assembly: MachineCode| {
+            let offset = instructions.len();
+            fn_offset_map.insert(label, FnLocation::MemoryOffset(AssemblyOffset(offset)));
+            relocations.extend(assembly.calls.into_iter().map(|c| CallRelocation { position: c.position + offset, target: c.target }));
+            data_relocations.extend(assembly.data.into_iter().map(|mut d| { d.position += offset; d }));
+            let mut code = assembly.code;
+            for trap in assembly.traps {
+                code[trap.position..trap.position + 4].copy_from_slice(&(traps.len() as u32).to_le_bytes());
+                traps.push(trap.trap);
+            }
+            for message in assembly.messages {
+                code[message.position..message.position + 4].copy_from_slice(&(log_messages.len() as u32).to_le_bytes());
+                log_messages.push(message.message);
+            }
+            instructions.extend(code);
+        };
+
+        for label in link_order {
+            let assembly = assemble_function(label, &program, &mut shared)?;
+            link(label, assembly);
+        }
+
+        // closures are compiled while compiling the functions that contain them
+        for closure in shared.closures.drain(..) {
+            link(closure.label, closure.code);
+        }
+
+        if let Some(entry_function) = declarations.entry_fn {
+            link(trap_entry, assemble_trap_entry(entry_function, trap_state, 0));
+        }
+        for (&(_, label), entry) in declarations.exports.iter().zip(&export_entries) {
+            let stack_size = program.signatures[&label].stack_size(&shared.types);
+            link(*entry, assemble_trap_entry(label, trap_state, stack_size));
+        }
+        link(program.trap_handler, assemble_trap_handler(trap_state));
+    }
+
+    // read-only data follows the code, 16-byte aligned
+    while !instructions.len().is_multiple_of(16) {
+        instructions.push(0xCC);
+    }
+    let rodata_start = instructions.len();
+    instructions.extend_from_slice(&shared.rodata.bytes);
+
+    for relocation in data_relocations {
+        let rel = (rodata_start + relocation.offset) as i64 - (relocation.position as i64 + 4);
+        instructions[relocation.position..relocation.position + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    for relocation in relocations {
+        // every function of the module is linked, so every call target has an offset
+        let target = match fn_offset_map.get(&relocation.target) {
+            Some(FnLocation::MemoryOffset(AssemblyOffset(o))) => *o,
+            _ => unreachable!("call to unlinked function {}", relocation.target),
+        };
+        let rel = target as i64 - (relocation.position as i64 + 4);
+        instructions[relocation.position..relocation.position + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    for (label, location) in fn_offset_map.iter() {
+        if let (Some(f), FnLocation::MemoryOffset(offset)) = (program.functions.get_mut(label), location) {
+            f.memory_location = Some(*offset);
+        }
+    }
+
+    let offset = |label: &GlobalLabel| match fn_offset_map.get(label) {
+        Some(FnLocation::MemoryOffset(AssemblyOffset(o))) => *o,
+        _ => unreachable!("function {} is not linked", label),
+    };
+    let trap_entry = declarations.entry_fn.map(|_| offset(&trap_entry));
+    let mut exports = declarations.exports.iter().zip(&export_entries).map(|(&(ref name, label), entry)| Export {
+        name: name.clone(),
+        offset: offset(&label),
+        trap_entry: offset(entry),
+        signature: program.signatures[&label].clone(),
+    }).collect::<Vec<_>>();
+    exports.sort_by(|a, b| a.name.cmp(&b.name));
 
     Ok(AssemblyBuf {
-        instructions: assembly,
+        instructions,
+        trap_entry,
+        exports,
+        trap_state: rodata_start + trap_state,
+        traps,
+        logger: options.logger.clone(),
+        log_context: log_context.map_or(0, |offset| rodata_start + offset),
+        log_messages,
     })
 }
 
-fn has_first_segment(path: &Path, expected: &'static str) -> bool {
-    path.segments.first().and_then(|segment|
-        if segment.value().ident == expected {
-            Some(())
-        } else {
-            None
-        }).is_some()
+/// Registers that the host expects to be preserved, saved and restored by the trap entry
+const CALLEE_SAVED: [Reg;6] = [Reg::Rbp, Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// Calls the entry function or an exported function, after saving the registers the host
+/// expects to be preserved and the stack pointer the trap handler returns to. The previous stack
+/// pointer is kept on the stack, so that a host function can run the script again. The argument
+/// registers are passed on, the `stack_size` bytes of arguments on the stack are copied.
+fn assemble_trap_entry(function: GlobalLabel, trap_state: usize, stack_size: i32) -> MachineCode {
+    let mut asm = Assembler::new();
+    for reg in CALLEE_SAVED.iter() {
+        asm.push(*reg);
+    }
+    asm.lea_rodata(Reg::R10, trap_state);
+    asm.load(Reg::R11, Reg::R10, 0, 8, false);
+    // 8 pushes including the return address keep the stack 16-byte aligned for the call
+    asm.push(Reg::R11);
+    asm.store(Reg::R10, 0, Reg::Rsp, 8);
+    // the arguments of the caller are above the pushed registers and the return address
+    let copy_size = (stack_size + 15) / 16 * 16;
+    if copy_size > 0 {
+        asm.alu_ri(AluOp::Sub, Reg::Rsp, copy_size);
+        for offset in (0..stack_size).step_by(8) {
+            asm.load(Reg::R11, Reg::Rsp, copy_size + 64 + offset, 8, false);
+            asm.store(Reg::Rsp, offset, Reg::R11, 8);
+        }
+    }
+    asm.call_fn(function);
+    if copy_size > 0 {
+        asm.alu_ri(AluOp::Add, Reg::Rsp, copy_size);
+    }
+    // rax, rdx and xmm0 hold the result
+    asm.lea_rodata(Reg::Rcx, trap_state);
+    restore_trap_entry(&mut asm);
+    asm.finish()
+}
+
+/// Continues after the call in the trap entry, with the number of the site in edi. If the
+/// script was started with `JitMemory::run` instead, there is nothing to return to.
+fn assemble_trap_handler(trap_state: usize) -> MachineCode {
+    let mut asm = Assembler::new();
+    let entered = asm.new_label();
+    asm.lea_rodata(Reg::Rcx, trap_state);
+    asm.load(Reg::R11, Reg::Rcx, 0, 8, false);
+    asm.test_rr(Reg::R11, Reg::R11);
+    asm.jcc(Cond::NotEqual, entered);
+    asm.ud2();
+    asm.bind(entered);
+    // 0 means that no site trapped
+    asm.alu_ri(AluOp::Add, Reg::Rdi, 1);
+    asm.store(Reg::Rcx, 8, Reg::Rdi, 8);
+    asm.mov_rr(Reg::Rsp, Reg::R11);
+    // the result of a trapped call is zero
+    asm.alu_rr(AluOp::Xor, Reg::Rax, Reg::Rax);
+    asm.alu_rr(AluOp::Xor, Reg::Rdx, Reg::Rdx);
+    asm.xorps(Xmm::Xmm0, Xmm::Xmm0);
+    restore_trap_entry(&mut asm);
+    asm.finish()
 }
 
-fn get_first_segment(path: &Path) -> Option<&Ident> {
-    path.segments.first().and_then(|segment| Some(&segment.value().ident))
+/// Pops what the trap entry pushed and returns to the host, rcx points to the trap state
+fn restore_trap_entry(asm: &mut Assembler) {
+    asm.pop(Reg::R11);
+    asm.store(Reg::Rcx, 0, Reg::R11, 8);
+    for reg in CALLEE_SAVED.iter().rev() {
+        asm.pop(*reg);
+    }
+    asm.ret();
+}
+
+/// Walks the items of a module (recursively for inline `mod` blocks) and
+/// registers functions, modules and `use` declarations in the module tree
+fn collect_items(items: &[Item], module: ModuleId, options: &CompileOptions, program: &mut Program,
+                 types: &mut TypeTable, declarations: &mut Declarations)
+-> Result<(), AssembleError>
+{
+    for item in items {
+        match *item {
+            Item::Fn(ref f) => {
+                let fn_name = FnName(f.ident.clone());
+                let fn_label = new_global_label();
+                let vis = program.modules.visibility(module, &f.vis)?;
+                if program.modules.define(module, &f.ident.to_string(), Def::Fn(fn_label), vis).is_none() {
+                    return Err(AssembleError::FunctionDeclaredMultipleTimes(fn_name.to_string()));
+                }
+
+                let return_type: Option<Type> = match f.decl.output {
+                    ReturnType::Default => None,
+                    ReturnType::Type(_, ref t) => Some((*(*t)).clone())
+                };
+
+                let statements = f.block.stmts.clone();
+                let arguments = f.decl.inputs.iter().cloned().collect();
+
+                let result_fn = Function {
+                    name: fn_name,
+                    module,
+                    arguments,
+                    statements,
+                    return_type,
+                    memory_location: None,
+                };
+                program.functions.insert(fn_label, result_fn);
+                if let Some(name) = export_name(f)? {
+                    if declarations.exports.iter().any(|e| e.0 == name) {
+                        return Err(AssembleError::DuplicateExport(name));
+                    }
+                    declarations.exports.push((name, fn_label));
+                }
+                if is_start_label(f) {
+                    if declarations.entry_fn.is_some() {
+                        return Err(AssembleError::MultipleEntryPoints);
+                    } else {
+                        declarations.entry_fn = Some(fn_label);
+                    }
+                }
+            },
+            Item::Mod(ref m) => {
+                let name = m.ident.to_string();
+                let content = match m.content {
+                    Some((_, ref content)) => content,
+                    None => return Err(AssembleError::ModuleNotInline(name)),
+                };
+                let vis = program.modules.visibility(module, &m.vis)?;
+                let child = program.modules.add_module(module, &name, vis)
+                    .ok_or_else(|| AssembleError::ItemDeclaredMultipleTimes(name.clone()))?;
+                collect_items(content, child, options, program, types, declarations)?;
+            },
+            // the operator traits don't have to be imported
+            Item::Use(ref u) if operators::is_ops_import(&u.tree) => { },
+            Item::Use(ref u) => {
+                if u.leading_colon.is_some() {
+                    return Err(AssembleError::UnresolvedPath("::".into()));
+                }
+                let vis = program.modules.visibility(module, &u.vis)?;
+                program.modules.add_use(module, &u.tree, vis);
+            },
+            Item::ForeignMod(ref m) => {
+                for foreign_item in &m.items {
+                    let f = match *foreign_item {
+                        ForeignItem::Fn(ref f) if f.decl.variadic.is_none() => f,
+                        _ => return Err(AssembleError::UnsupportedHostItem(foreign_item.into_token_stream().to_string())),
+                    };
+                    let name = f.ident.to_string();
+                    let address = options.host_functions.get(&name)
+                        .ok_or_else(|| AssembleError::UnknownHostFunction(name.clone()))?;
+                    let vis = program.modules.visibility(module, &f.vis)?;
+                    let def = Def::HostFn(declarations.host_functions.len());
+                    if program.modules.define(module, &name, def, vis).is_none() {
+                        return Err(AssembleError::FunctionDeclaredMultipleTimes(format!("fn {}", name)));
+                    }
+                    // the signature may name structs, it is resolved after all imports are
+                    declarations.host_functions.push((f.clone(), module, address as usize));
+                }
+            },
+            Item::Struct(ref s) => {
+                let name = s.ident.to_string();
+                let id = types.declare_struct(s, module)?;
+                let vis = program.modules.visibility(module, &s.vis)?;
+                if program.modules.define(module, &name, Def::Struct(id), vis).is_none() {
+                    return Err(AssembleError::ItemDeclaredMultipleTimes(name));
+                }
+                // tuple and unit structs can also be used as values: `Money(5)`, `Marker`
+                if let Fields::Unnamed(_) | Fields::Unit = s.fields {
+                    if program.modules.define(module, &name, Def::Ctor(id), vis).is_none() {
+                        return Err(AssembleError::ItemDeclaredMultipleTimes(name));
+                    }
+                }
+                declarations.structs.push(s.clone());
+            },
+            Item::Static(ref s) => {
+                let name = s.ident.to_string();
+                let vis = program.modules.visibility(module, &s.vis)?;
+                if program.modules.define(module, &name, Def::Static(declarations.statics.len()), vis).is_none() {
+                    return Err(AssembleError::ItemDeclaredMultipleTimes(name));
+                }
+                declarations.statics.push((s.clone(), module));
+            },
+            Item::Impl(ref i) => {
+                let (function, operator) = operators::operator_impl(i, module)?;
+                program.functions.insert(operator.label, function);
+                declarations.operators.push(operator);
+            },
+            Item::ExternCrate(ref e) => {
+                return Err(AssembleError::ExternCrateForbidden(e.ident.to_string()));
+            },
+            _ => { }
+        }
+    }
+
+    Ok(())
+}
+
+/// The bytes of the initial value `value` of a static. Like constants, they are evaluated at compile
+/// time, only (negated) literals of numbers, `bool` and `char` and arrays of them are supported.
+fn static_value(s: &ItemStatic, value: &Expr, ty: Ret, types: &TypeTable) -> Result<Vec<u8>, AssembleError> {
+    let name = format!("static {}", s.ident);
+    let unsupported = || AssembleFunctionError::UnsupportedExpression(value.clone().into_token_stream().to_string()).into();
+    // `[1, 2, 3]` and `[0; 16]`, the elements of an array follow each other
+    if let Some((elem, len)) = if let Ret::Struct(id) = ty { types.array(id) } else { None } {
+        return match *value {
+            Expr::Array(ref a) if a.elems.len() == len => {
+                let mut bytes = Vec::new();
+                for e in a.elems.iter() {
+                    bytes.extend(static_value(s, e, elem, types)?);
+                }
+                Ok(bytes)
+            },
+            Expr::Repeat(ref r) => match *r.len {
+                Expr::Lit(ExprLit { lit: Lit::Int(ref n), .. }) if n.value() as usize == len => {
+                    Ok(static_value(s, &r.expr, elem, types)?.repeat(len))
+                },
+                _ => Err(unsupported()),
+            },
+            _ => Err(unsupported()),
+        };
+    }
+    let (expr, negative) = match *value {
+        Expr::Unary(ref u) if matches!(u.op, UnOp::Neg(_)) => (&*u.expr, true),
+        ref e => (e, false),
+    };
+    let mismatch = |found| AssembleFunctionError::TypeMismatch { function: name.clone(), expected: ty, found }.into();
+    let out_of_range = || AssembleFunctionError::LiteralOutOfRange {
+        function: name.clone(),
+        literal: value.clone().into_token_stream().to_string(),
+        ty,
+    }.into();
+
+    let lit = match *expr {
+        Expr::Lit(ExprLit { ref lit, .. }) => lit,
+        _ => return Err(unsupported()),
+    };
+    match (lit, ty) {
+        (Lit::Int(i), Ret::Int(int)) => {
+            if let Some(suffix) = StaticIntLiteral::from_suffix(i.suffix()).filter(|suffix| *suffix != int) {
+                return Err(mismatch(Ret::Int(suffix)));
+            }
+            try_match_u64_value(i.value(), negative, &int).map_err(|_| out_of_range())?;
+            // the 128-bit types are sign-extended
+            let value = u128::from(i.value());
+            let bits = if negative { value.wrapping_neg() } else { value };
+            Ok(bits.to_le_bytes()[..int.size() as usize].to_vec())
+        },
+        (Lit::Float(f), Ret::Float(float)) => {
+            match (f.suffix(), float) {
+                (FloatSuffix::F32, StaticFloatLiteral::F64) => return Err(mismatch(Ret::Float(StaticFloatLiteral::F32))),
+                (FloatSuffix::F64, StaticFloatLiteral::F32) => return Err(mismatch(Ret::Float(StaticFloatLiteral::F64))),
+                _ => { },
+            }
+            let value = if negative { -f.value() } else { f.value() };
+            if float.is_double() {
+                Ok(value.to_bits().to_le_bytes().to_vec())
+            } else if value.is_finite() && (value as f32).is_infinite() {
+                Err(out_of_range())
+            } else {
+                Ok((value as f32).to_bits().to_le_bytes().to_vec())
+            }
+        },
+        (Lit::Bool(b), Ret::Bool) if !negative => Ok(vec![b.value as u8]),
+        (Lit::Char(c), Ret::Char) if !negative => Ok(u32::from(c.value()).to_le_bytes().to_vec()),
+        (Lit::Byte(b), Ret::Int(StaticIntLiteral::U8)) if !negative => Ok(vec![b.value()]),
+        _ => Err(unsupported()),
+    }
+}
+
+pub fn has_first_segment(path: &Path, expected: &'static str) -> bool {
+    path.segments.first().map(|segment| segment.value().ident == expected).unwrap_or(false)
+}
+
+pub fn get_first_segment(path: &Path) -> Option<&Ident> {
+    path.segments.first().map(|segment| &segment.value().ident)
 }
 
 fn is_start_label(f: &ItemFn) -> bool {
@@ -218,6 +1115,25 @@ fn is_start_label(f: &ItemFn) -> bool {
         has_first_segment(&attr.path, "start"))
 }
 
+/// The name of a function with `#[export]`, or the one given by `#[export(name = "on_tick")]`
+fn export_name(f: &ItemFn) -> Result<Option<String>, AssembleError> {
+    let attr = match f.attrs.iter().find(|attr| attr.path.leading_colon.is_none() && has_first_segment(&attr.path, "export")) {
+        Some(attr) => attr,
+        None => return Ok(None),
+    };
+    let name = match attr.interpret_meta() {
+        Some(Meta::Word(_)) => Some(f.ident.to_string()),
+        Some(Meta::List(ref list)) if list.nested.len() == 1 => match list.nested[0] {
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue { ref ident, lit: Lit::Str(ref name), .. })) if ident == "name" => {
+                Some(name.value()).filter(|name| !name.is_empty())
+            },
+            _ => None,
+        },
+        _ => None,
+    };
+    name.map(Some).ok_or_else(|| AssembleError::InvalidAttribute(attr.clone().into_token_stream().to_string()))
+}
+
 #[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct AssemblyOffset(pub usize);
 
@@ -231,9 +1147,84 @@ pub enum FnLocation {
 pub enum AssembleError {
     /// Mismatch between the body of the function and the return type
     FunctionError(AssembleFunctionError),
+    /// There is neither a `#[start]` function nor an `#[export]`ed one
     NoEntryFunction,
     MultipleEntryPoints,
+    /// Two functions are exported under the same name
+    DuplicateExport(String),
+    /// An attribute with arguments that the compiler doesn't understand, i.e. `#[export(nmae = "..")]`
+    InvalidAttribute(String),
     FunctionDeclaredMultipleTimes(String),
+    /// A module, import or other non-function item uses a name that is already taken
+    ItemDeclaredMultipleTimes(String),
+    /// A path (in a `use` declaration, a call, a type, ...) does not lead to any item
+    UnresolvedPath(String),
+    /// A path names an item that is not visible from the module the path is used in
+    PrivateItem(String),
+    /// A name that more than one glob import brings into a module, each with a different item
+    AmbiguousName(String),
+    /// `mod foo;` - modules have to be declared inline as `mod foo { ... }`
+    ModuleNotInline(String),
+    /// `extern crate` is forbidden in scripts
+    ExternCrateForbidden(String),
+    /// A function declared in an `extern` block was not registered in `HostFunctions`
+    UnknownHostFunction(String),
+    /// Only (non-variadic) functions can be declared in `extern` blocks
+    UnsupportedHostItem(String),
+    /// A struct contains itself (directly or through other structs) by value
+    RecursiveType(String),
+    /// `#[derive(Clone)]` or `#[derive(Copy)]` on a struct with a field that is not `Clone` or `Copy`,
+    /// or `Copy` without `Clone`
+    InvalidDerive(String),
+    /// An impl of an operator trait that is generic, not for a struct, or whose method has other
+    /// parameters than the trait, i.e. `fn add(&self, rhs: Money)`
+    InvalidImpl(String),
+    /// An inherent impl or an impl of a trait that isn't an operator, i.e. `impl AddAssign for Money`
+    UnsupportedImpl(String),
+    /// `index` returns something else than a reference to a place, i.e. `let r = &self.a; r`
+    UnsupportedIndexResult(String),
+}
+
+/// Where in the script an error happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub function: String,
+    /// The tokens of the expression that raised the error
+    pub expression: String,
+    /// Where the expression starts in the script, the line and column count from 1
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` in `{}` at {}:{}", self.expression, self.function, self.line, self.column)
+    }
+}
+
+/// An error that stopped a script while it was running, returned by `JitMemory::call`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    /// Integer arithmetic overflowed in `CompileMode::Debug`, or the smallest value of a signed
+    /// integer was divided by -1 (in any mode)
+    Overflow(SourceLocation),
+    /// An integer was divided by zero, or the remainder of a division by zero was taken
+    DivideByZero(SourceLocation),
+    /// A slice was indexed with an index that is not smaller than its length
+    IndexOutOfBounds(SourceLocation),
+    /// `panic!`, `unreachable!` or a failed assertion
+    Panic { message: String, file: String, line: u32 },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScriptError::Overflow(ref location) => write!(f, "attempt to compute {} with overflow", location),
+            ScriptError::DivideByZero(ref location) => write!(f, "attempt to divide by zero: {}", location),
+            ScriptError::IndexOutOfBounds(ref location) => write!(f, "index out of bounds: {}", location),
+            ScriptError::Panic { ref message, ref file, line } => write!(f, "panicked at {}:{}:\n{}", file, line, message),
+        }
+    }
 }
 
 impl From<AssembleFunctionError> for AssembleError {
@@ -246,6 +1237,73 @@ impl From<AssembleFunctionError> for AssembleError {
 pub enum AssembleFunctionError {
     ReturnTypeMismatch(String),
     GetReturnTypeError(GetReturnTypeInnerError),
+    /// An expression has a different type than the surrounding code requires
+    TypeMismatch { function: String, expected: Ret, found: Ret },
+    /// A type that the compiler does not know about (yet)
+    UnsupportedType(String),
+    /// A syntax construct that the compiler does not know about (yet)
+    UnsupportedExpression(String),
+    /// The path in a call expression does not refer to a function
+    NotAFunction(String),
+    /// A function was called with the wrong number of arguments
+    WrongArgumentCount { function: String, expected: usize, found: usize },
+    /// An `as` conversion that Rust does not allow, i.e. `u32 as char` or `u8 as bool`
+    InvalidCast { function: String, from: Ret, to: Ret },
+    /// A literal does not fit into the type that was inferred for it, i.e. `let x: u8 = 256;`
+    LiteralOutOfRange { function: String, literal: String, ty: Ret },
+    /// The path of a struct literal (`Hit { .. }`) does not refer to a struct
+    NotAStruct(String),
+    /// A struct literal or field access names a field that the struct doesn't have
+    UnknownField { function: String, ty: String, field: String },
+    /// A struct literal does not initialize all fields
+    MissingField { function: String, ty: String, field: String },
+    /// Closures can't use the local variables of the surrounding function, only their arguments
+    CapturingClosure { function: String, name: String },
+    /// The argument types of a closure are neither annotated nor known from the context
+    TypeAnnotationNeeded(String),
+    /// A method or associated function that the type doesn't have, i.e. `Vec2::cross`
+    UnknownMethod { function: String, ty: Ret, method: String },
+    /// An operator on a struct that doesn't implement its trait, i.e. `a - b` with only `impl Add`
+    MissingTraitImpl { function: String, ty: Ret, trait_name: String },
+    /// A macro that the compiler does not know about, i.e. `vec!`
+    UnknownMacro { function: String, name: String },
+    /// The arguments of a macro don't match its format string, or can't be formatted
+    InvalidFormat { function: String, message: String },
+    /// A pattern that can't match a value of the type, i.e. `Some(x)` for a `u32`
+    InvalidPattern { function: String, pattern: String, ty: String },
+    /// A pattern of a `let` or a parameter that doesn't match every value, i.e. `let Some(x) = a;`
+    RefutablePattern { function: String, pattern: String },
+    /// The arms of a `match` don't cover every value of the matched expression
+    NonExhaustiveMatch { function: String, expression: String },
+    /// A name that is not bound by every alternative of `a | b`, or to values of different types
+    InconsistentBinding { function: String, pattern: String, name: String },
+    /// `?` on a value that is not an `Option` or `Result`, or in a function that doesn't return
+    /// the same kind of value
+    InvalidTry { function: String, expression: String, return_type: String },
+    /// `?` on a `Result` whose error type isn't the one of the returned `Result`, there are no
+    /// `From` conversions between error types
+    TryErrorMismatch { function: String, expression: String, expected: Ret, found: Ret },
+    /// An assignment to something that can't be changed, i.e. a local or a `static` without `mut`
+    /// or an element of a `&[T]`
+    AssignToImmutable { function: String, place: String },
+    /// A `ref mut` binding or a `&mut` reference of a local that is not `mut`
+    BorrowOfImmutable { function: String, place: String },
+    /// A `ref mut` binding or a `&mut` reference of a value that another binding or reference in
+    /// scope refers to, or a `ref` binding or `&` reference of a value that is borrowed mutably
+    OverlappingBorrow { function: String, name: String },
+    /// A value is used in the statement that passes a `&mut` reference to it, or moved while a
+    /// reference refers to it
+    UseOfBorrowedValue { function: String, expression: String },
+    /// A value that is not `Copy` is used after it was moved, or moved again by every iteration of a loop
+    UseOfMovedValue { function: String, expression: String },
+    /// A value that is not `Copy` is used by value through a reference, i.e. `*r` or `r.field`
+    MoveOutOfReference { function: String, expression: String },
+    /// A reference to a local outlives the block of the local, i.e. `let r = { let x = 1; &x };`
+    DanglingReference { function: String, name: String },
+    /// A `for` loop or an iterator chain over a value that is not a slice
+    NotIterable { function: String, expression: String },
+    /// A `static mut` is read or assigned outside of an `unsafe` block
+    UseOfMutableStatic { function: String, name: String },
 }
 
 impl From<GetReturnTypeInnerError> for AssembleFunctionError {
@@ -254,31 +1312,22 @@ impl From<GetReturnTypeInnerError> for AssembleFunctionError {
     }
 }
 
-fn assemble_function(fn_location: GlobalLabel, fn_map: &mut FnMap, fn_offset_map: &mut FnOffsetMap)
--> Result<Vec<u8>, AssembleFunctionError>
+fn assemble_function(fn_location: GlobalLabel, program: &Program, shared: &mut SharedData)
+-> Result<MachineCode, AssembleError>
 {
-    // what are the offsets of the label into the assembly
-    // (offsetfrom the start of the memory)
-    let entry = fn_map.get(&fn_location)
-        .ok_or(AssembleFunctionError::ReturnTypeMismatch(format!("{}", fn_location)))?;
-
-    let return_type_outer = get_return_type_outer(entry.return_type.as_ref()).unwrap_or_default();
-    let return_type_inner = get_return_type_inner(&entry.statements, return_type_outer)?;
-
-    if return_type_outer != return_type_inner {
-        return Err(AssembleFunctionError::ReturnTypeMismatch(entry.name.to_string()));
-    }
-
-    let mut assembly = match assemble_statements(&entry.statements, return_type_outer, fn_map) {
-        Some(i) => i,
-        None => return Err(AssembleFunctionError::ReturnTypeMismatch(entry.name.to_string())),
+    let entry = program.functions.get(&fn_location)
+        .ok_or_else(|| AssembleFunctionError::ReturnTypeMismatch(format!("{}", fn_location)))?;
+    let signature = &program.signatures[&fn_location];
+
+    let source = FnSource {
+        name: entry.name.to_string(),
+        module: entry.module,
+        arguments: entry.arguments.iter().collect(),
+        body: FnBody::Block(&entry.statements),
+        in_unsafe: false,
     };
-
-    let mut instructions = Vec::with_capacity(6);
-    instructions.extend_from_slice(&FN_PROLOGUE);
-    instructions.append(&mut assembly);
-    instructions.extend_from_slice(&FN_EPILOGUE);
-    Ok(instructions)
+    let (code, _) = compile_function(program, shared, &source, &signature.arguments, Some(signature.return_type), &[])?;
+    Ok(code)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -294,186 +1343,71 @@ impl From<TryMatchError> for GetReturnTypeInnerError {
     }
 }
 
-fn get_return_type_inner(statements: &Vec<Stmt>, expected_type: Ret)
--> Result<Ret, GetReturnTypeInnerError>
-{
-    // this will need a lot of work to work correctly
-
-    // TODO: check for early-return statements
-
-    // check last expression
-    let last_statement = statements.last().ok_or(GetReturnTypeInnerError::EmptyFunction)?;
-
-    match expected_type {
-        Ret::Int(expected) => {
-            let i = match last_statement {
-                Stmt::Expr(Expr::Lit(ExprLit { lit: Lit::Int(i), .. })) => i,
-                _ => return Err(GetReturnTypeInnerError::UnexpectedExpressionType),
-            };
-            match i.suffix() {
-                IntSuffix::None => return Ok(try_match_u64_value(i.value(), &expected)?),
-                IntSuffix::I8 => return Ok(Ret::Int(StaticIntLiteral::I8)),
-                IntSuffix::I16 => return Ok(Ret::Int(StaticIntLiteral::I16)),
-                IntSuffix::I32 => return Ok(Ret::Int(StaticIntLiteral::I32)),
-                IntSuffix::I64 => return Ok(Ret::Int(StaticIntLiteral::I64)),
-                IntSuffix::U8 => return Ok(Ret::Int(StaticIntLiteral::U8)),
-                IntSuffix::U16 => return Ok(Ret::Int(StaticIntLiteral::U16)),
-                IntSuffix::U32 => return Ok(Ret::Int(StaticIntLiteral::U32)),
-                IntSuffix::U64 => return Ok(Ret::Int(StaticIntLiteral::U64)),
-                _ => { },
-            }
-        },
-        _ => { }
-    }
-
-    Err(GetReturnTypeInnerError::UnexpectedExpressionType)
-}
-
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TryMatchError {
     /// warn: value: numeric value {:?} doesn't fit in return value!
     ReturnValueDoesntFit(u64),
 }
 
-fn try_match_u64_value(actual: u64, expected: &StaticIntLiteral) -> Result<Ret, TryMatchError> {
-
-    let minimal_size = determine_minimal_size(actual);
+/// Checks whether the (possibly negated) integer literal fits into the expected type
+pub fn try_match_u64_value(actual: u64, negative: bool, expected: &StaticIntLiteral) -> Result<Ret, TryMatchError> {
     use self::StaticIntLiteral::*;
 
-    match *expected {
-        U64 => return Ok(Ret::Int(*expected)),
-        U32 => {
-            match minimal_size {
-                U32 | U16 | U8 => return Ok(Ret::Int(*expected)),
-                _ => { },
-            }
-        },
-        StaticIntLiteral::U16 => {
-            match minimal_size {
-                U16 | U8 => return Ok(Ret::Int(*expected)),
-                _ => { },
-            }
-        },
-        StaticIntLiteral::U8 => {
-            match minimal_size {
-                U8 => return Ok(Ret::Int(*expected)),
-                _ => { },
-            }
-        },
-        _ => { }
-    }
+    let max_positive = match *expected {
+        U8 => u64::from(u8::MAX),
+        U16 => u64::from(u16::MAX),
+        U32 => u64::from(u32::MAX),
+        // a literal doesn't have more than 64 bits
+        U64 | Usize | U128 | I128 => u64::MAX,
+        I8 => i8::MAX as u64,
+        I16 => i16::MAX as u64,
+        I32 => i32::MAX as u64,
+        I64 | Isize | UnknownSize(_) => i64::MAX as u64,
+    };
 
-    Err(TryMatchError::ReturnValueDoesntFit(actual))
-}
+    let fits = if negative {
+        // the absolute value of MIN is one larger than MAX
+        expected.is_signed() && (actual == 0 || actual - 1 <= max_positive)
+    } else {
+        actual <= max_positive
+    };
 
-fn determine_minimal_size(actual: u64) -> StaticIntLiteral {
-    if actual < 0xff {
-        StaticIntLiteral::U8
-    } else if actual < 0xffff {
-        StaticIntLiteral::U16
-    } else if actual < 0xffffffff {
-        StaticIntLiteral::U32
+    if fits {
+        Ok(Ret::Int(*expected))
     } else {
-        StaticIntLiteral::U64
+        Err(TryMatchError::ReturnValueDoesntFit(actual))
     }
 }
 
-fn get_return_type_outer(return_type: Option<&Type>) -> Option<Ret> {
+pub fn get_return_type_outer(return_type: Option<&Type>) -> Option<Ret> {
     use syn::Type;
 
     let return_type = return_type?;
     match *return_type {
         Type::Path(ref p) => {
-            if p.path.leading_colon.is_some() {
+            if p.path.leading_colon.is_some() || p.path.segments.len() != 1 {
                 return None;
             }
-            match &*get_first_segment(&p.path)?.to_string() {
-                "u8" => Some(Ret::Int(StaticIntLiteral::U8)),
-                "u16" => Some(Ret::Int(StaticIntLiteral::U16)),
-                "u32" => Some(Ret::Int(StaticIntLiteral::U32)),
-                "u64" => Some(Ret::Int(StaticIntLiteral::U64)),
-                _ => None,
+            let name = get_first_segment(&p.path)?.to_string();
+            if let Some(i) = StaticIntLiteral::from_name(&name) {
+                return Some(Ret::Int(i));
+            }
+            match &*name {
+                "f32" => Some(Ret::Float(StaticFloatLiteral::F32)),
+                "f64" => Some(Ret::Float(StaticFloatLiteral::F64)),
+                "char" => Some(Ret::Char),
+                "bool" => Some(Ret::Bool),
+                name => math_type_from_name(name),
             }
         },
+        // &str, slices are resolved by the `TypeTable`
+        Type::Reference(ref r) if r.mutability.is_none() => match *r.elem {
+            Type::Path(ref p) if p.qself.is_none() && p.path.leading_colon.is_none() &&
+                                 p.path.segments.len() == 1 && has_first_segment(&p.path, "str") => Some(Ret::Str),
+            _ => None,
+        },
+        Type::Tuple(ref t) if t.elems.is_empty() => Some(Ret::Void),
+        Type::Paren(ref p) => get_return_type_outer(Some(&p.elem)),
         _ => None
     }
 }
-
-fn assemble_statements(stmts: &Vec<Stmt>, return_type: Ret, fn_map: &FnMap) -> Option<Vec<u8>> {
-    let mut assembly_vec = Vec::<u8>::new();
-
-    for stmt in stmts {
-        if let Stmt::Expr(Expr::Lit(ExprLit { lit: Lit::Int(i), .. })) = stmt {
-
-            let val = i.value();
-            let min_size = determine_minimal_size(val);
-            let mut optimal_return_size = return_type;
-
-            if return_type == Ret::Int(StaticIntLiteral::U64) {
-                if min_size == StaticIntLiteral::U32 ||
-                   min_size == StaticIntLiteral::U16 ||
-                   min_size == StaticIntLiteral::U8 {
-                    optimal_return_size = Ret::Int(StaticIntLiteral::U32);
-                }
-            }
-
-            let asm_instr = optimal_return_size.get_optimal_register_return();
-
-            if let Some(asm_instr) = asm_instr {
-                match asm_instr {
-                    Instruction::OneComponent(a) => {
-                        assembly_vec.push(a);
-                    },
-                    Instruction::TwoComponent((a, b)) => {
-                        assembly_vec.push(a);
-                        assembly_vec.push(b);
-                    },
-                }
-
-                match optimal_return_size {
-                    Ret::Int(i) => {
-                        match i {
-                            StaticIntLiteral::U64 => assembly_vec.extend_from_slice(&transform_u64_to_array_of_u8_le(val)),
-                            StaticIntLiteral::U32 => assembly_vec.extend_from_slice(&transform_u32_to_array_of_u8_le(val as u32)),
-                            StaticIntLiteral::U16 => assembly_vec.extend_from_slice(&transform_u16_to_array_of_u8_le(val as u16)),
-                            StaticIntLiteral::U8 => assembly_vec.push(val as u8),
-                            _ => { },
-                        }
-                    },
-                    _ => { /* do nothing for now*/ }
-                }
-            }
-        }
-    }
-
-    Some(assembly_vec)
-}
-
-fn transform_u32_to_array_of_u8_le(x:u32) -> [u8;4] {
-    let b1 : u8 = ((x >> 24) & 0xff) as u8;
-    let b2 : u8 = ((x >> 16) & 0xff) as u8;
-    let b3 : u8 = ((x >> 8) & 0xff) as u8;
-    let b4 : u8 = (x & 0xff) as u8;
-    [b4, b3, b2, b1]
-}
-
-fn transform_u16_to_array_of_u8_le(x:u16) -> [u8;2] {
-    let b1 : u8 = ((x >> 8) & 0xff) as u8;
-    let b2 : u8 = (x & 0xff) as u8;
-    [b2, b1]
-}
-
-// -5394849584509 => 0x83, 0x2A, 0xE8, 0xE9, 0x17, 0xFB, 0xFF, 0xFF
-
-// 0x7D, 0xD5, 0x17, 0x16, 0xE8, 0x04, 0x00, 0x00
-fn transform_u64_to_array_of_u8_le(x:u64) -> [u8;8] {
-    let b1 : u8 = ((x >> 56) & 0xff) as u8;
-    let b2 : u8 = ((x >> 48) & 0xff) as u8;
-    let b3 : u8 = ((x >> 40) & 0xff) as u8;
-    let b4 : u8 = ((x >> 32) & 0xff) as u8;
-    let b5 : u8 = ((x >> 24) & 0xff) as u8;
-    let b6 : u8 = ((x >> 16) & 0xff) as u8;
-    let b7 : u8 = ((x >> 8) & 0xff) as u8;
-    let b8 : u8 = (x & 0xff) as u8;
-    [b8, b7, b6, b5, b4, b3, b2, b1]
-}
\ No newline at end of file
@@ -0,0 +1,242 @@
+//! `Fixed`, a 32.32 fixed-point number for simulations that have to produce bit-identical
+//! results on every machine, i.e. games with lockstep networking.
+//!
+//! Scripts use the type by its name, the compiler knows it like the vector types. The code it
+//! generates only uses integer instructions, and where it calls a function, it calls the one
+//! of this module, so the host computes the same bits with this type. Results are rounded to
+//! the nearest representable value, ties away from zero. Like the integers of a script,
+//! arithmetic wraps around unless overflow is checked (only scripts check it).
+
+use std::fmt::{self, Debug, Display};
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+
+/// A signed number with 32 integer and 32 fractional bits, laid out like an `i64` of its bits
+#[repr(transparent)]
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i64);
+
+/// The fraction of a full turn and π/2 in the formats used by `sin`
+const TWO_OVER_PI: u64 = 0xa2f9_836e_4e44_152a;
+const HALF_PI: u128 = 0x6487_ed51_10b4_611a;
+
+impl Fixed {
+    pub const FRACTION_BITS: u32 = 32;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << 32);
+    pub const MIN: Fixed = Fixed(i64::MIN);
+    pub const MAX: Fixed = Fixed(i64::MAX);
+    pub const PI: Fixed = Fixed(0x3_243f_6a89);
+
+    pub const fn from_bits(bits: i64) -> Fixed {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    pub const fn from_int(value: i32) -> Fixed {
+        Fixed((value as i64) << 32)
+    }
+
+    /// The nearest value, saturating like `as`. Scripts can only convert literals.
+    pub fn from_num(value: f64) -> Fixed {
+        Fixed((value * 2f64.powi(32)).round() as i64)
+    }
+
+    /// The integer part, rounded towards zero like `as`
+    pub fn to_int(self) -> i32 {
+        let bias = if self.0 < 0 { (1 << 32) - 1 } else { 0 };
+        (self.0.wrapping_add(bias) >> 32) as i32
+    }
+
+    /// The nearest integer, ties away from zero like `f64::round`
+    pub fn round_to_int(self) -> i32 {
+        (self.0.wrapping_add(rounding_bias(self.0.into())) >> 32) as i32
+    }
+
+    /// Only meant for rendering, the simulation should stay in fixed-point
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / 2f32.powi(32)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / 2f64.powi(32)
+    }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.wrapping_abs())
+    }
+
+    pub fn min(self, other: Fixed) -> Fixed {
+        Ord::min(self, other)
+    }
+
+    pub fn max(self, other: Fixed) -> Fixed {
+        Ord::max(self, other)
+    }
+
+    /// The square root, rounded to the nearest value. It is 0 for negative numbers.
+    pub fn sqrt(self) -> Fixed {
+        Fixed(sqrt(self.0))
+    }
+
+    /// The sine of an angle in radians, within one unit in the last place
+    pub fn sin(self) -> Fixed {
+        Fixed(sin(self.0, 0))
+    }
+
+    pub fn cos(self) -> Fixed {
+        Fixed(sin(self.0, 1))
+    }
+}
+
+/// Half of the last bit that is shifted out, minus one for negative values, so that
+/// shifting right rounds ties away from zero
+fn rounding_bias(value: i128) -> i64 {
+    (1 << 31) - (value < 0) as i64
+}
+
+/// The bits of the product, wrapped around to 64 bits
+pub(crate) fn product(a: i64, b: i64) -> i64 {
+    let product = i128::from(a) * i128::from(b);
+    ((product + i128::from(rounding_bias(product))) >> 32) as i64
+}
+
+/// The bits of the quotient before it is wrapped around, so that a script can check for
+/// overflow. The divisor must not be 0.
+pub(crate) fn quotient(a: i64, b: i64) -> i128 {
+    let dividend = i128::from(a) << 32;
+    let divisor = i128::from(b);
+    let (quotient, remainder) = (dividend / divisor, dividend % divisor);
+    if 2 * remainder.abs() < divisor.abs() {
+        quotient
+    } else if (dividend < 0) == (divisor < 0) {
+        quotient + 1
+    } else {
+        quotient - 1
+    }
+}
+
+/// `sqrt(bits / 2^32) * 2^32`, which is the square root of `bits * 2^32`
+pub(crate) fn sqrt(bits: i64) -> i64 {
+    if bits <= 0 {
+        return 0;
+    }
+    let n = (bits as u128) << 32;
+    // digit by digit, two bits of `n` per bit of the root
+    let (mut root, mut remainder) = (0u128, n);
+    let mut bit = 1u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    // n is an integer, so it is above (root + 0.5)^2 exactly if it is above root^2 + root
+    if remainder > root {
+        root += 1;
+    }
+    root as i64
+}
+
+/// The sine of `bits / 2^32` radians shifted by `quarters` quarter turns. The angle is reduced to
+/// a quarter turn, where the Taylor series up to x^17 is precise to 2^-44, computed with 62 fractional bits.
+pub(crate) fn sin(bits: i64, quarters: i64) -> i64 {
+    const ONE: u128 = 1 << 62;
+    // the angle in quarter turns with 96 fractional bits
+    let turns = i128::from(bits) * i128::from(TWO_OVER_PI);
+    let quadrant = ((turns >> 96) as i64).wrapping_add(quarters) & 3;
+    let mut fraction = ((turns >> 34) as u128) & (ONE - 1);
+    if quadrant & 1 == 1 {
+        fraction = ONE - fraction;
+    }
+    let x = (fraction * HALF_PI) >> 62;
+    let square = (x * x) >> 62;
+    // x (1 - x^2 / (2 * 3) (1 - x^2 / (4 * 5) (1 - ..)))
+    let mut series = ONE;
+    for &k in &[16u128, 14, 12, 10, 8, 6, 4, 2] {
+        series = ONE - ((square * series) >> 62) / (k * (k + 1));
+    }
+    let sine = (((x * series) >> 62) + (1 << 29)) >> 30;
+    if quadrant >= 2 { -(sine as i64) } else { sine as i64 }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, rhs: Fixed) -> Fixed {
+        Fixed(product(self.0, rhs.0))
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    /// Panics if `rhs` is zero
+    fn div(self, rhs: Fixed) -> Fixed {
+        Fixed(quotient(self.0, rhs.0) as i64)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Fixed) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Fixed) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, rhs: Fixed) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign for Fixed {
+    fn div_assign(&mut self, rhs: Fixed) {
+        *self = *self / rhs;
+    }
+}
+
+/// Formatted like the nearest `f64`
+impl Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.to_f64(), f)
+    }
+}
+
+impl Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.to_f64(), f)
+    }
+}
@@ -0,0 +1,216 @@
+//! Messages in the style of `format!`, for `panic!`, `println!` and the other macros.
+//!
+//! The format string is parsed when the script is compiled. The values of the arguments
+//! are only known while the script runs: the generated code copies them to a buffer,
+//! 16 bytes per argument (the value, the lanes of a vector, or the pointer and the length
+//! of a `&str`), and the host formats the message from there. Arguments are referred to by position
+//! (`{}`, `{1}`), can be formatted with `Debug` (`{:?}`) and with a precision (`{:.2}`).
+
+use std::fmt::{Debug, Display, Write};
+use std::{char, slice, str};
+use compiler::{Ret, StaticFloatLiteral, StaticIntLiteral};
+use fixed::Fixed;
+
+/// Bytes that every argument takes in the buffer
+pub const ARGUMENT_SIZE: i32 = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(String),
+    Argument { index: usize, debug: bool, precision: Option<usize> },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormatString {
+    pieces: Vec<Piece>,
+    /// The type of every argument, known once the arguments are compiled
+    pub arguments: Vec<Ret>,
+}
+
+impl FormatString {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a format string, `first_argument` is the index of its first argument
+    /// in the buffer. The error describes what is wrong with the string.
+    pub fn parse(template: &str, first_argument: usize) -> Result<Self, String> {
+        let mut format = FormatString::new();
+        let mut next_argument = first_argument;
+        let mut chars = template.chars().peekable();
+        let mut literal = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '}' => return Err(format!("unmatched `}}` in format string {:?}", template)),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("unmatched `{{` in format string {:?}", template)),
+                        }
+                    }
+                    let (position, spec) = match placeholder.find(':') {
+                        Some(colon) => (&placeholder[..colon], &placeholder[colon + 1..]),
+                        None => (&placeholder[..], ""),
+                    };
+                    let index = if position.is_empty() {
+                        next_argument += 1;
+                        next_argument - 1
+                    } else {
+                        let index = position.parse::<usize>()
+                            .map_err(|_| format!("`{{{}}}`: only positional arguments are supported", placeholder))?;
+                        first_argument + index
+                    };
+                    let debug = spec.ends_with('?');
+                    let spec = spec.trim_end_matches('?');
+                    let precision = match spec.strip_prefix('.') {
+                        _ if spec.is_empty() => None,
+                        Some(digits) => Some(digits.parse::<usize>()
+                            .map_err(|_| format!("`{{{}}}`: invalid precision", placeholder))?),
+                        None => return Err(format!("`{{{}}}`: only `?` and a precision are supported", placeholder)),
+                    };
+                    format.push_str(&literal);
+                    literal.clear();
+                    format.push_argument(index, debug, precision);
+                },
+                c => literal.push(c),
+            }
+        }
+        format.push_str(&literal);
+        Ok(format)
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Some(Piece::Literal(ref mut last)) = self.pieces.last_mut() {
+            last.push_str(text);
+            return;
+        }
+        self.pieces.push(Piece::Literal(text.to_string()));
+    }
+
+    pub fn push_argument(&mut self, index: usize, debug: bool, precision: Option<usize>) {
+        self.pieces.push(Piece::Argument { index, debug, precision });
+    }
+
+    pub fn append(&mut self, other: FormatString) {
+        for piece in other.pieces {
+            match piece {
+                Piece::Literal(text) => self.push_str(&text),
+                argument => self.pieces.push(argument),
+            }
+        }
+    }
+
+    /// Whether a piece refers to the argument
+    pub fn uses_argument(&self, index: usize) -> bool {
+        self.pieces.iter().any(|p| match *p {
+            Piece::Argument { index: i, .. } => i == index,
+            _ => false,
+        })
+    }
+
+    /// One more than the largest index that a piece refers to
+    pub fn argument_count(&self) -> usize {
+        self.pieces.iter().filter_map(|p| match *p {
+            Piece::Argument { index, .. } => Some(index + 1),
+            _ => None,
+        }).max().unwrap_or(0)
+    }
+
+    /// Formats the message with the values in `buffer`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer has to hold a value of the type in `arguments` for every argument,
+    /// a `&str` has to be valid.
+    pub unsafe fn format(&self, buffer: *const u64) -> String {
+        let mut out = String::new();
+        self.write_pieces(buffer, |text| out.push_str(text));
+        out
+    }
+
+    /// Passes the literal pieces and the formatted values one by one to `write`.
+    ///
+    /// # Safety
+    ///
+    /// Like `format`.
+    pub unsafe fn write_pieces<F: FnMut(&str)>(&self, buffer: *const u64, mut write: F) {
+        let mut value_text = String::new();
+        for piece in &self.pieces {
+            match *piece {
+                Piece::Literal(ref text) => write(text),
+                Piece::Argument { index, debug, precision } => {
+                    let value = buffer.add(index * (ARGUMENT_SIZE as usize / 8));
+                    value_text.clear();
+                    format_value(&mut value_text, self.arguments[index], value, debug, precision);
+                    write(&value_text);
+                },
+            }
+        }
+    }
+}
+
+/// Whether values of the type can be formatted
+pub fn is_formattable(ty: Ret) -> bool {
+    matches!(ty, Ret::Int(_) | Ret::Float(_) | Ret::Fixed | Ret::Bool | Ret::Char | Ret::Str | Ret::Vec(_))
+}
+
+unsafe fn format_value(out: &mut String, ty: Ret, value: *const u64, debug: bool, precision: Option<usize>) {
+    use self::StaticIntLiteral::*;
+
+    let bits = *value;
+    match ty {
+        // integers are sign- or zero-extended to 64 bits, the slot of a 128-bit one is only aligned to 8
+        Ret::Int(U128) => write_value(out, (value as *const u128).read_unaligned(), debug, precision),
+        Ret::Int(I128) => write_value(out, (value as *const i128).read_unaligned(), debug, precision),
+        Ret::Int(U8) | Ret::Int(U16) | Ret::Int(U32) | Ret::Int(U64) | Ret::Int(Usize) => write_value(out, bits, debug, precision),
+        Ret::Int(_) => write_value(out, bits as i64, debug, precision),
+        Ret::Float(StaticFloatLiteral::F32) => write_value(out, f32::from_bits(bits as u32), debug, precision),
+        Ret::Float(_) => write_value(out, f64::from_bits(bits), debug, precision),
+        Ret::Fixed => write_value(out, Fixed::from_bits(bits as i64), debug, precision),
+        Ret::Bool => write_value(out, bits != 0, debug, precision),
+        Ret::Char => write_value(out, char::from_u32(bits as u32).unwrap_or(char::REPLACEMENT_CHARACTER), debug, precision),
+        Ret::Str => {
+            let bytes = slice::from_raw_parts(bits as *const u8, *value.add(1) as usize);
+            write_value(out, str::from_utf8_unchecked(bytes), debug, precision)
+        },
+        // like glam: `[1, 2]`, or `Vec2(1.0, 2.0)` with `Debug`
+        Ret::Vec(v) => {
+            let lanes = slice::from_raw_parts(value as *const f32, v.lanes());
+            let (open, close) = if debug { (format!("{:?}(", v), ")") } else { ("[".to_string(), "]") };
+            out.push_str(&open);
+            for (i, lane) in lanes.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_value(out, *lane, debug, precision);
+            }
+            out.push_str(close);
+        },
+        _ => unreachable!("{:?} can't be formatted", ty),
+    }
+}
+
+fn write_value<T: Display + Debug>(out: &mut String, value: T, debug: bool, precision: Option<usize>) {
+    // writing to a string can't fail
+    let _ = match (debug, precision) {
+        (false, None) => write!(out, "{}", value),
+        (false, Some(p)) => write!(out, "{:.*}", p, value),
+        (true, None) => write!(out, "{:?}", value),
+        (true, Some(p)) => write!(out, "{:.*?}", p, value),
+    };
+}
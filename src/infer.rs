@@ -0,0 +1,174 @@
+//! Local type inference for unsuffixed literals.
+//!
+//! Every function is compiled twice. In the first pass, an unsuffixed literal
+//! whose type is not known from its context gets a type variable
+//! (`StaticIntLiteral::UnknownSize` / `StaticFloatLiteral::UnknownSize`),
+//! and wherever two types have to be equal they are unified. Afterwards the
+//! variables that are still unknown default to `i32` / `f64`, as in rustc. The
+//! second pass looks up the final type of each literal and emits the code.
+//!
+//! A closure without argument types that is not passed where a function pointer is expected,
+//! `let triple = |x| x * 3;`, gets a placeholder function pointer type in the first pass. It
+//! becomes the type of the parameter the closure is passed to later, `apply(triple, 5)`.
+
+use std::collections::BTreeMap;
+use syn::{LitFloat, LitInt};
+use compiler::{Ret, StaticFloatLiteral, StaticIntLiteral};
+use types::FnTypeId;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct TypeVar {
+    parent: u64,
+    value: Option<Ret>,
+    /// Type if nothing else is known, `i32` for integer and `f64` for float literals
+    default: Ret,
+}
+
+#[derive(Debug, Default)]
+pub struct Inference {
+    vars: Vec<TypeVar>,
+    /// Type of every unsuffixed literal, keyed by the address of the literal in the AST
+    literals: BTreeMap<usize, Ret>,
+    /// Placeholder type and final type of every closure whose type is inferred from a later use,
+    /// keyed by the address of the closure in the AST
+    closures: BTreeMap<usize, (FnTypeId, Option<Ret>)>,
+}
+
+impl Inference {
+
+    fn new_var(&mut self, default: Ret) -> u64 {
+        let id = self.vars.len() as u64;
+        self.vars.push(TypeVar { parent: id, value: None, default });
+        id
+    }
+
+    pub fn new_int(&mut self) -> Ret {
+        Ret::Int(StaticIntLiteral::UnknownSize(self.new_var(Ret::Int(StaticIntLiteral::I32))))
+    }
+
+    pub fn new_float(&mut self) -> Ret {
+        Ret::Float(StaticFloatLiteral::UnknownSize(self.new_var(Ret::Float(StaticFloatLiteral::F64))))
+    }
+
+    fn find(&mut self, id: u64) -> u64 {
+        let parent = self.vars[id as usize].parent;
+        if parent == id {
+            return id;
+        }
+        let root = self.find(parent);
+        self.vars[id as usize].parent = root;
+        root
+    }
+
+    fn var_of(ty: Ret) -> Option<u64> {
+        match ty {
+            Ret::Int(StaticIntLiteral::UnknownSize(id)) | Ret::Float(StaticFloatLiteral::UnknownSize(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Replaces a type variable by its type, if it is known already
+    pub fn resolve(&mut self, ty: Ret) -> Ret {
+        if let Ret::FnPtr(id) = ty {
+            return self.closures.values().find(|c| c.0 == id).and_then(|c| c.1).unwrap_or(ty);
+        }
+        let id = match Self::var_of(ty) {
+            Some(id) => self.find(id),
+            None => return ty,
+        };
+        match self.vars[id as usize].value {
+            Some(value) => value,
+            None => match ty {
+                Ret::Int(_) => Ret::Int(StaticIntLiteral::UnknownSize(id)),
+                _ => Ret::Float(StaticFloatLiteral::UnknownSize(id)),
+            },
+        }
+    }
+
+    /// Whether the type still contains an unknown integer or float type
+    pub fn is_unknown(&mut self, ty: Ret) -> bool {
+        Self::var_of(self.resolve(ty)).is_some()
+    }
+
+    /// Whether the type is the placeholder of a closure whose type is not known yet
+    pub fn is_placeholder(&mut self, ty: Ret) -> bool {
+        match self.resolve(ty) {
+            Ret::FnPtr(id) => self.closures.values().any(|c| c.0 == id),
+            _ => false,
+        }
+    }
+
+    /// Records that both types have to be equal. Returns `false` if they can't be.
+    pub fn unify(&mut self, a: Ret, b: Ret) -> bool {
+        use compiler::Ret::{Float, Int};
+        use compiler::StaticIntLiteral::UnknownSize as IntVar;
+        use compiler::StaticFloatLiteral::UnknownSize as FloatVar;
+
+        let a = self.resolve(a);
+        let b = self.resolve(b);
+        if a == b {
+            return true;
+        }
+
+        match (a, b) {
+            (Int(IntVar(x)), Int(IntVar(y))) | (Float(FloatVar(x)), Float(FloatVar(y))) => {
+                self.vars[x as usize].parent = y;
+                true
+            },
+            (Int(IntVar(x)), known @ Int(_)) | (known @ Int(_), Int(IntVar(x))) |
+            (Float(FloatVar(x)), known @ Float(_)) | (known @ Float(_), Float(FloatVar(x))) => {
+                self.vars[x as usize].value = Some(known);
+                true
+            },
+            (Ret::FnPtr(x), Ret::FnPtr(y)) => {
+                // one of them has to be the placeholder of a closure
+                let (placeholder, known) = if self.closures.values().any(|c| c.0 == x) { (x, b) } else { (y, a) };
+                match self.closures.values_mut().find(|c| c.0 == placeholder) {
+                    Some(closure) => {
+                        closure.1 = Some(known);
+                        true
+                    },
+                    None => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    /// Gives every variable that is still unknown its default type, called between the two passes
+    pub fn apply_defaults(&mut self) {
+        for id in 0..self.vars.len() as u64 {
+            let root = self.find(id) as usize;
+            if self.vars[root].value.is_none() {
+                self.vars[root].value = Some(self.vars[root].default);
+            }
+        }
+    }
+
+    pub fn int_literal(&mut self, lit: &LitInt) -> Option<Ret> {
+        let ty = self.literals.get(&(lit as *const LitInt as usize)).cloned()?;
+        Some(self.resolve(ty))
+    }
+
+    pub fn set_int_literal(&mut self, lit: &LitInt, ty: Ret) {
+        self.literals.insert(lit as *const LitInt as usize, ty);
+    }
+
+    pub fn float_literal(&mut self, lit: &LitFloat) -> Option<Ret> {
+        let ty = self.literals.get(&(lit as *const LitFloat as usize)).cloned()?;
+        Some(self.resolve(ty))
+    }
+
+    pub fn set_float_literal(&mut self, lit: &LitFloat, ty: Ret) {
+        self.literals.insert(lit as *const LitFloat as usize, ty);
+    }
+
+    /// The type of a closure that was inferred from its use, or its placeholder type
+    pub fn closure(&mut self, key: usize) -> Option<Ret> {
+        self.closures.get(&key).map(|&(placeholder, ty)| ty.unwrap_or(Ret::FnPtr(placeholder)))
+    }
+
+    pub fn set_closure_placeholder(&mut self, key: usize, placeholder: FnTypeId) {
+        self.closures.insert(key, (placeholder, None));
+    }
+}
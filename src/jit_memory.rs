@@ -1,4 +1,5 @@
-use compiler::{AssemblyBuf, AllocationError};
+use compiler::{AssemblyBuf, AllocationError, Export, ScriptError, Trap, TRAP_ARGUMENTS};
+use logger::LogContext;
 use std::ptr;
 use std::ops::{Index, IndexMut};
 use libc;
@@ -14,6 +15,16 @@ pub struct JitMemory {
     allocated_size: usize,
     /// Pointer to the memory
     memory_ptr: *mut u8,
+    /// Offset of the code that calls the entry function and catches traps, if there is a `#[start]` function
+    trap_entry: Option<usize>,
+    /// The functions marked with `#[export]`, sorted by name
+    exports: Vec<Export>,
+    /// Offset of the state of the trap handler
+    trap_state: usize,
+    /// What every place in the code that can trap reports
+    traps: Vec<Trap>,
+    /// The logger and the messages of the logging macros, the code points to it
+    log_context: Option<Box<LogContext>>,
 }
 
 struct JitSetup {
@@ -27,8 +38,8 @@ impl JitMemory {
         let page_size = page_size::get();
         let allocation_size_in_bytes = num_pages * page_size;
         JitSetup {
-            page_size: page_size,
-            allocation_size_in_bytes: allocation_size_in_bytes,
+            page_size,
+            allocation_size_in_bytes,
         }
     }
 
@@ -38,13 +49,25 @@ impl JitMemory {
         let necessary_pages = (buf_len as f32 / page_size as f32).ceil() as usize;
         let mut memory = Self::new(necessary_pages)?;
         memory.load_assembly(assembly).ok()?;
+        memory.trap_entry = assembly.trap_entry;
+        memory.exports = assembly.exports.clone();
+        memory.trap_state = assembly.trap_state;
+        memory.traps = assembly.traps.clone();
+        if let Some(ref logger) = assembly.logger {
+            let context = Box::new(LogContext { logger: logger.clone(), messages: assembly.log_messages.clone() });
+            unsafe {
+                let pointer = memory.memory_ptr.add(assembly.log_context) as *mut *const LogContext;
+                ptr::write_unaligned(pointer, &*context);
+            }
+            memory.log_context = Some(context);
+        }
         Some(memory)
     }
 
     #[cfg(target_os = "linux")]
     fn new(num_pages: usize) -> Option<Self> {
-        let JitSetup { page_size, allocation_size_in_bytes, mut memory_ptr } =
-            Self::pre_setup(num_pages);
+        let JitSetup { page_size, allocation_size_in_bytes } = Self::pre_setup(num_pages);
+        let mut memory_ptr: *mut libc::c_void = ptr::null_mut();
 
         let alloc_error = unsafe {
           libc::posix_memalign(&mut memory_ptr, page_size::get(), allocation_size_in_bytes)
@@ -82,15 +105,20 @@ impl JitMemory {
         // It is not important if this function actually succeeds,
         // if it doesn't, the pages are uninitialized
         let ptr_memory_area = unsafe { libc::memset(memory_ptr, 0xCC, allocation_size_in_bytes) };
-        if ptr_memory_area as usize != memory_ptr as usize {
+        if !std::ptr::eq(ptr_memory_area, memory_ptr) {
             println!("warning: memset error!");
         }
 
         Some(JitMemory {
             number_of_pages: num_pages,
-            page_size: page_size,
+            page_size,
             allocated_size: allocation_size_in_bytes,
             memory_ptr: memory_ptr as *mut u8,
+            trap_entry: None,
+            exports: Vec::new(),
+            trap_state: 0,
+            traps: Vec::new(),
+            log_context: None,
         })
     }
 
@@ -126,6 +154,11 @@ impl JitMemory {
             page_size: page_size,
             allocated_size: allocation_size_in_bytes,
             memory_ptr: memory_ptr as *mut u8,
+            trap_entry: None,
+            exports: Vec::new(),
+            trap_state: 0,
+            traps: Vec::new(),
+            log_context: None,
         })
     }
 
@@ -146,13 +179,21 @@ impl JitMemory {
     }
 
     /// Returns a pointer to the element at the given index, without doing bounds checking.
+    ///
+    /// # Safety
+    ///
+    /// `index` has to be smaller than the allocated size.
     pub unsafe fn get_unchecked(&self, index: usize) -> &u8 {
-        &*self.memory_ptr.offset(index as isize)
+        &*self.memory_ptr.add(index)
     }
 
     /// Returns an unsafe mutable pointer to the element in index
+    ///
+    /// # Safety
+    ///
+    /// `index` has to be smaller than the allocated size.
     pub unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut u8 {
-        &mut *self.memory_ptr.offset(index as isize)
+        &mut *self.memory_ptr.add(index)
     }
 
     // Dump the JIT memory in hex
@@ -163,12 +204,12 @@ impl JitMemory {
         for i in 0..self.allocated_size {
             if i > 160 { break; }
             if i % self.page_size == 0 {
-                let page_start = unsafe { self.memory_ptr.offset((page_counter * self.page_size) as isize) };
+                let page_start = unsafe { self.memory_ptr.add(page_counter * self.page_size) };
                 write!(&mut s, "\n>>>>> JIT memory - page {} @ 0x{:x}\n", page_counter, page_start as usize).unwrap();
                 page_counter += 1;
             }
             if i != 0 && i % 16 == 0 {
-                write!(&mut s, "\n").unwrap();
+                writeln!(&mut s).unwrap();
             }
             write!(&mut s, "{:02x} ", self[i]).unwrap();
         }
@@ -184,11 +225,109 @@ impl JitMemory {
         }
     }
 
-    pub fn run<T>(&self) -> (fn() -> T) {
+    /// Returns the entry function. If the script traps (i.e. on an overflow in
+    /// `CompileMode::Debug`), the process is aborted, use `call` to handle the error instead.
+    pub fn run<T>(&self) -> fn() -> T {
+        assert!(self.trap_entry.is_some(), "the script has no #[start] function");
         unsafe { ::std::mem::transmute(self.memory_ptr) }
     }
+
+    /// Runs the entry function and returns its result, or the error that stopped the script.
+    /// `T` has to be valid when all its bits are zero, which is what a trapped call returns.
+    /// A host function may call the script again while it runs.
+    pub fn call<T>(&self) -> Result<T, ScriptError> {
+        let trap_entry = self.trap_entry.expect("the script has no #[start] function");
+        unsafe { self.catch_traps(|| ().call(self.memory_ptr.add(trap_entry))) }
+    }
+
+    /// The functions marked with `#[export]`, sorted by name
+    pub fn exports(&self) -> &[Export] {
+        &self.exports
+    }
+
+    /// Looks up an exported function by the name it is exported under
+    pub fn export(&self, name: &str) -> Option<&Export> {
+        self.exports.binary_search_by(|e| e.name.as_str().cmp(name)).ok().map(|i| &self.exports[i])
+    }
+
+    /// The address of an exported function, which can be transmuted to an `extern "sysv64" fn`
+    /// of its signature. Like `run`, traps in it abort the process.
+    pub fn export_address(&self, export: &Export) -> *const u8 {
+        unsafe { self.memory_ptr.add(export.offset) }
+    }
+
+    /// Calls an exported function with a tuple of arguments and returns its result, or the error
+    /// that stopped the script. The arguments and `T` have to match the signature of the function,
+    /// like the types of a host function, and `T` has to be valid when all its bits are zero.
+    pub fn call_export<A: ScriptArgs, T>(&self, export: &Export, args: A) -> Result<T, ScriptError> {
+        assert_eq!(A::COUNT, export.signature.arguments.len(), "wrong number of arguments for {}", export.name);
+        unsafe { self.catch_traps(|| args.call(self.memory_ptr.add(export.trap_entry))) }
+    }
+}
+
+impl JitMemory {
+
+    /// Runs a trap entry, returning the error of the site that trapped instead of the result
+    unsafe fn catch_traps<T, F: FnOnce() -> T>(&self, entry: F) -> Result<T, ScriptError> {
+        // the number of the site that trapped, plus one
+        let site = self.memory_ptr.add(self.trap_state + 8) as *mut u64;
+        ptr::write_volatile(site, 0);
+        let result = entry();
+        let trapped = ptr::read_volatile(site);
+        ptr::write_volatile(site, 0);
+        match trapped {
+            0 => Ok(result),
+            n => Err(self.trap_error(&self.traps[n as usize - 1])),
+        }
+    }
+
+    /// The error of a trap, formatting the message of a panic
+    unsafe fn trap_error(&self, trap: &Trap) -> ScriptError {
+        match *trap {
+            Trap::Error(ref e) => e.clone(),
+            Trap::Panic { ref message, ref file, line } => {
+                let arguments = self.memory_ptr.add(self.trap_state + TRAP_ARGUMENTS) as *const u64;
+                ScriptError::Panic { message: message.format(arguments), file: file.clone(), line }
+            },
+        }
+    }
 }
 
+/// The arguments of `JitMemory::call_export`, a tuple of up to 6 values
+pub trait ScriptArgs {
+    const COUNT: usize;
+
+    /// Calls the code at `code` as an `extern "sysv64" fn` with these arguments
+    ///
+    /// # Safety
+    ///
+    /// `code` has to be a function that takes these arguments and returns `T`.
+    unsafe fn call<T>(self, code: *const u8) -> T;
+}
+
+macro_rules! script_args {
+    ($($arg:ident),*) => {
+        impl<$($arg),*> ScriptArgs for ($($arg,)*) {
+            const COUNT: usize = 0 $(+ { stringify!($arg); 1 })*;
+
+            #[allow(non_snake_case)]
+            unsafe fn call<T>(self, code: *const u8) -> T {
+                let function: extern "sysv64" fn($($arg),*) -> T = ::std::mem::transmute(code);
+                let ($($arg,)*) = self;
+                function($($arg),*)
+            }
+        }
+    };
+}
+
+script_args!();
+script_args!(A);
+script_args!(A, B);
+script_args!(A, B, C);
+script_args!(A, B, C, D);
+script_args!(A, B, C, D, E);
+script_args!(A, B, C, D, E, F);
+
 impl Index<usize> for JitMemory {
     type Output = u8;
 
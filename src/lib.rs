@@ -3,13 +3,31 @@
 
 extern crate libc;
 extern crate page_size;
+extern crate proc_macro2;
+extern crate quote;
 extern crate syn;
 #[cfg(target_os = "windows")]
 extern crate winapi;
 
 mod jit_memory;
+mod assembler;
+mod resolve;
+mod infer;
+mod types;
+mod codegen;
 mod compiler;
+mod operators;
+mod format;
+mod fixed;
+mod logger;
 
-pub use jit_memory::JitMemory;
+pub use jit_memory::{JitMemory, ScriptArgs};
 pub use syn::parse_file;
-pub use compiler::compile;
\ No newline at end of file
+pub use compiler::{compile, compile_with_options, CompileMode, CompileOptions, CpuFeatures, HostFunctions};
+pub use compiler::{Export, ScriptError, SourceLocation};
+pub use codegen::FnSignature;
+pub use logger::{LogTarget, ScriptLogger, SharedLogger, StdLogger};
+pub use fixed::Fixed;
+pub use compiler::{AssembleError, AssembleFunctionError, GetReturnTypeInnerError, TryMatchError};
+pub use compiler::{Ret, StaticIntLiteral, StaticFloatLiteral, StaticVecLiteral, StaticMatLiteral, StaticSimdLiteral};
+pub use types::{StructId, FnTypeId, SliceId, OptionId, ResultId};
@@ -0,0 +1,110 @@
+//! `print!`, `println!`, `eprint!`, `eprintln!` and `log!`, written to a logger of the host.
+//!
+//! The format string of every macro is parsed when the script is compiled and numbered while
+//! linking. At runtime the script copies the values to a buffer on its stack and calls
+//! `write_message` with the number of the message, which passes the pieces of the message one
+//! by one to the `ScriptLogger` that was installed with `CompileOptions::logger`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use format::FormatString;
+
+/// Where the script wrote a message to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LogTarget {
+    /// `print!` and `println!`
+    Stdout,
+    /// `eprint!` and `eprintln!`
+    Stderr,
+    /// `log!`
+    Log,
+}
+
+/// Receives what the script prints. A message arrives as a sequence of `write` calls, one for
+/// every literal part of the format string and for every formatted value. The messages of
+/// `println!`, `eprintln!` and `log!` end with a call to `end_line`.
+pub trait ScriptLogger {
+    fn write(&mut self, target: LogTarget, text: &str);
+
+    fn end_line(&mut self, target: LogTarget) {
+        self.write(target, "\n");
+    }
+}
+
+/// Prints to the standard output and error of the process, `log!` goes to the standard error
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StdLogger;
+
+impl ScriptLogger for StdLogger {
+    fn write(&mut self, target: LogTarget, text: &str) {
+        match target {
+            LogTarget::Stdout => print!("{}", text),
+            LogTarget::Stderr | LogTarget::Log => eprint!("{}", text),
+        }
+    }
+}
+
+/// Collects everything the script prints, regardless of the target
+impl ScriptLogger for String {
+    fn write(&mut self, _target: LogTarget, text: &str) {
+        self.push_str(text);
+    }
+}
+
+/// The logger of `CompileOptions`, shared between the host and every `JitMemory` compiled with it.
+/// While the host borrows the logger, the messages of the script are dropped.
+#[derive(Clone)]
+pub struct SharedLogger(pub Rc<RefCell<dyn ScriptLogger>>);
+
+impl SharedLogger {
+    pub fn new<L: ScriptLogger + 'static>(logger: L) -> Self {
+        SharedLogger(Rc::new(RefCell::new(logger)))
+    }
+}
+
+impl fmt::Debug for SharedLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedLogger({:p})", Rc::as_ptr(&self.0) as *const u8)
+    }
+}
+
+impl PartialEq for SharedLogger {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A logging macro of the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogMessage {
+    pub target: LogTarget,
+    /// Whether the message ends with `end_line`
+    pub newline: bool,
+    pub format: FormatString,
+}
+
+/// What `write_message` needs at runtime, owned by the `JitMemory`
+#[derive(Debug)]
+pub struct LogContext {
+    pub logger: SharedLogger,
+    pub messages: Vec<LogMessage>,
+}
+
+/// Called by the script with the context stored in the read-only data, the number of the
+/// message and the buffer with the values of its arguments.
+///
+/// # Safety
+///
+/// The context has to be alive and the buffer has to hold the values that the message expects.
+pub unsafe extern "sysv64" fn write_message(context: *const LogContext, message: u32, values: *const u64) {
+    let context = &*context;
+    let message = &context.messages[message as usize];
+    // a logger that runs the script again while it writes doesn't receive the nested messages
+    if let Ok(mut logger) = context.logger.0.try_borrow_mut() {
+        message.format.write_pieces(values, |text| logger.write(message.target, text));
+        if message.newline {
+            logger.end_line(message.target);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Operators that scripts implement for their own structs, `impl Add for Money { .. }`.
+//!
+//! The traits of `std::ops` are known to the compiler, they are named with or without their
+//! path and don't have to be imported. The method of an impl becomes a function without a name,
+//! with a `self` parameter of the struct type and `Self` replaced by the type. An operator on a
+//! struct calls the function of the impl whose right side has the type of the operand. `Index`
+//! takes `&self` and returns `&Self::Output`, both are passed by value instead, so `index` can
+//! only read an element and `place[index] = value` is not supported. `index` has to return `&place`
+//! directly, as its value or with `return`. Inherent impls and impls of other traits are rejected.
+
+use proc_macro2::{Delimiter, Group, Ident, TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{self, ArgCaptured, Block, Expr, FnArg, ImplItem, ItemImpl, Pat, PatIdent, ReturnType, Stmt, Type, UseTree};
+use compiler::{AssembleError, FnName, Function, GlobalLabel, Ret};
+use resolve::ModuleId;
+
+/// The traits that scripts can implement: their name, the name of their method and the number of
+/// parameters of the method, including `self`
+const OPERATOR_TRAITS: [(&str, &str, usize); 7] = [
+    ("Add", "add", 2),
+    ("Sub", "sub", 2),
+    ("Mul", "mul", 2),
+    ("Div", "div", 2),
+    ("Rem", "rem", 2),
+    ("Neg", "neg", 1),
+    ("Index", "index", 2),
+];
+
+/// An operator trait that is implemented for a struct
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operator {
+    pub trait_name: &'static str,
+    /// The type of `self`, the left side of a binary operator
+    pub ty: Ret,
+    /// The function of the method
+    pub label: GlobalLabel,
+}
+
+/// An impl whose struct type is resolved once all structs are declared
+#[derive(Debug, Clone)]
+pub struct OperatorDecl {
+    pub trait_name: &'static str,
+    pub self_ty: Type,
+    pub module: ModuleId,
+    pub label: GlobalLabel,
+}
+
+/// `std::ops::Add` or `Add`, if it names one of `OPERATOR_TRAITS`
+fn operator_trait(path: &syn::Path) -> Option<(&'static str, &'static str, usize)> {
+    let segments = path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>();
+    let (name, prefix) = segments.split_last()?;
+    if !prefix.is_empty() && prefix != ["std", "ops"] && prefix != ["core", "ops"] {
+        return None;
+    }
+    OPERATOR_TRAITS.iter().find(|t| t.0 == name).cloned()
+}
+
+/// `use std::ops::Add;` or `use core::ops::{Add, Mul};`, which scripts don't need
+pub fn is_ops_import(tree: &UseTree) -> bool {
+    match *tree {
+        UseTree::Path(ref p) if p.ident == "std" || p.ident == "core" => match *p.tree {
+            UseTree::Path(ref ops) => ops.ident == "ops",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The header of an impl for errors, `impl Add for Money`
+pub fn impl_name(i: &ItemImpl) -> String {
+    let trait_name = match i.trait_ {
+        Some((_, ref path, _)) => format!("{} for ", path.clone().into_token_stream()),
+        None => String::new(),
+    };
+    format!("impl {}{}", trait_name, i.self_ty.clone().into_token_stream())
+}
+
+/// Replaces `Self` by the type of the impl and `Self::Output` by the `Output` type
+fn replace_self(tokens: TokenStream, self_ty: &Type, output: Option<&Type>) -> TokenStream {
+    let tokens = tokens.into_iter().collect::<Vec<_>>();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            TokenTree::Ident(ref ident) if ident == "Self" => {
+                let is_output = match (tokens.get(i + 1), tokens.get(i + 2), tokens.get(i + 3)) {
+                    (Some(TokenTree::Punct(a)), Some(TokenTree::Punct(b)), Some(TokenTree::Ident(name))) => {
+                        a.as_char() == ':' && b.as_char() == ':' && name == "Output"
+                    },
+                    _ => false,
+                };
+                match output {
+                    Some(output) if is_output => {
+                        let output = replace_self(output.into_token_stream(), self_ty, None);
+                        result.push(TokenTree::Group(Group::new(Delimiter::Parenthesis, output)));
+                        i += 3;
+                    },
+                    _ => result.extend(self_ty.into_token_stream()),
+                }
+            },
+            TokenTree::Group(ref g) => {
+                let mut group = Group::new(g.delimiter(), replace_self(g.stream(), self_ty, output));
+                group.set_span(g.span());
+                result.push(TokenTree::Group(group));
+            },
+            ref token => result.push(token.clone()),
+        }
+        i += 1;
+    }
+    result.into_iter().collect()
+}
+
+/// `&value` in the places where the value of `index` is returned, an `if`, `match` or block. Other
+/// values, like a reference kept in a local, are rejected; `return` is handled by `strip_returns`.
+fn strip_reference(expr: &mut Expr) -> Result<(), AssembleError> {
+    let inner = match *expr {
+        Expr::Reference(ref r) if r.mutability.is_none() => (*r.expr).clone(),
+        Expr::Paren(ref mut p) => return strip_reference(&mut p.expr),
+        Expr::Block(ref mut b) => return strip_block_reference(&mut b.block),
+        Expr::Unsafe(ref mut u) => return strip_block_reference(&mut u.block),
+        Expr::If(ref mut i) => {
+            strip_block_reference(&mut i.then_branch)?;
+            if let Some((_, ref mut e)) = i.else_branch {
+                strip_reference(e)?;
+            }
+            return Ok(());
+        },
+        Expr::IfLet(ref mut i) => {
+            strip_block_reference(&mut i.then_branch)?;
+            if let Some((_, ref mut e)) = i.else_branch {
+                strip_reference(e)?;
+            }
+            return Ok(());
+        },
+        Expr::Match(ref mut m) => {
+            for arm in &mut m.arms {
+                strip_reference(&mut arm.body)?;
+            }
+            return Ok(());
+        },
+        // `panic!(..)` and `return` don't produce the value
+        Expr::Macro(_) | Expr::Return(_) => return Ok(()),
+        ref e => return Err(AssembleError::UnsupportedIndexResult(e.clone().into_token_stream().to_string())),
+    };
+    *expr = inner;
+    Ok(())
+}
+
+/// The value of a block, a block that ends with a statement doesn't produce one
+fn strip_block_reference(block: &mut Block) -> Result<(), AssembleError> {
+    match block.stmts.last_mut() {
+        Some(&mut Stmt::Expr(ref mut e)) => strip_reference(e),
+        _ => Ok(()),
+    }
+}
+
+/// `return &value` anywhere in the statements and control flow of `index`
+fn strip_returns(expr: &mut Expr) -> Result<(), AssembleError> {
+    match *expr {
+        Expr::Return(ref mut r) => match r.expr {
+            Some(ref mut e) => strip_reference(e),
+            None => Ok(()),
+        },
+        Expr::Paren(ref mut p) => strip_returns(&mut p.expr),
+        Expr::Block(ref mut b) => strip_block_returns(&mut b.block),
+        Expr::Unsafe(ref mut u) => strip_block_returns(&mut u.block),
+        Expr::Loop(ref mut l) => strip_block_returns(&mut l.body),
+        Expr::While(ref mut w) => strip_block_returns(&mut w.body),
+        Expr::WhileLet(ref mut w) => strip_block_returns(&mut w.body),
+        Expr::ForLoop(ref mut f) => strip_block_returns(&mut f.body),
+        Expr::If(ref mut i) => {
+            strip_block_returns(&mut i.then_branch)?;
+            match i.else_branch {
+                Some((_, ref mut e)) => strip_returns(e),
+                None => Ok(()),
+            }
+        },
+        Expr::IfLet(ref mut i) => {
+            strip_block_returns(&mut i.then_branch)?;
+            match i.else_branch {
+                Some((_, ref mut e)) => strip_returns(e),
+                None => Ok(()),
+            }
+        },
+        Expr::Match(ref mut m) => {
+            for arm in &mut m.arms {
+                strip_returns(&mut arm.body)?;
+            }
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+fn strip_block_returns(block: &mut Block) -> Result<(), AssembleError> {
+    for stmt in &mut block.stmts {
+        match *stmt {
+            Stmt::Local(ref mut l) => if let Some((_, ref mut init)) = l.init {
+                strip_returns(init)?;
+            },
+            Stmt::Expr(ref mut e) | Stmt::Semi(ref mut e, _) => strip_returns(e)?,
+            Stmt::Item(_) => { },
+        }
+    }
+    Ok(())
+}
+
+/// The function of an impl of an operator trait, other impls are not supported
+pub fn operator_impl(i: &ItemImpl, module: ModuleId) -> Result<(Function, OperatorDecl), AssembleError> {
+    let (trait_name, method, parameters) = match i.trait_ {
+        Some((None, ref path, _)) => operator_trait(path).ok_or_else(|| AssembleError::UnsupportedImpl(impl_name(i)))?,
+        _ => return Err(AssembleError::UnsupportedImpl(impl_name(i))),
+    };
+    let invalid = || AssembleError::InvalidImpl(impl_name(i));
+    if !i.generics.params.is_empty() || !matches!(*i.self_ty, Type::Path(_)) {
+        return Err(invalid());
+    }
+    let self_ty = &*i.self_ty;
+    let output = i.items.iter().filter_map(|item| match *item {
+        ImplItem::Type(ref t) if t.ident == "Output" => Some(&t.ty),
+        _ => None,
+    }).next();
+    let m = i.items.iter().filter_map(|item| match *item {
+        ImplItem::Method(ref m) if m.sig.ident == method => Some(m),
+        _ => None,
+    }).next().ok_or_else(invalid)?;
+    let replace = |node: &dyn ToTokens| replace_self(node.into_token_stream(), self_ty, output);
+
+    let mut arguments = Vec::new();
+    for (index, arg) in m.sig.decl.inputs.iter().enumerate() {
+        let (mutability, span) = match *arg {
+            FnArg::SelfValue(ref s) if index == 0 && trait_name != "Index" => (s.mutability, s.self_token.0),
+            FnArg::SelfRef(ref s) if index == 0 && trait_name == "Index" && s.mutability.is_none() => (None, s.self_token.0),
+            FnArg::Captured(ref c) if index > 0 => {
+                let ty = syn::parse2(replace(&c.ty)).map_err(|_| invalid())?;
+                arguments.push(FnArg::Captured(ArgCaptured { ty, ..c.clone() }));
+                continue;
+            },
+            _ => return Err(invalid()),
+        };
+        arguments.push(FnArg::Captured(ArgCaptured {
+            pat: Pat::Ident(PatIdent { by_ref: None, mutability, ident: Ident::new("self", span), subpat: None }),
+            colon_token: Default::default(),
+            ty: self_ty.clone(),
+        }));
+    }
+    if arguments.len() != parameters {
+        return Err(invalid());
+    }
+
+    let return_type = match m.sig.decl.output {
+        ReturnType::Type(_, ref ty) => syn::parse2::<Type>(replace(ty)).map_err(|_| invalid())?,
+        ReturnType::Default => return Err(invalid()),
+    };
+    let mut block = syn::parse2::<Block>(replace(&m.block)).map_err(|_| invalid())?;
+    let return_type = match return_type {
+        Type::Reference(ref r) if trait_name == "Index" && r.mutability.is_none() => {
+            strip_block_returns(&mut block)?;
+            strip_block_reference(&mut block)?;
+            (*r.elem).clone()
+        },
+        _ if trait_name == "Index" => return Err(invalid()),
+        ty => ty,
+    };
+
+    let label = ::compiler::new_global_label();
+    let function = Function {
+        name: FnName(m.sig.ident.clone()),
+        module,
+        arguments,
+        statements: block.stmts,
+        return_type: Some(return_type),
+        memory_location: None,
+    };
+    Ok((function, OperatorDecl { trait_name, self_ty: self_ty.clone(), module, label }))
+}
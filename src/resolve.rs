@@ -0,0 +1,410 @@
+//! Module tree and name resolution.
+//!
+//! Every `mod` block of a script becomes a `Module` with two namespaces
+//! (types and values, like in rustc). `use` declarations are resolved up
+//! front by `ModuleTree::resolve_imports`, paths inside of function bodies
+//! are resolved on demand via `ModuleTree::resolve_path`. Both check the
+//! `pub` visibility of every segment that is named from another module.
+
+use std::collections::BTreeMap;
+use syn::{Path, UseTree, Visibility};
+use compiler::{AssembleError, GlobalLabel};
+use types::StructId;
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ModuleId(pub usize);
+
+/// The module of the file that was passed to `compile`, i.e. `crate`
+pub const ROOT_MODULE: ModuleId = ModuleId(0);
+
+/// What a name refers to after resolution
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Def {
+    Module(ModuleId),
+    Fn(GlobalLabel),
+    /// Index into `Program::host_functions`
+    HostFn(usize),
+    Struct(StructId),
+    /// The constructor of a tuple or unit struct, i.e. `Money` in `Money(5)`
+    Ctor(StructId),
+    /// Index into `Program::statics`
+    Static(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Namespace {
+    Type,
+    Value,
+}
+
+impl Def {
+    pub fn namespace(&self) -> Namespace {
+        match *self {
+            Def::Module(_) | Def::Struct(_) => Namespace::Type,
+            Def::Fn(_) | Def::HostFn(_) | Def::Ctor(_) | Def::Static(_) => Namespace::Value,
+        }
+    }
+}
+
+/// From where an item may be named
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Vis {
+    /// `pub` and `pub(crate)`: since a script is a single crate, these are the same
+    Public,
+    /// Visible inside of the given module and all of its descendants.
+    /// Private items are restricted to the module they are declared in.
+    Restricted(ModuleId),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Binding {
+    def: Def,
+    vis: Vis,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ImportKind {
+    /// `use a::b;` or `use a::b as c;`, binds the last segment under the given name
+    Single(String),
+    /// `use a::*;`
+    Glob,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Import {
+    path: Vec<String>,
+    kind: ImportKind,
+    vis: Vis,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub parent: Option<ModuleId>,
+    types: BTreeMap<String, Binding>,
+    values: BTreeMap<String, Binding>,
+    /// Modules whose public items are glob-imported, explicit bindings take precedence
+    globs: Vec<(ModuleId, Vis)>,
+    imports: Vec<Import>,
+}
+
+impl Module {
+    fn new(name: String, parent: Option<ModuleId>) -> Self {
+        Module {
+            name,
+            parent,
+            types: BTreeMap::new(),
+            values: BTreeMap::new(),
+            globs: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+
+    fn namespace(&self, ns: Namespace) -> &BTreeMap<String, Binding> {
+        match ns {
+            Namespace::Type => &self.types,
+            Namespace::Value => &self.values,
+        }
+    }
+
+    fn namespace_mut(&mut self, ns: Namespace) -> &mut BTreeMap<String, Binding> {
+        match ns {
+            Namespace::Type => &mut self.types,
+            Namespace::Value => &mut self.values,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleTree {
+    pub modules: Vec<Module>,
+}
+
+impl Default for ModuleTree {
+    fn default() -> Self {
+        ModuleTree { modules: vec![Module::new("crate".into(), None)] }
+    }
+}
+
+pub fn path_to_string(path: &Path) -> String {
+    let segments = path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>();
+    let prefix = if path.leading_colon.is_some() { "::" } else { "" };
+    format!("{}{}", prefix, segments.join("::"))
+}
+
+impl ModuleTree {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn module(&self, id: ModuleId) -> &Module {
+        &self.modules[id.0]
+    }
+
+    /// Returns the full path of a module, i.e. `crate::ai::patrol`
+    pub fn module_path(&self, id: ModuleId) -> String {
+        let mut names = vec![self.module(id).name.clone()];
+        let mut current = self.module(id).parent;
+        while let Some(parent) = current {
+            names.push(self.module(parent).name.clone());
+            current = self.module(parent).parent;
+        }
+        names.reverse();
+        names.join("::")
+    }
+
+    /// Creates a new (empty) child module, returns `None` if the name is already taken
+    pub fn add_module(&mut self, parent: ModuleId, name: &str, vis: Vis) -> Option<ModuleId> {
+        let id = ModuleId(self.modules.len());
+        self.define(parent, name, Def::Module(id), vis)?;
+        self.modules.push(Module::new(name.into(), Some(parent)));
+        Some(id)
+    }
+
+    /// Declares an item in a module, returns `None` if the name is already taken
+    pub fn define(&mut self, module: ModuleId, name: &str, def: Def, vis: Vis) -> Option<()> {
+        let ns = self.modules[module.0].namespace_mut(def.namespace());
+        if ns.contains_key(name) {
+            return None;
+        }
+        ns.insert(name.into(), Binding { def, vis });
+        Some(())
+    }
+
+    /// Whether `inner` is `outer` or (transitively) nested inside of it
+    pub fn is_descendant(&self, inner: ModuleId, outer: ModuleId) -> bool {
+        let mut current = Some(inner);
+        while let Some(module) = current {
+            if module == outer {
+                return true;
+            }
+            current = self.module(module).parent;
+        }
+        false
+    }
+
+    /// Whether an item (or field) with the visibility `vis` can be named from inside of `from`
+    pub fn is_visible(&self, vis: Vis, from: ModuleId) -> bool {
+        match vis {
+            Vis::Public => true,
+            Vis::Restricted(module) => self.is_descendant(from, module),
+        }
+    }
+
+    /// Translates the `pub` / `pub(crate)` / `pub(super)` / `pub(in path)` of
+    /// an item declared in `module` into a `Vis`
+    pub fn visibility(&self, module: ModuleId, vis: &Visibility) -> Result<Vis, AssembleError> {
+        match *vis {
+            Visibility::Public(_) | Visibility::Crate(_) => Ok(Vis::Public),
+            Visibility::Inherited => Ok(Vis::Restricted(module)),
+            Visibility::Restricted(ref r) => {
+                let segments = r.path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>();
+                if r.in_token.is_none() && segments.len() == 1 && segments[0] == "crate" {
+                    return Ok(Vis::Public);
+                }
+                match self.resolve_segments(module, &segments, Namespace::Type, false) {
+                    Some(Ok(Def::Module(m))) if self.is_descendant(module, m) => Ok(Vis::Restricted(m)),
+                    _ => Err(AssembleError::UnresolvedPath(path_to_string(&r.path))),
+                }
+            }
+        }
+    }
+
+    /// Registers all imports of a `use` declaration, they are resolved later by `resolve_imports`
+    pub fn add_use(&mut self, module: ModuleId, tree: &UseTree, vis: Vis) {
+        let mut imports = Vec::new();
+        flatten_use_tree(tree, &mut Vec::new(), vis, &mut imports);
+        self.modules[module.0].imports.extend(imports);
+    }
+
+    /// Looks up a name directly in a module (explicit bindings, then glob imports). An explicit
+    /// binding shadows the globs, but two globs that bring different items are ambiguous.
+    fn lookup(&self, module: ModuleId, name: &str, ns: Namespace) -> Option<Result<Binding, AssembleError>> {
+        let m = self.module(module);
+        if let Some(binding) = m.namespace(ns).get(name) {
+            return Some(Ok(*binding));
+        }
+        let mut found: Option<Binding> = None;
+        for &(glob_source, glob_vis) in &m.globs {
+            if let Some(binding) = self.module(glob_source).namespace(ns).get(name) {
+                if !self.is_visible(binding.vis, module) {
+                    continue;
+                }
+                match found {
+                    Some(other) if other.def != binding.def => {
+                        return Some(Err(AssembleError::AmbiguousName(name.into())));
+                    },
+                    Some(_) => { },
+                    None => found = Some(Binding { def: binding.def, vis: glob_vis }),
+                }
+            }
+        }
+        found.map(Ok)
+    }
+
+    /// Resolves a path, given as segments, from inside of `from`.
+    ///
+    /// Returns `None` if a segment could not be found (which may change while
+    /// imports are still being resolved), `Some(Err(_))` for definitive errors.
+    fn resolve_segments(&self, from: ModuleId, segments: &[String], ns: Namespace, global: bool)
+    -> Option<Result<Def, AssembleError>>
+    {
+        let full_path = || segments.join("::");
+        let mut current = if global { ROOT_MODULE } else { from };
+        let mut rest = segments;
+
+        // path roots: `crate::`, `self::` and (repeated) `super::`
+        let mut first = true;
+        while let Some(segment) = rest.first() {
+            match segment.as_str() {
+                "crate" if first && !global => current = ROOT_MODULE,
+                "self" if first && !global => { },
+                "super" if !global => {
+                    match self.module(current).parent {
+                        Some(parent) => current = parent,
+                        None => return Some(Err(AssembleError::UnresolvedPath(full_path()))),
+                    }
+                },
+                _ => break,
+            }
+            first = false;
+            rest = &rest[1..];
+        }
+
+        if rest.is_empty() {
+            return match ns {
+                Namespace::Type => Some(Ok(Def::Module(current))),
+                Namespace::Value => Some(Err(AssembleError::UnresolvedPath(full_path()))),
+            };
+        }
+
+        for (i, segment) in rest.iter().enumerate() {
+            let is_last = i == rest.len() - 1;
+            let segment_ns = if is_last { ns } else { Namespace::Type };
+            let binding = match self.lookup(current, segment, segment_ns)? {
+                Ok(binding) => binding,
+                Err(e) => return Some(Err(e)),
+            };
+            if !self.is_visible(binding.vis, from) {
+                return Some(Err(AssembleError::PrivateItem(full_path())));
+            }
+            if is_last {
+                return Some(Ok(binding.def));
+            }
+            match binding.def {
+                Def::Module(m) => current = m,
+                _ => return Some(Err(AssembleError::UnresolvedPath(full_path()))),
+            }
+        }
+
+        None
+    }
+
+    /// Resolves all `use` declarations of all modules. Imports may depend on
+    /// each other, so this iterates until no further progress can be made.
+    pub fn resolve_imports(&mut self) -> Result<(), AssembleError> {
+        loop {
+            let mut progress = false;
+            for module_index in 0..self.modules.len() {
+                let module = ModuleId(module_index);
+                let pending = std::mem::take(&mut self.modules[module_index].imports);
+                let mut unresolved = Vec::new();
+                for import in pending {
+                    if self.try_resolve_import(module, &import)? {
+                        progress = true;
+                    } else {
+                        unresolved.push(import);
+                    }
+                }
+                self.modules[module_index].imports = unresolved;
+            }
+            if !progress {
+                break;
+            }
+        }
+
+        match self.modules.iter().filter_map(|m| m.imports.first()).next() {
+            Some(import) => Err(AssembleError::UnresolvedPath(import.path.join("::"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `Ok(false)` if the import can not be resolved yet
+    fn try_resolve_import(&mut self, module: ModuleId, import: &Import) -> Result<bool, AssembleError> {
+        match import.kind {
+            ImportKind::Glob => {
+                match self.resolve_segments(module, &import.path, Namespace::Type, false) {
+                    Some(Ok(Def::Module(source))) => {
+                        self.modules[module.0].globs.push((source, import.vis));
+                        Ok(true)
+                    },
+                    Some(Ok(_)) => Err(AssembleError::UnresolvedPath(import.path.join("::"))),
+                    Some(Err(e)) => Err(e),
+                    None => Ok(false),
+                }
+            },
+            ImportKind::Single(ref name) => {
+                let mut found = false;
+                for &ns in &[Namespace::Type, Namespace::Value] {
+                    match self.resolve_segments(module, &import.path, ns, false) {
+                        Some(Ok(def)) => {
+                            if self.define(module, name, def, import.vis).is_none() {
+                                return Err(AssembleError::ItemDeclaredMultipleTimes(name.clone()));
+                            }
+                            found = true;
+                        },
+                        Some(Err(AssembleError::UnresolvedPath(_))) | None => { },
+                        Some(Err(e)) => return Err(e),
+                    }
+                }
+                Ok(found)
+            },
+        }
+    }
+
+    /// Resolves a path used inside of a function body of module `from`
+    pub fn resolve_path(&self, from: ModuleId, path: &Path, ns: Namespace) -> Result<Def, AssembleError> {
+        let segments = path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<_>>();
+        self.resolve_segments(from, &segments, ns, path.leading_colon.is_some())
+            .unwrap_or_else(|| Err(AssembleError::UnresolvedPath(path_to_string(path))))
+    }
+}
+
+fn flatten_use_tree(tree: &UseTree, prefix: &mut Vec<String>, vis: Vis, out: &mut Vec<Import>) {
+    match *tree {
+        UseTree::Path(ref p) => {
+            prefix.push(p.ident.to_string());
+            flatten_use_tree(&p.tree, prefix, vis, out);
+            prefix.pop();
+        },
+        UseTree::Name(ref n) => {
+            let name = n.ident.to_string();
+            if name == "self" {
+                // `use a::b::{self}` imports the module `b` itself
+                if let Some(last) = prefix.last().cloned() {
+                    out.push(Import { path: prefix.clone(), kind: ImportKind::Single(last), vis });
+                }
+            } else {
+                let mut path = prefix.clone();
+                path.push(name.clone());
+                out.push(Import { path, kind: ImportKind::Single(name), vis });
+            }
+        },
+        UseTree::Rename(ref r) => {
+            let mut path = prefix.clone();
+            if r.ident != "self" {
+                path.push(r.ident.to_string());
+            }
+            out.push(Import { path, kind: ImportKind::Single(r.rename.to_string()), vis });
+        },
+        UseTree::Glob(_) => {
+            out.push(Import { path: prefix.clone(), kind: ImportKind::Glob, vis });
+        },
+        UseTree::Group(ref g) => {
+            for item in g.items.iter() {
+                flatten_use_tree(item, prefix, vis, out);
+            }
+        },
+    }
+}
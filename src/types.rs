@@ -0,0 +1,649 @@
+//! Struct layouts, tuple, array, option, function pointer, slice and reference types.
+//!
+//! `Ret` is `Copy`, so types that carry more information than a name (the
+//! fields of a struct, the signature of a function pointer, the element type
+//! of a slice) are stored in the
+//! `TypeTable` and referred to by an id. Structs are always laid out like
+//! `#[repr(C)]` structs, so the host can declare matching Rust types.
+//! A tuple `(u32, bool)` is an anonymous tuple struct with the same layout, an
+//! array `[u32; 3]` one whose fields `[0]`, `[1]` and `[2]` can only be indexed, and
+//! an `Option<T>` is laid out like `#[repr(C)] struct { is_some: bool, value: T }`.
+//! A `Result<T, E>` is laid out like `#[repr(C, u8)] enum { Err(E), Ok(T) }`: an
+//! `is_ok` flag followed by a union of the two values.
+//! A struct is moved instead of copied unless it has `#[derive(Clone, Copy)]`.
+//! References to other types than slices are only parameters and locals, a value can't contain
+//! them, so that they never outlive the referenced local.
+
+use quote::ToTokens;
+use syn::{Attribute, Expr, ExprLit, Fields, GenericArgument, ItemStruct, Lit, Meta, NestedMeta, Path, PathArguments, ReturnType, Type};
+use codegen::FnSignature;
+use compiler::{AssembleError, AssembleFunctionError, Ret, get_return_type_outer};
+use resolve::{Def, ModuleId, ModuleTree, Namespace, Vis, ROOT_MODULE};
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct StructId(pub usize);
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct FnTypeId(pub usize);
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct SliceId(pub usize);
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct RefId(pub usize);
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct OptionId(pub usize);
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ResultId(pub usize);
+
+/// `&[elem]` or `&mut [elem]`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SliceType {
+    pub elem: Ret,
+    pub mutable: bool,
+}
+
+/// `&elem` or `&mut elem`, the address of the value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RefType {
+    pub elem: Ret,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StructKind {
+    /// `struct Hit { dmg: u32 }`
+    Named,
+    /// `struct Money(u64);`
+    Tuple,
+    /// `struct Marker;`
+    Unit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Fields of tuple structs are named `0`, `1`, ...
+    pub name: String,
+    pub ty: Ret,
+    pub offset: i32,
+    pub vis: Vis,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructDef {
+    pub name: String,
+    pub module: ModuleId,
+    pub kind: StructKind,
+    pub fields: Vec<Field>,
+    pub size: i32,
+    pub align: i32,
+    /// `#[derive(Clone)]`
+    pub clone: bool,
+    /// `#[derive(Copy)]`, a tuple or array is `Copy` if its elements are
+    pub copy: bool,
+}
+
+impl StructDef {
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TypeTable {
+    structs: Vec<StructDef>,
+    fn_types: Vec<FnSignature>,
+    /// Types that stand for closures until their type is inferred, see `placeholder_fn_type`
+    placeholder_fn_types: Vec<FnTypeId>,
+    slices: Vec<SliceType>,
+    refs: Vec<RefType>,
+    /// The element types of the anonymous tuple structs
+    tuples: Vec<(Vec<Ret>, StructId)>,
+    /// The element types and lengths of the anonymous array structs
+    arrays: Vec<(Ret, usize, StructId)>,
+    /// The value types of `Option`s
+    options: Vec<Ret>,
+    /// The value and error types of `Result`s
+    results: Vec<(Ret, Ret)>,
+}
+
+/// Whether one of the `#[derive(..)]` attributes names the trait
+fn derives(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().filter_map(|attr| attr.interpret_meta()).any(|meta| match meta {
+        Meta::List(ref list) if list.ident == "derive" => list.nested.iter().any(|nested| match *nested {
+            NestedMeta::Meta(Meta::Word(ref ident)) => ident == name,
+            _ => false,
+        }),
+        _ => false,
+    })
+}
+
+fn align_up(value: i32, align: i32) -> i32 {
+    (value + align - 1) / align * align
+}
+
+impl TypeTable {
+
+    /// Returns the type of a function pointer with the given signature
+    pub fn fn_type(&mut self, signature: FnSignature) -> Ret {
+        let placeholders = &self.placeholder_fn_types;
+        let found = self.fn_types.iter().enumerate().position(|(i, s)| *s == signature && !placeholders.contains(&FnTypeId(i)));
+        let id = match found {
+            Some(index) => index,
+            None => {
+                self.fn_types.push(signature);
+                self.fn_types.len() - 1
+            },
+        };
+        Ret::FnPtr(FnTypeId(id))
+    }
+
+    /// Returns a function pointer type that is different from every other one, for a closure
+    /// whose argument types are inferred from a later use
+    pub fn placeholder_fn_type(&mut self) -> FnTypeId {
+        self.fn_types.push(FnSignature { arguments: Vec::new(), return_type: Ret::Void });
+        let id = FnTypeId(self.fn_types.len() - 1);
+        self.placeholder_fn_types.push(id);
+        id
+    }
+
+    pub fn signature(&self, id: FnTypeId) -> &FnSignature {
+        &self.fn_types[id.0]
+    }
+
+    /// Returns the type of a slice of `elem`
+    pub fn slice_type(&mut self, elem: Ret, mutable: bool) -> Ret {
+        let slice = SliceType { elem, mutable };
+        let id = match self.slices.iter().position(|s| *s == slice) {
+            Some(index) => index,
+            None => {
+                self.slices.push(slice);
+                self.slices.len() - 1
+            },
+        };
+        Ret::Slice(SliceId(id))
+    }
+
+    pub fn slice(&self, id: SliceId) -> SliceType {
+        self.slices[id.0]
+    }
+
+    /// Returns the type of a reference to `elem`
+    pub fn ref_type(&mut self, elem: Ret, mutable: bool) -> Ret {
+        let reference = RefType { elem, mutable };
+        let id = match self.refs.iter().position(|r| *r == reference) {
+            Some(index) => index,
+            None => {
+                self.refs.push(reference);
+                self.refs.len() - 1
+            },
+        };
+        Ret::Ref(RefId(id))
+    }
+
+    pub fn reference(&self, id: RefId) -> RefType {
+        self.refs[id.0]
+    }
+
+    /// Whether a value of the type is or contains a reference to something else than a slice
+    pub fn contains_ref(&self, ty: Ret) -> bool {
+        match ty {
+            Ret::Ref(_) => true,
+            Ret::Struct(id) => self.struct_def(id).fields.iter().any(|f| self.contains_ref(f.ty)),
+            Ret::Option(id) => self.contains_ref(self.option(id)),
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                self.contains_ref(ok) || self.contains_ref(err)
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether a value of the type is or contains a slice
+    pub fn contains_slice(&self, ty: Ret) -> bool {
+        match ty {
+            Ret::Slice(_) => true,
+            Ret::Struct(id) => match self.array(id) {
+                Some((elem, _)) => self.contains_slice(elem),
+                None => self.struct_def(id).fields.iter().any(|f| self.contains_slice(f.ty)),
+            },
+            Ret::Option(id) => self.contains_slice(self.option(id)),
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                self.contains_slice(ok) || self.contains_slice(err)
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns the anonymous tuple struct with the given element types
+    pub fn tuple_type(&mut self, elems: Vec<Ret>) -> Ret {
+        if let Some(&(_, id)) = self.tuples.iter().find(|t| t.0 == elems) {
+            return Ret::Struct(id);
+        }
+        let names = elems.iter().map(|elem| self.type_name(*elem)).collect::<Vec<_>>();
+        let name = if names.len() == 1 { format!("({},)", names[0]) } else { format!("({})", names.join(", ")) };
+        let id = StructId(self.structs.len());
+        self.structs.push(StructDef {
+            name, module: ROOT_MODULE, kind: StructKind::Tuple, fields: Vec::new(), size: 0, align: 1, clone: false, copy: false,
+        });
+        self.tuples.push((elems, id));
+        self.layout_tuple(id);
+        Ret::Struct(id)
+    }
+
+    /// The element types, if the struct is a tuple
+    pub fn tuple_elements(&self, id: StructId) -> Option<&[Ret]> {
+        self.tuples.iter().find(|t| t.1 == id).map(|t| &*t.0)
+    }
+
+    /// Computes the layout of a tuple, again if its elements contain a struct that wasn't laid out before
+    fn layout_tuple(&mut self, id: StructId) {
+        let elems = self.tuple_elements(id).expect("not a tuple").to_vec();
+        let mut fields = Vec::new();
+        let mut offset = 0;
+        let mut align = 1;
+        for (index, ty) in elems.into_iter().enumerate() {
+            let field_align = self.align_of(ty);
+            offset = align_up(offset, field_align);
+            align = align.max(field_align);
+            fields.push(Field { name: index.to_string(), ty, offset, vis: Vis::Public });
+            offset += self.size_of(ty);
+        }
+        let def = &mut self.structs[id.0];
+        def.fields = fields;
+        def.size = align_up(offset, align);
+        def.align = align;
+    }
+
+    /// Returns the anonymous struct of an array of `len` elements, or `None` if it is too large
+    pub fn array_type(&mut self, elem: Ret, len: usize) -> Option<Ret> {
+        if let Some(&(_, _, id)) = self.arrays.iter().find(|a| a.0 == elem && a.1 == len) {
+            return Some(Ret::Struct(id));
+        }
+        // the offsets of the fields are `i32`s
+        (self.size_of(elem) as i64).checked_mul(len as i64).filter(|size| *size <= i64::from(i32::MAX))?;
+        let name = format!("[{}; {}]", self.type_name(elem), len);
+        let id = StructId(self.structs.len());
+        self.structs.push(StructDef {
+            name, module: ROOT_MODULE, kind: StructKind::Tuple, fields: Vec::new(), size: 0, align: 1, clone: false, copy: false,
+        });
+        self.arrays.push((elem, len, id));
+        self.layout_array(id);
+        Some(Ret::Struct(id))
+    }
+
+    /// The element type and the length, if the struct is an array
+    pub fn array(&self, id: StructId) -> Option<(Ret, usize)> {
+        self.arrays.iter().find(|a| a.2 == id).map(|a| (a.0, a.1))
+    }
+
+    /// Computes the layout of an array, again if its element is a struct that wasn't laid out before
+    fn layout_array(&mut self, id: StructId) {
+        let (elem, len) = self.array(id).expect("not an array");
+        let size = self.size_of(elem);
+        let fields = (0..len).map(|index| {
+            Field { name: format!("[{}]", index), ty: elem, offset: index as i32 * size, vis: Vis::Public }
+        }).collect();
+        let align = self.align_of(elem);
+        let def = &mut self.structs[id.0];
+        def.fields = fields;
+        def.size = size * len as i32;
+        def.align = align;
+    }
+
+    /// Returns the type of an `Option<value>`
+    pub fn option_type(&mut self, value: Ret) -> Ret {
+        let id = match self.options.iter().position(|o| *o == value) {
+            Some(index) => index,
+            None => {
+                self.options.push(value);
+                self.options.len() - 1
+            },
+        };
+        Ret::Option(OptionId(id))
+    }
+
+    /// The type of the value of an option
+    pub fn option(&self, id: OptionId) -> Ret {
+        self.options[id.0]
+    }
+
+    /// Offset of the value in an option, the flag is the first byte
+    pub fn option_value_offset(&self, id: OptionId) -> i32 {
+        self.align_of(self.option(id))
+    }
+
+    /// Returns the type of a `Result<ok, err>`
+    pub fn result_type(&mut self, ok: Ret, err: Ret) -> Ret {
+        let id = match self.results.iter().position(|r| *r == (ok, err)) {
+            Some(index) => index,
+            None => {
+                self.results.push((ok, err));
+                self.results.len() - 1
+            },
+        };
+        Ret::Result(ResultId(id))
+    }
+
+    /// The value and the error type of a result
+    pub fn result(&self, id: ResultId) -> (Ret, Ret) {
+        self.results[id.0]
+    }
+
+    /// Offset of the value or the error in a result, the flag is the first byte
+    pub fn result_payload_offset(&self, id: ResultId) -> i32 {
+        let (ok, err) = self.result(id);
+        self.align_of(ok).max(self.align_of(err))
+    }
+
+    pub fn struct_def(&self, id: StructId) -> &StructDef {
+        &self.structs[id.0]
+    }
+
+    /// Whether values of the type are copied instead of moved. Slices are always copied,
+    /// passing a `&mut [T]` reborrows it.
+    pub fn is_copy(&self, ty: Ret) -> bool {
+        match ty {
+            Ret::Struct(id) => match (self.tuple_elements(id), self.array(id)) {
+                (Some(elems), _) => elems.iter().all(|elem| self.is_copy(*elem)),
+                (_, Some((elem, _))) => self.is_copy(elem),
+                _ => self.struct_def(id).copy,
+            },
+            Ret::Option(id) => self.is_copy(self.option(id)),
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                self.is_copy(ok) && self.is_copy(err)
+            },
+            _ => true,
+        }
+    }
+
+    /// Whether values of the type have a `clone` method
+    pub fn is_clone(&self, ty: Ret) -> bool {
+        match ty {
+            Ret::Struct(id) => match (self.tuple_elements(id), self.array(id)) {
+                (Some(elems), _) => elems.iter().all(|elem| self.is_clone(*elem)),
+                (_, Some((elem, _))) => self.is_clone(elem),
+                _ => self.struct_def(id).clone,
+            },
+            Ret::Option(id) => self.is_clone(self.option(id)),
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                self.is_clone(ok) && self.is_clone(err)
+            },
+            _ => true,
+        }
+    }
+
+    pub fn size_of(&self, ty: Ret) -> i32 {
+        match ty {
+            Ret::Struct(id) => self.struct_def(id).size,
+            Ret::Option(id) => {
+                let align = self.align_of(ty);
+                align_up(self.option_value_offset(id) + self.size_of(self.option(id)), align)
+            },
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                let align = self.align_of(ty);
+                align_up(self.result_payload_offset(id) + self.size_of(ok).max(self.size_of(err)), align)
+            },
+            _ => ty.size(),
+        }
+    }
+
+    pub fn align_of(&self, ty: Ret) -> i32 {
+        match ty {
+            Ret::Struct(id) => self.struct_def(id).align,
+            Ret::Vec(v) => v.align(),
+            Ret::Quat | Ret::Mat(_) | Ret::Simd(_) => 16,
+            // like `__int128`
+            Ret::Int(i) if i.size() == 16 => 16,
+            Ret::Option(id) => self.align_of(self.option(id)),
+            Ret::Result(id) => self.result_payload_offset(id),
+            _ => ty.size().clamp(1, 8),
+        }
+    }
+
+    /// Name of the type as it would be written in the script, for error messages
+    pub fn type_name(&self, ty: Ret) -> String {
+        match ty {
+            Ret::Struct(id) => self.struct_def(id).name.clone(),
+            Ret::Slice(id) => {
+                let slice = self.slice(id);
+                let mutability = if slice.mutable { "mut " } else { "" };
+                format!("&{}[{}]", mutability, self.type_name(slice.elem))
+            },
+            Ret::Ref(id) => {
+                let reference = self.reference(id);
+                let mutability = if reference.mutable { "mut " } else { "" };
+                format!("&{}{}", mutability, self.type_name(reference.elem))
+            },
+            Ret::Option(id) => format!("Option<{}>", self.type_name(self.option(id))),
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                format!("Result<{}, {}>", self.type_name(ok), self.type_name(err))
+            },
+            _ => format!("{:?}", ty),
+        }
+    }
+
+    /// Resolves a type that is written in module `module`, which may be a reference if it is the
+    /// type of a parameter or a local
+    pub fn resolve(&mut self, modules: &ModuleTree, module: ModuleId, ty: &Type) -> Result<Ret, AssembleError> {
+        if let Some(ret) = get_return_type_outer(Some(ty)) {
+            return Ok(ret);
+        }
+
+        let unsupported = || -> AssembleError {
+            AssembleFunctionError::UnsupportedType(ty.into_token_stream().to_string()).into()
+        };
+
+        match *ty {
+            // `fn(u32) -> u32` and `extern "C" fn(u32) -> u32` are the same, scripts always use the System V ABI
+            Type::BareFn(ref f) if f.lifetimes.is_none() && f.variadic.is_none() => {
+                let mut arguments = Vec::new();
+                for arg in f.inputs.iter() {
+                    arguments.push(self.resolve(modules, module, &arg.ty)?);
+                }
+                let return_type = match f.output {
+                    ReturnType::Default => Ret::Void,
+                    ReturnType::Type(_, ref t) => self.resolve_value(modules, module, t)?,
+                };
+                Ok(self.fn_type(FnSignature { arguments, return_type }))
+            },
+            Type::Path(ref p) if p.qself.is_none() && is_prelude_path(modules, module, &p.path, "Option") => {
+                match p.path.segments[0].arguments {
+                    PathArguments::AngleBracketed(ref a) if a.args.len() == 1 => match a.args[0] {
+                        GenericArgument::Type(ref value) => {
+                            let value = self.resolve_value(modules, module, value)?;
+                            Ok(self.option_type(value))
+                        },
+                        _ => Err(unsupported()),
+                    },
+                    _ => Err(unsupported()),
+                }
+            },
+            Type::Path(ref p) if p.qself.is_none() && is_prelude_path(modules, module, &p.path, "Result") => {
+                match p.path.segments[0].arguments {
+                    PathArguments::AngleBracketed(ref a) if a.args.len() == 2 => match (&a.args[0], &a.args[1]) {
+                        (GenericArgument::Type(ok), GenericArgument::Type(err)) => {
+                            let ok = self.resolve_value(modules, module, ok)?;
+                            let err = self.resolve_value(modules, module, err)?;
+                            Ok(self.result_type(ok, err))
+                        },
+                        _ => Err(unsupported()),
+                    },
+                    _ => Err(unsupported()),
+                }
+            },
+            Type::Path(ref p) if p.qself.is_none() => {
+                match modules.resolve_path(module, &p.path, Namespace::Type) {
+                    Ok(Def::Struct(id)) => Ok(Ret::Struct(id)),
+                    // unknown single names are most likely primitive types that aren't supported
+                    Err(AssembleError::UnresolvedPath(_)) if p.path.segments.len() == 1 => Err(unsupported()),
+                    Err(e) => Err(e),
+                    Ok(_) => Err(unsupported()),
+                }
+            },
+            Type::Reference(ref r) => match *r.elem {
+                Type::Slice(ref slice) => {
+                    let elem = self.resolve_value(modules, module, &slice.elem)?;
+                    Ok(self.slice_type(elem, r.mutability.is_some()))
+                },
+                // `&array` is a slice
+                Type::Array(_) => Err(unsupported()),
+                ref elem => {
+                    let elem = self.resolve_value(modules, module, elem)?;
+                    Ok(self.ref_type(elem, r.mutability.is_some()))
+                },
+            },
+            Type::Tuple(ref t) => {
+                let mut elems = Vec::new();
+                for elem in t.elems.iter() {
+                    elems.push(self.resolve_value(modules, module, elem)?);
+                }
+                Ok(self.tuple_type(elems))
+            },
+            // `[u32; 4]`, the length is an integer literal
+            Type::Array(ref a) => {
+                let elem = self.resolve_value(modules, module, &a.elem)?;
+                let len = match a.len {
+                    Expr::Lit(ExprLit { lit: Lit::Int(ref len), .. }) => len.value() as usize,
+                    _ => return Err(unsupported()),
+                };
+                self.array_type(elem, len).ok_or_else(unsupported)
+            },
+            Type::Paren(ref p) => self.resolve(modules, module, &p.elem),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// Resolves the type of a value that can be returned or stored, which doesn't contain references
+    pub fn resolve_value(&mut self, modules: &ModuleTree, module: ModuleId, ty: &Type) -> Result<Ret, AssembleError> {
+        match self.resolve(modules, module, ty)? {
+            Ret::Ref(_) => Err(AssembleFunctionError::UnsupportedType(ty.into_token_stream().to_string()).into()),
+            ret => Ok(ret),
+        }
+    }
+
+    /// Registers a struct declaration, its fields are resolved later by `layout_structs`
+    pub fn declare_struct(&mut self, s: &ItemStruct, module: ModuleId) -> Result<StructId, AssembleError> {
+        if !s.generics.params.is_empty() {
+            return Err(AssembleFunctionError::UnsupportedType(s.into_token_stream().to_string()).into());
+        }
+        let kind = match s.fields {
+            Fields::Named(_) => StructKind::Named,
+            Fields::Unnamed(_) => StructKind::Tuple,
+            Fields::Unit => StructKind::Unit,
+        };
+        let (clone, copy) = (derives(&s.attrs, "Clone"), derives(&s.attrs, "Copy"));
+        self.structs.push(StructDef { name: s.ident.to_string(), module, kind, fields: Vec::new(), size: 0, align: 1, clone, copy });
+        Ok(StructId(self.structs.len() - 1))
+    }
+
+    /// Resolves the field types of all declared structs and computes their layout.
+    /// `items` contains the declaration of every struct, indexed by `StructId`.
+    pub fn layout_structs(&mut self, modules: &ModuleTree, items: &[ItemStruct]) -> Result<(), AssembleError> {
+        let mut state = vec![LayoutState::Pending; items.len()];
+        for index in 0..items.len() {
+            self.layout_struct(modules, items, StructId(index), &mut state)?;
+        }
+        Ok(())
+    }
+
+    fn layout_struct(&mut self, modules: &ModuleTree, items: &[ItemStruct], id: StructId, state: &mut [LayoutState])
+    -> Result<(), AssembleError>
+    {
+        match state[id.0] {
+            LayoutState::Done => return Ok(()),
+            // a struct that contains itself by value would be infinitely large
+            LayoutState::InProgress => return Err(AssembleError::RecursiveType(self.structs[id.0].name.clone())),
+            LayoutState::Pending => { },
+        }
+        state[id.0] = LayoutState::InProgress;
+
+        let module = self.structs[id.0].module;
+        let mut fields = Vec::new();
+        let mut offset = 0;
+        let mut align = 1;
+
+        for (index, field) in items[id.0].fields.iter().enumerate() {
+            let ty = self.resolve_value(modules, module, &field.ty)?;
+            self.layout_field_type(modules, items, ty, state)?;
+            let field_align = self.align_of(ty);
+            offset = align_up(offset, field_align);
+            align = align.max(field_align);
+            let name = match field.ident {
+                Some(ref ident) => ident.to_string(),
+                None => index.to_string(),
+            };
+            let vis = modules.visibility(module, &field.vis)?;
+            fields.push(Field { name, ty, offset, vis });
+            offset += self.size_of(ty);
+        }
+
+        let def = &self.structs[id.0];
+        let clone_fields = fields.iter().all(|field| self.is_clone(field.ty));
+        let copy_fields = fields.iter().all(|field| self.is_copy(field.ty));
+        if (def.clone && !clone_fields) || (def.copy && !(def.clone && copy_fields)) {
+            return Err(AssembleError::InvalidDerive(def.name.clone()));
+        }
+        let def = &mut self.structs[id.0];
+        def.fields = fields;
+        def.size = align_up(offset, align);
+        def.align = align;
+        state[id.0] = LayoutState::Done;
+        Ok(())
+    }
+}
+
+impl TypeTable {
+    /// Lays out the structs that a field of type `ty` contains by value
+    fn layout_field_type(&mut self, modules: &ModuleTree, items: &[ItemStruct], ty: Ret, state: &mut [LayoutState])
+    -> Result<(), AssembleError>
+    {
+        match ty {
+            Ret::Struct(id) => match (self.tuple_elements(id).map(|elems| elems.to_vec()), self.array(id)) {
+                (Some(elems), _) => {
+                    for elem in elems {
+                        self.layout_field_type(modules, items, elem, state)?;
+                    }
+                    self.layout_tuple(id);
+                },
+                (_, Some((elem, _))) => {
+                    self.layout_field_type(modules, items, elem, state)?;
+                    self.layout_array(id);
+                },
+                _ => self.layout_struct(modules, items, id, state)?,
+            },
+            Ret::Option(id) => {
+                let value = self.option(id);
+                self.layout_field_type(modules, items, value, state)?;
+            },
+            Ret::Result(id) => {
+                let (ok, err) = self.result(id);
+                self.layout_field_type(modules, items, ok, state)?;
+                self.layout_field_type(modules, items, err, state)?;
+            },
+            _ => { },
+        }
+        Ok(())
+    }
+}
+
+/// `Option<T>` or `Result<T, E>`, which are known to the compiler without an import,
+/// unless the script declares a type with the same name
+fn is_prelude_path(modules: &ModuleTree, module: ModuleId, path: &Path, name: &str) -> bool {
+    path.leading_colon.is_none() && path.segments.len() == 1 && path.segments[0].ident == name
+        && modules.resolve_path(module, path, Namespace::Type).is_err()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum LayoutState {
+    Pending,
+    InProgress,
+    Done,
+}
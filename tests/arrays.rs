@@ -0,0 +1,152 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::{build, err};
+
+#[test]
+fn literals_and_indexing() {
+    let j = build("#[start] fn main() -> u64 {
+            let xs = [1u32, 2, 3];
+            let zeros = [0u8; 5];
+            let fs: [f32; 2] = [0.5, 1.5];
+            let nested = [[1u64, 2], [3, 4]];
+            let i = 2;
+            xs[i] as u64 * 1000 + zeros.len() as u64 * 100 + (fs[1] * 10.0) as u64 + nested[1][0] * nested[0][1]
+        }").unwrap();
+    assert_eq!(j.call::<u64>(), Ok(3000 + 500 + 15 + 6));
+
+    let j = build("#[export] fn get(i: u64) -> u32 { let xs = [10u32, 20, 30]; xs[i] }").unwrap();
+    let get = j.export("get").unwrap();
+    assert_eq!(j.call_export::<_, u32>(get, (1u64,)), Ok(20));
+    match j.call_export::<_, u32>(get, (3u64,)) {
+        Err(ScriptError::IndexOutOfBounds(l)) => assert_eq!(l.expression, "xs [ i ]"),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn values() {
+    let j = build("#[derive(Clone, Copy)] struct P { x: i32, y: i32 }
+        fn flip(ps: [P; 2]) -> [P; 2] { [ps[1], ps[0]] }
+        fn total(xs: &[i32]) -> i32 { let mut t = 0; for x in xs { t += *x; } t }
+        fn double(xs: &mut [i32]) { for x in xs.iter_mut() { *x *= 2; } }
+        #[start] fn main() -> i32 {
+            let ps = flip([P { x: 1, y: 2 }, P { x: 3, y: 4 }]);
+            let mut xs = [5; 3];
+            double(&mut xs);
+            let copy = xs;
+            ps[0].x * 1000 + ps[1].y * 100 + total(&copy) + total(&[1, 2]) + copy.is_empty() as i32
+        }").unwrap();
+    assert_eq!(j.call::<i32>(), Ok(3000 + 200 + 30 + 3));
+}
+
+#[test]
+fn loops() {
+    let j = build("#[start] fn main() -> u32 {
+            let mut xs = [1u32, 2, 3, 4];
+            for x in &mut xs { *x += 10; }
+            for x in xs.iter_mut().rev() { *x *= 2; }
+            let mut total = 0;
+            for (i, x) in xs.iter().enumerate() { total += i as u32 * *x; }
+            for x in xs { total += x; }
+            total + xs.iter().sum::<u32>()
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(24 + 2 * 26 + 3 * 28 + 2 * (22 + 24 + 26 + 28)));
+}
+
+#[test]
+fn statics() {
+    let j = build("static TABLE: [u16; 4] = [1, 10, 100, 1000];
+        static mut COUNTS: [u32; 3] = [0; 3];
+        fn sum(xs: &[u16]) -> u32 { let mut t = 0; for x in xs { t += *x as u32; } t }
+        #[start] fn main() -> u32 {
+            unsafe { for c in &mut COUNTS { *c += 2; } }
+            let counted = unsafe { COUNTS[0] + COUNTS[2] };
+            sum(&TABLE) + TABLE[3] as u32 + counted
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(1111 + 1000 + 4));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("#[start] fn main() -> u32 { let xs = []; 0 }"), AssembleFunctionError::TypeAnnotationNeeded(_)));
+    assert!(matches!(err("#[start] fn main() -> u32 { let xs = [1u32, 2u8]; 0 }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("struct T(u32); #[start] fn main() -> u32 { let ts = [T(1); 2]; 0 }"), AssembleFunctionError::UseOfMovedValue { .. }));
+    assert!(matches!(err("fn f(xs: &[u32; 2]) {} #[start] fn main() -> u32 { 0 }"), AssembleFunctionError::UnsupportedType(_)));
+    assert!(matches!(err("#[start] fn main() -> u32 { let xs = [1u32, 2]; for x in &mut xs {} 0 }"), AssembleFunctionError::BorrowOfImmutable { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; for x in &mut xs { xs[0]; } 0 }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; for x in &xs { xs = [3, 4]; } 0 }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; let s = &mut xs; let t = &xs; 0 }"), AssembleFunctionError::OverlappingBorrow { .. }));
+    assert!(matches!(err("static T: [u32; 2] = [1, 2]; #[start] fn main() -> u32 { let s = &mut T; 0 }"), AssembleFunctionError::BorrowOfImmutable { .. }));
+    assert!(matches!(err("static T: [u32; 2] = [1]; #[start] fn main() -> u32 { 0 }"), AssembleFunctionError::UnsupportedExpression(_)));
+}
+
+#[test]
+fn dangling_slices() {
+    for src in &[
+        "let s = { let xs = [1u32, 2]; &xs }; s[0]",
+        "let s = { let xs = [1u32, 2]; let t = &xs; t }; s[0]",
+        "let s: &[u32] = { &[1, 2] }; s[0]",
+        "let mut s: &[u32] = &[0]; { let xs = [1u32, 2]; s = &xs; } s[0]",
+    ] {
+        match build(&format!("#[start] fn main() -> u32 {{ {} }}", src)) {
+            Err(AssembleError::FunctionError(AssembleFunctionError::DanglingReference { .. })) => {},
+            r => panic!("{}: {:?}", src, r.map(|_| ())),
+        }
+    }
+    assert!(matches!(err("fn f() -> &[u32] { let xs = [1u32]; &xs } #[start] fn main() -> u32 { f()[0] }"), AssembleFunctionError::DanglingReference { .. }));
+}
+
+#[test]
+fn readme() {
+    let j = build("static WEIGHTS: [f32; 3] = [0.5, 0.3, 0.2];
+        fn smooth(history: [f32; 3]) -> f32 {
+            let mut total = 0.0;
+            for (w, h) in WEIGHTS.iter().zip(&history) {
+                total += *w * *h;
+            }
+            total
+        }
+        #[start] fn main() -> f32 { smooth([10.0, 20.0, 30.0]) }").unwrap();
+    assert_eq!(j.call::<f32>(), Ok(5.0 + 6.0 + 6.0));
+}
+
+#[test]
+fn subslices() {
+    let j = build("fn total(xs: &[u32]) -> u32 { xs.iter().sum() }
+        #[export] fn window(xs: &[u32], start: u64, end: u64) -> u32 { total(&xs[start..end]) }
+        #[export] fn inclusive(xs: &[u32], last: i32) -> u32 { total(&xs[..=last]) }
+        #[export] fn zero_tail(xs: &mut [u32], start: usize) { for x in &mut xs[start..] { *x = 0; } }
+        #[start] fn main() -> u32 {
+            let mut xs = [1u32, 2, 3, 4, 5];
+            {
+                let middle = &mut xs[1..4];
+                middle[0] = 20;
+            }
+            total(&xs[..2]) * 1000 + total(&xs[3..]) * 10 + (&xs[..]).len() as u32
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(21_000 + 90 + 5));
+    let f = |name: &str| j.export(name).unwrap();
+    let xs = [1u32, 2, 3, 4];
+    assert_eq!(j.call_export::<_, u32>(f("window"), (&xs[..], 1u64, 3u64)), Ok(5));
+    assert_eq!(j.call_export::<_, u32>(f("window"), (&xs[..], 4u64, 4u64)), Ok(0));
+    assert_eq!(j.call_export::<_, u32>(f("inclusive"), (&xs[..], 1)), Ok(3));
+    for (start, end) in [(3u64, 2u64), (0, 5), (5, 5)] {
+        match j.call_export::<_, u32>(f("window"), (&xs[..], start, end)) {
+            Err(ScriptError::IndexOutOfBounds(l)) => assert_eq!(l.expression, "xs [ start .. end ]"),
+            r => panic!("{}..{}: {:?}", start, end, r),
+        }
+    }
+    assert!(matches!(j.call_export::<_, u32>(f("inclusive"), (&xs[..], 4)), Err(ScriptError::IndexOutOfBounds(_))));
+    assert!(matches!(j.call_export::<_, u32>(f("inclusive"), (&xs[..], -1)), Err(ScriptError::IndexOutOfBounds(_))));
+    let mut ys = [1u32, 2, 3];
+    assert_eq!(j.call_export::<_, ()>(f("zero_tail"), (&mut ys[..], 1usize)), Ok(()));
+    assert_eq!(ys, [1, 0, 0]);
+
+    assert!(matches!(err("fn f(xs: &[u32]) { let s = &mut xs[1..]; } #[start] fn main() -> u32 { 0 }"), AssembleFunctionError::BorrowOfImmutable { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let xs = [1u32, 2]; let s = &mut xs[1..]; 0 }"), AssembleFunctionError::BorrowOfImmutable { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; let s = &mut xs[1..]; xs[0] }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let s = { let xs = [1u32, 2]; &xs[1..] }; s[0] }"), AssembleFunctionError::DanglingReference { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; for x in &mut xs[1..] { let y = 1; xs[0]; } 0 }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+}
@@ -0,0 +1,234 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::{build, build_mode, err};
+
+#[test]
+fn locals_and_fields() {
+    let j = build("struct State { timer: f32, pos: Vec3, hits: u8, inner: Inner }
+        struct Inner(i64, bool);
+        #[start] fn main() -> f32 {
+            let mut s = State { timer: 10.0, pos: Vec3::new(1.0, 2.0, 3.0), hits: 250, inner: Inner(5, false) };
+            let dt = 0.5;
+            s.timer -= dt;
+            s.timer *= 2.0;
+            s.pos.y += 10.0;
+            s.pos *= 2.0;
+            s.hits += 10;
+            s.inner.0 <<= 3;
+            s.inner.0 -= 1;
+            s.inner.0 %= 7;
+            s.inner.1 |= true;
+            let mut x = 3u32;
+            x = x * 4;
+            x ^= 1;
+            x /= 2;
+            let mut t = (1u8, 2i16);
+            t.1 -= 5;
+            t = (t.0 + 1, t.1 * 2);
+            s.timer + s.pos.y + s.hits as f32 + s.inner.0 as f32 + if s.inner.1 { 100.0 } else { 0.0 } + x as f32 + t.0 as f32 + t.1 as f32
+        }").unwrap();
+    // 19 + 24 + 4 + (39 % 7 = 4) + 100 + 6 + 2 - 6
+    assert_eq!(j.call::<f32>(), Ok(19.0 + 24.0 + 4.0 + 4.0 + 100.0 + 6.0 + 2.0 - 6.0));
+}
+
+#[test]
+fn slices_refs_and_statics() {
+    let j = build("static mut COUNTER: u32 = 0;
+        static LIMIT: i16 = -3;
+        static mut SCALE: f64 = 1.5;
+        fn sum(s: &[u8]) -> u32 { let n = s.len(); if n == 0 { 0 } else { s[0] as u32 + s[n - 1] as u32 } }
+        #[start] fn main() -> u32 {
+            unsafe { COUNTER += 1; }
+            unsafe { SCALE *= 2.0 };
+            let mut pair = (1u32, 2u32);
+            let (ref mut a, _) = pair;
+            *a += 40;
+            *a = *a * 2;
+            let counter = unsafe { COUNTER };
+            counter * 1000 + pair.0 + sum(b\"az\") + (LIMIT + 3) as u32 + unsafe { SCALE as u32 }
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(1000 + 82 + 97 + 122 + 3));
+    assert_eq!(j.call::<u32>(), Ok(2000 + 82 + 97 + 122 + 6));
+
+    let j = build("#[repr(C)] struct P { x: i32, y: i32 }
+        #[export] fn fill(out: &mut [u16], v: u16) -> u16 {
+            out[0] = v;
+            out[1] += v;
+            out[2] *= 3;
+            out[0] + out[1] + out[2]
+        }
+        #[export] fn shift(ps: &mut [P]) -> i32 { ps[1].x -= 10; ps[1] = P { x: ps[1].x, y: 7 }; ps[0].y + ps[1].x + ps[1].y }
+        #[export] fn get(s: &[u8], i: u64) -> u8 { s[i] }").unwrap();
+    let mut data = [1u16, 2, 3];
+    let r = j.call_export::<_, u16>(j.export("fill").unwrap(), (&mut data[..], 5u16));
+    assert_eq!(r, Ok(5 + 7 + 9));
+    assert_eq!(data, [5, 7, 9]);
+    #[repr(C)] #[derive(Debug, PartialEq)] struct P { x: i32, y: i32 }
+    let mut ps = [P { x: 1, y: 2 }, P { x: 3, y: 4 }];
+    assert_eq!(j.call_export::<_, i32>(j.export("shift").unwrap(), (&mut ps[..],)), Ok(2 - 7 + 7));
+    assert_eq!(ps[1], P { x: -7, y: 7 });
+    let g = j.export("get").unwrap();
+    let bytes = b"abc";
+    assert_eq!(j.call_export::<_, u8>(g, (&bytes[..], 2u64)), Ok(b'c'));
+    match j.call_export::<_, u8>(g, (&bytes[..], 3u64)) {
+        Err(ScriptError::IndexOutOfBounds(l)) => assert_eq!(l.expression, "s [ i ]"),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn overflow_checks() {
+    let src = "#[export] fn add(a: u8, b: u8) -> u8 { let mut x = a; x += b; x }
+        #[export] fn sub(a: i32, b: i32) -> i32 { let mut x = (a, 0u8); x.0 -= b; x.0 }
+        #[export] fn div(a: i32, b: i32) -> i32 { let mut x = a; x /= b; x }";
+    let r = build_mode(src, CompileMode::Release).unwrap();
+    assert_eq!(r.call_export::<_, u8>(r.export("add").unwrap(), (200u8, 100u8)), Ok(44));
+    assert_eq!(r.call_export::<_, i32>(r.export("sub").unwrap(), (i32::MIN, 1)), Ok(i32::MAX));
+    assert!(matches!(r.call_export::<_, i32>(r.export("div").unwrap(), (1, 0)), Err(ScriptError::DivideByZero(_))));
+    let d = build_mode(src, CompileMode::Debug).unwrap();
+    assert!(matches!(d.call_export::<_, u8>(d.export("add").unwrap(), (200u8, 100u8)), Err(ScriptError::Overflow(_))));
+    assert_eq!(d.call_export::<_, u8>(d.export("add").unwrap(), (200u8, 50u8)), Ok(250));
+    assert!(matches!(d.call_export::<_, i32>(d.export("sub").unwrap(), (i32::MIN, 1)), Err(ScriptError::Overflow(_))));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("static X: u32 = 1; #[start] fn main() -> u32 { X = 2; X }"), AssembleFunctionError::AssignToImmutable { .. }));
+    assert!(matches!(err("fn f(s: &[u8]) { s[0] = 1; } #[start] fn main() -> u32 { 0 }"), AssembleFunctionError::AssignToImmutable { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut x = 1u32; x += 1.5; x }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("#[start] fn main() -> f32 { let mut v = Vec2::new(1.0, 2.0); v.z = 1.0; v.x }"), AssembleFunctionError::UnknownField { .. }));
+    assert!(matches!(err("static X: u8 = 300; #[start] fn main() -> u8 { X }"), AssembleFunctionError::LiteralOutOfRange { .. }));
+    assert!(matches!(err("static X: u8 = 3u32; #[start] fn main() -> u8 { X }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("fn f() -> u32 { 1 } static X: u32 = f(); #[start] fn main() -> u32 { X }"), AssembleFunctionError::UnsupportedExpression(_)));
+    assert!(matches!(err("#[start] fn main() -> u32 { 1 = 2; 0 }"), AssembleFunctionError::UnsupportedExpression(_)));
+}
+
+#[test]
+fn references() {
+    let j = build("#[derive(Clone, Copy)] struct Timer { remaining: f32, fired: u32 }
+        struct Unit { hp: i32, pos: Vec2 }
+        fn tick(timer: &mut Timer, dt: f32) -> bool {
+            timer.remaining -= dt;
+            if timer.remaining <= 0.0 {
+                timer.fired += 1;
+                timer.remaining = 2.5;
+            }
+            timer.fired > 1
+        }
+        fn hurt(hp: &mut i32, dmg: i32) { *hp -= dmg; if *hp < 0 { *hp = 0; } }
+        fn heal(unit: &mut Unit, amount: i32) { hurt(&mut unit.hp, -amount); unit.pos.x += 1.0; }
+        fn total(a: &u32, b: &u32) -> u32 { *a + *b }
+        fn swap(a: &mut u32, b: &mut u32) { let t = *a; *a = *b; *b = t; }
+        #[start] fn main() -> i64 {
+            let mut t = Timer { remaining: 1.0, fired: 0 };
+            let mut done = tick(&mut t, 0.5);
+            done = done || tick(&mut t, 0.75);
+            let mut hp = 10;
+            hurt(&mut hp, 3);
+            let mut u = Unit { hp: 5, pos: Vec2::new(0.0, 0.0) };
+            heal(&mut u, 4);
+            let (mut a, mut b) = (1u32, 2u32);
+            swap(&mut a, &mut b);
+            let r = &mut a;
+            *r *= 10;
+            let copy = *r;
+            let s = &b;
+            let view = &t;
+            t.fired as i64 * 1000000 + done as i64 * 100000 + hp as i64 * 1000 + u.hp as i64 * 100 + u.pos.x as i64 * 10
+                + total(&copy, s) as i64 + (view.remaining * 0.0) as i64
+        }").unwrap();
+    assert_eq!(j.call::<i64>(), Ok(1000000 + 7000 + 900 + 10 + 21));
+
+    // the host passes references to its own values
+    let j = build("struct P { x: i32, y: i32 }
+        #[export] fn bump(p: &mut P, n: &mut u64) -> i32 { p.x += 1; *n <<= 2; p.x + p.y }").unwrap();
+    #[repr(C)] struct P { x: i32, y: i32 }
+    let (mut p, mut n) = (P { x: 1, y: 5 }, 3u64);
+    let bump: extern "sysv64" fn(&mut P, &mut u64) -> i32 = unsafe { std::mem::transmute(j.export_address(j.export("bump").unwrap())) };
+    assert_eq!(bump(&mut p, &mut n), 7);
+    assert_eq!((p.x, n), (2, 12));
+}
+
+#[test]
+fn reference_errors() {
+    let header = "struct Big { n: u32 } fn set(a: &mut u32, b: u32) { *a = b; } fn both(a: &mut u32, b: &mut u32) { *a = *b; }";
+    type Check = fn(&AssembleFunctionError) -> bool;
+    let cases: [(&str, Check); 9] = [
+        ("let x = 1u32; set(&mut x, 2); x", |e| matches!(e, AssembleFunctionError::BorrowOfImmutable { .. })),
+        ("let mut x = 1u32; both(&mut x, &mut x); x", |e| matches!(e, AssembleFunctionError::OverlappingBorrow { .. })),
+        ("let mut x = 1u32; set(&mut x, x); x", |e| matches!(e, AssembleFunctionError::UseOfBorrowedValue { .. })),
+        ("let mut x = 1u32; let r = &mut x; let s = &x; *r", |e| matches!(e, AssembleFunctionError::OverlappingBorrow { .. })),
+        ("let mut x = 1u32; let ref y = x; set(&mut x, 2); *y", |e| matches!(e, AssembleFunctionError::OverlappingBorrow { .. })),
+        ("let x = 1u32; let r = &x; *r = 2; x", |e| matches!(e, AssembleFunctionError::AssignToImmutable { .. })),
+        ("let b = Big { n: 1 }; let r = &b; let c = *r; c.n", |e| matches!(e, AssembleFunctionError::MoveOutOfReference { .. })),
+        ("let b = Big { n: 1 }; let r = &b; let c = b; c.n", |e| matches!(e, AssembleFunctionError::UseOfBorrowedValue { .. })),
+        ("let f = |x: &mut u32| x; 0", |e| matches!(e, AssembleFunctionError::UnsupportedType(_))),
+    ];
+    for (body, check) in cases.iter() {
+        let src = format!("{} #[start] fn main() -> u32 {{ {} }}", header, body);
+        let e = err(&src);
+        assert!(check(&e), "{}: {:?}", body, e);
+    }
+    // references can't outlive the locals they refer to
+    for src in ["fn f(x: &mut u32) -> &mut u32 { x } #[start] fn main() -> u32 { 0 }",
+                "struct S { r: &u32 } #[start] fn main() -> u32 { 0 }",
+                "#[start] fn main() -> u32 { let o: Option<&u32> = None; 0 }"] {
+        assert!(matches!(build(src), Err(AssembleError::FunctionError(AssembleFunctionError::UnsupportedType(_)))), "{}", src);
+    }
+    // the borrow of a call argument ends with the statement
+    assert!(build(&format!("{} #[start] fn main() -> u32 {{ let mut x = 1u32; set(&mut x, 2); set(&mut x, 3); let y = &mut x; *y }}", header)).is_ok());
+}
+
+#[test]
+fn readme_references() {
+    let j = build("struct Unit { hp: u32, armor: u32 }
+        fn damage(hp: &mut u32, amount: u32) { *hp = hp.saturating_sub(amount); }
+        fn hit(unit: &mut Unit, amount: u32) -> u32 {
+            damage(&mut unit.hp, amount.saturating_sub(unit.armor));
+            unit.hp
+        }
+        #[start] fn main() -> u32 {
+            let mut unit = Unit { hp: 20, armor: 3 };
+            let first = hit(&mut unit, 10);
+            first * 100 + hit(&mut unit, 30)
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(1300));
+}
+
+#[test]
+fn array_elements() {
+    let j = build("static mut HISTORY: [u32; 4] = [0; 4];
+        struct Board { cells: [[u8; 3]; 3], moves: u32 }
+        fn play(board: &mut Board, x: u64, y: u64) { board.cells[y][x] = 1; board.moves += 1; }
+        #[export] fn set(i: u64) -> u32 {
+            let mut xs = [1u32, 2, 3];
+            xs[i] = 10;
+            xs[0] += 5;
+            xs[0] * 100 + xs[1] * 10 + xs[2]
+        }
+        #[start] fn main() -> u32 {
+            let mut board = Board { cells: [[0; 3]; 3], moves: 0 };
+            play(&mut board, 2, 1);
+            board.cells[0][0] += 2;
+            let mut grid = [[0u32; 2]; 2];
+            grid[1][0] = 7;
+            unsafe { HISTORY[3] = 4; HISTORY[3] *= 2; }
+            board.cells[1][2] as u32 * 1000 + board.cells[0][0] as u32 * 100 + grid[1][0] * 10 + unsafe { HISTORY[3] }
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(1000 + 200 + 70 + 8));
+    let set = j.export("set").unwrap();
+    assert_eq!(j.call_export::<_, u32>(set, (2u64,)), Ok(600 + 20 + 10));
+    match j.call_export::<_, u32>(set, (3u64,)) {
+        Err(ScriptError::IndexOutOfBounds(l)) => assert_eq!(l.expression, "xs [ i ]"),
+        r => panic!("{:?}", r),
+    }
+
+    assert!(matches!(err("#[start] fn main() -> u32 { let xs = [1u32]; xs[0] = 2; 0 }"), AssembleFunctionError::AssignToImmutable { .. }));
+    assert!(matches!(err("static XS: [u32; 1] = [1]; #[start] fn main() -> u32 { XS[0] = 2; 0 }"), AssembleFunctionError::AssignToImmutable { .. }));
+    assert!(matches!(err("static mut XS: [u32; 1] = [1]; #[start] fn main() -> u32 { XS[0] = 2; 0 }"), AssembleFunctionError::UseOfMutableStatic { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; let s = &xs; xs[1] = 3; s[0] }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let mut xs = [1u32, 2]; for x in &xs { xs[0] += 1; } 0 }"), AssembleFunctionError::UseOfBorrowedValue { .. }));
+    assert!(matches!(err("struct T(u32); fn take(ts: [T; 1]) {} #[start] fn main() -> u32 { let mut ts = [T(1)]; take(ts); ts[0] = T(2); 0 }"), AssembleFunctionError::UseOfMovedValue { .. }));
+}
@@ -0,0 +1,44 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+fn compile(body: &str, native: bool) -> JitMemory {
+    let mut o = CompileOptions::default();
+    if !native { o.features.popcnt = false; o.features.lzcnt = false; o.features.bmi1 = false; }
+    let buf = compile_with_options(parse_file(body).unwrap(), &o).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+macro_rules! check {
+    ($t:ty, $values:expr) => {
+        for native in [false, true] {
+            let j = compile(&format!("#[start] fn main(x: {0}, n: u32) -> u64 {{ (x.count_ones() as u64) | (x.leading_zeros() as u64) << 8 | (x.trailing_zeros() as u64) << 16 }}", stringify!($t)), native);
+            let f: extern "sysv64" fn($t, u32) -> u64 = unsafe { std::mem::transmute(j.run::<()>()) };
+            let j2 = compile(&format!("#[start] fn main(x: {0}, n: u32) -> {0} {{ x.swap_bytes() ^ x.rotate_left(n) }}", stringify!($t)), native);
+            let g: extern "sysv64" fn($t, u32) -> $t = unsafe { std::mem::transmute(j2.run::<()>()) };
+            let j3 = compile(&format!("#[start] fn main(x: {0}, n: u32) -> {0} {{ x.rotate_right(n) }}", stringify!($t)), native);
+            let h: extern "sysv64" fn($t, u32) -> $t = unsafe { std::mem::transmute(j3.run::<()>()) };
+            for &x in $values.iter() {
+                let x: $t = x as $t;
+                assert_eq!(f(x, 0), (x.count_ones() as u64) | (x.leading_zeros() as u64) << 8 | (x.trailing_zeros() as u64) << 16, "{} {}", x, native);
+                for n in [0u32, 1, 3, 7, 8, 15, 31, 33, 63, 64, 100] {
+                    assert_eq!(g(x, n), x.swap_bytes() ^ x.rotate_left(n), "{} {}", x, n);
+                    assert_eq!(h(x, n), x.rotate_right(n), "{} {}", x, n);
+                }
+            }
+        }
+    };
+}
+
+const V: [i64; 10] = [0, 1, -1, 2, 0x80, 0x1234_5678_9ABC_DEF0, i64::MIN, i64::MAX, 0x100, -256];
+
+#[test]
+fn bits() {
+    check!(u8, V); check!(i8, V); check!(u16, V); check!(i16, V); check!(u32, V); check!(i32, V); check!(u64, V); check!(i64, V);
+}
+
+#[test]
+fn inferred() {
+    let j = compile("#[start] fn main() -> u32 { let x = 0x10; let y: u8 = x; x.leading_zeros() + y.count_ones() }", false);
+    let f: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(), 3 + 1);
+}
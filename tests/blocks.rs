@@ -0,0 +1,106 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::build;
+
+#[test]
+fn block_values_and_shadowing() {
+    let j = build("#[start] fn main() -> i64 {
+            let x = { let a = 2; a * 3 };
+            let x = x + 1;
+            let y = {
+                let x = x * 10;
+                let x = { x + 1 };
+                x
+            };
+            let unit = { 5; };
+            let z = { let s = 0; let t = { let s = s + 100; s }; t + s };
+            x * 1000 + y + z
+        }").unwrap();
+    assert_eq!(j.call::<i64>(), Ok(7000 + 71 + 100));
+}
+
+#[test]
+fn memory_values_and_slot_reuse() {
+    let j = build("struct P { x: i64, y: i64 }
+        fn diff(a: P, b: P) -> i64 { (a.x - b.x) * 100 + a.y - b.y }
+        #[start] fn main() -> i64 {
+            let d = diff({ let p = P { x: 9, y: 8 }; p }, { let q = P { x: 1, y: 2 }; q });
+            let m = match d { 806 => { let r = P { x: 3, y: 4 }; r }, _ => P { x: 0, y: 0 } };
+            let mut total = 0;
+            { let t = 5; total += t; }
+            { let u = 7; total += u; }
+            if let Some(v) = Some(m.x) { let w = v * 2; total += w; }
+            d * 1000 + m.y * 100 + total
+        }").unwrap();
+    assert_eq!(j.call::<i64>(), Ok(806_000 + 400 + 18));
+}
+
+#[test]
+fn side_effects_and_unit_statements() {
+    let j = build("static mut N: u32 = 0;
+        fn bump() -> u32 { unsafe { N += 1; N } }
+        #[start] fn main() -> u32 {
+            bump();
+            bump() + 1;
+            if unsafe { N } > 1 { bump(); }
+            { bump() };
+            unsafe { N }
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(4));
+
+    let e = build("#[start] fn main() -> u32 { let c = true; if c { 1 } else { 2 } 3 }").err().unwrap();
+    assert!(format!("{:?}", e).contains("TypeMismatch"), "{:?}", e);
+}
+
+#[test]
+fn scopes_end_and_traps() {
+    // the locals of a block are not visible after it
+    let e = build("#[start] fn main() -> u32 { { let a = 1u32; } a }").err().unwrap();
+    assert!(matches!(e, AssembleError::UnresolvedPath(ref name) if name == "a"), "{:?}", e);
+
+    // a trap inside a nested block stops the script like anywhere else
+    let j = build("fn f(x: u8) -> u8 { let y = { let x = x - 100; 7 / x }; y }
+        #[start] fn main() -> u8 { { f(100) } }").unwrap();
+    assert!(matches!(j.call::<u8>(), Err(ScriptError::DivideByZero(_))));
+
+    // a slot reused by a shadowing local in a later block doesn't keep the old value
+    let j = build("#[start] fn main() -> u64 {
+            let a = { let t = 40u64; t + 2 };
+            let b = { let u = 0u64; u };
+            a * 10 + b
+        }").unwrap();
+    assert_eq!(j.call::<u64>(), Ok(420));
+}
+
+#[test]
+fn references_to_reused_slots() {
+    for src in &[
+        "let mut a = 1u32; let r = { let mut x = 3u32; &mut x }; let y = 100u32; *r = 5; y + a",
+        "let r = { let x = 3u32; &x }; let y = 100u32; *r",
+        "let r = if true { let x = 3u32; &x } else { let z = 4u32; &z }; *r",
+        "let mut a = 1u32; let mut r = &mut a; { let mut x = 3u32; r = &mut x; } let y = 100u32; *r = 5; y",
+        "let o = Some(3u32); let r = match o { Some(v) => &v, None => &0u32 }; *r",
+    ] {
+        match build(&format!("#[start] fn main() -> u32 {{ {} }}", src)) {
+            Err(AssembleError::FunctionError(AssembleFunctionError::DanglingReference { .. })) => {},
+            r => panic!("{}: {:?}", src, r.map(|_| ())),
+        }
+    }
+
+    // a reference to an outer local can be the result of a block, and a reference that ends
+    // with its block doesn't keep the slot
+    let j = build("fn get(r: &u32) -> u32 { *r }
+        #[start] fn main() -> u32 {
+            let mut a = 1u32;
+            {
+                let r = { let x = 3u32; a += x; &mut a };
+                *r += 10;
+            }
+            let b = { let x = 20u32; get(&x) };
+            let y = 100u32;
+            a + b + y
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(14 + 20 + 100));
+}
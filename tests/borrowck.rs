@@ -0,0 +1,183 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::{build, err};
+
+const STRUCTS: &str = "struct Item { id: u32, weight: u32 }
+    #[derive(Clone, Copy)] struct Pos { x: i32, y: i32 }
+    #[derive(Clone)] struct Tag { id: u32 }
+    struct Pair { a: Item, b: Item }
+    fn weigh(i: Item) -> u32 { i.weight }";
+
+#[test]
+fn accepted() {
+    let j = build(&format!("{}
+        #[start] fn main() -> u32 {{
+            let item = Item {{ id: 1, weight: 10 }};
+            let id = item.id;
+            let w = weigh(item);
+            let mut moved = Item {{ id: 2, weight: 20 }};
+            let w2 = weigh(moved);
+            moved = Item {{ id: 3, weight: 30 }};
+            let w3 = weigh(moved);
+            let p = Pos {{ x: 1, y: 2 }};
+            let q = p;
+            let t = Tag {{ id: 7 }};
+            let u = t.clone();
+            let pair = Pair {{ a: Item {{ id: 4, weight: 40 }}, b: Item {{ id: 5, weight: 50 }} }};
+            let a = pair.a;
+            let b_id = pair.b.id;
+            let w4 = weigh(a);
+            let c = Item {{ id: 6, weight: 60 }};
+            if id == 0 {{ return weigh(c); }}
+            let w5 = 1;
+            let w6 = if id == 1 {{ weigh(c) }} else {{ 2 }};
+            let mut pos = Pos {{ x: 0, y: 0 }};
+            let (ref mut px, _) = (1u32, 2u32);
+            {{ let Pos {{ ref mut x, ref mut y }} = pos; *x += 5; *y += 6; }}
+            let o = Some(Item {{ id: 8, weight: 80 }});
+            let some = if o.is_some() {{ 1 }} else {{ 0 }};
+            let w7 = match o {{ Some(i) => weigh(i), None => 0 }};
+            w + w2 + w3 + (p.x + q.y) as u32 + t.id + u.id + b_id + w4 + w5 + w6 + (pos.x + pos.y) as u32 + *px + some + w7
+        }}", STRUCTS)).unwrap();
+    assert_eq!(j.call::<u32>(), Ok(10 + 20 + 30 + 3 + 7 + 7 + 5 + 40 + 1 + 60 + 11 + 1 + 1 + 80));
+}
+
+#[test]
+fn mutability() {
+    for src in &[
+        "#[start] fn main() -> u32 { let x = 1; x = 2; x }",
+        "#[start] fn main() -> u32 { let x = 1; x += 2; x }",
+        "#[start] fn main() -> i32 { let p = Pos { x: 1, y: 2 }; p.x = 3; p.x }",
+        "#[start] fn main() -> u32 { let mut x = 1; let ref r = x; *r = 2; x }",
+        "fn f(x: u32) -> u32 { x += 1; x } #[start] fn main() -> u32 { f(1) }",
+    ] {
+        match err(&format!("{} {}", STRUCTS, src)) {
+            AssembleFunctionError::AssignToImmutable { .. } => {},
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+    match err(&format!("{} #[start] fn main() -> i32 {{ let p = Pos {{ x: 1, y: 2 }}; let Pos {{ ref mut x, .. }} = p; *x }}", STRUCTS)) {
+        AssembleFunctionError::BorrowOfImmutable { place, .. } => assert_eq!(place, "ref mut x"),
+        e => panic!("{:?}", e),
+    }
+    let ok = build(&format!("{} fn f(mut x: u32) -> u32 {{ x += 1; x }} #[start] fn main() -> u32 {{ f(1) }}", STRUCTS)).unwrap();
+    assert_eq!(ok.call::<u32>(), Ok(2));
+}
+
+#[test]
+fn borrows() {
+    for src in &[
+        "#[start] fn main() -> i32 { let mut p = Pos { x: 1, y: 2 }; let Pos { ref mut x, .. } = p; let Pos { ref x, .. } = p; *x }",
+        "#[start] fn main() -> i32 { let mut p = Pos { x: 1, y: 2 }; let ref a = p; let Pos { ref mut y, .. } = p; *y }",
+        "#[start] fn main() -> i32 { let mut p = Pos { x: 1, y: 2 }; match p { ref mut a @ Pos { ref mut x, .. } => *x } }",
+    ] {
+        match err(&format!("{} {}", STRUCTS, src)) {
+            AssembleFunctionError::OverlappingBorrow { .. } => {},
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+    // different fields and bindings whose scope ended don't overlap
+    let ok = build(&format!("{} #[start] fn main() -> i32 {{
+            let mut p = Pos {{ x: 1, y: 2 }};
+            {{ let ref a = p; let ref b = p; }}
+            {{ let Pos {{ ref mut x, .. }} = p; *x += 10; }}
+            let Pos {{ ref mut x, ref mut y }} = p;
+            *x + *y
+        }}", STRUCTS)).unwrap();
+    assert_eq!(ok.call::<i32>(), Ok(13));
+}
+
+#[test]
+fn moves() {
+    for (src, expression) in &[
+        ("let i = Item { id: 1, weight: 2 }; let a = weigh(i); weigh(i)", "i"),
+        ("let i = Item { id: 1, weight: 2 }; let j = i; i.id", "i . id"),
+        ("let p = Pair { a: Item { id: 1, weight: 2 }, b: Item { id: 3, weight: 4 } }; let a = p.a; weigh(p.a)", "p . a"),
+        ("let p = Pair { a: Item { id: 1, weight: 2 }, b: Item { id: 3, weight: 4 } }; let Pair { b, .. } = p; match p { Pair { a, .. } => a.id }", "p"),
+        ("let i = Item { id: 1, weight: 2 }; if true { weigh(i); } i.id", "i . id"),
+        ("let i = Item { id: 1, weight: 2 }; let a = match 1 { 0 => 0, _ => weigh(i) }; i.weight", "i . weight"),
+        ("let mut i = Item { id: 1, weight: 2 }; weigh(i); i.id = 5; 1", "i . id"),
+        ("let t = Tag { id: 1 }; let o = Some(t); let u = o.unwrap_or(Tag { id: 0 }); o.is_some(); 1", "o"),
+        ("let i = Item { id: 1, weight: 2 }; let mut o = Some(1); while let Some(x) = o { o = None; weigh(i); } 1", "i"),
+    ] {
+        match err(&format!("{} #[start] fn main() -> u32 {{ {} }}", STRUCTS, src)) {
+            AssembleFunctionError::UseOfMovedValue { expression: e, .. } => assert_eq!(e, *expression, "{}", src),
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+    let derive = |s: &str| match build(&format!("{} #[start] fn main() -> u32 {{ 1 }}", s)) {
+        Err(AssembleError::InvalidDerive(name)) => name,
+        r => panic!("{}: {:?}", s, r.err()),
+    };
+    assert_eq!(derive("#[derive(Copy)] struct A { x: u32 }"), "A");
+    assert_eq!(derive("struct B { x: u32 } #[derive(Clone, Copy)] struct C { b: B }"), "C");
+    assert_eq!(derive("struct D { x: u32 } #[derive(Clone)] struct E { d: (D, u32) }"), "E");
+}
+
+#[test]
+fn unsafe_statics() {
+    let j = build("static mut HITS: u32 = 0;
+        static BONUS: u32 = 5;
+        fn hit() -> u32 { unsafe { HITS += 1; HITS } }
+        #[start] fn main() -> u32 {
+            hit();
+            // a closure inside of an `unsafe` block can use the static too
+            let f = unsafe { |x: u32| HITS * x };
+            f(10) + BONUS + unsafe { hit() }
+        }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(10 + 5 + 2));
+    assert_eq!(j.call::<u32>(), Ok(30 + 5 + 4));
+
+    for src in &[
+        "fn main() -> u32 { HITS }",
+        "fn main() -> u32 { HITS += 1; 0 }",
+        "fn main() -> u32 { HITS = 2; 0 }",
+        "fn main() -> u32 { if unsafe { HITS } > 0 { HITS } else { 0 } }",
+    ] {
+        match err(&format!("static mut HITS: u32 = 0; #[start] {}", src)) {
+            AssembleFunctionError::UseOfMutableStatic { name, .. } => assert_eq!(name, "HITS"),
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+}
+
+#[test]
+fn live_references() {
+    for src in &[
+        "let mut a = 1u32; let r = &mut a; a = 5; *r",
+        "let mut a = 1u32; let r = &mut a; a += 5; *r",
+        "let mut a = 1u32; let r = &mut a; let b = a; *r + b",
+        "let mut a = 1u32; let r = &a; a = 5; *r",
+        "let mut a = 1u32; let r = &a; a *= 5; *r",
+        "let mut a = 1u32; let mut b = 2u32; let mut r = &mut b; r = &mut a; a = 3; *r",
+        "let mut p = Pos { x: 1, y: 2 }; let r = &mut p.x; p.x = 3; *r as u32",
+        "let mut p = Pos { x: 1, y: 2 }; let r = &p.x; p = Pos { x: 3, y: 4 }; *r as u32",
+    ] {
+        match err(&format!("{} #[start] fn main() -> u32 {{ {} }}", STRUCTS, src)) {
+            AssembleFunctionError::UseOfBorrowedValue { .. } => {},
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+
+    // other values, other fields, reads through a shared reference and uses after the block of the reference
+    let j = build(&format!("{}
+        #[start] fn main() -> u32 {{
+            let mut a = 1u32;
+            let mut b = 2u32;
+            let mut p = Pos {{ x: 1, y: 2 }};
+            {{
+                let r = &mut a;
+                let s = &b;
+                let x = &mut p.x;
+                *r += *s + a_plus(b);
+                *x += p.y;
+            }}
+            a = a * 10;
+            b += 1;
+            a + b + p.x as u32
+        }}
+        fn a_plus(b: u32) -> u32 {{ b * 100 }}", STRUCTS)).unwrap();
+    assert_eq!(j.call::<u32>(), Ok(2030 + 3 + 3));
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the integration tests, each of which includes this module with `mod common;`
+
+#![allow(dead_code)]
+
+use gsr_jit::*;
+
+pub fn build_mode(src: &str, mode: CompileMode) -> Result<JitMemory, AssembleError> {
+    let o = CompileOptions { mode, ..CompileOptions::default() };
+    let buf = compile_with_options(parse_file(src).unwrap(), &o)?;
+    Ok(JitMemory::from_assembly_buf(&buf).unwrap())
+}
+
+pub fn build(src: &str) -> Result<JitMemory, AssembleError> {
+    build_mode(src, CompileMode::Release)
+}
+
+/// The error of a function of a script that must not compile
+pub fn err(src: &str) -> AssembleFunctionError {
+    match build(src) {
+        Err(AssembleError::FunctionError(e)) => e,
+        Err(e) => panic!("{}: {:?}", src, e),
+        Ok(_) => panic!("{} compiled", src),
+    }
+}
@@ -0,0 +1,68 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::Cell;
+
+thread_local! {
+    static A: Cell<i64> = const { Cell::new(0) };
+    static B: Cell<i64> = const { Cell::new(0) };
+}
+
+extern "sysv64" fn input_a() -> i64 { A.with(|a| a.get()) }
+extern "sysv64" fn input_b() -> i64 { B.with(|b| b.get()) }
+
+fn jit(src: &str, mode: CompileMode) -> JitMemory {
+    let mut o = CompileOptions { mode, ..CompileOptions::default() };
+    o.host_functions.insert("input_a", input_a as *const u8);
+    o.host_functions.insert("input_b", input_b as *const u8);
+    let buf = compile_with_options(parse_file(src).unwrap(), &o).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+macro_rules! check {
+    ($t:ty) => {{
+        let t = stringify!($t);
+        let vals: Vec<$t> = [0i128, 1, -1, 2, 3, 7, -7, 100, 127, 128, -128, -129, 255, 256, 1000, 32767, -32768, 65535, 70000,
+            i32::MAX as i128, i32::MIN as i128, u32::MAX as i128, i64::MAX as i128, i64::MIN as i128, u64::MAX as i128]
+            .iter().map(|v| *v as $t).collect();
+        let header = format!("extern \"C\" {{ fn input_a() -> i64; fn input_b() -> i64; }}
+            fn a() -> {0} {{ input_a() as {0} }} fn b() -> {0} {{ input_b() as {0} }}", t);
+        for &mode in &[CompileMode::Debug, CompileMode::Release] {
+            let div = jit(&format!("{} fn divide(x: {1}, y: {1}) -> {1} {{ x / y }} #[start] fn main() -> {1} {{ divide(a(), b()) }}", header, t), mode);
+            let rem = jit(&format!("{} #[start] fn main() -> {} {{ a() % b() }}", header, t), mode);
+            for &a in &vals {
+                for &b in &vals {
+                    A.with(|c| c.set(a as i64));
+                    B.with(|c| c.set(b as i64));
+                    match (div.call::<$t>(), a.checked_div(b)) {
+                        (Ok(v), Some(s)) => assert_eq!(v, s),
+                        (Err(ScriptError::DivideByZero(l)), None) if b == 0 => {
+                            assert_eq!((&*l.function, &*l.expression, l.line), ("fn divide", "x / y", 2));
+                        },
+                        (Err(ScriptError::Overflow(l)), None) if b != 0 => assert_eq!(l.expression, "x / y"),
+                        (r, s) => panic!("{} {} {}: {:?} {:?}", t, a, b, r, s),
+                    }
+                    match (rem.call::<$t>(), a.checked_rem(b)) {
+                        (Ok(v), Some(s)) => assert_eq!(v, s),
+                        (Err(ScriptError::DivideByZero(l)), None) if b == 0 => assert_eq!(l.expression, "a ( ) % b ( )"),
+                        (Err(ScriptError::Overflow(_)), None) if b != 0 => { },
+                        (r, s) => panic!("{} {} {}: {:?} {:?}", t, a, b, r, s),
+                    }
+                }
+            }
+        }
+    }};
+}
+
+#[test]
+fn division() {
+    check!(u8); check!(i8); check!(u16); check!(i16); check!(u32); check!(i32); check!(u64); check!(i64);
+}
+
+#[test]
+fn message() {
+    let j = jit("#[start] fn main() -> u32 { let zero = 0; 10 / zero }", CompileMode::Release);
+    assert_eq!(j.call::<u32>().unwrap_err().to_string(), "attempt to divide by zero: `10 / zero` in `fn main` at 1:43");
+    let j = jit("fn pick(s: &[u8], i: usize) -> u8 {\n    s[i]\n}\n#[export] fn main(s: &[u8]) -> u8 { pick(s, 3) }", CompileMode::Release);
+    let error = j.call_export::<_, u8>(j.export("main").unwrap(), (&[1u8, 2][..],)).unwrap_err();
+    assert_eq!(error.to_string(), "index out of bounds: `s [ i ]` in `fn pick` at 2:5");
+}
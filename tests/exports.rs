@@ -0,0 +1,58 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::build;
+
+#[test]
+fn exports() {
+    let j = build("
+        fn helper(x: u32) -> u32 { x * 2 }
+        #[export] fn double(x: u32) -> u32 { helper(x) }
+        #[export(name = \"on_tick\")] fn tick(dt: f32, n: i64) -> f32 { dt * n as f32 }
+        #[export] fn many(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64) -> i64 { a + b * 10 + c * 100 + d * 1000 + e * 10000 + f * 100000 }
+        #[export] fn seven(a: i64, b: f64, c: i64, d: i64, e: i64, f: i64, g: i64) -> f64 { (a + c + d + e + f + g * 100) as f64 + b }
+        #[export] fn div(a: u32, b: u32) -> u32 { a / b }
+        #[export] fn none() -> u8 { 42 }
+        #[start] fn main() -> u32 { double(21) }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(42));
+    let names: Vec<_> = j.exports().iter().map(|e| e.name.clone()).collect();
+    assert_eq!(names, vec!["div", "double", "many", "none", "on_tick", "seven"]);
+    assert!(j.export("tick").is_none());
+    let e = j.export("double").unwrap();
+    assert_eq!(e.signature.arguments, vec![Ret::Int(StaticIntLiteral::U32)]);
+    assert_eq!(j.call_export::<_, u32>(e, (5u32,)), Ok(10));
+    let f: extern "sysv64" fn(u32) -> u32 = unsafe { std::mem::transmute(j.export_address(e)) };
+    assert_eq!(f(8), 16);
+    assert_eq!(j.call_export::<_, f32>(j.export("on_tick").unwrap(), (0.5f32, 3i64)), Ok(1.5));
+    assert_eq!(j.call_export::<_, i64>(j.export("many").unwrap(), (1i64, 2i64, 3i64, 4i64, 5i64, 6i64)), Ok(654321));
+    assert_eq!(j.call_export::<_, u8>(j.export("none").unwrap(), ()), Ok(42));
+    let seven: extern "sysv64" fn(i64, f64, i64, i64, i64, i64, i64) -> f64 =
+        unsafe { std::mem::transmute(j.export_address(j.export("seven").unwrap())) };
+    assert_eq!(seven(1, 0.5, 2, 3, 4, 5, 7), 715.5);
+    let d = j.export("div").unwrap();
+    assert_eq!(j.call_export::<_, u32>(d, (9u32, 3u32)), Ok(3));
+    assert!(j.call_export::<_, u32>(d, (9u32, 0u32)).is_err());
+    assert_eq!(j.call_export::<_, u32>(d, (8u32, 2u32)), Ok(4));
+}
+
+#[test]
+fn only_exports() {
+    let j = build("#[export] fn f(a: u32, b: u32) -> u32 { a / b }").unwrap();
+    let f = j.export("f").unwrap().clone();
+    assert_eq!(j.call_export::<_, u32>(&f, (6u32, 3u32)), Ok(2));
+    assert!(j.call_export::<_, u32>(&f, (3u32, 0u32)).is_err());
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| j.call::<u32>())).is_err());
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(build("fn f() {}"), Err(AssembleError::NoEntryFunction)));
+    match build("#[export] fn f() {} mod m { #[export(name = \"f\")] pub fn g() {} }") {
+        Err(AssembleError::DuplicateExport(name)) => assert_eq!(name, "f"),
+        r => panic!("{:?}", r.err()),
+    }
+    for src in &["#[export(nmae = \"x\")] fn f() {}", "#[export(name = 1)] fn f() {}", "#[export = \"x\"] fn f() {}"] {
+        assert!(matches!(build(src), Err(AssembleError::InvalidAttribute(_))), "{}", src);
+    }
+}
@@ -0,0 +1,271 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod common;
+use common::{build, build_mode, err};
+
+const SRC: &str = "
+    #[export] fn add(a: Fixed, b: Fixed) -> Fixed { a + b }
+    #[export] fn sub(a: Fixed, b: Fixed) -> Fixed { a - b }
+    #[export] fn mul(a: Fixed, b: Fixed) -> Fixed { a * b }
+    #[export] fn div(a: Fixed, b: Fixed) -> Fixed { a / b }
+    #[export] fn neg(a: Fixed) -> Fixed { -a }
+    #[export] fn sqrt(a: Fixed) -> Fixed { a.sqrt() }
+    #[export] fn sin(a: Fixed) -> Fixed { a.sin() }
+    #[export] fn cos(a: Fixed) -> Fixed { a.cos() }
+    #[export] fn abs(a: Fixed) -> Fixed { a.abs() }
+    #[export] fn to_int(a: Fixed) -> i32 { a.to_int() }
+    #[export] fn round_to_int(a: Fixed) -> i32 { a.round_to_int() }
+    #[export] fn to_f64(a: Fixed) -> f64 { a.to_f64() }
+    #[export] fn to_f32(a: Fixed) -> f32 { a.to_f32() }
+    #[export] fn from_int(a: i32) -> Fixed { Fixed::from_int(a) }
+    #[export] fn bits(a: Fixed) -> i64 { Fixed::from_bits(a.to_bits() + 1).to_bits() }
+    #[export] fn cmp(a: Fixed, b: Fixed) -> u8 { (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3 | ((a == b) as u8) << 4 | ((a != b) as u8) << 5 }
+    #[export] fn min_max(a: Fixed, b: Fixed) -> Fixed { a.min(b) * Fixed::from_int(1000) + a.max(b) }
+";
+
+fn values() -> Vec<Fixed> {
+    let mut v = vec![Fixed::ZERO, Fixed::ONE, -Fixed::ONE, Fixed::PI, Fixed::MIN, Fixed::MAX, Fixed::from_bits(1), Fixed::from_bits(-1),
+                     Fixed::from_num(0.5), Fixed::from_num(-0.5), Fixed::from_num(1.5), Fixed::from_num(-2.5), Fixed::from_num(12345.678),
+                     Fixed::from_num(-31415.9265), Fixed::from_num(1e-5), Fixed::from_num(2e9)];
+    // a simple generator, so that the inputs are the same on every run
+    let mut state = 0x2545f4914f6cdd1du64;
+    for _ in 0..200 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        v.push(Fixed::from_bits((state as i64) >> (state % 40)));
+    }
+    v
+}
+
+#[test]
+fn matches_host() {
+    let j = build(SRC).unwrap();
+    let e = |n: &str| j.export(n).unwrap();
+    let values = values();
+    for &a in &values {
+        assert_eq!(j.call_export::<_, Fixed>(e("neg"), (a,)), Ok(-a));
+        assert_eq!(j.call_export::<_, Fixed>(e("sqrt"), (a,)), Ok(a.sqrt()), "sqrt {:?}", a);
+        assert_eq!(j.call_export::<_, Fixed>(e("sin"), (a,)), Ok(a.sin()), "sin {:?}", a);
+        assert_eq!(j.call_export::<_, Fixed>(e("cos"), (a,)), Ok(a.cos()), "cos {:?}", a);
+        assert_eq!(j.call_export::<_, Fixed>(e("abs"), (a,)), Ok(a.abs()));
+        assert_eq!(j.call_export::<_, i32>(e("to_int"), (a,)), Ok(a.to_int()), "to_int {:?}", a);
+        assert_eq!(j.call_export::<_, i32>(e("round_to_int"), (a,)), Ok(a.round_to_int()), "round {:?}", a);
+        assert_eq!(j.call_export::<_, f64>(e("to_f64"), (a,)), Ok(a.to_f64()));
+        assert_eq!(j.call_export::<_, f32>(e("to_f32"), (a,)), Ok(a.to_f32()));
+        assert_eq!(j.call_export::<_, i64>(e("bits"), (a,)), Ok(a.to_bits().wrapping_add(1)));
+        for &b in values.iter().step_by(7) {
+            assert_eq!(j.call_export::<_, Fixed>(e("add"), (a, b)), Ok(a + b));
+            assert_eq!(j.call_export::<_, Fixed>(e("sub"), (a, b)), Ok(a - b));
+            assert_eq!(j.call_export::<_, Fixed>(e("mul"), (a, b)), Ok(a * b), "{:?} * {:?}", a, b);
+            if b != Fixed::ZERO {
+                assert_eq!(j.call_export::<_, Fixed>(e("div"), (a, b)), Ok(a / b), "{:?} / {:?}", a, b);
+            }
+            let flags = (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3 | ((a == b) as u8) << 4 | ((a != b) as u8) << 5;
+            assert_eq!(j.call_export::<_, u8>(e("cmp"), (a, b)), Ok(flags));
+            assert_eq!(j.call_export::<_, Fixed>(e("min_max"), (a, b)), Ok(a.min(b) * Fixed::from_int(1000) + a.max(b)));
+        }
+    }
+    for &i in &[0, 1, -1, 7, i32::MAX, i32::MIN] {
+        assert_eq!(j.call_export::<_, Fixed>(e("from_int"), (i,)), Ok(Fixed::from_int(i)));
+    }
+}
+
+#[test]
+fn rounding_and_accuracy() {
+    let f = Fixed::from_num;
+    assert_eq!(f(1.5) * f(-2.25), f(-3.375));
+    // 2^-32 * 0.5 rounds away from zero
+    assert_eq!(Fixed::from_bits(1) * f(0.5), Fixed::from_bits(1));
+    assert_eq!(Fixed::from_bits(-1) * f(0.5), Fixed::from_bits(-1));
+    assert_eq!(Fixed::from_bits(1) * f(0.25), Fixed::ZERO);
+    assert_eq!(Fixed::ONE / Fixed::from_int(3), f(1.0 / 3.0));
+    assert_eq!(-Fixed::ONE / Fixed::from_int(3), f(-1.0 / 3.0));
+    assert_eq!(Fixed::from_int(2).sqrt(), f(2f64.sqrt()));
+    assert_eq!(f(-4.0).sqrt(), Fixed::ZERO);
+    assert_eq!((Fixed::PI / Fixed::from_int(2)).sin(), Fixed::ONE);
+    assert_eq!(Fixed::ZERO.cos(), Fixed::ONE);
+    for i in -2000..2000 {
+        let x = Fixed::from_bits(i64::from(i) * 0x1234_5678 + 17);
+        assert!((x.sin().to_f64() - x.to_f64().sin()).abs() <= 2f64.powi(-32), "{:?}", x);
+        assert!((x.cos().to_f64() - x.to_f64().cos()).abs() <= 2f64.powi(-32), "{:?}", x);
+    }
+    assert_eq!(f(-2.5).to_int(), -2);
+    assert_eq!(f(-2.5).round_to_int(), -3);
+    assert_eq!(f(2.4).round_to_int(), 2);
+    assert_eq!(format!("{:.3} {:?}", Fixed::PI, f(-0.25)), "3.142 -0.25");
+}
+
+#[test]
+fn literals_locals_and_structs() {
+    let rec = Rc::new(RefCell::new(String::new()));
+    struct Out(Rc<RefCell<String>>);
+    impl ScriptLogger for Out {
+        fn write(&mut self, _: LogTarget, text: &str) { self.0.borrow_mut().push_str(text); }
+        fn end_line(&mut self, _: LogTarget) { self.0.borrow_mut().push('\n'); }
+    }
+    let o = CompileOptions { logger: Some(SharedLogger(Rc::new(RefCell::new(Out(rec.clone()))))), ..CompileOptions::default() };
+    let src = "struct Unit { pos: Fixed, speed: Fixed }
+        fn step(u: &mut [Unit], dt: Fixed) {
+            for unit in u.iter_mut() {
+                unit.pos += unit.speed * dt;
+                if unit.pos > Fixed::from_num(100) { unit.pos = Fixed::from_num(100); }
+            }
+        }
+        fn steps(u: &mut [Unit], dt: Fixed, n: u32) { if n > 0 { step(u, dt); steps(u, dt, n - 1) } }
+        #[export] fn simulate(units: &mut [Unit]) -> Fixed {
+            let dt = Fixed::from_num(0.016);
+            steps(units, dt, 10);
+            units[0].pos
+        }
+        #[export] fn total(units: &[Unit]) -> Fixed {
+            let mut sum = Fixed::ZERO;
+            for u in units { sum += u.pos; }
+            println!(\"{} {:.2}\", sum, Fixed::from_num(-1.125));
+            sum
+        }";
+    let j = JitMemory::from_assembly_buf(&compile_with_options(parse_file(src).unwrap(), &o).unwrap()).unwrap();
+    #[repr(C)] #[derive(Debug, Clone, Copy, PartialEq)] struct Unit { pos: Fixed, speed: Fixed }
+    let mut units = [Unit { pos: Fixed::ZERO, speed: Fixed::from_num(3.5) }, Unit { pos: Fixed::from_num(99.99), speed: Fixed::from_int(7) }];
+    let mut host = units;
+    let dt = Fixed::from_num(0.016);
+    for _ in 0..10 {
+        for u in host.iter_mut() {
+            u.pos += u.speed * dt;
+            if u.pos > Fixed::from_int(100) { u.pos = Fixed::from_int(100); }
+        }
+    }
+    assert_eq!(j.call_export::<_, Fixed>(j.export("simulate").unwrap(), (&mut units[..],)), Ok(host[0].pos));
+    assert_eq!(units, host);
+    let sum = host[0].pos + host[1].pos;
+    assert_eq!(j.call_export::<_, Fixed>(j.export("total").unwrap(), (&units[..],)), Ok(sum));
+    assert_eq!(*rec.borrow(), format!("{} -1.12\n", sum));
+}
+
+#[test]
+fn overflow_checks() {
+    let src = "#[export] fn add(a: Fixed, b: Fixed) -> Fixed { a + b }
+        #[export] fn mul(a: Fixed, b: Fixed) -> Fixed { a * b }
+        #[export] fn div(a: Fixed, b: Fixed) -> Fixed { a / b }
+        #[export] fn neg(a: Fixed) -> Fixed { -a }";
+    let d = build_mode(src, CompileMode::Debug).unwrap();
+    let e = |n: &str| d.export(n).unwrap();
+    let big = Fixed::from_int(70000);
+    assert!(matches!(d.call_export::<_, Fixed>(e("add"), (Fixed::MAX, Fixed::from_bits(1))), Err(ScriptError::Overflow(_))));
+    assert!(matches!(d.call_export::<_, Fixed>(e("mul"), (big, big)), Err(ScriptError::Overflow(_))));
+    assert_eq!(d.call_export::<_, Fixed>(e("mul"), (big, -Fixed::from_int(30000))), Ok(Fixed::from_int(-2100000000)));
+    assert!(matches!(d.call_export::<_, Fixed>(e("div"), (big, Fixed::from_num(0.00001))), Err(ScriptError::Overflow(_))));
+    assert!(matches!(d.call_export::<_, Fixed>(e("div"), (big, Fixed::ZERO)), Err(ScriptError::DivideByZero(_))));
+    assert!(matches!(d.call_export::<_, Fixed>(e("neg"), (Fixed::MIN,)), Err(ScriptError::Overflow(_))));
+    let r = build(src).unwrap();
+    assert_eq!(r.call_export::<_, Fixed>(r.export("mul").unwrap(), (big, big)), Ok(big * big));
+    assert_eq!(r.call_export::<_, Fixed>(r.export("div").unwrap(), (big, Fixed::from_num(0.00001))), Ok(big / Fixed::from_num(0.00001)));
+    assert!(matches!(r.call_export::<_, Fixed>(r.export("div").unwrap(), (big, Fixed::ZERO)), Err(ScriptError::DivideByZero(_))));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::from_num(3000000000.0) }"), AssembleFunctionError::LiteralOutOfRange { .. }));
+    assert!(matches!(err("#[start] fn main() -> Fixed { let x = 1.5; Fixed::from_num(x) }"), AssembleFunctionError::UnsupportedExpression(_)));
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::ONE * 2.0 }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::ONE + 2 }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::ONE.tan() }"), AssembleFunctionError::UnknownMethod { .. }));
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::E }"), AssembleFunctionError::UnknownMethod { .. }));
+    assert!(matches!(err("#[start] fn main() -> f32 { Fixed::ONE as f32 }"), AssembleFunctionError::InvalidCast { .. }));
+    assert!(matches!(err("#[start] fn main() -> Fixed { Fixed::ONE % Fixed::ONE }"), AssembleFunctionError::UnsupportedExpression(_)));
+}
+
+#[test]
+fn iterator_reductions() {
+    let j = build("#[export] fn s(x: &[Fixed]) -> Fixed { x.iter().sum() }
+        #[export] fn lo(x: &[Fixed]) -> Fixed { x.iter().min().unwrap_or(Fixed::ZERO) }
+        #[export] fn hi(x: &[Fixed]) -> Fixed { x.iter().max().unwrap_or(Fixed::ZERO) }").unwrap();
+    let xs = [Fixed::from_num(1.5), Fixed::from_num(-7.25), Fixed::PI];
+    assert_eq!(j.call_export::<_, Fixed>(j.export("s").unwrap(), (&xs[..],)), Ok(xs[0] + xs[1] + xs[2]));
+    assert_eq!(j.call_export::<_, Fixed>(j.export("lo").unwrap(), (&xs[..],)), Ok(xs[1]));
+    assert_eq!(j.call_export::<_, Fixed>(j.export("hi").unwrap(), (&xs[..],)), Ok(Fixed::PI));
+}
+
+#[test]
+fn host_accuracy() {
+    let mut worst = 0f64;
+    let mut a = -200.0f64;
+    while a < 200.0 {
+        let x = Fixed::from_num(a);
+        for (got, want) in [(x.sin(), x.to_f64().sin()), (x.cos(), x.to_f64().cos())] {
+            let err = (got.to_f64() - want).abs() * 2f64.powi(32);
+            if err > worst { worst = err; }
+        }
+        a += 0.0137;
+    }
+    assert!(worst <= 1.0);
+    for &v in &[0.0, 1.0, 2.0, 0.25, 1e-9, 12345.678, 2147483647.0] {
+        let x = Fixed::from_num(v);
+        let err = (x.sqrt().to_f64() - x.to_f64().sqrt()).abs() * 2f64.powi(32);
+        assert!(err <= 0.5 + 1e-3 || v > 1e6, "{} {}", v, err);
+    }
+    assert_eq!(Fixed::from_int(2).sqrt(), Fixed::from_num(2f64.sqrt()));
+    assert_eq!(Fixed::from_num(1.5) * Fixed::from_num(-2.25), Fixed::from_num(-3.375));
+    assert_eq!(Fixed::from_int(1) / Fixed::from_int(3), Fixed::from_num(1.0 / 3.0));
+    assert_eq!(Fixed::from_num(-2.5).to_int(), -2);
+    assert_eq!(Fixed::from_num(-2.5).round_to_int(), -3);
+    assert_eq!(Fixed::from_num(2.5).round_to_int(), 3);
+    assert_eq!(format!("{:.3}", Fixed::PI), "3.142");
+}
+
+#[test]
+fn readme() {
+    let src = "struct Body { pos: Fixed, vel: Fixed }
+
+fn integrate(bodies: &mut [Body], dt: Fixed) {
+    let gravity = Fixed::from_num(-9.81);
+    for body in bodies.iter_mut() {
+        body.vel += gravity * dt;
+        body.pos = (body.pos + body.vel * dt).max(Fixed::ZERO);
+    }
+}
+#[export] fn run(b: &mut [Body], dt: Fixed) { integrate(b, dt) }";
+    let buf = compile_with_options(parse_file(src).unwrap(), &CompileOptions::default()).unwrap();
+    let j = JitMemory::from_assembly_buf(&buf).unwrap();
+    #[repr(C)] #[derive(Clone, Copy, Debug, PartialEq)] struct Body { pos: Fixed, vel: Fixed }
+    let mut b = [Body { pos: Fixed::from_int(10), vel: Fixed::ZERO }];
+    let dt = Fixed::ONE / Fixed::from_int(60);
+    let mut h = b;
+    for _ in 0..100 {
+        j.call_export::<_, ()>(j.export("run").unwrap(), (&mut b[..], dt)).unwrap();
+        h[0].vel += Fixed::from_num(-9.81) * dt;
+        h[0].pos = (h[0].pos + h[0].vel * dt).max(Fixed::ZERO);
+    }
+    assert_eq!(b, h);
+    assert_eq!(b[0].pos, Fixed::ZERO);
+}
+
+#[test]
+fn patterns() {
+    let j = build("#[export] fn classify(x: Fixed) -> u32 {
+            match x {
+                Fixed::ZERO => 0,
+                Fixed::MIN..=Fixed::ZERO => 1,
+                Fixed::ONE => 2,
+                Fixed::PI..=Fixed::MAX => 4,
+                _ => 3,
+            }
+        }
+        #[export] fn limits(x: i32) -> u32 { match x { i32::MIN => 0, i32::MAX => 2, _ => 1 } }").unwrap();
+    let e = |name: &str| j.export(name).unwrap();
+    for (x, want) in [(Fixed::ZERO, 0), (Fixed::MIN, 1), (Fixed::from_num(-0.5), 1), (Fixed::ONE, 2),
+                      (Fixed::from_num(2.5), 3), (Fixed::PI, 4), (Fixed::MAX, 4)] {
+        assert_eq!(j.call_export::<_, u32>(e("classify"), (x,)), Ok(want), "{:?}", x);
+    }
+    assert_eq!(j.call_export::<_, u32>(e("limits"), (i32::MIN,)), Ok(0));
+    assert_eq!(j.call_export::<_, u32>(e("limits"), (7,)), Ok(1));
+    assert_eq!(j.call_export::<_, u32>(e("limits"), (i32::MAX,)), Ok(2));
+    // the ranges have to cover every value
+    assert!(matches!(err("#[start] fn main() -> u32 { match Fixed::ONE { Fixed::MIN..=Fixed::ZERO => 0, Fixed::ONE..=Fixed::MAX => 1 } }"),
+        AssembleFunctionError::NonExhaustiveMatch { .. }));
+    let j = build("#[start] fn main() -> u32 { match Fixed::PI { Fixed::MIN..=Fixed::ZERO => 0, Fixed::ZERO..=Fixed::MAX => 1 } }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(1));
+}
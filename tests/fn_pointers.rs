@@ -0,0 +1,166 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static CALLBACK: AtomicUsize = AtomicUsize::new(0);
+extern "sysv64" fn register_callback(cb: extern "sysv64" fn(u32) -> u32) { CALLBACK.store(cb as usize, Ordering::SeqCst); }
+extern "sysv64" fn apply_host(cb: extern "sysv64" fn(u32) -> u32, x: u32) -> u32 { cb(x) + 1 }
+extern "sysv64" fn host_double(x: u32) -> u32 { x * 2 }
+
+#[repr(C)] #[derive(Debug, PartialEq, Clone, Copy)] struct Small { a: u32, b: f32 }
+#[repr(C)] #[derive(Debug, PartialEq, Clone, Copy)] struct Big { a: u64, b: u64, c: u8 }
+#[repr(C)] #[derive(Debug, PartialEq, Clone, Copy)] struct V2 { x: f32, y: f32 }
+extern "sysv64" fn host_small(s: Small) -> Small { Small { a: s.a + 1, b: s.b * 2.0 } }
+extern "sysv64" fn host_big(s: Big) -> Big { Big { a: s.a + 1, b: s.b + 2, c: s.c + 3 } }
+extern "sysv64" fn host_v2(v: V2) -> V2 { V2 { x: v.y, y: v.x } }
+
+fn opts() -> CompileOptions {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("register_callback", register_callback as *const u8);
+    o.host_functions.insert("apply_host", apply_host as *const u8);
+    o.host_functions.insert("host_double", host_double as *const u8);
+    o.host_functions.insert("host_small", host_small as *const u8);
+    o.host_functions.insert("host_big", host_big as *const u8);
+    o.host_functions.insert("host_v2", host_v2 as *const u8);
+    o
+}
+fn jit(src: &str) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), &opts()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+fn err(src: &str) -> AssembleError { compile_with_options(parse_file(src).unwrap(), &opts()).err().unwrap() }
+
+const EXTERNS: &str = r#"
+extern "C" {
+    fn register_callback(cb: fn(u32) -> u32);
+    fn apply_host(cb: extern "C" fn(u32) -> u32, x: u32) -> u32;
+    fn host_double(x: u32) -> u32;
+    fn host_small(s: Small) -> Small;
+    fn host_big(s: Big) -> Big;
+    fn host_v2(v: V2) -> V2;
+}
+struct Small { a: u32, b: f32 }
+struct Big { a: u64, b: u64, c: u8 }
+struct V2 { x: f32, y: f32 }
+"#;
+
+fn run_u64(body: &str) -> u64 {
+    jit(&format!("{}{}", EXTERNS, body)).run::<u64>()()
+}
+
+#[test]
+fn callbacks() {
+    let j = jit(&format!("{}{}", EXTERNS, "fn on_hit(x: u32) -> u32 { x + 100 } #[start] fn main() -> u32 { register_callback(on_hit); 0 }"));
+    j.run::<u32>()();
+    let cb: extern "sysv64" fn(u32) -> u32 = unsafe { std::mem::transmute(CALLBACK.load(Ordering::SeqCst)) };
+    assert_eq!(cb(5), 105);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { apply_host(|x| x * 3, 5) as u64 }"), 16);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let f = host_double; f(21) as u64 }"), 42);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { apply_host(host_double, 4) as u64 }"), 9);
+}
+
+#[test]
+fn fn_pointers() {
+    assert_eq!(run_u64("fn double(x: u32) -> u32 { x * 2 } fn apply(f: fn(u32) -> u32, x: u32) -> u32 { f(x) } #[start] fn main() -> u64 { let f: fn(u32) -> u32 = double; (apply(f, 4) + apply(|x| x + 1, 4)) as u64 }"), 13);
+    assert_eq!(run_u64("fn pick(b: bool) -> fn(u64) -> u64 { if b { |x| x + 1 } else { |x| x - 1 } } #[start] fn main() -> u64 { pick(true)(10) * 100 + pick(false)(10) }"), 1109);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let f = |x: u64, y: u64| x * y; let g = f; g(6, 7) }"), 42);
+    assert_eq!(run_u64("fn a() {} fn b() {} #[start] fn main() -> u64 { let x = a; ((x == a) as u64) * 10 + (x == b) as u64 }"), 10);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let f = |x: u64| -> u64 { if x > 3 { return 1; } 2 }; f(5) * 10 + f(1) }"), 12);
+    assert_eq!(run_u64("struct Ops { f: fn(u64) -> u64, k: u64 } #[start] fn main() -> u64 { let o = Ops { f: |x| x * 3, k: 2 }; (o.f)(o.k) }"), 6);
+    match err("#[start] fn main() -> u64 { let y = 2u64; let f = |x: u64| x + y; f(1) }") {
+        AssembleError::FunctionError(AssembleFunctionError::CapturingClosure { ref name, .. }) if name == "y" => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> u64 { let f = |x| x; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::TypeAnnotationNeeded(_)) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> u64 { let y = 2u64; y(1) }") {
+        AssembleError::FunctionError(AssembleFunctionError::NotAFunction(_)) => {},
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn structs() {
+    assert_eq!(run_u64("struct P { x: u8, y: u64, z: u16 } fn sum(p: P) -> u64 { p.x as u64 + p.y + p.z as u64 } #[start] fn main() -> u64 { let p = P { z: 3, x: 1, y: 20 }; sum(p) }"), 24);
+    assert_eq!(run_u64("struct Money(u64); struct Wallet { m: Money, n: u32 } fn mk(n: u32) -> Wallet { Wallet { m: Money(7), n } } #[start] fn main() -> u64 { let w = mk(3); w.m.0 * 10 + w.n as u64 }"), 73);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let s = host_small(Small { a: 1, b: 1.5 }); s.a as u64 * 10 + s.b as u64 }"), 23);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let b = host_big(Big { a: 1, b: 2, c: 3 }); b.a * 100 + b.b * 10 + b.c as u64 }"), 246);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let v = host_v2(V2 { x: 1.0, y: 2.0 }); (v.x * 10.0 + v.y) as u64 }"), 21);
+    assert_eq!(run_u64("fn big(a: u64) -> Big { Big { a, b: a + 1, c: 9 } } fn id(b: Big) -> Big { b } #[start] fn main() -> u64 { let b = id(big(4)); b.a * 100 + b.b * 10 + b.c as u64 }"), 459);
+    // many arguments: structs go on the stack once registers run out
+    assert_eq!(run_u64("fn f(a: u64, b: u64, c: u64, d: u64, e: u64, s: Small, t: Small) -> u64 { a + b + c + d + e + s.a as u64 + t.a as u64 } #[start] fn main() -> u64 { f(1, 2, 3, 4, 5, Small { a: 6, b: 0.0 }, Small { a: 7, b: 0.0 }) }"), 28);
+    assert_eq!(run_u64("struct S { s: &str, n: u8 } fn len(x: S) -> u64 { x.n as u64 } #[start] fn main() -> u64 { len(S { s: \"abc\", n: 5 }) }"), 5);
+    assert_eq!(run_u64("struct Marker; fn m(_x: Marker) -> u64 { 3 } #[start] fn main() -> u64 { m(Marker) }"), 3);
+    match err("struct P { x: u8 } #[start] fn main() -> u64 { let p = P { x: 1, y: 2 }; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::UnknownField { ref field, .. }) if field == "y" => {},
+        e => panic!("{:?}", e),
+    }
+    match err("struct P { x: u8, y: u8 } #[start] fn main() -> u64 { let p = P { x: 1 }; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::MissingField { ref field, .. }) if field == "y" => {},
+        e => panic!("{:?}", e),
+    }
+    match err("mod m { pub struct P { x: u8 } } #[start] fn main() -> u64 { let p = m::P { x: 1 }; 0 }") {
+        AssembleError::PrivateItem(_) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("struct A { b: B } struct B { a: A } #[start] fn main() -> u64 { 0 }") {
+        AssembleError::RecursiveType(_) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("fn f() {} #[start] fn main() -> u64 { let p = f { x: 1 }; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::NotAStruct(_)) => {},
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn mixed_classes() {
+    assert_eq!(run_u64("struct M { a: u64, b: f64 } fn swap(m: M) -> M { M { a: m.b as u64, b: m.a as f64 } } #[start] fn main() -> u64 { let m = swap(M { a: 3, b: 4.0 }); m.a * 10 + m.b as u64 }"), 43);
+    assert_eq!(run_u64("struct F { a: f64, b: f64 } #[start] fn main() -> u64 { let mk = |x: f64| F { a: x, b: x * 2.0 }; let f = mk(1.5); (f.a + f.b) as u64 }"), 4);
+    assert_eq!(run_u64("#[start] fn main() -> u64 { let mk = |x: u64| Big { a: x, b: x, c: 1 }; let b = mk(2); b.a + b.b + b.c as u64 }"), 5);
+    match err("#[start] fn main() -> u64 { let y = 1u64; let f = |x: u64| { let g = |z: u64| z + y; g(x) }; f(1) }") {
+        AssembleError::FunctionError(AssembleFunctionError::CapturingClosure { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn readme_example() {
+    let src = r#"
+extern "C" {
+    fn register_callback(cb: fn(u32) -> u32);
+}
+
+struct Hit { damage: u32, critical: bool }
+
+fn on_hit(damage: u32) -> u32 { damage * 2 }
+
+#[start]
+fn main() -> u32 {
+    register_callback(on_hit);
+    let modifier: fn(Hit) -> u32 = |hit| if hit.critical { hit.damage * 3 } else { hit.damage };
+    let damage = modifier(Hit { damage: 10, critical: true });
+    damage
+}
+"#;
+    assert_eq!(jit(src).run::<u32>()(), 30);
+}
+
+#[test]
+fn closure_types_from_later_use() {
+    let j = jit("fn apply(f: fn(u32) -> u32, x: u32) -> u32 { f(x) }
+        #[start] fn main() -> u32 { let triple = |x| x * 3; let twice = triple; apply(twice, 5) + triple(1) }");
+    assert_eq!(j.run::<u32>()(), 18);
+    let j = jit("fn apply(f: fn(f32, f32) -> f32) -> f32 { f(1.5, 2.0) }
+        #[start] fn main() -> f32 { let mul = |a, b| a * b; apply(mul) }");
+    assert_eq!(j.run::<f32>()(), 3.0);
+    // the closure is called before its type is known
+    match err("fn apply(f: fn(u32) -> u32, x: u32) -> u32 { f(x) } #[start] fn main() -> u32 { let f = |x| x; f(1) + apply(f, 2) }") {
+        AssembleError::FunctionError(AssembleFunctionError::TypeAnnotationNeeded(_)) => {},
+        e => panic!("{:?}", e),
+    }
+    let j = jit("#[start] fn main() -> u64 { let f = |x| x + 1; let g: fn(u64) -> u64 = f; g(1 << 40) }");
+    assert_eq!(j.run::<u64>()(), (1 << 40) + 1);
+}
@@ -0,0 +1,110 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+extern "sysv64" fn half(x: f64) -> f64 { x / 2.0 }
+extern "sysv64" fn mix(a: u32, b: f32, c: f64, d: u8) -> f64 { a as f64 + b as f64 + c + d as f64 }
+
+fn opts() -> CompileOptions {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("half", half as *const u8);
+    o.host_functions.insert("mix", mix as *const u8);
+    o
+}
+fn jit(src: &str) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), &opts()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+fn err(src: &str) -> AssembleError { compile_with_options(parse_file(src).unwrap(), &opts()).err().unwrap() }
+
+#[test]
+fn infer_from_later_use() {
+    let j = jit("fn takes_u16(x: u16) -> u64 { x as u64 } #[start] fn main() -> u64 { let x = 65535; takes_u16(x) }");
+    assert_eq!(j.run::<u64>()(), 65535);
+    // would not fit into the default i32
+    let j = jit("#[start] fn main() -> u64 { let x = 5000000000; let y = x + 1; y }");
+    assert_eq!(j.run::<u64>()(), 5000000001);
+    let j = jit("#[start] fn main() -> i64 { let x = 1; let y = 2; let z = x - y; z * 3 }");
+    assert_eq!(j.run::<i64>()(), -3);
+    let j = jit("#[start] fn main() -> u8 { let a = 200; let b = 100; a + b }");
+    assert_eq!(j.run::<u8>()(), 44);
+    let j = jit("#[start] fn main() -> bool { let x = 5; let y: u8 = 5; x == y }");
+    assert!(j.run::<bool>()());
+    let j = jit("#[start] fn main() -> bool { let x: u8 = 250; 5 < x }");
+    assert!(j.run::<bool>()());
+}
+
+#[test]
+fn defaults() {
+    // i32 default: 3_000_000_000 does not fit
+    match err("#[start] fn main() -> u32 { let x = 3000000000; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::LiteralOutOfRange { ref literal, ty: Ret::Int(StaticIntLiteral::I32), .. }) if literal == "3000000000" => {},
+        e => panic!("{:?}", e),
+    }
+    match err("fn t(x: u8) {} #[start] fn main() -> u32 { let x = 256; t(x); 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::LiteralOutOfRange { ty: Ret::Int(StaticIntLiteral::U8), .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("fn t(x: u8) {} #[start] fn main() -> u32 { let x = -1; t(x); 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::LiteralOutOfRange { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> u32 { let x = 1; let y: u8 = x; let z: u16 = x; 0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    let j = jit("#[start] fn main() -> f64 { let x = 1.5; x * 2.0 }");
+    assert_eq!(j.run::<f64>()(), 3.0);
+    let j = jit("#[start] fn main() -> i64 { let x = 7; let y = -2; (x / y) as i64 * 10 + (x % y) as i64 }");
+    assert_eq!(j.run::<i64>()(), -30 + 1);
+}
+
+#[test]
+fn floats() {
+    let j = jit("fn f(a: f32, b: f32) -> f32 { a * b - 0.5 } #[start] fn main() -> f32 { f(1.5, 3.0) }");
+    assert_eq!(j.run::<f32>()(), 4.0);
+    let j = jit(r#"extern "C" { fn half(x: f64) -> f64; fn mix(a: u32, b: f32, c: f64, d: u8) -> f64; } #[start] fn main() -> f64 { mix(1, 2.5, half(-3.0), 4) }"#);
+    assert_eq!(j.run::<f64>()(), 1.0 + 2.5 - 1.5 + 4.0);
+    let j = jit("#[start] fn main() -> bool { let n = 0.0 / 0.0; !(n == n) && n != n && !(n < 1.0) && 1.0 < 2.0 && 2.0 >= 2.0 }");
+    assert!(j.run::<bool>()());
+    let j = jit("#[start] fn main() -> f64 { -(2.5f32 as f64) }");
+    assert_eq!(j.run::<f64>()(), -2.5);
+    // many float args, some on the stack
+    let j = jit("fn s(a: f64, b: f64, c: f64, d: f64, e: f64, f: f64, g: f64, h: f64, i: f64, k: f32) -> f64 { a + b + c + d + e + f + g + h + i + k as f64 } #[start] fn main() -> f64 { s(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0) }");
+    assert_eq!(j.run::<f64>()(), 55.0);
+}
+
+#[test]
+fn casts() {
+    let cases: &[(&str, i64)] = &[
+        ("300.7 as u8", 255), ("-5.0 as u8", 0), ("(0.0 / 0.0) as i32", 0), ("-3.9 as i8", -3),
+        ("1e20 as i64", i64::MAX), ("-1e20 as i64", i64::MIN), ("-129.0 as i8", -128),
+    ];
+    for (expr, expected) in cases {
+        let j = jit(&format!("#[start] fn main() -> i64 {{ ({}) as i64 }}", expr));
+        assert_eq!(j.run::<i64>()(), *expected, "{}", expr);
+    }
+    let j = jit("#[start] fn main() -> u64 { 1.8e19 as u64 }");
+    assert_eq!(j.run::<u64>()(), 18000000000000000000);
+    let j = jit("#[start] fn main() -> u64 { 1e30 as u64 }");
+    assert_eq!(j.run::<u64>()(), u64::MAX);
+    let j = jit("#[start] fn main() -> f64 { 18000000000000000001u64 as f64 }");
+    assert_eq!(j.run::<f64>()(), 18000000000000000001u64 as f64);
+    let j = jit("#[start] fn main() -> f32 { -7i8 as f32 }");
+    assert_eq!(j.run::<f32>()(), -7.0);
+    let j = jit("#[start] fn main() -> f32 { 16777217u64 as f32 }");
+    assert_eq!(j.run::<f32>()(), 16777217u64 as f32);
+}
+
+#[test]
+fn shifts_and_scopes() {
+    let j = jit("#[start] fn main() -> i32 { let x: i8 = -64; let y = x >> 2; (y as i32) << 1u8 }");
+    assert_eq!(j.run::<i32>()(), -32);
+    let j = jit("#[start] fn main() -> u8 { 1u8 << 9 }");
+    assert_eq!(j.run::<u8>()(), 2);
+    let j = jit("#[start] fn main() -> u32 { let x = 1; let x = x + 10; if x > 5 { let x = 100; } x }");
+    assert_eq!(j.run::<u32>()(), 11);
+    match err("#[start] fn main() -> u32 { if true { let y = 1; } y }") {
+        AssembleError::FunctionError(AssembleFunctionError::UnsupportedExpression(_)) | AssembleError::UnresolvedPath(_) => {},
+        e => panic!("{:?}", e),
+    }
+}
@@ -0,0 +1,87 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Opt<T> { is_some: bool, value: T }
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pair<T> { value: T, overflow: bool }
+
+fn jit(src: &str) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), &CompileOptions::default()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+macro_rules! check {
+    ($t:ty) => {{
+        let t = stringify!($t);
+        let vals: Vec<$t> = [0i128, 1, -1, 2, 3, 7, 100, 127, 128, -128, -129, 255, 256, 1000, 32767, -32768, 65535, 70000,
+            i32::MAX as i128, i32::MIN as i128, u32::MAX as i128, i64::MAX as i128, i64::MIN as i128, u64::MAX as i128]
+            .iter().map(|v| *v as $t).collect();
+        let wrap = jit(&format!("#[start] fn main(a: {0}, b: {0}) -> {0} {{ a.wrapping_add(b) ^ a.wrapping_sub(b).rotate_left(3) ^ a.wrapping_mul(b).rotate_left(7) }}", t));
+        let wrap: extern "sysv64" fn($t, $t) -> $t = unsafe { std::mem::transmute(wrap.run::<()>()) };
+        let sat_add = jit(&format!("#[start] fn main(a: {0}, b: {0}) -> {0} {{ a.saturating_add(b) }}", t));
+        let sat_add: extern "sysv64" fn($t, $t) -> $t = unsafe { std::mem::transmute(sat_add.run::<()>()) };
+        let sat_sub = jit(&format!("#[start] fn main(a: {0}, b: {0}) -> {0} {{ a.saturating_sub(b) }}", t));
+        let sat_sub: extern "sysv64" fn($t, $t) -> $t = unsafe { std::mem::transmute(sat_sub.run::<()>()) };
+        let mut checked = Vec::new();
+        let mut overflowing = Vec::new();
+        for op in ["add", "sub", "mul"] {
+            let j = jit(&format!("#[start] fn main(a: {0}, b: {0}) -> Option<{0}> {{ a.checked_{1}(b) }}", t, op));
+            checked.push(j);
+            let j = jit(&format!("#[start] fn main(a: {0}, b: {0}) -> ({0}, bool) {{ a.overflowing_{1}(b) }}", t, op));
+            overflowing.push(j);
+        }
+        let c: Vec<extern "C" fn($t, $t) -> Opt<$t>> = checked.iter().map(|j| unsafe { std::mem::transmute(j.run::<()>()) }).collect();
+        let o: Vec<extern "C" fn($t, $t) -> Pair<$t>> = overflowing.iter().map(|j| unsafe { std::mem::transmute(j.run::<()>()) }).collect();
+        for &a in &vals {
+            for &b in &vals {
+                assert_eq!(wrap(a, b), a.wrapping_add(b) ^ a.wrapping_sub(b).rotate_left(3) ^ a.wrapping_mul(b).rotate_left(7));
+                assert_eq!(sat_add(a, b), a.saturating_add(b), "{} {} {}", t, a, b);
+                assert_eq!(sat_sub(a, b), a.saturating_sub(b), "{} {} {}", t, a, b);
+                let std_checked = [a.checked_add(b), a.checked_sub(b), a.checked_mul(b)];
+                let std_over = [a.overflowing_add(b), a.overflowing_sub(b), a.overflowing_mul(b)];
+                for k in 0..3 {
+                    let r = c[k](a, b);
+                    assert_eq!(r.is_some, std_checked[k].is_some(), "{} {} {} {}", t, k, a, b);
+                    if r.is_some { assert_eq!(r.value, std_checked[k].unwrap()); }
+                    let r = o[k](a, b);
+                    assert_eq!((r.value, r.overflow), std_over[k], "{} {} {} {}", t, k, a, b);
+                }
+            }
+        }
+    }};
+}
+
+#[test]
+fn all_types() {
+    check!(u8); check!(i8); check!(u16); check!(i16); check!(u32); check!(i32); check!(u64); check!(i64);
+}
+
+#[test]
+fn options_and_tuples() {
+    let j = jit("#[start] fn main(a: u8, b: u8) -> u32 { let sum = a.checked_add(b); let fallback = sum.unwrap_or(0); if sum.is_some() { fallback as u32 } else { 1000 } }");
+    let f: extern "sysv64" fn(u8, u8) -> u32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(100, 100), 200);
+    assert_eq!(f(200, 100), 1000);
+    let j = jit("fn score(a: i32) -> (i32, bool) { a.overflowing_mul(1000) }
+                 #[start] fn main(a: i32) -> i64 { let r = score(a); let t: (i64, (bool, u8)) = (r.0 as i64, (r.1, 3)); if (t.1).0 { -1 } else { t.0 + (t.1).1 as i64 } }");
+    let f: extern "sysv64" fn(i32) -> i64 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(5), 5003);
+    assert_eq!(f(i32::MAX), -1);
+    // literals get their type from the option
+    let j = jit("fn get() -> Option<u8> { let x = 250; x.checked_add(10) }
+                 #[start] fn main() -> u32 { get().unwrap_or(7) as u32 + get().is_none() as u32 }");
+    let f: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(), 8);
+}
+
+#[test]
+fn tuple_fields() {
+    let j = jit("struct A { p: (B, u8) } struct B { x: u64, y: u32 }
+                 #[start] fn main(v: u64) -> u64 { let a = A { p: (B { x: v, y: 2 }, 9) }; (a.p.0).x + a.p.1 as u64 + (a.p.0).y as u64 }");
+    let f: extern "sysv64" fn(u64) -> u64 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(100), 111);
+}
@@ -0,0 +1,254 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::{build, err};
+
+const SRC: &str = "
+    #[repr(C)] #[derive(Clone, Copy)] struct Enemy { hp: i32, armor: i32 }
+
+    #[export] fn weighted(xs: &[u32]) -> u64 {
+        let mut total = 0u64;
+        for (i, x) in xs.iter().enumerate() { total += i as u64 * 100 + *x as u64; }
+        total
+    }
+    #[export] fn reversed(xs: &[u32]) -> u64 {
+        let mut total = 0u64;
+        for (i, &x) in xs.iter().enumerate().rev() { total = total * 10 + x as u64 + i as u64; }
+        total
+    }
+    #[export] fn rev_enum(xs: &[u32]) -> u64 {
+        let mut total = 0u64;
+        for (i, x) in xs.iter().rev().enumerate() { total = total * 10 + *x as u64 + i as u64; }
+        total
+    }
+    #[export] fn dot(xs: &[i32], ys: &[i32]) -> i32 {
+        let mut total = 0;
+        for (a, b) in xs.iter().zip(ys) { total += a * b; }
+        total
+    }
+    #[export] fn pairs(xs: &[u32], ys: &[u32]) -> u64 {
+        let mut total = 0u64;
+        for (a, b) in xs.iter().zip(ys.iter()).rev() { total = total * 100 + (*a * 10 + *b) as u64; }
+        total
+    }
+    #[export] fn rev_zip(xs: &[u32], ys: &[u32]) -> u64 {
+        let mut total = 0u64;
+        for (i, (a, b)) in xs.iter().rev().zip(ys).enumerate() { total = total * 1000 + i as u64 * 100 + (*a * 10 + *b) as u64; }
+        total
+    }
+    #[export] fn damage(enemies: &mut [Enemy], amount: i32) -> i32 {
+        for e in enemies.iter_mut() {
+            e.hp -= amount - e.armor;
+            if e.hp < 0 { e.hp = 0; }
+        }
+        let mut alive = 0;
+        for e in enemies.iter() { if e.hp > 0 { alive += 1; } }
+        alive
+    }
+    #[export] fn double(xs: &mut [u32]) -> u32 {
+        for x in xs.iter_mut() { *x *= 2; }
+        for (i, x) in xs.iter_mut().enumerate() { if i == 0 { return *x; } }
+        0
+    }
+    #[export] fn first_big(xs: &mut [u32]) -> u32 {
+        for x in xs.iter_mut() { *x += 1; if *x > 5 { return *x; } }
+        0
+    }
+    #[export] fn sum(xs: &[u32]) -> u32 { xs.iter().sum() }
+    #[export] fn fsum(xs: &[f32]) -> f32 { xs.iter().sum::<f32>() }
+    #[export] fn min(xs: &[i32]) -> i32 { xs.iter().min().unwrap_or(99) }
+    #[export] fn max(xs: &[i32]) -> i32 { xs.iter().rev().max().unwrap_or(-99) }
+    #[export] fn any_above(xs: &[i32], limit: i32) -> bool { xs.iter().any(|x| *x > limit) }
+    #[export] fn all_below(xs: &[i32], limit: i32) -> bool { xs.iter().all(|&x| x < limit) }
+    #[export] fn any_index(xs: &[i32]) -> bool { xs.iter().enumerate().any(|(i, x)| i == 2 && *x == 0) }
+    #[export] fn bare(xs: &[u32]) -> u32 { let mut n = 0; for x in xs { n += *x; } n }
+";
+
+#[test]
+fn loops() {
+    let j = build(SRC).unwrap();
+    let f = |name: &str| j.export(name).unwrap();
+    let xs = [1u32, 2, 3];
+    assert_eq!(j.call_export::<_, u64>(f("weighted"), (&xs[..],)), Ok(306));
+    assert_eq!(j.call_export::<_, u64>(f("reversed"), (&xs[..],)), Ok(5 * 100 + 3 * 10 + 1));
+    assert_eq!(j.call_export::<_, u64>(f("rev_enum"), (&xs[..],)), Ok(3 * 100 + 3 * 10 + 3));
+    assert_eq!(j.call_export::<_, u64>(f("bare"), (&xs[..],)), Ok(6));
+    assert_eq!(j.call_export::<_, i32>(f("dot"), (&[1, 2, 3][..], &[4, 5][..])), Ok(14));
+    assert_eq!(j.call_export::<_, u64>(f("pairs"), (&[1u32, 2, 3][..], &[4u32, 5][..])), Ok(2514));
+    assert_eq!(j.call_export::<_, u64>(f("rev_zip"), (&[1u32, 2, 3][..], &[4u32, 5][..])), Ok(34 * 1000 + 125));
+    let empty: [u32; 0] = [];
+    assert_eq!(j.call_export::<_, u64>(f("reversed"), (&empty[..],)), Ok(0));
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Enemy { hp: i32, armor: i32 }
+
+#[test]
+fn mutation() {
+    let j = build(SRC).unwrap();
+    let mut enemies = [Enemy { hp: 10, armor: 2 }, Enemy { hp: 3, armor: 0 }, Enemy { hp: 8, armor: 5 }];
+    assert_eq!(j.call_export::<_, i32>(j.export("damage").unwrap(), (&mut enemies[..], 6)), Ok(2));
+    assert_eq!(enemies, [Enemy { hp: 6, armor: 2 }, Enemy { hp: 0, armor: 0 }, Enemy { hp: 7, armor: 5 }]);
+    let mut xs = [1u32, 2, 3];
+    assert_eq!(j.call_export::<_, u32>(j.export("double").unwrap(), (&mut xs[..],)), Ok(2));
+    assert_eq!(xs, [2, 4, 6]);
+    let mut xs = [1u32, 5, 3];
+    assert_eq!(j.call_export::<_, u32>(j.export("first_big").unwrap(), (&mut xs[..],)), Ok(6));
+    assert_eq!(xs, [2, 6, 3]);
+}
+
+#[test]
+fn terminators() {
+    let j = build(SRC).unwrap();
+    let f = |name: &str| j.export(name).unwrap();
+    let xs = [3i32, -7, 0, 12];
+    let empty: [i32; 0] = [];
+    assert_eq!(j.call_export::<_, u32>(f("sum"), (&[1u32, 2, 3][..],)), Ok(6));
+    assert_eq!(j.call_export::<_, f32>(f("fsum"), (&[0.5f32, 0.25][..],)), Ok(0.75));
+    assert_eq!(j.call_export::<_, i32>(f("min"), (&xs[..],)), Ok(-7));
+    assert_eq!(j.call_export::<_, i32>(f("max"), (&xs[..],)), Ok(12));
+    assert_eq!(j.call_export::<_, i32>(f("min"), (&empty[..],)), Ok(99));
+    assert_eq!(j.call_export::<_, bool>(f("any_above"), (&xs[..], 11)), Ok(true));
+    assert_eq!(j.call_export::<_, bool>(f("any_above"), (&xs[..], 12)), Ok(false));
+    assert_eq!(j.call_export::<_, bool>(f("all_below"), (&xs[..], 13)), Ok(true));
+    assert_eq!(j.call_export::<_, bool>(f("all_below"), (&xs[..], 12)), Ok(false));
+    assert_eq!(j.call_export::<_, bool>(f("any_index"), (&xs[..],)), Ok(true));
+    let o = CompileOptions { mode: CompileMode::Debug, ..CompileOptions::default() };
+    let buf = compile_with_options(parse_file("#[export] fn s(xs: &[u8]) -> u8 { xs.iter().sum() }").unwrap(), &o).unwrap();
+    let sum = JitMemory::from_assembly_buf(&buf).unwrap();
+    match sum.call_export::<_, u8>(sum.export("s").unwrap(), (&[200u8, 100][..],)) {
+        Err(ScriptError::Overflow(location)) => assert_eq!(location.expression, "xs . iter ( ) . sum ( )"),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn continue_and_break() {
+    let j = build("
+        #[export] fn odd_until_zero(xs: &[u32]) -> u32 {
+            let mut sum = 0;
+            for x in xs {
+                if *x == 0 { break; }
+                if x % 2 == 0 { continue; }
+                sum += x;
+            }
+            sum
+        }
+        #[export] fn first_some(xs: &[u32]) -> u32 {
+            let mut found = 0;
+            for (i, x) in xs.iter().enumerate() {
+                let v = match if *x > 10 { Some(*x) } else { None } { Some(v) => v, None => continue };
+                found = v * 10 + i as u32;
+                break;
+            }
+            found
+        }
+        #[export] fn countdown(n: u32) -> u32 {
+            let mut left = Some(n);
+            let mut steps = 0;
+            while let Some(k) = left {
+                left = if k == 0 { None } else { Some(k - 1) };
+                if k % 3 == 0 { continue; }
+                if k == 1 { break; }
+                steps += 1;
+            }
+            steps
+        }").unwrap();
+    let f = |name: &str| j.export(name).unwrap();
+    assert_eq!(j.call_export::<_, u32>(f("odd_until_zero"), (&[1u32, 2, 3, 4, 5, 0, 7][..],)), Ok(9));
+    assert_eq!(j.call_export::<_, u32>(f("first_some"), (&[1u32, 20, 30][..],)), Ok(201));
+    assert_eq!(j.call_export::<_, u32>(f("first_some"), (&[1u32][..],)), Ok(0));
+    // 10, 8, 7, 5, 4, 2 are counted, 9, 6 and 3 are skipped and 1 stops the loop
+    assert_eq!(j.call_export::<_, u32>(f("countdown"), (10,)), Ok(6));
+
+    let moved = "struct T(u32); fn take(t: T) {}
+        #[export] fn f(xs: &[u32]) { let t = T(1); for x in xs { if *x == 0 { take(t); continue; } } }";
+    assert!(matches!(err(moved), AssembleFunctionError::UseOfMovedValue { .. }));
+    let broken = "struct T(u32); fn take(t: T) {}
+        #[export] fn f(xs: &[u32]) -> u32 { let t = T(1); for x in xs { take(t); break; } t.0 }";
+    assert!(matches!(err(broken), AssembleFunctionError::UseOfMovedValue { .. }));
+    let once = "struct T(u32); fn take(t: T) {}
+        #[export] fn f(xs: &[u32]) { let t = T(1); for x in xs { take(t); break; } }";
+    assert!(build(once).is_ok());
+    for src in &[
+        "#[export] fn f() { continue; }",
+        "#[export] fn f(xs: &[u32]) { 'outer: for x in xs { continue 'outer; } }",
+        "#[export] fn f(xs: &[u32]) -> bool { for x in xs { let _ = xs.iter().any(|y| { break; }); } true }",
+    ] {
+        assert!(matches!(err(src), AssembleFunctionError::UnsupportedExpression(_)), "{}", src);
+    }
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("#[export] fn f(x: u32) { for i in x {} }"), AssembleFunctionError::NotIterable { .. }));
+    assert!(matches!(err("#[export] fn f(xs: &[u32]) { for x in xs.iter_mut() { *x = 1; } }"), AssembleFunctionError::BorrowOfImmutable { .. }));
+    assert!(matches!(err("#[export] fn f(xs: &[u32]) { for x in xs.iter() { *x = 1; } }"), AssembleFunctionError::UnsupportedExpression(_)));
+    assert!(matches!(err("#[export] fn f(xs: &[f32]) -> Option<f32> { xs.iter().max() }"), AssembleFunctionError::UnknownMethod { .. }));
+    assert!(matches!(err("#[export] fn f(xs: &[u32]) -> bool { xs.iter().any(|x| return true) }"), AssembleFunctionError::UnsupportedExpression(_)));
+}
+
+#[test]
+fn borrowed_sources() {
+    for (src, expression) in &[
+        ("#[export] fn f(xs: &mut [u32]) { for (a, b) in xs.iter_mut().zip(xs.iter_mut()) { *a += *b; } }", "xs"),
+        ("#[export] fn f(xs: &mut [u32]) { for (a, b) in xs.iter_mut().zip(xs.iter()) { *a += *b; } }", "xs"),
+        ("#[export] fn f(xs: &mut [u32]) { for (a, b) in xs.iter().zip(xs.iter_mut()) { *b += *a; } }", "xs"),
+        ("#[export] fn f(xs: &mut [u32]) { for x in xs.iter() { for y in xs.iter_mut() { *y += *x; } } }", "xs"),
+    ] {
+        match err(src) {
+            AssembleFunctionError::OverlappingBorrow { name, .. } => assert_eq!(name, *expression, "{}", src),
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+    for src in &[
+        "#[export] fn f(xs: &mut [u32]) { for (i, x) in xs.iter_mut().enumerate() { *x = xs[i] + 1; } }",
+        "#[export] fn f(xs: &mut [u32]) { for x in xs.iter_mut() { *x = xs.len() as u32; } }",
+        "#[export] fn f(xs: &mut [u32]) { for x in xs { xs[0] = *x; } }",
+        "#[export] fn f(xs: &mut [u32]) { for x in xs.iter() { xs[0] += *x; } }",
+        "#[export] fn f(xs: &mut [u32]) -> bool { xs.iter_mut().any(|x| xs[0] == *x) }",
+    ] {
+        match err(src) {
+            AssembleFunctionError::UseOfBorrowedValue { expression, .. } => assert_eq!(expression, "xs", "{}", src),
+            e => panic!("{}: {:?}", src, e),
+        }
+    }
+
+    // shared iterators of the same slice and other slices in the body are fine
+    let j = build("#[export] fn f(xs: &[u32], ys: &mut [u32]) -> u32 {
+            let mut n = 0;
+            for (a, b) in xs.iter().zip(xs.iter().rev()) { n += *a * *b; }
+            for (i, x) in xs.iter().enumerate() { ys[i] = *x + xs[i] + xs.len() as u32; }
+            for y in ys.iter_mut() { *y += n; }
+            n
+        }").unwrap();
+    let mut ys = [0u32; 3];
+    assert_eq!(j.call_export::<_, u32>(j.export("f").unwrap(), (&[1u32, 2, 3][..], &mut ys[..])), Ok(3 + 4 + 3));
+    assert_eq!(ys, [5 + 10, 7 + 10, 9 + 10]);
+}
+
+#[test]
+fn trap_keeps_writes() {
+    // the elements of `iter_mut` are changed in place, so the writes before a trap are kept
+    let j = build("#[export] fn f(xs: &mut [u32], d: u32) {
+            for x in xs.iter_mut() {
+                *x += 1;
+                *x = *x * 10 / d;
+            }
+        }").unwrap();
+    let mut xs = [1u32, 2, 3];
+    assert!(matches!(j.call_export::<_, ()>(j.export("f").unwrap(), (&mut xs[..], 0)), Err(ScriptError::DivideByZero(_))));
+    assert_eq!(xs, [2, 2, 3]);
+    assert_eq!(j.call_export::<_, ()>(j.export("f").unwrap(), (&mut xs[..], 2)), Ok(()));
+    assert_eq!(xs, [15, 15, 20]);
+
+    // and so are the writes before a `return` or the end of `any`
+    let j = build("#[export] fn f(xs: &mut [u32]) -> bool {
+            xs.iter_mut().any(|x| { *x += 1; *x > 2 })
+        }").unwrap();
+    let mut xs = [1u32, 2, 3];
+    assert_eq!(j.call_export::<_, bool>(j.export("f").unwrap(), (&mut xs[..],)), Ok(true));
+    assert_eq!(xs, [2, 3, 3]);
+}
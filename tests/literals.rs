@@ -0,0 +1,50 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+fn jit(src: &str) -> JitMemory {
+    let buf = compile(parse_file(src).unwrap()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+fn err(src: &str) -> AssembleError { compile(parse_file(src).unwrap()).err().unwrap() }
+
+#[test]
+fn chars() {
+    let j = jit("#[start] fn main() -> char { 'ß' }");
+    assert_eq!(j.run::<char>()(), 'ß');
+    let j = jit("#[start] fn main() -> u8 { b'x' }");
+    assert_eq!(j.run::<u8>()(), b'x');
+    let j = jit("#[start] fn main() -> u32 { key('w') } fn key(c: char) -> u32 { if c == 'w' { return 1; } if c >= 'a' && c <= 'z' { 2 } else if c == '€' { 3 } else { 4 } }");
+    assert_eq!(j.run::<u32>()(), 1);
+    let j = jit("#[start] fn main() -> u32 { key('€') } fn key(c: char) -> u32 { if c == 'w' { return 1; } if c >= 'a' && c <= 'z' { 2 } else if c == '€' { 3 } else { 4 } }");
+    assert_eq!(j.run::<u32>()(), 3);
+}
+
+#[test]
+fn casts() {
+    let j = jit("#[start] fn main() -> u32 { '€' as u32 }");
+    assert_eq!(j.run::<u32>()(), 0x20AC);
+    let j = jit("#[start] fn main() -> u8 { '€' as u8 }");
+    assert_eq!(j.run::<u8>()(), 0xAC);
+    let j = jit("#[start] fn main() -> char { (b'a' as u8) as char }");
+    assert_eq!(j.run::<char>()(), 'a');
+    let j = jit("#[start] fn main() -> i64 { -1i8 as i64 }");
+    assert_eq!(j.run::<i64>()(), -1);
+    let j = jit("#[start] fn main() -> u64 { -1i8 as u64 }");
+    assert_eq!(j.run::<u64>()(), u64::MAX);
+    let j = jit("#[start] fn main() -> i8 { 200u8 as i8 }");
+    assert_eq!(j.run::<i8>()(), -56);
+    let j = jit("#[start] fn main() -> bool { (true as u32) > 0 && !false }");
+    assert!(j.run::<bool>()());
+    let j = jit("#[start] fn main() -> bool { -5i32 < 3 || f() } fn f() -> bool { false }");
+    assert!(j.run::<bool>()());
+    let j = jit("#[start] fn main() -> bool { 200u8 < 3 }");
+    assert!(!j.run::<bool>()());
+    match err("#[start] fn main() -> char { 65u32 as char }") {
+        AssembleError::FunctionError(AssembleFunctionError::InvalidCast { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> bool { 1u8 as bool }") {
+        AssembleError::FunctionError(AssembleFunctionError::InvalidCast { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+}
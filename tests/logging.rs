@@ -0,0 +1,92 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct Recorder {
+    calls: Vec<(LogTarget, String)>,
+}
+
+impl ScriptLogger for Recorder {
+    fn write(&mut self, target: LogTarget, text: &str) {
+        self.calls.push((target, text.to_string()));
+    }
+    fn end_line(&mut self, target: LogTarget) {
+        self.calls.push((target, "<eol>".to_string()));
+    }
+}
+
+fn with_logger(src: &str) -> (JitMemory, Rc<RefCell<Recorder>>) {
+    let rec = Rc::new(RefCell::new(Recorder::default()));
+    let o = CompileOptions { logger: Some(SharedLogger(rec.clone())), ..CompileOptions::default() };
+    let buf = compile_with_options(parse_file(src).unwrap(), &o).unwrap();
+    (JitMemory::from_assembly_buf(&buf).unwrap(), rec)
+}
+
+fn text(rec: &Rc<RefCell<Recorder>>) -> String {
+    rec.borrow().calls.iter().map(|(_, t)| if t == "<eol>" { "\n".to_string() } else { t.clone() }).collect()
+}
+
+#[test]
+fn println_pieces() {
+    let (j, rec) = with_logger("#[start] fn main() -> i32 { let x = -3i8; println!(\"x = {}, y = {:?}!\", x, 2.5f64); eprint!(\"{}\", 'c'); log!(\"{:?} {}\", \"q\", true); println!(); 4 }");
+    assert_eq!(j.call::<i32>(), Ok(4));
+    let calls = rec.borrow().calls.clone();
+    let expect: Vec<(LogTarget, &str)> = vec![
+        (LogTarget::Stdout, "x = "), (LogTarget::Stdout, "-3"), (LogTarget::Stdout, ", y = "), (LogTarget::Stdout, "2.5"),
+        (LogTarget::Stdout, "!"), (LogTarget::Stdout, "<eol>"),
+        (LogTarget::Stderr, "c"),
+        (LogTarget::Log, "\"q\""), (LogTarget::Log, " "), (LogTarget::Log, "true"), (LogTarget::Log, "<eol>"),
+        (LogTarget::Stdout, "<eol>"),
+    ];
+    let got: Vec<(LogTarget, &str)> = calls.iter().map(|(t, s)| (*t, s.as_str())).collect();
+    assert_eq!(got, expect);
+    // runs again
+    rec.borrow_mut().calls.clear();
+    assert_eq!(j.call::<i32>(), Ok(4));
+    assert_eq!(rec.borrow().calls.len(), 12);
+    assert_eq!(j.run::<i32>()(), 4);
+}
+
+#[test]
+fn vectors_and_loops() {
+    let (j, rec) = with_logger("fn squares(i: u32) { if i < 3 { print!(\"{},\", i * i); squares(i + 1) } }
+        #[start] fn main() -> f32 { let v = Vec3::new(1.0, 2.5, -3.0); let w = Vec2::new(0.5, 1.0); let q = Vec4::new(1.0, 2.0, 3.0, 4.0);
+        println!(\"{} {:?} {:.1} {:?} {}\", v, v, w, q, v.x + w.y);
+        squares(0); v.y }");
+    assert_eq!(j.call::<f32>(), Ok(2.5));
+    let g3 = glam::Vec3::new(1.0, 2.5, -3.0);
+    let g4 = glam::Vec4::new(1.0, 2.0, 3.0, 4.0);
+    assert_eq!(text(&rec), format!("{} {:?} [0.5, 1.0] {:?} 2\n0,1,4,", g3, g3, g4));
+}
+
+#[test]
+fn no_logger_and_errors() {
+    // without a logger the macros compile to nothing, but their arguments are evaluated
+    let src = "extern \"C\" { fn side() -> i32; } #[start] fn main() -> i32 { println!(\"{}\", side()); side() }";
+    extern "C" fn side() -> i32 { COUNT.with(|c| { c.set(c.get() + 1); c.get() }) }
+    thread_local!(static COUNT: std::cell::Cell<i32> = const { std::cell::Cell::new(0) });
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("side", side as *const u8);
+    let j = JitMemory::from_assembly_buf(&compile_with_options(parse_file(src).unwrap(), &o).unwrap()).unwrap();
+    assert_eq!(j.call::<i32>(), Ok(2));
+
+    let o = CompileOptions { logger: Some(SharedLogger::new(String::new())), ..CompileOptions::default() };
+    for bad in &["print!()", "println!(\"{}\")", "println!(\"{}\", 1, 2)", "log!()", "println!(x)"] {
+        let src = format!("#[start] fn main() -> i32 {{ let x = 1; {}; 0 }}", bad);
+        assert!(compile_with_options(parse_file(&src).unwrap(), &o).is_err(), "{}", bad);
+    }
+    // the logger is kept alive by the memory, strings work
+    let logger = Rc::new(RefCell::new(String::new()));
+    let o = CompileOptions { logger: Some(SharedLogger(logger.clone())), ..CompileOptions::default() };
+    let j = JitMemory::from_assembly_buf(&compile_with_options(parse_file("#[start] fn main() { let s = \"hey\"; println!(\"{}{{}}{:?}\", s, s); }").unwrap(), &o).unwrap()).unwrap();
+    drop(o);
+    let _: () = j.call::<()>().unwrap();
+    assert_eq!(&*logger.borrow(), "hey{}\"hey\"\n");
+    // while the host borrows the logger, messages are dropped
+    let guard = logger.borrow();
+    let _: () = j.call::<()>().unwrap();
+    drop(guard);
+    assert_eq!(logger.borrow().len(), 11);
+}
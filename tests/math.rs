@@ -0,0 +1,97 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+fn compile(body: &str, sse41: bool) -> JitMemory {
+    let mut o = CompileOptions::default();
+    o.features.sse41 = sse41;
+    let buf = compile_with_options(parse_file(body).unwrap(), &o).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+const VALUES: [f64; 22] = [0.0, -0.0, 0.5, -0.5, 1.5, -1.5, 2.5, -2.5, 0.49999997, -0.49999997, 1e20, -1e20, 8388607.5, -8388607.5,
+    4503599627370495.5, -4503599627370495.5, f64::INFINITY, f64::NEG_INFINITY, f64::NAN, 3.7, -3.7, 1e-30];
+
+fn same32(a: f32, b: f32) -> bool { (a.is_nan() && b.is_nan()) || a.to_bits() == b.to_bits() }
+fn same64(a: f64, b: f64) -> bool { (a.is_nan() && b.is_nan()) || a.to_bits() == b.to_bits() }
+
+#[test]
+fn rounding() {
+    for sse41 in [false, true] {
+        for m in ["floor", "ceil", "round", "trunc", "abs", "sqrt", "sin", "ln", "cbrt"] {
+            let j32 = compile(&format!("#[start] fn main(x: f32) -> f32 {{ x.{}() }}", m), sse41);
+            let f32f: extern "sysv64" fn(f32) -> f32 = unsafe { std::mem::transmute(j32.run::<()>()) };
+            let j64 = compile(&format!("#[start] fn main(x: f64) -> f64 {{ x.{}() }}", m), sse41);
+            let f64f: extern "sysv64" fn(f64) -> f64 = unsafe { std::mem::transmute(j64.run::<()>()) };
+            for &v in VALUES.iter() {
+                let e32 = match m { "floor" => (v as f32).floor(), "ceil" => (v as f32).ceil(), "round" => (v as f32).round(), "trunc" => (v as f32).trunc(),
+                    "abs" => (v as f32).abs(), "sqrt" => (v as f32).sqrt(), "sin" => (v as f32).sin(), "ln" => (v as f32).ln(), _ => (v as f32).cbrt() };
+                let e64 = match m { "floor" => v.floor(), "ceil" => v.ceil(), "round" => v.round(), "trunc" => v.trunc(),
+                    "abs" => v.abs(), "sqrt" => v.sqrt(), "sin" => v.sin(), "ln" => v.ln(), _ => v.cbrt() };
+                assert!(same32(f32f(v as f32), e32), "{} f32 {} sse41 {}: {} vs {}", m, v, sse41, f32f(v as f32), e32);
+                assert!(same64(f64f(v), e64), "{} f64 {} sse41 {}: {} vs {}", m, v, sse41, f64f(v), e64);
+            }
+        }
+    }
+}
+
+#[test]
+fn binary() {
+    for m in ["min", "max", "atan2", "powf", "hypot"] {
+        let j = compile(&format!("#[start] fn main(x: f32, y: f32) -> f32 {{ x.{}(y) }}", m), false);
+        let f: extern "sysv64" fn(f32, f32) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let j64 = compile(&format!("#[start] fn main(x: f64, y: f64) -> f64 {{ x.{}(y) }}", m), false);
+        let f64f: extern "sysv64" fn(f64, f64) -> f64 = unsafe { std::mem::transmute(j64.run::<()>()) };
+        for &a in VALUES.iter() { for &b in VALUES.iter() {
+            let (x, y) = (a as f32, b as f32);
+            let e = match m { "min" => x.min(y), "max" => x.max(y), "atan2" => x.atan2(y), "powf" => x.powf(y), _ => x.hypot(y) };
+            let e64 = match m { "min" => a.min(b), "max" => a.max(b), "atan2" => a.atan2(b), "powf" => a.powf(b), _ => a.hypot(b) };
+            // the sign of a zero result is unspecified
+            if (m == "min" || m == "max") && x == 0.0 && y == 0.0 { continue; }
+            assert!(same32(f(x, y), e), "{} {} {}: {}", m, x, y, f(x, y));
+            assert!(same64(f64f(a, b), e64), "{} {} {}", m, a, b);
+        }}
+    }
+    let j = compile("#[start] fn main(x: f32, a: f32, b: f32) -> f32 { x.clamp(a, b) + 0.0 * x.lerp(b, 0.5) }", false);
+    let f: extern "sysv64" fn(f32, f32, f32) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(5.0, 1.0, 3.0), 3.0); assert_eq!(f(-5.0, 1.0, 3.0), 1.0); assert_eq!(f(2.0, 1.0, 3.0), 2.0); assert!(f(f32::NAN, 1.0, 3.0).is_nan());
+    let j = compile("#[start] fn main(x: f32, b: f32, t: f32) -> f32 { x.lerp(b, t) }", false);
+    let f: extern "sysv64" fn(f32, f32, f32) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(1.0, 3.0, 0.25), 1.5);
+    let j = compile("#[start] fn main(x: f64, n: i32) -> f64 { x.powi(n) }", false);
+    let f: extern "sysv64" fn(f64, i32) -> f64 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(1.5, 3), 1.5f64.powi(3)); assert_eq!(f(2.0, -2), 0.25);
+    let j = compile("#[start] fn main() -> f32 { let x = 2.0; let y: f32 = x.sqrt(); y }", false);
+    let f: extern "sysv64" fn() -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(), 2f32.sqrt());
+}
+
+#[test]
+fn ints() {
+    let j = compile("#[start] fn main(a: i32, b: i32) -> i32 { a.abs() * 1000 + a.min(b) * 100 + a.max(b) * 10 + a.clamp(-1, 1) }", false);
+    let f: extern "sysv64" fn(i32, i32) -> i32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    for a in -5..5 { for b in -5..5 { assert_eq!(f(a, b), a.abs() * 1000 + a.min(b) * 100 + a.max(b) * 10 + a.clamp(-1, 1)); } }
+    let j = compile("#[start] fn main(a: i32) -> i32 { a.abs() }", false);
+    let f: extern "sysv64" fn(i32) -> i32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(i32::MIN), i32::MIN);
+    let j = compile("#[start] fn main(a: u8, b: u8) -> u8 { a.min(b) + a.max(b).pow(2) }", false);
+    let f: extern "sysv64" fn(u8, u8) -> u8 = unsafe { std::mem::transmute(j.run::<()>()) };
+    for a in [0u8, 1, 3, 200, 255] { for b in [0u8, 7, 129, 255] { assert_eq!(f(a, b), a.min(b).wrapping_add(a.max(b).wrapping_pow(2))); } }
+    let j = compile("#[start] fn main(a: i64, e: u32) -> i64 { a.pow(e) }", false);
+    let f: extern "sysv64" fn(i64, u32) -> i64 = unsafe { std::mem::transmute(j.run::<()>()) };
+    for a in [-3i64, 0, 1, 2, 7, 12345] { for e in 0..70 { assert_eq!(f(a, e), a.wrapping_pow(e)); } }
+    let j = compile("#[start] fn main() -> i64 { let x = -3; let y: i64 = x.abs(); y.pow(3) }", false);
+    let f: extern "sysv64" fn() -> i64 = unsafe { std::mem::transmute(j.run::<()>()) };
+    assert_eq!(f(), 27);
+}
+
+#[test]
+fn errors() {
+    let e = compile_with_options(parse_file("#[start] fn main(a: u32) -> u32 { a.abs() }").unwrap(), &CompileOptions::default()).err().unwrap();
+    assert!(matches!(e, AssembleError::FunctionError(AssembleFunctionError::UnknownMethod { .. })), "{:?}", e);
+    let e = compile_with_options(parse_file("#[start] fn main(a: f32) -> f32 { a.min() }").unwrap(), &CompileOptions::default()).err().unwrap();
+    assert!(matches!(e, AssembleError::FunctionError(AssembleFunctionError::WrongArgumentCount { .. })), "{:?}", e);
+    let e = compile_with_options(parse_file("#[start] fn main(a: f32, b: f64) -> f32 { a.min(b) }").unwrap(), &CompileOptions::default()).err().unwrap();
+    assert!(matches!(e, AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. })), "{:?}", e);
+    let e = compile_with_options(parse_file("#[start] fn main(a: f32) -> f32 { a.frobnicate() }").unwrap(), &CompileOptions::default()).err().unwrap();
+    assert!(matches!(e, AssembleError::FunctionError(AssembleFunctionError::UnknownMethod { .. })), "{:?}", e);
+}
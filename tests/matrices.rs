@@ -0,0 +1,170 @@
+// the host functions return matrices through a hidden pointer, like the JIT expects
+#![allow(improper_ctypes_definitions)]
+
+extern crate glam;
+extern crate gsr_jit;
+use gsr_jit::*;
+use glam::{Mat3A, Mat4, Quat, Vec3, Vec4};
+use std::sync::Mutex;
+
+static SINK: Mutex<Vec<Mat4>> = Mutex::new(Vec::new());
+
+fn input_a() -> Mat4 {
+    Mat4::from_cols(
+        Vec4::new(2.0, 0.5, -1.25, 0.0),
+        Vec4::new(0.3, 1.7, 0.2, 0.0),
+        Vec4::new(-0.6, 0.1, 3.3, 0.0),
+        Vec4::new(4.0, -2.0, 7.5, 1.0),
+    )
+}
+fn input_b() -> Mat4 {
+    Mat4::from_cols(
+        Vec4::new(1.1, 2.2, 0.3, 0.4),
+        Vec4::new(-0.5, 0.6, 0.7, -0.8),
+        Vec4::new(0.9, -1.0, 1.1, 1.2),
+        Vec4::new(1.3, 1.4, -1.5, 1.6),
+    )
+}
+extern "sysv64" fn get_a() -> Mat4 { input_a() }
+extern "sysv64" fn get_b() -> Mat4 { input_b() }
+extern "sysv64" fn sink(m: &Mat4) { assert_eq!(m as *const Mat4 as usize % 16, 0); SINK.lock().unwrap().push(*m); }
+extern "sysv64" fn sum(m: &Mat4, k: f32) -> f32 { m.to_cols_array().iter().sum::<f32>() * k }
+
+const EXTERNS: &str = r#"extern "C" { fn get_a() -> Mat4; fn get_b() -> Mat4; fn sink(m: Mat4); fn sum(m: Mat4, k: f32) -> f32; } "#;
+
+fn compile(body: &str, avx: bool) -> JitMemory {
+    let mut o = CompileOptions::default();
+    o.features.avx = avx;
+    o.host_functions.insert("get_a", get_a as *const u8);
+    o.host_functions.insert("get_b", get_b as *const u8);
+    o.host_functions.insert("sink", sink as *const u8);
+    o.host_functions.insert("sum", sum as *const u8);
+    let buf = compile_with_options(parse_file(&format!("{}{}", EXTERNS, body)).unwrap(), &o).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+fn mat_of(body: &str) -> Mat4 {
+    let mut result = None;
+    for avx in [false, CpuFeatures::detect().avx] {
+        let jit = compile(body, avx);
+        let f: extern "sysv64" fn() -> Mat4 = unsafe { std::mem::transmute(jit.run::<()>()) };
+        let m = f();
+        if let Some(r) = result { assert_eq!(r, m, "avx {}", avx); }
+        result = Some(m);
+    }
+    result.unwrap()
+}
+fn f32_of(body: &str) -> f32 {
+    let jit = compile(body, false);
+    let f: extern "sysv64" fn() -> f32 = unsafe { std::mem::transmute(jit.run::<()>()) };
+    f()
+}
+fn quat_of(body: &str) -> Quat {
+    let jit = compile(body, false);
+    let f: extern "sysv64" fn() -> std::arch::x86_64::__m128 = unsafe { std::mem::transmute(jit.run::<()>()) };
+    let v: [f32; 4] = unsafe { std::mem::transmute(f()) };
+    Quat::from_xyzw(v[0], v[1], v[2], v[3])
+}
+fn vec3_of(body: &str) -> Vec3 {
+    let q = quat_of(body);
+    Vec3::new(q.x, q.y, q.z)
+}
+fn err(body: &str) -> AssembleError {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("get_a", get_a as *const u8);
+    o.host_functions.insert("get_b", get_b as *const u8);
+    o.host_functions.insert("sink", sink as *const u8);
+    o.host_functions.insert("sum", sum as *const u8);
+    compile_with_options(parse_file(&format!("{}{}", EXTERNS, body)).unwrap(), &o).err().unwrap()
+}
+
+#[test]
+fn mat4_ops_match_glam() {
+    let (a, b) = (input_a(), input_b());
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { get_a() }"), a);
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { get_a() * get_b() }"), a * b);
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { let m = get_b(); m.inverse() }"), b.inverse());
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { get_a().inverse() }"), a.inverse());
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { get_b().transpose() }"), b.transpose());
+    assert_eq!(f32_of("#[start] fn main() -> f32 { get_b().determinant() }"), b.determinant());
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::IDENTITY }"), Mat4::IDENTITY);
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::ZERO }"), Mat4::ZERO);
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)) }"), Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)));
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::from_scale(Vec3::new(1.0, 2.0, 3.0)) }"), Mat4::from_scale(Vec3::new(1.0, 2.0, 3.0)));
+    let q = Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0).normalize(), 0.7);
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0).normalize(), 0.7) }"), q);
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::from_quat(Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0).normalize(), 0.7)) }"), Mat4::from_quat(q));
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { Mat4::from_rotation_translation(Quat::from_axis_angle(Vec3::new(1.0, 2.0, 3.0).normalize(), 0.7), Vec3::new(4.0, 5.0, 6.0)) }"),
+               Mat4::from_rotation_translation(q, Vec3::new(4.0, 5.0, 6.0)));
+    let p = Vec3::new(0.5, -1.5, 2.0);
+    assert_eq!(vec3_of("#[start] fn main() -> Vec3 { get_a().transform_point3(Vec3::new(0.5, -1.5, 2.0)) }"), a.transform_point3(p));
+    assert_eq!(vec3_of("#[start] fn main() -> Vec3 { get_b().transform_vector3(Vec3::new(0.5, -1.5, 2.0)) }"), b.transform_vector3(p));
+    let v = quat_of("#[start] fn main() -> Vec4 { get_b() * Vec4::new(0.5, -1.5, 2.0, 3.0) }");
+    assert_eq!(Vec4::new(v.x, v.y, v.z, v.w), b * Vec4::new(0.5, -1.5, 2.0, 3.0));
+    let m = mat_of("#[start] fn main() -> Mat4 { Mat4::from_cols(Vec4::new(1.0, 2.0, 3.0, 4.0), Vec4::ONE, Vec4::ZERO, Vec4::splat(2.0)) }");
+    assert_eq!(m, Mat4::from_cols(Vec4::new(1.0, 2.0, 3.0, 4.0), Vec4::ONE, Vec4::ZERO, Vec4::splat(2.0)));
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let m = get_b(); m.w_axis.y + m.x_axis.x }"), 1.4 + 1.1);
+}
+
+#[test]
+fn passing() {
+    SINK.lock().unwrap().clear();
+    let body = "fn twice(m: Mat4, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: Mat4) -> Mat4 { m * g } \
+                #[start] fn main() -> f32 { let m = twice(get_a(), 1, 2, 3, 4, 5, 6, get_b()); sink(m); sink(get_b()); sum(m, 2.0) }";
+    let r = f32_of(body);
+    let sunk = SINK.lock().unwrap().clone();
+    assert_eq!(sunk, vec![input_a() * input_b(), input_b()]);
+    assert_eq!(r, (input_a() * input_b()).to_cols_array().iter().sum::<f32>() * 2.0);
+    // struct with a matrix, closures
+    assert_eq!(mat_of("struct T { m: Mat4, s: f32 } fn get(t: T) -> Mat4 { t.m } #[start] fn main() -> Mat4 { let t = T { m: get_a(), s: 1.0 }; get(t) }"), input_a());
+    assert_eq!(mat_of("#[start] fn main() -> Mat4 { let f = |m: Mat4| m.transpose(); f(get_a()) }"), input_a().transpose());
+}
+
+#[test]
+fn mat3() {
+    let a = Mat3A::from_cols(Vec3::new(2.0, 0.5, -1.25).into(), Vec3::new(0.3, 1.7, 0.2).into(), Vec3::new(-0.6, 0.1, 3.3).into());
+    let cols = "Mat3::from_cols(Vec3::new(2.0, 0.5, -1.25), Vec3::new(0.3, 1.7, 0.2), Vec3::new(-0.6, 0.1, 3.3))";
+    assert_eq!(f32_of(&format!("#[start] fn main() -> f32 {{ {}.determinant() }}", cols)), a.determinant());
+    let inv = a.inverse();
+    for (i, axis) in ["x_axis", "y_axis", "z_axis"].iter().enumerate() {
+        let v = vec3_of(&format!("#[start] fn main() -> Vec3 {{ {}.inverse().{} }}", cols, axis));
+        assert_eq!(v, Vec3::from(inv.col(i)));
+        let v = vec3_of(&format!("#[start] fn main() -> Vec3 {{ ({} * {}.transpose()).{} }}", cols, cols, axis));
+        assert_eq!(v, Vec3::from((a * a.transpose()).col(i)));
+    }
+    let v = vec3_of(&format!("#[start] fn main() -> Vec3 {{ {} * Vec3::new(1.0, 2.0, 3.0) }}", cols));
+    assert_eq!(v, Vec3::from(a * glam::Vec3A::new(1.0, 2.0, 3.0)));
+    let q = Quat::from_rotation_z(0.4);
+    let v = vec3_of("#[start] fn main() -> Vec3 { Mat3::from_quat(Quat::from_rotation_z(0.4)).y_axis }");
+    assert_eq!(v, Vec3::from(Mat3A::from_quat(q).y_axis));
+}
+
+#[test]
+fn quat() {
+    let a = Quat::from_rotation_x(0.3);
+    let b = Quat::from_axis_angle(Vec3::new(0.0, 0.6, 0.8), 1.1);
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3) }"), a);
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::from_rotation_y(0.3) }"), Quat::from_rotation_y(0.3));
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3) * Quat::from_axis_angle(Vec3::new(0.0, 0.6, 0.8), 1.1) }"), a * b);
+    assert_eq!(vec3_of("#[start] fn main() -> Vec3 { Quat::from_axis_angle(Vec3::new(0.0, 0.6, 0.8), 1.1) * Vec3::new(1.0, 2.0, 3.0) }"), b * Vec3::new(1.0, 2.0, 3.0));
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3).inverse() }"), a.inverse());
+    assert_eq!(quat_of("#[start] fn main() -> Quat { Quat::IDENTITY }"), Quat::IDENTITY);
+    assert_eq!(quat_of("#[start] fn main() -> Quat { -Quat::from_xyzw(0.0, 0.6, 0.0, 0.8).normalize() }"), -Quat::from_xyzw(0.0, 0.6, 0.0, 0.8).normalize());
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let q = Quat::from_xyzw(1.0, 2.0, 3.0, 4.0); q.w * 10.0 + q.y + q.length_squared() }"), 42.0 + 30.0);
+    let s = quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3).slerp(Quat::from_axis_angle(Vec3::new(0.0, 0.6, 0.8), 1.1), 0.25) }");
+    assert!(s.abs_diff_eq(a.slerp(b, 0.25), 1e-5), "{:?} {:?}", s, a.slerp(b, 0.25));
+    // opposite hemisphere, nearly identical
+    let s = quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3).slerp(-Quat::from_rotation_x(0.2), 0.5) }");
+    assert!(s.abs_diff_eq(a.slerp(-Quat::from_rotation_x(0.2), 0.5), 1e-6), "{:?}", s);
+    let s = quat_of("#[start] fn main() -> Quat { Quat::from_rotation_x(0.3).slerp(Quat::from_rotation_x(0.30001), 0.5) }");
+    assert_eq!(s, a.slerp(Quat::from_rotation_x(0.30001), 0.5));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("#[start] fn main() -> Mat4 { Mat3::IDENTITY }"), AssembleError::FunctionError(AssembleFunctionError::ReturnTypeMismatch(_))));
+    assert!(matches!(err("#[start] fn main() { let m = Mat3::from_translation(Vec3::ONE); }"), AssembleError::FunctionError(AssembleFunctionError::UnknownMethod { .. })));
+    assert!(matches!(err("#[start] fn main() { let v = Mat3::IDENTITY * Vec4::ONE; }"), AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. })));
+    assert!(matches!(err("#[start] fn main() { let v = Mat3::IDENTITY.w_axis; }"), AssembleError::FunctionError(AssembleFunctionError::UnknownField { .. })));
+    assert!(matches!(err("#[start] fn main() { let v = Quat::IDENTITY.cross(Quat::IDENTITY); }"), AssembleError::FunctionError(AssembleFunctionError::UnknownMethod { .. })));
+    assert!(matches!(err("#[start] fn main() { let v = Mat4::IDENTITY + Mat4::IDENTITY; }"), AssembleError::FunctionError(AssembleFunctionError::UnsupportedExpression(_))));
+}
@@ -0,0 +1,76 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+fn run_u64(src: &str) -> u64 {
+    let buf = compile(parse_file(src).unwrap()).unwrap();
+    let jit = JitMemory::from_assembly_buf(&buf).unwrap();
+    jit.run::<u64>()()
+}
+fn err(src: &str) -> AssembleError {
+    match compile(parse_file(src).unwrap()) { Err(e) => e, Ok(_) => panic!("expected error") }
+}
+
+#[test]
+fn literal() { assert_eq!(run_u64("#[start] fn f() -> u64 { 143 }"), 143); }
+#[test]
+fn big() { assert_eq!(run_u64("#[start] fn f() -> u64 { 5394849584509 }"), 5394849584509); }
+
+#[test]
+fn modules() {
+    let src = r#"
+        mod ai {
+            pub fn patrol() -> u64 { helper() }
+            fn helper() -> u64 { super::base() }
+            pub mod deep { pub fn x(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64, g: u64, h: u64) -> u64 { h } }
+        }
+        fn base() -> u64 { 42 }
+        use crate::ai::patrol;
+        use ai::deep::{self, x as y};
+        #[start]
+        fn main() -> u64 { patrol(); deep::x(1,2,3,4,5,6,7,ai::patrol()); y(1,2,3,4,5,6,7,8) }
+    "#;
+    assert_eq!(run_u64(src), 8);
+    let src2 = r#"
+        mod ai { pub fn patrol() -> u64 { 9 } }
+        mod b { pub use crate::ai::*; }
+        #[start] fn main() -> u64 { b::patrol() }
+    "#;
+    assert_eq!(run_u64(src2), 9);
+}
+
+#[test]
+fn glob_ambiguity() {
+    let e = err("mod a { pub fn f() -> u64 { 1 } } mod b { pub fn f() -> u64 { 2 } }
+        use a::*; use b::*; #[start] fn main() -> u64 { f() }");
+    assert_eq!(e, AssembleError::AmbiguousName("f".into()));
+    // the same item through two globs, an explicit binding or a private item aren't ambiguous
+    let src = "mod a { pub fn f() -> u64 { 1 } } mod b { pub use crate::a::f; fn g() -> u64 { 0 } } mod c { pub fn g() -> u64 { 3 } }
+        use a::*; use b::*; use c::*; #[start] fn main() -> u64 { f() + g() }";
+    assert_eq!(run_u64(src), 4);
+    let src = "mod a { pub fn f() -> u64 { 1 } } mod b { pub fn f() -> u64 { 2 } }
+        use a::*; use b::*; fn f() -> u64 { 5 } #[start] fn main() -> u64 { f() }";
+    assert_eq!(run_u64(src), 5);
+}
+
+#[test]
+fn private() {
+    let e = err("mod ai { fn patrol() -> u64 { 1 } } #[start] fn main() -> u64 { ai::patrol() }");
+    assert_eq!(e, AssembleError::PrivateItem("ai::patrol".into()));
+    let e = err("mod ai { pub fn patrol() -> u64 { 1 } } #[start] fn main() -> u64 { ai::nope() }");
+    assert_eq!(e, AssembleError::UnresolvedPath("ai::nope".into()));
+    let e = err("mod ai { pub fn patrol() -> u64 { 1 } } use ai::nope; #[start] fn main() -> u64 { 1 }");
+    assert_eq!(e, AssembleError::UnresolvedPath("ai::nope".into()));
+    let e = err("mod a { mod b { pub fn f() -> u64 { 1 } } } #[start] fn main() -> u64 { a::b::f() }");
+    assert_eq!(e, AssembleError::PrivateItem("a::b::f".into()));
+    // pub(super)
+    let src = "mod a { pub mod b { pub(super) fn f() -> u64 { 3 } } pub fn g() -> u64 { b::f() } } #[start] fn main() -> u64 { a::g() }";
+    assert_eq!(run_u64(src), 3);
+    let e = err("mod a { pub mod b { pub(super) fn f() -> u64 { 3 } } } #[start] fn main() -> u64 { a::b::f() }");
+    assert_eq!(e, AssembleError::PrivateItem("a::b::f".into()));
+}
+
+#[test]
+fn signed() {
+    assert_eq!(run_u64("#[start] fn f() -> i64 { neg(5) } fn neg(x: i64) -> i64 { -x }") as i64, -5);
+    assert_eq!(run_u64("#[start] fn f() -> i64 { g(-128) } fn g(x: i8) -> i64 { h(x) } fn h(x: i8) -> i64 { 0 }") as i64, 0);
+}
@@ -0,0 +1,121 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+
+mod common;
+use common::build;
+
+const IMPLS: &str = "
+    use std::ops::{Add, Mul};
+    #[derive(Clone, Copy)] struct Money(u64);
+    impl Add for Money { type Output = Money; fn add(self, other: Money) -> Money { Money(self.0 + other.0) } }
+    impl std::ops::Sub for Money { type Output = Self; fn sub(self, other: Self) -> Self::Output { Money(self.0 - other.0) } }
+    #[derive(Clone, Copy)] struct Angle(f32);
+    impl Mul<f32> for Angle { type Output = Angle; fn mul(self, k: f32) -> Angle { Angle(self.0 * k) } }
+    impl Mul for Angle { type Output = f32; fn mul(self, o: Angle) -> f32 { self.0 * o.0 } }
+    impl Neg for Angle { type Output = Angle; fn neg(self) -> Angle { Angle(-self.0) } }
+    struct Table { a: u32, b: u32 }
+    impl Index<u32> for Table { type Output = u32; fn index(&self, i: u32) -> &u32 { if i == 0 { &self.a } else { &self.b } } }
+";
+
+#[test]
+fn operators() {
+    let j = build(&format!("{}
+        #[start] fn main() -> u64 {{
+            let a = Money(5);
+            let b = a + Money(7) - Money(2);
+            let t = Table {{ a: 3, b: 4 }};
+            let x = t[0] + t[1];
+            let d = -(Angle(2.0) * 1.5);
+            let f = d * Angle(2.0);
+            b.0 + x as u64 + (-f) as u64
+        }}", IMPLS)).unwrap();
+    assert_eq!(j.call::<u64>(), Ok(10 + 7 + 6));
+}
+
+#[test]
+fn errors() {
+    for src in &[
+        "struct A(u32); impl<T> Add<T> for A { type Output = A; fn add(self, o: T) -> A { self } }",
+        "struct A(u32); impl Add for A { type Output = A; }",
+        "struct A(u32); impl Index<u32> for A { type Output = u32; fn index(self, i: u32) -> u32 { self.0 } }",
+        "impl Add for u32 { type Output = u32; fn add(self, o: u32) -> u32 { o } }",
+    ] {
+        match build(&format!("{} #[start] fn main() {{}}", src)) {
+            Err(AssembleError::InvalidImpl(_)) => {},
+            r => panic!("{}: {:?}", src, r.map(|_| ())),
+        }
+    }
+    let dup = "struct A(u32);
+        impl Add for A { type Output = A; fn add(self, o: A) -> A { o } }
+        impl std::ops::Add<A> for A { type Output = A; fn add(self, o: A) -> A { self } }
+        #[start] fn main() {}";
+    assert!(matches!(build(dup), Err(AssembleError::ItemDeclaredMultipleTimes(..))), "{:?}", build(dup).map(|_| ()));
+    let mismatch = format!("{} #[start] fn main() -> u64 {{ (Money(1) + 2u32).0 }}", IMPLS);
+    assert!(matches!(build(&mismatch), Err(AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. }))));
+}
+
+#[test]
+fn index_with_return() {
+    let j = build("struct Table { a: u32, b: u32 }
+        impl Index<u32> for Table {
+            type Output = u32;
+            fn index(&self, i: u32) -> &u32 {
+                if i == 0 { return &self.a; }
+                match i { 1 => &self.b, _ => panic!() }
+            }
+        }
+        #[start] fn main() -> u32 { let t = Table { a: 3, b: 4 }; t[0] * 10 + t[1] }").unwrap();
+    assert_eq!(j.call::<u32>(), Ok(34));
+}
+
+#[test]
+fn unsupported_impls() {
+    for src in &[
+        "struct A(u32); impl A { fn get(&self) -> u32 { self.0 } }",
+        "struct A(u32); impl AddAssign for A { fn add_assign(&mut self, o: A) { self.0 += o.0; } }",
+        "struct A(u32); impl Clone for A { fn clone(&self) -> A { A(self.0) } }",
+    ] {
+        match build(&format!("{} #[start] fn main() {{}}", src)) {
+            Err(AssembleError::UnsupportedImpl(_)) => {},
+            r => panic!("{}: {:?}", src, r.map(|_| ())),
+        }
+    }
+    let local = "struct A { a: u32 }
+        impl Index<u32> for A { type Output = u32; fn index(&self, i: u32) -> &u32 { let r = &self.a; r } }
+        #[start] fn main() {}";
+    assert_eq!(build(local).err(), Some(AssembleError::UnsupportedIndexResult("r".to_string())));
+}
+
+#[test]
+fn missing_impls() {
+    for (body, trait_name) in &[
+        ("let a = Money(1); (a - a).0", "Sub"),
+        ("let a = Money(1); (a * a).0", "Mul"),
+        ("let a = Money(1); (a & a).0", "BitAnd"),
+        ("let a = Money(1); (-a).0", "Neg"),
+        ("let mut a = Money(1); a += Money(2); a.0", "AddAssign"),
+        ("let a = Money(1); a[0]", "Index"),
+        ("let mut t = Table { a: 1, b: 2 }; t[0] = 5; t.a as u64", "IndexMut"),
+    ] {
+        let src = format!("{}
+            use std::ops::Add;
+            #[derive(Clone, Copy)] struct Money(u64);
+            impl Add for Money {{ type Output = Money; fn add(self, other: Money) -> Money {{ Money(self.0 + other.0) }} }}
+            #[start] fn main() -> u64 {{ {} }}", "struct Table { a: u32, b: u32 }
+            impl Index<u32> for Table { type Output = u32; fn index(&self, i: u32) -> &u32 { if i == 0 { &self.a } else { &self.b } } }", body);
+        match build(&src) {
+            Err(AssembleError::FunctionError(AssembleFunctionError::MissingTraitImpl { trait_name: t, .. })) => assert_eq!(t, *trait_name),
+            r => panic!("{}: {:?}", body, r.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn trap_in_impl() {
+    let j = build("use std::ops::Div;
+        #[derive(Clone, Copy)] struct Money(u64);
+        impl Div<u64> for Money { type Output = Money; fn div(self, n: u64) -> Money { Money(self.0 / n) } }
+        fn share(m: Money, n: u64) -> u64 { (m / n).0 }
+        #[start] fn main() -> u64 { share(Money(10), 2) * 100 + share(Money(10), 0) }").unwrap();
+    assert!(matches!(j.call::<u64>(), Err(ScriptError::DivideByZero(_))));
+}
@@ -0,0 +1,148 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::{Cell, RefCell};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct OptU32 { is_some: bool, value: u32 }
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct OptI64 { is_some: bool, value: i64 }
+
+#[repr(C, u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ResF32 { Err(i32), Ok(f32) }
+
+#[repr(C, u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ResU64 { Err(u8), Ok(u64) }
+
+thread_local! {
+    static ENEMIES: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+    static HITS: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+    static A: Cell<i64> = const { Cell::new(0) };
+}
+
+extern "sysv64" fn next_enemy() -> OptU32 {
+    ENEMIES.with(|e| match e.borrow_mut().pop() {
+        Some(v) => OptU32 { is_some: true, value: v },
+        None => OptU32 { is_some: false, value: 0 },
+    })
+}
+extern "sysv64" fn hit(e: u32) { HITS.with(|h| h.borrow_mut().push(e)) }
+extern "sysv64" fn input() -> i64 { A.with(|a| a.get()) }
+
+const HEADER: &str = "extern \"C\" { fn next_enemy() -> Option<u32>; fn hit(e: u32); fn input() -> i64; }\n";
+
+fn build(src: &str) -> Result<JitMemory, AssembleError> {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("next_enemy", next_enemy as *const u8);
+    o.host_functions.insert("hit", hit as *const u8);
+    o.host_functions.insert("input", input as *const u8);
+    let buf = compile_with_options(parse_file(&format!("{}{}", HEADER, src)).unwrap(), &o)?;
+    Ok(JitMemory::from_assembly_buf(&buf).unwrap())
+}
+
+fn jit(src: &str) -> JitMemory { build(src).unwrap() }
+
+#[test]
+fn options() {
+    let j = jit("fn half(x: i64) -> Option<i64> { if x % 2 == 0 { Some(x / 2) } else { None } }
+        fn quarter(x: i64) -> Option<i64> { let h = half(x)?; let q = half(h)?; Some(q) }
+        #[start] fn main() -> Option<i64> { quarter(input()) }");
+    for &(a, r) in &[(8i64, Some(2i64)), (6, None), (7, None), (-12, Some(-3)), (0, Some(0))] {
+        A.with(|c| c.set(a));
+        let o = j.call::<OptI64>().unwrap();
+        assert_eq!(o.is_some, r.is_some(), "{}", a);
+        if let Some(v) = r { assert_eq!(o.value, v); }
+    }
+    let j = jit("#[start] fn main() -> u32 { let a: Option<u8> = None; let b = Some(7u8); let c = Some(300);
+        a.unwrap_or(1) as u32 + b.unwrap_or(2) as u32 * 10 + c.unwrap_or(0) + if a.is_some() { 1000 } else { 0 } + if b.is_none() { 1000 } else { 0 } }");
+    assert_eq!(j.call::<u32>(), Ok(1 + 70 + 300));
+}
+
+#[test]
+fn results() {
+    let j = jit("fn parse(x: i64) -> Result<u64, u8> { if x < 0 { Err(1) } else if x > 100 { Err(2) } else { Ok(x as u64 * 3) } }
+        fn twice(x: i64) -> Result<u64, u8> { let v = parse(x)?; Ok(v + parse(x + 1)?) }
+        #[start] fn main() -> Result<u64, u8> { twice(input()) }");
+    for &(a, r) in &[(5i64, ResU64::Ok(33)), (-1, ResU64::Err(1)), (100, ResU64::Err(2)), (101, ResU64::Err(2))] {
+        A.with(|c| c.set(a));
+        assert_eq!(j.call::<ResU64>(), Ok(r), "{}", a);
+    }
+    let j = jit("#[start] fn main() -> Result<f32, i32> { if input() > 0 { Ok(input() as f32 * 1.5) } else { Err(input() as i32 - 1) } }");
+    A.with(|c| c.set(3));
+    assert_eq!(j.call::<ResF32>(), Ok(ResF32::Ok(4.5)));
+    A.with(|c| c.set(-3));
+    assert_eq!(j.call::<ResF32>(), Ok(ResF32::Err(-4)));
+    let j = jit("fn r(x: i64) -> Result<i32, bool> { if x > 0 { Ok(x as i32) } else { Err(x == 0) } }
+        #[start] fn main() -> i32 { let a = r(input()); let mut s = a.unwrap_or(-1); if a.is_ok() { 100 + s } else if a.is_err() { 200 + s } else { 0 } }");
+    A.with(|c| c.set(4));
+    assert_eq!(j.call::<i32>(), Ok(104));
+    A.with(|c| c.set(0));
+    assert_eq!(j.call::<i32>(), Ok(199));
+}
+
+#[test]
+fn if_let_and_while_let() {
+    let j = jit("struct Hit { dmg: u32, crit: bool }
+        fn find(x: i64) -> Option<Hit> { if x > 0 { Some(Hit { dmg: x as u32, crit: x > 10 }) } else { None } }
+        fn nested(x: i64) -> Option<Option<u32>> { if x > 5 { Some(Some(x as u32)) } else if x > 0 { Some(None) } else { None } }
+        fn check(x: i64) -> Result<i64, u8> { if x > 3 { Ok(x) } else { Err(x as u8) } }
+        #[start] fn main() -> u32 {
+            let a = if let Some(h) = find(input()) { if h.crit { h.dmg * 2 } else { h.dmg } } else { 0 };
+            let b = if let Some(Some(v)) = nested(input()) { v } else if let Some(None) = nested(input()) { 1000 } else { 2000 };
+            let c = if let Ok(v) = check(input()) { v as u32 } else if let Err(e) = check(input()) { e as u32 + 500 } else { 9 };
+            let d = if let None = find(input()) { 7 } else { 0 };
+            let e = if let Some(_) = find(input()) { 1 } else { 0 };
+            let f = if let Some(..) = Some(input()) { 1 } else { 0 };
+            a + b * 10 + c * 100000 + d + e + f
+        }");
+    for &a in &[20i64, 8, 4, 2, 0, -3] {
+        A.with(|c| c.set(a));
+        let aa = if a > 0 { if a > 10 { a * 2 } else { a } } else { 0 } as u32;
+        let b = if a > 5 { a as u32 } else if a > 0 { 1000 } else { 2000 };
+        let c = if a > 3 { a as u32 } else { (a as u8) as u32 + 500 };
+        let d = if a > 0 { 0 } else { 7 };
+        let e = if a > 0 { 1 } else { 0 };
+        assert_eq!(j.call::<u32>(), Ok(aa + b * 10 + c * 100000 + d + e + 1), "{}", a);
+    }
+
+    let j = jit("#[start] fn main() -> u32 { while let Some(e) = next_enemy() { if e != 3 { hit(e * 10) } } 5 }");
+    ENEMIES.with(|e| *e.borrow_mut() = vec![1, 2, 3, 4]);
+    assert_eq!(j.call::<u32>(), Ok(5));
+    assert_eq!(HITS.with(|h| h.borrow().clone()), vec![40, 20, 10]);
+    assert_eq!(j.call::<u32>(), Ok(5));
+    assert_eq!(HITS.with(|h| h.borrow().len()), 3);
+}
+
+#[test]
+fn errors() {
+    let bad = [
+        "#[start] fn main() -> u32 { let a = None; 0 }",
+        "#[start] fn main() -> u32 { let a = Ok(1); 0 }",
+        "fn f() -> Option<u32> { None } #[start] fn main() -> u32 { f()? }",
+        "fn f() -> Option<u32> { None } fn g() -> Result<u32, u8> { Ok(f()?) } #[start] fn main() -> u32 { 0 }",
+        "fn f() -> Result<u32, u16> { Ok(1) } fn g() -> Result<u32, u8> { Ok(f()?) } #[start] fn main() -> u32 { 0 }",
+        "#[start] fn main() -> u32 { if let Some(x) = 5u32 { x } else { 0 } }",
+        "#[start] fn main() -> u32 { let a: Option<u32> = Some(true); 0 }",
+        "#[start] fn main() -> u32 { if let Some(x) = Some(1u32) { x } else { x } }",
+    ];
+    for src in &bad {
+        assert!(build(src).is_err(), "{}", src);
+    }
+    match build("#[start] fn main() -> u32 { if let Ok(x) = Some(1u32) { x } else { 0 } }") {
+        Err(AssembleError::FunctionError(AssembleFunctionError::InvalidPattern { ty, .. })) => assert_eq!(ty, "Option<Int(U32)>"),
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+    match build("fn f() -> Result<u32, u16> { Ok(1) } fn g() -> Result<u32, u8> { Ok(f()?) } #[start] fn main() -> u32 { 0 }") {
+        Err(AssembleError::FunctionError(AssembleFunctionError::TryErrorMismatch { expression, expected, found, .. })) => {
+            assert_eq!((&*expression, expected, found), ("f ( ) ?", Ret::Int(StaticIntLiteral::U8), Ret::Int(StaticIntLiteral::U16)));
+        },
+        r => panic!("{:?}", r.map(|_| ())),
+    }
+    // a script type named like a variant takes precedence
+    let j = jit("struct Some(u32); #[start] fn main() -> u32 { let s = Some(4); s.0 }");
+    assert_eq!(j.call::<u32>(), Ok(4));
+}
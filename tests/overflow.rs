@@ -0,0 +1,176 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::Cell;
+
+thread_local! {
+    static A: Cell<i64> = const { Cell::new(0) };
+    static B: Cell<i64> = const { Cell::new(0) };
+    static NESTED: Cell<usize> = const { Cell::new(0) };
+}
+
+extern "sysv64" fn input_a() -> i64 { A.with(|a| a.get()) }
+extern "sysv64" fn input_b() -> i64 { B.with(|b| b.get()) }
+
+fn options(mode: CompileMode) -> CompileOptions {
+    let mut o = CompileOptions { mode, ..CompileOptions::default() };
+    o.host_functions.insert("input_a", input_a as *const u8);
+    o.host_functions.insert("input_b", input_b as *const u8);
+    o
+}
+
+fn jit(src: &str, mode: CompileMode) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), &options(mode)).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+macro_rules! check {
+    ($t:ty) => {{
+        let t = stringify!($t);
+        let vals: Vec<$t> = [0i128, 1, -1, 2, 3, 7, 100, 127, 128, -128, -129, 255, 256, 1000, 32767, -32768, 65535, 70000,
+            i32::MAX as i128, i32::MIN as i128, u32::MAX as i128, i64::MAX as i128, i64::MIN as i128, u64::MAX as i128]
+            .iter().map(|v| *v as $t).collect();
+        let header = format!("extern \"C\" {{ fn input_a() -> i64; fn input_b() -> i64; }}
+            fn a() -> {0} {{ input_a() as {0} }} fn b() -> {0} {{ input_b() as {0} }}", t);
+        let ops = ["a() + b()", "a() - b()", "a() * b()"];
+        let debug: Vec<_> = ops.iter().map(|op| jit(&format!("{} #[start] fn main() -> {} {{ {} }}", header, t, op), CompileMode::Debug)).collect();
+        let release: Vec<_> = ops.iter().map(|op| jit(&format!("{} #[start] fn main() -> {} {{ {} }}", header, t, op), CompileMode::Release)).collect();
+        let pow = jit(&format!("{} #[start] fn main() -> {} {{ a().pow(b() as u32 % 70) }}", header, t), CompileMode::Debug);
+        for &a in &vals {
+            for &b in &vals {
+                A.with(|c| c.set(a as i64));
+                B.with(|c| c.set(b as i64));
+                let std = [a.checked_add(b), a.checked_sub(b), a.checked_mul(b)];
+                let wrapped = [a.wrapping_add(b), a.wrapping_sub(b), a.wrapping_mul(b)];
+                for k in 0..3 {
+                    match (debug[k].call::<$t>(), std[k]) {
+                        (Ok(v), Some(s)) => assert_eq!(v, s),
+                        (Err(ScriptError::Overflow(l)), None) => {
+                            assert_eq!(l.function, "fn main");
+                            assert_eq!(l.expression, ops[k].replace("()", " ( )"));
+                        },
+                        (r, s) => panic!("{} {} {} {}: {:?} {:?}", t, ops[k], a, b, r, s),
+                    }
+                    assert_eq!(release[k].call::<$t>(), Ok(wrapped[k]));
+                    assert_eq!(release[k].run::<$t>()(), wrapped[k]);
+                }
+                let e = (b as i64 as u32) % 70;
+                assert_eq!(pow.call::<$t>().ok(), a.checked_pow(e), "{} {} {}", t, a, e);
+            }
+        }
+    }};
+}
+
+#[test]
+fn arithmetic() {
+    check!(u8); check!(i8); check!(u16); check!(i16); check!(u32); check!(i32); check!(u64); check!(i64);
+}
+
+#[test]
+fn negation() {
+    for &(a, ok) in &[(5i64, true), (i64::MIN, false), (i64::MAX, true)] {
+        A.with(|c| c.set(a));
+        let j = jit("extern \"C\" { fn input_a() -> i64; } #[start] fn main() -> i64 { -input_a() }", CompileMode::Debug);
+        assert_eq!(j.call::<i64>().is_ok(), ok);
+        let j = jit("extern \"C\" { fn input_a() -> i64; } #[start] fn main() -> i64 { input_a().abs() }", CompileMode::Debug);
+        assert_eq!(j.call::<i64>().ok(), a.checked_abs());
+    }
+    let j = jit("extern \"C\" { fn input_a() -> i64; } #[start] fn main() -> i8 { -(input_a() as i8) }", CompileMode::Debug);
+    A.with(|c| c.set(-128));
+    assert!(j.call::<i8>().is_err());
+    A.with(|c| c.set(-127));
+    assert_eq!(j.call::<i8>(), Ok(127));
+    // wrapping methods never trap, literals keep working
+    let j = jit("#[start] fn main() -> i32 { let x = -2147483648; x.wrapping_sub(1) + 0 }", CompileMode::Debug);
+    assert_eq!(j.call::<i32>(), Ok(i32::MAX));
+}
+
+#[test]
+fn trap_unwinds_nested_frames() {
+    let src = "extern \"C\" { fn input_a() -> i64; }
+        fn deep(n: u8, x: u8) -> u8 { if n == 0 { x * 2 } else { deep(n - 1, x) + 1 } }
+        #[start] fn main() -> f64 { let x = deep(10, input_a() as u8); x as f64 + 0.5 }";
+    let j = jit(src, CompileMode::Debug);
+    A.with(|c| c.set(3));
+    assert_eq!(j.call::<f64>(), Ok(16.5));
+    A.with(|c| c.set(130));
+    let e = j.call::<f64>().unwrap_err();
+    assert_eq!(e, ScriptError::Overflow(SourceLocation { function: "fn deep".into(), expression: "x * 2".into(), line: 2, column: 51 }));
+    assert_eq!(e.to_string(), "attempt to compute `x * 2` in `fn deep` at 2:51 with overflow");
+    A.with(|c| c.set(127));
+    match j.call::<f64>() {
+        Err(ScriptError::Overflow(l)) => assert_eq!(l.expression, "deep ( n - 1 , x ) + 1"),
+        r => panic!("{:?}", r),
+    }
+    // still works after traps
+    A.with(|c| c.set(1));
+    assert_eq!(j.call::<f64>(), Ok(12.5));
+}
+
+static mut INNER: Option<JitMemory> = None;
+
+extern "sysv64" fn reenter() -> i64 {
+    NESTED.with(|n| n.set(n.get() + 1));
+    #[allow(static_mut_refs)]
+    let inner = unsafe { INNER.as_ref().unwrap() };
+    inner.call::<i64>().unwrap_or(-1)
+}
+
+#[test]
+fn reentrant_call() {
+    let mut o = CompileOptions { mode: CompileMode::Debug, ..CompileOptions::default() };
+    o.host_functions.insert("reenter", reenter as *const u8);
+    o.host_functions.insert("input_a", input_a as *const u8);
+    let inner = "extern \"C\" { fn input_a() -> i64; } #[start] fn main() -> i64 { input_a() * 4611686018427387904 }";
+    unsafe { INNER = Some(JitMemory::from_assembly_buf(&compile_with_options(parse_file(inner).unwrap(), &o).unwrap()).unwrap()); }
+    let outer = "extern \"C\" { fn reenter() -> i64; fn input_a() -> i64; } #[start] fn main() -> i64 { let r = reenter(); r + input_a() * 9223372036854775807 }";
+    let outer = JitMemory::from_assembly_buf(&compile_with_options(parse_file(outer).unwrap(), &o).unwrap()).unwrap();
+    A.with(|c| c.set(1));
+    // inner 4611686018427387904, then 4611686018427387904 + i64::MAX overflows
+    assert!(matches!(outer.call::<i64>(), Err(ScriptError::Overflow(_))));
+    A.with(|c| c.set(0));
+    assert_eq!(outer.call::<i64>(), Ok(0));
+    A.with(|c| c.set(2));
+    // inner traps (returns -1), outer then overflows on 2 * i64::MAX
+    assert!(matches!(outer.call::<i64>(), Err(ScriptError::Overflow(l)) if l.expression.contains("9223372036854775807")));
+    A.with(|c| c.set(-1));
+    // inner -4611686018427387904, outer -4611686018427387904 - i64::MAX overflows
+    assert!(outer.call::<i64>().is_err());
+    assert_eq!(NESTED.with(|n| n.get()), 4);
+}
+
+#[test]
+fn shift_amounts() {
+    let src = "extern \"C\" { fn input_a() -> i64; fn input_b() -> i64; }
+        #[export] fn shl() -> u32 { 1u32 << input_a() }
+        #[export] fn shr() -> i8 { -128i8 >> (input_a() as u8) }
+        #[export] fn wide() -> u128 { 1u128 << input_b() as i128 }
+        #[export] fn assign() -> u64 { let mut x = 3u64; x <<= input_a() as u32; x }";
+    let debug = jit(src, CompileMode::Debug);
+    let release = jit(src, CompileMode::Release);
+    let call = |j: &JitMemory, name: &str, a: i64, b: i64| -> Result<u128, ScriptError> {
+        A.with(|c| c.set(a));
+        B.with(|c| c.set(b));
+        let export = j.export(name).unwrap();
+        match name {
+            "shl" => j.call_export::<_, u32>(export, ()).map(u128::from),
+            "shr" => j.call_export::<_, i8>(export, ()).map(|x| x as u8 as u128),
+            "wide" => j.call_export::<_, u128>(export, ()),
+            _ => j.call_export::<_, u64>(export, ()).map(u128::from),
+        }
+    };
+    assert_eq!(call(&debug, "shl", 31, 0), Ok(1 << 31));
+    assert_eq!(call(&debug, "shr", 7, 0), Ok(0xff));
+    assert_eq!(call(&debug, "wide", 0, 127), Ok(1 << 127));
+    assert_eq!(call(&debug, "assign", 62, 0), Ok(3 << 62));
+    for &(name, a, b) in &[("shl", 32, 0), ("shl", -1, 0), ("shr", 8, 0), ("shr", 255, 0), ("wide", 0, 128), ("wide", 0, -1), ("assign", 64, 0)] {
+        match call(&debug, name, a, b) {
+            Err(ScriptError::Overflow(location)) => assert_eq!(location.function, format!("fn {}", name)),
+            r => panic!("{} {} {}: {:?}", name, a, b, r),
+        }
+    }
+    // release builds mask the amount like `wrapping_shl`
+    assert_eq!(call(&release, "shl", 33, 0), Ok(2));
+    assert_eq!(call(&release, "shr", 9, 0), Ok(0xc0));
+    assert_eq!(call(&release, "wide", 0, 129), Ok(2));
+    assert_eq!(call(&release, "assign", 65, 0), Ok(6));
+}
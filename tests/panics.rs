@@ -0,0 +1,105 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::Cell;
+
+thread_local! {
+    static A: Cell<i64> = const { Cell::new(0) };
+}
+
+extern "sysv64" fn input() -> i64 { A.with(|a| a.get()) }
+
+fn build(src: &str, mode: CompileMode) -> Result<JitMemory, AssembleError> {
+    let mut o = CompileOptions { mode, file_name: "level1.rs".into(), ..CompileOptions::default() };
+    o.host_functions.insert("input", input as *const u8);
+    let buf = compile_with_options(parse_file(src).unwrap(), &o)?;
+    Ok(JitMemory::from_assembly_buf(&buf).unwrap())
+}
+
+fn jit(src: &str) -> JitMemory { build(src, CompileMode::Debug).unwrap() }
+
+fn panic_of<T: std::fmt::Debug>(r: Result<T, ScriptError>) -> (String, String, u32) {
+    match r {
+        Err(ScriptError::Panic { message, file, line }) => (message, file, line),
+        r => panic!("expected a panic: {:?}", r),
+    }
+}
+
+const HEADER: &str = "extern \"C\" { fn input() -> i64; }\n";
+
+#[test]
+fn panics() {
+    let j = jit(&format!("{}#[start]\nfn main() -> u32 {{\n    if input() > 3 {{\n        panic!(\"too big: {{}} > {{:?}} {{:.2}} {{:?}} {{}} {{1}} {{{{}}}}\", input(), 'x', 1.5f32, \"s\\n\", true);\n    }}\n    7\n}}", HEADER));
+    A.with(|c| c.set(2));
+    assert_eq!(j.call::<u32>(), Ok(7));
+    A.with(|c| c.set(-5i64 + 10));
+    let (m, f, l) = panic_of(j.call::<u32>());
+    assert_eq!(m, "too big: 5 > 'x' 1.50 \"s\\n\" true x {}");
+    assert_eq!(f, "level1.rs");
+    assert_eq!(l, 5);
+
+    let j = jit("#[start] fn main() -> u32 { panic!() }");
+    assert_eq!(panic_of(j.call::<u32>()).0, "explicit panic");
+    let j = jit("fn pick(n: u32) -> u32 { if n == 0 { 1 } else { unreachable!(\"n = {}\", n) } }\n#[start] fn main() -> u32 { pick(0) + pick(3) }");
+    let (m, f, l) = panic_of(j.call::<u32>());
+    assert_eq!((m.as_str(), f.as_str(), l), ("internal error: entered unreachable code: n = 3", "level1.rs", 1));
+    let j = jit("fn f() -> u8 { unreachable!(); }\n#[start] fn main() -> u8 { f() }");
+    assert_eq!(panic_of(j.call::<u8>()).0, "internal error: entered unreachable code");
+    let e = panic_of(jit("#[start] fn main() { panic!(\"{}\", -3i8) }").call::<()>());
+    assert_eq!(e.0, "-3");
+    let err = jit("#[start] fn main() { panic!(\"{}\", 1u64 << 63) }").call::<()>().unwrap_err();
+    assert_eq!(err.to_string(), "panicked at level1.rs:1:\n9223372036854775808");
+}
+
+#[test]
+fn asserts() {
+    let src = format!("{}fn check(hp: i32, max: i32) {{\n    assert!(hp >= 0);\n    assert!(hp <= max, \"hp {{}} over {{}}\", hp, max);\n    assert_eq!(hp % 2, 0);\n    assert_ne!(hp, 42, \"the answer\");\n    debug_assert!(hp != 8, \"debug {{}}\", hp);\n    debug_assert_eq!(hp, hp + 0);\n}}\n#[start] fn main() -> i32 {{ let hp = input() as i32; check(hp, 100); hp }}", HEADER);
+    let debug = jit(&src);
+    let release = build(&src, CompileMode::Release).unwrap();
+    let expect = |v: i64, debug_result: Result<i32, (&str, u32)>, release_result: Result<i32, (&str, u32)>| {
+        A.with(|c| c.set(v));
+        for (j, expected) in [(&debug, debug_result), (&release, release_result)] {
+            match (j.call::<i32>(), expected) {
+                (Ok(a), Ok(b)) => assert_eq!(a, b),
+                (Err(ScriptError::Panic { message, line, .. }), Err((m, l))) => assert_eq!((message.as_str(), line), (m, l)),
+                (r, e) => panic!("{}: {:?} {:?}", v, r, e),
+            }
+        }
+    };
+    expect(4, Ok(4), Ok(4));
+    expect(-1, Err(("assertion failed: hp >= 0", 3)), Err(("assertion failed: hp >= 0", 3)));
+    expect(102, Err(("hp 102 over 100", 4)), Err(("hp 102 over 100", 4)));
+    expect(7, Err(("assertion `left == right` failed\n  left: 1\n right: 0", 5)), Err(("assertion `left == right` failed\n  left: 1\n right: 0", 5)));
+    expect(42, Err(("assertion `left != right` failed: the answer\n  left: 42\n right: 42", 6)), Err(("assertion `left != right` failed: the answer\n  left: 42\n right: 42", 6)));
+    expect(8, Err(("debug 8", 7)), Ok(8));
+
+    let j = jit("#[start] fn main() { let x = 0.1 + 0.2; assert_eq!(x, 0.3, \"{} {:.1}\", x, x); }");
+    assert_eq!(panic_of(j.call::<()>()).0, "assertion `left == right` failed: 0.30000000000000004 0.3\n  left: 0.30000000000000004\n right: 0.3");
+}
+
+#[test]
+fn message_values_are_lazy() {
+    // the message of an assertion is only evaluated if it fails
+    let j = jit(&format!("{}fn boom() -> u32 {{ panic!(\"boom\") }}\n#[start] fn main() -> u32 {{ assert!(input() < 10, \"{{}}\", boom()); 1 }}", HEADER));
+    A.with(|c| c.set(1));
+    assert_eq!(j.call::<u32>(), Ok(1));
+    A.with(|c| c.set(11));
+    assert_eq!(panic_of(j.call::<u32>()).0, "boom");
+}
+
+#[test]
+fn errors() {
+    let e = |src: &str| build(src, CompileMode::Debug).err().unwrap();
+    assert!(matches!(e("#[start] fn main() { vec!(1); }"), AssembleError::FunctionError(AssembleFunctionError::UnknownMacro { .. })));
+    for src in ["#[start] fn main() { panic!(\"{}\"); }", "#[start] fn main() { panic!(\"x\", 1); }", "#[start] fn main() { panic!(\"{name}\"); }",
+                "#[start] fn main() { panic!(\"{\"); }", "#[start] fn main() { let s = \"x\"; panic!(s); }", "#[start] fn main() { panic!(\"{:x}\", 1); }",
+                "#[start] fn main() { panic!(\"{}{}{}{}{}{}{}{}{}\", 1, 2, 3, 4, 5, 6, 7, 8, 9); }"] {
+        assert!(matches!(e(src), AssembleError::FunctionError(AssembleFunctionError::InvalidFormat { .. })), "{}", src);
+    }
+    // debug_assert! is type checked but doesn't run in release mode
+    let j = build("#[start] fn main() -> u32 { debug_assert!(false); debug_assert_eq!(1, 2, \"{}\", 3); 3 }", CompileMode::Release).unwrap();
+    assert_eq!(j.call::<u32>(), Ok(3));
+    for src in ["#[start] fn main() { debug_assert!(1); }", "#[start] fn main() { debug_assert_eq!(1u32, 2u8); }",
+                "#[start] fn main() { debug_assert_ne!(1, missing); }", "#[start] fn main() { debug_assert!(true, \"{}\"); }"] {
+        assert!(build(src, CompileMode::Release).is_err(), "{}", src);
+    }
+}
@@ -0,0 +1,246 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::Cell;
+
+thread_local! { static A: Cell<i64> = const { Cell::new(0) }; }
+extern "sysv64" fn input() -> i64 { A.with(|a| a.get()) }
+
+fn build(src: &str) -> Result<JitMemory, AssembleError> {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("input", input as *const u8);
+    let src = format!("extern \"C\" {{ fn input() -> i64; }}\n{}", src);
+    let buf = compile_with_options(parse_file(&src).unwrap(), &o)?;
+    Ok(JitMemory::from_assembly_buf(&buf).unwrap())
+}
+fn jit(src: &str) -> JitMemory { build(src).unwrap() }
+fn set(a: i64) { A.with(|c| c.set(a)) }
+
+#[test]
+fn let_and_params() {
+    let j = jit("struct Hit { dmg: u32, crit: bool, pos: Point }
+        struct Point(i32, i32);
+        fn sum((a, b): (u32, u32)) -> u32 { a + b }
+        fn dist(Point(x, y): Point, _: u8, Hit { dmg, .. }: Hit) -> i32 { x - y + dmg as i32 }
+        #[start] fn main() -> i32 {
+            let h = Hit { dmg: 7, crit: true, pos: Point(3, -4) };
+            let Hit { dmg, crit: c, pos: Point(px, py) } = h;
+            let (a, (b, _), ..) = (1u32, (2u32, 9u8), 5u8, 6u8);
+            let (.., last) = (1u8, 2u8, 30i32);
+            let f = |(p, q): (i32, i32)| p * q;
+            let n @ m = 4;
+            let t = (10u32, 20u32);
+            let s = sum(t) + sum((a, b));
+            (dmg as i32) * 1000 + if c { 100 } else { 0 } + px * 10 + py + last + f((2, 3)) + n * m + s as i32
+                + dist(Point(50, 8), 0, Hit { dmg: 1, crit: false, pos: Point(0, 0) })
+        }");
+    assert_eq!(j.call::<i32>(), Ok(7000 + 100 + 30 - 4 + 30 + 6 + 16 + 33 + 43));
+}
+
+#[test]
+fn match_arms() {
+    let j = jit("fn class(x: i64) -> u32 {
+            match x {
+                0 => 1,
+                1 | 2 | 3 => 2,
+                -5..=-1 => 3,
+                n @ 10..=19 if n % 2 == 0 => 4,
+                10..=19 => 5,
+                100 => { let a = 6; a }
+                _ => 7,
+            }
+        }
+        #[start] fn main() -> u32 { class(input()) }");
+    for &(a, r) in &[(0i64, 1u32), (2, 2), (3, 2), (-5, 3), (-1, 3), (-6, 7), (12, 4), (13, 5), (19, 5), (20, 7), (100, 6), (4, 7)] {
+        set(a);
+        assert_eq!(j.call::<u32>(), Ok(r), "{}", a);
+    }
+
+    let j = jit("fn nested(x: i64) -> Option<Option<u32>> { if x > 5 { Some(Some(x as u32)) } else if x > 0 { Some(None) } else { None } }
+        fn check(x: i64) -> Result<u8, bool> { if x > 3 { Ok(x as u8) } else { Err(x == 0) } }
+        fn flags(x: i64) -> (bool, bool) { (x > 0, x % 2 == 0) }
+        #[start] fn main() -> u32 {
+            let a = match nested(input()) { Some(Some(v)) => v, Some(None) => 1000, None => 2000 };
+            let b = match check(input()) { Ok(v @ 4..=9) => v as u32, Ok(v) => v as u32 + 100, Err(true) => 300, Err(false) => 400 };
+            let c = match flags(input()) { (true, true) => 1, (true, false) => 2, (false, _) => 3 };
+            let d = match input() > 2 { true => 10, false => 20 };
+            a * 10000 + b * 10 + c + d
+        }");
+    for &a in &[20i64, 8, 4, 3, 0, -3] {
+        set(a);
+        let na = if a > 5 { a as u32 } else if a > 0 { 1000 } else { 2000 };
+        let nb = if a > 3 { if a <= 9 { a as u32 } else { a as u32 + 100 } } else if a == 0 { 300 } else { 400 };
+        let nc = if a > 0 { if a % 2 == 0 { 1 } else { 2 } } else { 3 };
+        let nd = if a > 2 { 10 } else { 20 };
+        assert_eq!(j.call::<u32>(), Ok(na * 10000 + nb * 10 + nc + nd), "{}", a);
+    }
+}
+
+#[test]
+fn refs_slices_and_alternatives() {
+    let j = jit("struct Hit { dmg: u32, crit: bool }
+        fn first_last(s: &[u8]) -> u32 {
+            match s {
+                [] => 0,
+                [x] => *x as u32,
+                [a, b] => *a as u32 * 1000 + *b as u32,
+                [first, rest.., last] => *first as u32 * 1000 + *last as u32 + rest.len() as u32 * 1000000,
+            }
+        }
+        fn tail(s: &[u8]) -> u32 { if let [_, .., z] = s { *z as u32 } else { 0 } }
+        #[start] fn main() -> u32 {
+            let mut h = Hit { dmg: 9, crit: false };
+            let r = match h { Hit { ref dmg, crit: true } => *dmg * 2, Hit { ref dmg, .. } => *dmg };
+            let Hit { ref mut dmg, .. } = h;
+            let o = Some(5u32);
+            let v = match o { Some(ref x) => *x, None => 0 };
+            let w = match (1u32, Some(2u32)) { (x, None) | (_, Some(x)) => x };
+            let e: &[u8] = b\"\";
+            let s = match o { Some(3) | None => 1, Some(_) => 2 };
+            r + *dmg * 10 + v * 100 + w * 1000 + s * 10000
+                + first_last(b\"hello\") + first_last(b\"ab\") + first_last(b\"x\") + first_last(e) + tail(b\"xyz\")
+        }");
+    let expect = 9 + 90 + 500 + 2000 + 20000
+        + (3 * 1000000 + 104 * 1000 + 111) + (97 * 1000 + 98) + 120 + 122;
+    assert_eq!(j.call::<u32>(), Ok(expect));
+}
+
+#[test]
+fn errors() {
+    let err = |src: &str| match build(&format!("struct Hit {{ dmg: u32, crit: bool }} {}", src)) {
+        Err(AssembleError::FunctionError(e)) => e,
+        Err(e) => panic!("{}: {:?}", src, e),
+        Ok(_) => panic!("{} compiled", src),
+    };
+    match err("#[start] fn main() -> u32 { match Some(1u32) { Some(x) => x } }") {
+        AssembleFunctionError::NonExhaustiveMatch { expression, .. } => assert_eq!(expression, "Some ( 1u32 )"),
+        e => panic!("{:?}", e),
+    }
+    for src in &[
+        "#[start] fn main() -> u32 { match input() { 0 => 1, 1..=9 => 2 } }",
+        "#[start] fn main() -> u32 { match (true, false) { (true, _) => 1, (false, true) => 2 } }",
+        "#[start] fn main() -> u32 { match Some(Some(1u32)) { Some(Some(x)) => x, None => 0 } }",
+        "#[start] fn main() -> u32 { match Some(1u32) { Some(x) if x > 1 => x, None => 0 } }",
+        "fn f(s: &[u8]) -> u32 { match s { [] => 0, [_, _, ..] => 1 } } #[start] fn main() -> u32 { 0 }",
+    ] {
+        assert!(matches!(err(src), AssembleFunctionError::NonExhaustiveMatch { .. }), "{}", src);
+    }
+    for src in &[
+        "#[start] fn main() -> u32 { let Some(x) = Some(1u32); x }",
+        "fn f(Some(x): Option<u32>) -> u32 { x } #[start] fn main() -> u32 { 0 }",
+        "#[start] fn main() -> u32 { let (1, x) = (1, 2u32); x }",
+    ] {
+        assert!(matches!(err(src), AssembleFunctionError::RefutablePattern { .. }), "{}", src);
+    }
+    match err("#[start] fn main() -> u32 { match Some(1u32) { Some(x) | None => 0 } }") {
+        AssembleFunctionError::InconsistentBinding { name, .. } => assert_eq!(name, "x"),
+        e => panic!("{:?}", e),
+    }
+    assert!(matches!(err("#[start] fn main() -> u32 { let Hit { dmg } = Hit { dmg: 1, crit: true }; dmg }"),
+                     AssembleFunctionError::MissingField { .. }));
+    for src in &[
+        "#[start] fn main() -> u32 { let (a, b) = (1u32, 2u32, 3u32); a }",
+        "#[start] fn main() -> u32 { match 5u32 { (a, b) => a } }",
+        "#[start] fn main() -> u32 { match (Hit { dmg: 1, crit: true }) { 1 => 0, _ => 1 } }",
+        "#[start] fn main() -> u32 { match 1.5f32 { 1..=2 => 0, _ => 1 } }",
+    ] {
+        assert!(matches!(err(src), AssembleFunctionError::InvalidPattern { .. } | AssembleFunctionError::TypeMismatch { .. }), "{}", src);
+    }
+    // `*` only dereferences `ref` bindings
+    assert!(build("#[start] fn main() -> u32 { let a = 1u32; *a }").is_err());
+}
+
+#[test]
+fn ranges() {
+    let j = jit("fn half(b: u8) -> u32 { match b { 0..=127 => 1, 128..=255 => 2 } }
+        fn sign(x: i32) -> i32 { match x { i32::MIN..=-1 => -1, 0 => 0, 1..=i32::MAX => 1 } }
+        fn kind(c: char) -> u32 { match c { '\\0'..='9' => 1, ':'..='\\u{10ffff}' => 2 } }
+        fn low(x: i8) -> u32 { match x { -128..=-1 => 1, 0..10 => 2, 10..=127 => 3 } }
+        fn pair(p: (bool, u8)) -> u32 { match p { (true, 0..=9) => 1, (true, 10..=255) => 2, (false, _) => 3 } }
+        #[start] fn main() -> i64 {
+            let x = input();
+            half(x as u8) as i64 * 10000 + sign(x as i32) as i64 * 1000 + kind((x as u8) as char) as i64 * 100 + low(x as i8) as i64 * 10 + pair((x > 0, x as u8)) as i64
+        }");
+    for &a in &[0i64, 5, 57, 58, 127, 128, 255, -1, -2147483648, 2147483647] {
+        set(a);
+        let (b, x, i) = (a as u8, a as i32, a as i8);
+        let expect = if b < 128 { 1 } else { 2 } * 10000 + x.signum() as i64 * 1000 + if b <= b'9' { 1 } else { 2 } * 100
+            + if i < 0 { 1 } else if i < 10 { 2 } else { 3 } * 10 + if a > 0 { if b < 10 { 1 } else { 2 } } else { 3 };
+        assert_eq!(j.call::<i64>(), Ok(expect), "{}", a);
+    }
+    // the limits of the integer types are constants
+    let j = jit("#[start] fn main() -> u128 { let x = u64::MAX; let y = i8::MIN; u128::MAX - x as u128 + (y == -128) as u128 + i128::MIN as u128 }");
+    assert_eq!(j.call::<u128>(), Ok((u128::MAX - u64::MAX as u128 + 1).wrapping_add(i128::MIN as u128)));
+}
+
+#[test]
+fn range_gaps() {
+    let err = |src: &str| build(src).err().unwrap();
+    for src in &[
+        "#[start] fn main() -> u32 { match input() as u8 { 0..=127 => 1, 129..=255 => 2 } }",
+        "#[start] fn main() -> u32 { match input() as i32 { i32::MIN..=-1 => 1, 1..=i32::MAX => 2 } }",
+        "#[start] fn main() -> u32 { match input() as u8 { 0..255 => 1 } }",
+        "#[start] fn main() -> u32 { match 'a' { '\\0'..='\\u{d7ff}' => 1 } }",
+        "#[start] fn main() -> u32 { match (input() as u8, true) { (0..=9, _) => 1, (10..=255, true) => 2 } }",
+    ] {
+        assert!(matches!(err(src), AssembleError::FunctionError(AssembleFunctionError::NonExhaustiveMatch { .. })), "{}", src);
+    }
+    assert!(matches!(err("#[start] fn main() -> u32 { let 0..=254 = input() as u8; 1 }"),
+                     AssembleError::FunctionError(AssembleFunctionError::RefutablePattern { .. })));
+    // the surrogates are not `char`s
+    assert!(build("#[start] fn main() -> u32 { match 'a' { '\\0'..='\\u{d7ff}' => 1, '\\u{e000}'..='\\u{10ffff}' => 2 } }").is_ok());
+    assert!(build("#[start] fn main() -> u32 { let 0..=255 = input() as u8; 1 }").is_ok());
+}
+
+#[test]
+fn wide_ranges() {
+    let j = jit("fn class(x: i128) -> u32 { match x { i128::MIN..=-1 => 1, 0..1000 => 2, 1000..=i128::MAX => 3 } }
+        fn big(x: u128) -> u32 { match x { 0..=0xffff_ffff_ffff_ffff => 1, _ => 2 } }
+        #[start] fn main() -> i64 {
+            let x = input();
+            class(x as i128) as i64 * 100 + class((x as i128) << 64) as i64 * 10 + big((x as u128) << 1) as i64
+        }");
+    for &a in &[0i64, 1, 999, 1000, -1, i64::MIN, i64::MAX] {
+        set(a);
+        let class = |x: i128| if x < 0 { 1 } else if x < 1000 { 2 } else { 3 };
+        let expect = class(a as i128) * 100 + class((a as i128) << 64) * 10 + if (a as u128) << 1 <= u64::MAX as u128 { 1 } else { 2 };
+        assert_eq!(j.call::<i64>(), Ok(expect), "{}", a);
+    }
+    assert!(matches!(build("#[start] fn main() -> u32 { match input() as u128 { 0..=9 => 1, 11..=u128::MAX => 2 } }").err().unwrap(),
+                     AssembleError::FunctionError(AssembleFunctionError::NonExhaustiveMatch { .. })));
+}
+
+#[test]
+fn arrays() {
+    let j = jit("struct T(u32);
+        fn corners([first, .., last]: [i32; 4]) -> i32 { first * 10 + last }
+        fn classify(xs: [u8; 3]) -> u32 {
+            match xs {
+                [0, _, _] => 1,
+                [a, 0, b] if a == b => 2,
+                [_, rest.., 9] => rest[0] as u32 + rest.len() as u32 - 1,
+                [_, _, _] => 4,
+            }
+        }
+        #[start] fn main() -> i64 {
+            let [a, b, c] = [1i64, 2, 3];
+            let [first, rest.., last] = [T(5), T(6), T(7), T(8)];
+            let inner = rest[1].0 as i64;
+            let x = input() as u8;
+            a * 100000 + b * 10000 + c * 1000 + (first.0 + last.0) as i64 * 10 + inner + classify([x, 0, 9]) as i64 * 1000000
+                + corners([1, 2, 3, 4]) as i64 * 10000000
+        }");
+    for &(x, class) in &[(0i64, 1i64), (9, 2), (3, 0)] {
+        set(x);
+        assert_eq!(j.call::<i64>(), Ok(14 * 10000000 + class * 1000000 + 123000 + 130 + 7), "{}", x);
+    }
+    for src in &[
+        "#[start] fn main() -> u32 { let [a, b] = [1u32, 2, 3]; a }",
+        "#[start] fn main() -> u32 { let [a, b, c, d, ..] = [1u32, 2, 3]; a }",
+    ] {
+        assert!(matches!(build(src).err().unwrap(), AssembleError::FunctionError(AssembleFunctionError::InvalidPattern { .. })), "{}", src);
+    }
+    assert!(matches!(build("#[start] fn main() -> u32 { match [1u32, 2] { [0, _] => 1, [_, 0] => 2 } }").err().unwrap(),
+                     AssembleError::FunctionError(AssembleFunctionError::NonExhaustiveMatch { .. })));
+    assert!(matches!(build("struct T(u32); #[start] fn main() -> u32 { let ts = [T(1), T(2)]; let [a, _] = ts; ts[0].0 }").err().unwrap(),
+                     AssembleError::FunctionError(AssembleFunctionError::UseOfMovedValue { .. })));
+}
@@ -0,0 +1,178 @@
+// the host functions take the four lane types as `__m128`, like the JIT passes them
+#![allow(improper_ctypes_definitions)]
+
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::arch::x86_64::*;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct F8([f32; 8]);
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct I8([i32; 8]);
+
+fn configs() -> Vec<CompileOptions> {
+    let mut v = Vec::new();
+    let n = CompileOptions::default();
+    let mut a = CompileOptions::default(); a.features.avx = false; a.features.avx2 = false; a.features.sse41 = false;
+    let mut b = CompileOptions::default(); b.features.avx2 = false;
+    v.push(a); v.push(b); v.push(n);
+    v
+}
+
+fn jit(src: &str, o: &CompileOptions) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), o).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+const A: [f32; 8] = [1.0, -2.5, 3.0, 4.25, 0.5, 100.0, -7.0, 8.0];
+const B: [f32; 8] = [2.0, 2.5, -1.0, 4.25, 3.0, -0.5, 7.0, 1.0];
+const IA: [i32; 8] = [1, -2, 3, i32::MAX, 100000, -7, 0, 65537];
+const IB: [i32; 8] = [5, -2, -9, 2, 100000, 8, 0, 65536];
+
+#[test]
+fn float8() {
+    for o in configs() {
+        let j = jit("#[start] fn main(a: f32x8, b: f32x8) -> f32x8 { let c = (a + b) * a - b / a; -c }", &o);
+        let f: extern "sysv64" fn(&F8, &F8) -> F8 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let r = f(&F8(A), &F8(B));
+        for i in 0..8 { assert_eq!(r.0[i], -((A[i] + B[i]) * A[i] - B[i] / A[i])); }
+        let j = jit("#[start] fn main(a: f32x8, b: f32x8) -> f32x8 { a.simd_gt(b).select(a, b) + a.simd_le(b).select(a, b) + a.simd_ne(b).select(a, b) }", &o);
+        let f: extern "sysv64" fn(&F8, &F8) -> F8 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let r = f(&F8(A), &F8(B));
+        for i in 0..8 {
+            let (a, b) = (A[i], B[i]);
+            let e = (if a > b { a } else { b }) + (if a <= b { a } else { b }) + (if a != b { a } else { b });
+            assert_eq!(r.0[i], e);
+        }
+        let j = jit("#[start] fn main(a: f32x8) -> f32 { a.reduce_sum() + a.reduce_max() * 1000.0 + a.reduce_min() * 0.5 + a.reduce_product() }", &o);
+        let f: extern "sysv64" fn(&F8) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let s = A.iter().fold(0.0, |x, y| x + y);
+        let p = A[1..].iter().fold(A[0], |x, y| x * y);
+        assert_eq!(f(&F8(A)), s + 100.0 * 1000.0 + -7.0 * 0.5 + p);
+    }
+}
+
+#[test]
+fn int8() {
+    for o in configs() {
+        let j = jit("#[start] fn main(a: i32x8, b: i32x8) -> i32x8 { let c = a * b + a - b; (c ^ !a) & (c | -b) }", &o);
+        let f: extern "sysv64" fn(&I8, &I8) -> I8 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let r = f(&I8(IA), &I8(IB));
+        for i in 0..8 {
+            let (a, b) = (IA[i], IB[i]);
+            let c = a.wrapping_mul(b).wrapping_add(a).wrapping_sub(b);
+            assert_eq!(r.0[i], (c ^ !a) & (c | b.wrapping_neg()));
+        }
+        for (m, op) in [("simd_eq", 0), ("simd_ne", 1), ("simd_lt", 2), ("simd_le", 3), ("simd_gt", 4), ("simd_ge", 5)] {
+            let j = jit(&format!("#[start] fn main(a: i32x8, b: i32x8) -> i32x8 {{ a.{}(b).select(a, b * i32x8::splat(10)) }}", m), &o);
+            let f: extern "sysv64" fn(&I8, &I8) -> I8 = unsafe { std::mem::transmute(j.run::<()>()) };
+            let r = f(&I8(IA), &I8(IB));
+            for i in 0..8 {
+                let (a, b) = (IA[i], IB[i]);
+                let c = [a == b, a != b, a < b, a <= b, a > b, a >= b][op];
+                assert_eq!(r.0[i], if c { a } else { b.wrapping_mul(10) }, "{} {}", m, i);
+            }
+        }
+        let j = jit("#[start] fn main(a: i32x8) -> i32 { a.reduce_sum() ^ a.reduce_max() ^ a.reduce_min() ^ a.reduce_product() }", &o);
+        let f: extern "sysv64" fn(&I8) -> i32 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let s = IA.iter().fold(0i32, |x, y| x.wrapping_add(*y));
+        let p = IA.iter().fold(1i32, |x, y| x.wrapping_mul(*y));
+        assert_eq!(f(&I8(IA)), s ^ i32::MAX ^ -7 ^ p);
+    }
+}
+
+#[test]
+fn four_lanes() {
+    for o in configs() {
+        let j = jit("#[start] fn main(a: f32x4, b: f32x4) -> f32x4 { let m = a.simd_lt(b) | a.simd_eq(b); m.select(a * b, a - b) }", &o);
+        let f: extern "sysv64" fn(__m128, __m128) -> __m128 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let r: [f32; 4] = unsafe { std::mem::transmute(f(std::mem::transmute::<[f32; 4], __m128>([1.0f32, 5.0, 3.0, f32::NAN]), std::mem::transmute::<[f32; 4], __m128>([2.0f32, 4.0, 3.0, 1.0]))) };
+        assert_eq!(r[..3], [2.0, 1.0, 9.0]);
+        assert!(r[3].is_nan());
+        let j = jit("#[start] fn main(a: i32x4, b: i32x4) -> i32x4 { a * b - i32x4::from_array([1, 2, 3, 4]) }", &o);
+        let f: extern "sysv64" fn(__m128i, __m128i) -> __m128i = unsafe { std::mem::transmute(j.run::<()>()) };
+        let r: [i32; 4] = unsafe { std::mem::transmute(f(std::mem::transmute::<[i32; 4], __m128i>([-3i32, 70000, 5, 6]), std::mem::transmute::<[i32; 4], __m128i>([4i32, 70000, -5, 0]))) };
+        assert_eq!(r, [-13, 70000i32.wrapping_mul(70000) - 2, -28, -4]);
+        let j = jit("#[start] fn main(a: f32x4, t: f32) -> u32 { let m = a.simd_gt(f32x4::splat(t)); (m.any() as u32) | (m.all() as u32) << 1 | ((!m).any() as u32) << 2 }", &o);
+        let f: extern "sysv64" fn(__m128, f32) -> u32 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let v: __m128 = unsafe { std::mem::transmute([1.0f32, 2.0, 3.0, 4.0]) };
+        assert_eq!(f(v, 0.0), 0b011);
+        assert_eq!(f(v, 2.5), 0b101);
+        assert_eq!(f(v, 9.0), 0b100);
+        let j = jit("#[start] fn main(x: bool) -> bool { let m = mask32x8::from_array([true, x, true, true, true, true, true, true]); m.all() && (m & mask32x8::splat(x)).any() == x }", &o);
+        let f: extern "sysv64" fn(bool) -> bool = unsafe { std::mem::transmute(j.run::<()>()) };
+        assert!(f(true));
+        assert!(!f(false));
+    }
+}
+
+#[test]
+fn slices() {
+    for o in configs() {
+        let j = jit("#[start] fn main(s: &[f32], out: &mut [f32]) -> f32 { let v = f32x8::from_slice(s); (v * f32x8::splat(2.0)).copy_to_slice(out); v.reduce_sum() + out.len() as f32 }", &o);
+        let f: extern "sysv64" fn(&[f32], &mut [f32]) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+        let mut out = [0.0f32; 9];
+        assert_eq!(f(&A, &mut out), A.iter().sum::<f32>() + 9.0);
+        for i in 0..8 { assert_eq!(out[i], A[i] * 2.0); }
+        assert_eq!(out[8], 0.0);
+        let j = jit("#[start] fn main(s: &[i32], out: &mut [i32]) { (i32x4::from_slice(s) + i32x4::splat(1)).copy_to_slice(out) }", &o);
+        let f: extern "sysv64" fn(&[i32], &mut [i32]) = unsafe { std::mem::transmute(j.run::<()>()) };
+        let mut out = [0i32; 4];
+        f(&[1, 2, 3, 4, 5], &mut out);
+        assert_eq!(out, [2, 3, 4, 5]);
+    }
+}
+
+#[test]
+fn short_slices() {
+    let src = "#[export] fn load(s: &[f32]) -> f32 { f32x8::from_slice(s).reduce_sum() }
+        #[export] fn store(out: &mut [i32]) { i32x4::splat(7).copy_to_slice(out) }";
+    for o in configs() {
+        let j = jit(src, &o);
+        let (load, store) = (j.export("load").unwrap(), j.export("store").unwrap());
+        match j.call_export::<_, f32>(load, (&A[..7],)) {
+            Err(ScriptError::IndexOutOfBounds(location)) => assert_eq!(location.function, "fn load"),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(j.call_export::<_, f32>(load, (&A[..],)), Ok(A.iter().sum()));
+        let mut out = [0; 3];
+        assert!(matches!(j.call_export::<_, ()>(store, (&mut out[..],)), Err(ScriptError::IndexOutOfBounds(_))));
+        assert_eq!(out, [0; 3]);
+        let mut out = [0; 4];
+        assert_eq!(j.call_export::<_, ()>(store, (&mut out[..],)), Ok(()));
+        assert_eq!(out, [7; 4]);
+    }
+}
+
+#[test]
+fn errors() {
+    let o = CompileOptions::default();
+    for src in [
+        "#[start] fn main(s: &[f32]) { let v = i32x4::from_slice(s); }",
+        "#[start] fn main(s: &[f32]) { f32x4::splat(1.0).copy_to_slice(s) }",
+        "#[start] fn main(a: f32x4) -> f32x4 { a & a }",
+        "#[start] fn main(a: f32x4, b: f32x8) -> f32x4 { a + b }",
+        "#[start] fn main() { let v = f32x4::from_array([1.0, 2.0]); }",
+        "#[start] fn main(m: mask32x4) -> f32x8 { m.select(f32x8::splat(1.0), f32x8::splat(2.0)) }",
+    ] {
+        assert!(compile_with_options(parse_file(src).unwrap(), &o).is_err(), "{}", src);
+    }
+}
+
+#[test]
+fn readme() {
+    let src = "fn scale_all(weights: &[f32], out: &mut [f32], factor: f32) -> f32 {
+    let w = f32x8::from_slice(weights);
+    let positive = w.simd_gt(f32x8::splat(0.0));
+    positive.select(w * f32x8::splat(factor), f32x8::splat(0.0)).copy_to_slice(out);
+    w.reduce_sum()
+}
+#[start] fn main(w: &[f32], out: &mut [f32]) -> f32 { scale_all(w, out, 3.0) }";
+    let j = jit(src, &CompileOptions::default());
+    let f: extern "sysv64" fn(&[f32], &mut [f32]) -> f32 = unsafe { std::mem::transmute(j.run::<()>()) };
+    let mut out = [0.0f32; 8];
+    assert_eq!(f(&A, &mut out), A.iter().sum::<f32>());
+    for i in 0..8 { assert_eq!(out[i], if A[i] > 0.0 { A[i] * 3.0 } else { 0.0 }); }
+}
@@ -0,0 +1,57 @@
+// the host functions receive `&str` and `&[u8]` as pointer and length, like scripts pass them
+#![allow(improper_ctypes_definitions)]
+
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::sync::Mutex;
+
+static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+extern "sysv64" fn play_sound(name: &str, volume: u32) -> u32 { LOG.lock().unwrap().push(format!("{} {}", name, volume)); volume + 1 }
+extern "sysv64" fn bytes(data: &[u8]) -> u64 { data.iter().map(|b| *b as u64).sum() }
+extern "sysv64" fn many(a: u64, b: u64, c: u64, d: u64, e: u64, s: &str) -> u64 { a + b + c + d + e + s.len() as u64 }
+extern "sysv64" fn small() -> u8 { 200 }
+
+fn options() -> CompileOptions {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("play_sound", play_sound as *const u8);
+    o.host_functions.insert("bytes", bytes as *const u8);
+    o.host_functions.insert("many", many as *const u8);
+    o.host_functions.insert("small", small as *const u8);
+    o
+}
+
+fn jit(src: &str) -> JitMemory {
+    let buf = compile_with_options(parse_file(src).unwrap(), &options()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap()
+}
+
+#[test]
+fn strings() {
+    let j = jit(r#"
+        extern "C" { fn play_sound(name: &str, volume: u32) -> u32; fn bytes(data: &[u8]) -> u64; fn many(a: u64, b: u64, c: u64, d: u64, e: u64, s: &str) -> u64; }
+        #[start] fn main() -> u64 { play_sound("boom", 3); play_sound("boom", play_sound("crash", 1)); many(1, 2, 3, 4, bytes(b"\x01\x02"), "abcdef") }
+    "#);
+    assert_eq!(j.run::<u64>()(), 1 + 2 + 3 + 4 + 3 + 6);
+    assert_eq!(*LOG.lock().unwrap(), vec!["boom 3".to_string(), "crash 1".into(), "boom 2".into()]);
+}
+
+#[test]
+fn return_str() {
+    let j = jit(r#"fn name() -> &'static str { "dialog.intro" } #[start] fn main() -> &'static str { pass(name()) } fn pass(s: &str) -> &str { s }"#);
+    assert_eq!(j.run::<&'static str>()(), "dialog.intro");
+    let j = jit(r#"#[start] fn main() -> &'static [u8] { b"xyz" }"#);
+    assert_eq!(j.run::<&'static [u8]>()(), b"xyz");
+}
+
+#[test]
+fn normalize_host_return() {
+    let j = jit(r#"extern "C" { fn small() -> u8; } #[start] fn main() -> u64 { w(small()) } fn w(x: u8) -> u64 { 0 }"#);
+    assert_eq!(j.run::<u64>()(), 0);
+}
+
+#[test]
+fn unknown_host() {
+    let e = compile(parse_file(r#"extern "C" { fn nope(); } #[start] fn main() -> u32 { 1 }"#).unwrap()).err().unwrap();
+    assert_eq!(e, AssembleError::UnknownHostFunction("nope".into()));
+}
@@ -0,0 +1,91 @@
+// the host functions take vectors as `__m128`, like the JIT passes them
+#![allow(improper_ctypes_definitions)]
+
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::arch::x86_64::*;
+
+extern "sysv64" fn host_scale(v: __m128, s: f32) -> __m128 { unsafe { _mm_mul_ps(v, _mm_set1_ps(s)) } }
+#[repr(C)] #[derive(Debug, Clone, Copy, PartialEq)] struct V2 { x: f32, y: f32 }
+extern "sysv64" fn host_v2(v: V2) -> V2 { V2 { x: v.y, y: v.x } }
+extern "sysv64" fn host_many(a: __m128, b: __m128, c: __m128, d: __m128, e: __m128, f: __m128, g: __m128, h: __m128, i: __m128) -> f32 {
+    let mut o = [0f32; 4];
+    unsafe { _mm_storeu_ps(o.as_mut_ptr(), _mm_add_ps(_mm_add_ps(a, h), i)); }
+    let _ = (b, c, d, e, f, g);
+    o[0] + o[1] + o[2]
+}
+
+fn opts() -> CompileOptions {
+    let mut o = CompileOptions::default();
+    o.host_functions.insert("host_scale", host_scale as *const u8);
+    o.host_functions.insert("host_v2", host_v2 as *const u8);
+    o.host_functions.insert("host_many", host_many as *const u8);
+    o
+}
+const EXTERNS: &str = r#"extern "C" { fn host_scale(v: Vec3, s: f32) -> Vec3; fn host_v2(v: Vec2) -> Vec2; fn host_many(a: Vec4, b: Vec4, c: Vec4, d: Vec4, e: Vec4, f: Vec4, g: Vec4, h: Vec4, i: Vec3) -> f32; } "#;
+fn f32_of(body: &str) -> f32 {
+    let buf = compile_with_options(parse_file(&format!("{}{}", EXTERNS, body)).unwrap(), &opts()).unwrap();
+    JitMemory::from_assembly_buf(&buf).unwrap().run::<f32>()()
+}
+fn err(body: &str) -> AssembleError { compile_with_options(parse_file(&format!("{}{}", EXTERNS, body)).unwrap(), &opts()).err().unwrap() }
+
+#[test]
+fn basics() {
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let v = Vec3::new(1.0, 2.0, 3.0); v.x + v.y * 10.0 + v.z * 100.0 }"), 321.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let v = Vec4::new(1.0, 2.0, 3.0, 4.0); let s = v.wzyx; s.x * 1000.0 + s.y * 100.0 + s.z * 10.0 + s.w }"), 4321.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let v = Vec3::new(1.0, 2.0, 3.0); let s = v.zy; s.x * 10.0 + s.y }"), 32.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let a = Vec2::new(1.0, 2.0); let b = Vec2::splat(3.0); let c = (a + b) * 2.0 - b / 3.0; c.x * 10.0 + c.y }"), 7.0 * 10.0 + 9.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let a = Vec3::new(1.0, 2.0, 3.0); let c = 2.0 * a * a; c.x + c.y + c.z }"), 28.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let a = -Vec3::ONE + Vec3::ZERO; a.x + a.y + a.z }"), -3.0);
+}
+
+#[test]
+fn methods() {
+    assert_eq!(f32_of("#[start] fn main() -> f32 { Vec3::new(1.0, 2.0, 3.0).dot(Vec3::new(4.0, 5.0, 6.0)) }"), 32.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { Vec2::new(3.0, 4.0).length() }"), 5.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { Vec4::new(1.0, 1.0, 1.0, 1.0).length_squared() }"), 4.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let c = Vec3::new(1.0, 0.0, 0.0).cross(Vec3::new(0.0, 1.0, 0.0)); c.x * 100.0 + c.y * 10.0 + c.z }"), 1.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let c = Vec3::new(1.0, 2.0, 3.0).cross(Vec3::new(4.0, 5.0, 6.0)); c.x * 100.0 + c.y * 10.0 + c.z }"), -300.0 + 60.0 - 3.0);
+    let n = f32_of("#[start] fn main() -> f32 { Vec3::new(3.0, 0.0, 4.0).normalize().z }");
+    let l = (9f32 + 16.0).sqrt();
+    assert_eq!(n, 4.0 * (1.0 / l));
+}
+
+#[test]
+fn abi() {
+    assert_eq!(f32_of("fn f(v: Vec3) -> Vec3 { v * 2.0 } #[start] fn main() -> f32 { let v = host_scale(f(Vec3::new(1.0, 2.0, 3.0)), 0.5); v.x + v.y + v.z }"), 6.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let v = host_v2(Vec2::new(1.0, 2.0)); v.x * 10.0 + v.y }"), 21.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let o = Vec4::ONE; host_many(o, o, o, o, o, o, o, Vec4::new(1.0, 2.0, 3.0, 4.0), Vec3::new(10.0, 20.0, 30.0)) }"), 12.0 + 23.0 + 34.0);
+    assert_eq!(f32_of("fn g(a: Vec4, b: Vec4, c: Vec4, d: Vec4, e: Vec4, f: Vec4, g: Vec4, h: Vec4, i: Vec3, j: f32) -> f32 { h.y + i.z + j } #[start] fn main() -> f32 { let o = Vec4::ONE; g(o, o, o, o, o, o, o, Vec4::new(1.0, 2.0, 3.0, 4.0), Vec3::new(10.0, 20.0, 30.0), 0.5) }"), 32.5);
+    assert_eq!(f32_of("struct Body { mass: f32, vel: Vec3, pos: Vec2 } fn step(b: Body) -> Body { Body { mass: b.mass, vel: b.vel, pos: b.pos + b.vel.xy * 2.0 } } #[start] fn main() -> f32 { let b = step(Body { mass: 1.0, vel: Vec3::new(1.0, 2.0, 3.0), pos: Vec2::ZERO }); b.pos.x * 10.0 + b.pos.y }"), 24.0);
+    assert_eq!(f32_of("#[start] fn main() -> f32 { let f: fn(Vec3) -> f32 = |v| v.length_squared(); f(Vec3::ONE) }"), 3.0);
+}
+
+#[test]
+fn errors() {
+    match err("#[start] fn main() -> f32 { Vec2::new(1.0, 2.0).cross(Vec2::ZERO).x }") {
+        AssembleError::FunctionError(AssembleFunctionError::UnknownMethod { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> f32 { Vec2::new(1.0, 2.0).z }") {
+        AssembleError::FunctionError(AssembleFunctionError::UnknownField { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> f32 { let v = Vec2::ZERO + Vec3::ZERO; 0.0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> f32 { let v = Vec2::ZERO + 1.0; 0.0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::TypeMismatch { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+    match err("#[start] fn main() -> f32 { let v = Vec2::new(1.0); 0.0 }") {
+        AssembleError::FunctionError(AssembleFunctionError::WrongArgumentCount { .. }) => {},
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn readme() {
+    assert_eq!(f32_of("fn steer(position: Vec3, target: Vec3, speed: f32) -> Vec3 { let direction = (target - position).normalize(); position + direction * speed } #[start] fn main() -> f32 { steer(Vec3::ZERO, Vec3::new(0.0, 10.0, 0.0), 2.0).y }"), 2.0);
+}
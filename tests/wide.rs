@@ -0,0 +1,254 @@
+extern crate gsr_jit;
+use gsr_jit::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod common;
+use common::{build, build_mode, err};
+
+#[derive(Default)]
+struct Recorder(String);
+
+impl ScriptLogger for Recorder {
+    fn write(&mut self, _: LogTarget, text: &str) { self.0.push_str(text); }
+    fn end_line(&mut self, _: LogTarget) { self.0.push('\n'); }
+}
+
+const SRC: &str = "
+    #[export] fn add(a: u128, b: u128) -> u128 { a + b }
+    #[export] fn sub(a: i128, b: i128) -> i128 { a - b }
+    #[export] fn mul(a: u128, b: u128) -> u128 { a * b }
+    #[export] fn imul(a: i128, b: i128) -> i128 { a * b }
+    #[export] fn div(a: u128, b: u128) -> u128 { a / b }
+    #[export] fn idiv(a: i128, b: i128) -> i128 { a / b }
+    #[export] fn irem(a: i128, b: i128) -> i128 { a % b }
+    #[export] fn shl(a: u128, n: u32) -> u128 { a << n }
+    #[export] fn shr(a: i128, n: u32) -> i128 { a >> n }
+    #[export] fn bits(a: u128, b: u128) -> u128 { (a & b) ^ (a | !b) }
+    #[export] fn neg(a: i128) -> i128 { -a }
+    #[export] fn lt(a: i128, b: i128) -> u8 { (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3 | ((a == b) as u8) << 4 | ((a != b) as u8) << 5 }
+    #[export] fn ult(a: u128, b: u128) -> u8 { (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3 }
+    #[export] fn mulhi(a: u64, b: u64) -> u64 { ((a as u128 * b as u128) >> 64) as u64 }
+    #[export] fn widen(a: i32) -> i128 { a as i128 }
+    #[export] fn to_f64(a: i128) -> f64 { a as f64 }
+    #[export] fn to_f32(a: u128) -> f32 { a as f32 }
+    #[export] fn from_f64(a: f64) -> i128 { a as i128 }
+    #[export] fn from_f32(a: f32) -> u128 { a as u128 }
+    #[export] fn index(s: &[u32], i: usize) -> u32 { s[i] }
+    #[export] fn len(s: &[u32]) -> usize { s.len() }
+    #[export] fn isz(a: isize, b: isize) -> isize { (a - b) / 2 }
+    #[export] fn usz_float(a: usize) -> f64 { a as f64 }
+";
+
+#[test]
+fn arithmetic() {
+    let j = build(SRC).unwrap();
+    let e = |n: &str| j.export(n).unwrap();
+    let big = u128::MAX / 3;
+    assert_eq!(j.call_export::<_, u128>(e("add"), (big, u64::MAX as u128 + 7)), Ok(big.wrapping_add(u64::MAX as u128 + 7)));
+    assert_eq!(j.call_export::<_, u128>(e("add"), (u128::MAX, 2u128)), Ok(1));
+    assert_eq!(j.call_export::<_, i128>(e("sub"), (5i128, 1i128 << 100)), Ok(5 - (1i128 << 100)));
+    assert_eq!(j.call_export::<_, i128>(e("sub"), (i128::MIN, 1i128)), Ok(i128::MAX));
+    for &(a, b) in &[(big, 12345u128), (u64::MAX as u128 + 3, u64::MAX as u128 * 5), (7u128 << 90, 3u128 << 40), (u128::MAX, u128::MAX)] {
+        assert_eq!(j.call_export::<_, u128>(e("mul"), (a, b)), Ok(a.wrapping_mul(b)));
+        assert_eq!(j.call_export::<_, u128>(e("div"), (a, b)), Ok(a / b));
+        assert_eq!(j.call_export::<_, u128>(e("bits"), (a, b)), Ok((a & b) ^ (a | !b)));
+    }
+    for &(a, b) in &[(-(1i128 << 70) - 9, 3i128), (-5, -(1i128 << 80)), (i128::MAX, -7), (-1, -1)] {
+        assert_eq!(j.call_export::<_, i128>(e("imul"), (a, b)), Ok(a.wrapping_mul(b)));
+        assert_eq!(j.call_export::<_, i128>(e("idiv"), (a, b)), Ok(a / b));
+        assert_eq!(j.call_export::<_, i128>(e("irem"), (a, b)), Ok(a % b));
+    }
+    assert_eq!(j.call_export::<_, u128>(e("shl"), (3u128, 100u32)), Ok(3 << 100));
+    assert_eq!(j.call_export::<_, u128>(e("shl"), (3u128, 130u32)), Ok(12));
+    assert_eq!(j.call_export::<_, i128>(e("shr"), (-(1i128 << 100), 99u32)), Ok(-2));
+    for &a in &[0i128, 1, -1, 1 << 64, -(1 << 64), i128::MAX, u64::MAX as i128] {
+        assert_eq!(j.call_export::<_, i128>(e("neg"), (a,)), Ok(-a));
+    }
+    assert_eq!(j.call_export::<_, i128>(e("neg"), (i128::MIN,)), Ok(i128::MIN));
+    assert_eq!(j.call_export::<_, u64>(e("mulhi"), (u64::MAX, u64::MAX - 1)), Ok(((u64::MAX as u128 * (u64::MAX - 1) as u128) >> 64) as u64));
+    assert_eq!(j.call_export::<_, i128>(e("widen"), (-3,)), Ok(-3));
+}
+
+#[test]
+fn comparisons_and_conversions() {
+    let j = build(SRC).unwrap();
+    let e = |n: &str| j.export(n).unwrap();
+    let flags = |a: i128, b: i128| (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3 | ((a == b) as u8) << 4 | ((a != b) as u8) << 5;
+    let values = [0i128, 1, -1, 1 << 64, -(1 << 64), (1 << 64) - 1, i128::MIN, i128::MAX, 5 << 70, (5 << 70) + 1];
+    for &a in &values {
+        for &b in &values {
+            assert_eq!(j.call_export::<_, u8>(e("lt"), (a, b)), Ok(flags(a, b)), "{} {}", a, b);
+            let (a, b) = (a as u128, b as u128);
+            let unsigned = (a < b) as u8 | ((a <= b) as u8) << 1 | ((a > b) as u8) << 2 | ((a >= b) as u8) << 3;
+            assert_eq!(j.call_export::<_, u8>(e("ult"), (a, b)), Ok(unsigned), "{} {}", a, b);
+        }
+    }
+    assert_eq!(j.call_export::<_, f64>(e("to_f64"), (-(1i128 << 100) - 1,)), Ok((-(1i128 << 100) - 1) as f64));
+    assert_eq!(j.call_export::<_, f32>(e("to_f32"), (u128::MAX,)), Ok(u128::MAX as f32));
+    assert_eq!(j.call_export::<_, i128>(e("from_f64"), (-1.5e30f64,)), Ok(-1.5e30f64 as i128));
+    assert_eq!(j.call_export::<_, i128>(e("from_f64"), (f64::NAN,)), Ok(0));
+    assert_eq!(j.call_export::<_, u128>(e("from_f32"), (-3.0f32,)), Ok(0));
+    assert_eq!(j.call_export::<_, u128>(e("from_f32"), (3.0e38f32,)), Ok(3.0e38f32 as u128));
+    let data = [1u32, 2, 3];
+    assert_eq!(j.call_export::<_, u32>(e("index"), (&data[..], 2usize)), Ok(3));
+    assert_eq!(j.call_export::<_, usize>(e("len"), (&data[..],)), Ok(3));
+    assert_eq!(j.call_export::<_, isize>(e("isz"), (-7isize, 3isize)), Ok(-5));
+    assert_eq!(j.call_export::<_, f64>(e("usz_float"), (usize::MAX,)), Ok(usize::MAX as f64));
+}
+
+#[test]
+fn locals_structs_and_statics() {
+    let j = build("struct Timer { ticks: u128, id: u8 }
+        static mut TOTAL: i128 = -5;
+        static BIG: u128 = 18446744073709551615;
+        fn fnv(data: &[u8]) -> u128 {
+            let mut hash = 0x6c62272e07bb0142u128 << 64 | 0x62b821756295c58d;
+            let prime = 1u128 << 88 | 0x13b;
+            for b in data { hash ^= *b as u128; hash *= prime; }
+            hash
+        }
+        #[export] fn hash(data: &[u8]) -> u128 { fnv(data) }
+        #[export] fn timer(a: u128, xs: &mut [u128]) -> u128 {
+            let mut t = Timer { ticks: a, id: 3 };
+            t.ticks += BIG;
+            t.ticks <<= 1;
+            xs[0] = t.ticks;
+            let sum: u128 = xs.iter().sum();
+            match sum { 5 => 0, _ => sum + t.id as u128 }
+        }
+        #[export] fn total(x: i128) -> i128 { unsafe { TOTAL += x; TOTAL } }
+    ").unwrap();
+    let e = |n: &str| j.export(n).unwrap();
+    let fnv = |data: &[u8]| {
+        let mut hash = 0x6c62272e07bb0142_62b821756295c58du128;
+        for b in data { hash ^= *b as u128; hash = hash.wrapping_mul(0x0000000001000000000000000000013B); }
+        hash
+    };
+    assert_eq!(j.call_export::<_, u128>(e("hash"), (&b"hello"[..],)), Ok(fnv(b"hello")));
+    assert_eq!(j.call_export::<_, u128>(e("timer"), (1u128 << 64, &mut [0u128, 5][..])), Ok(((1u128 << 64) + u64::MAX as u128) * 2 + 5 + 3));
+    assert_eq!(j.call_export::<_, i128>(e("total"), (1i128 << 90,)), Ok((1i128 << 90) - 5));
+}
+
+#[test]
+fn overflow_checks() {
+    let src = "#[export] fn add(a: u128, b: u128) -> u128 { a + b }
+        #[export] fn sub(a: i128, b: i128) -> i128 { a - b }
+        #[export] fn mul(a: i128, b: i128) -> i128 { a * b }
+        #[export] fn neg(a: i128) -> i128 { -a }
+        #[export] fn div(a: i128, b: i128) -> i128 { a / b }";
+    let d = build_mode(src, CompileMode::Debug).unwrap();
+    let e = |n: &str| d.export(n).unwrap();
+    assert!(matches!(d.call_export::<_, u128>(e("add"), (u128::MAX, 1u128)), Err(ScriptError::Overflow(_))));
+    assert_eq!(d.call_export::<_, u128>(e("add"), (u64::MAX as u128, 1u128)), Ok(1 << 64));
+    assert!(matches!(d.call_export::<_, i128>(e("sub"), (i128::MIN, 1i128)), Err(ScriptError::Overflow(_))));
+    assert!(matches!(d.call_export::<_, i128>(e("mul"), (1i128 << 64, 1i128 << 63)), Err(ScriptError::Overflow(_))));
+    assert_eq!(d.call_export::<_, i128>(e("mul"), (-(1i128 << 64), 1i128 << 63)), Ok(i128::MIN));
+    assert!(matches!(d.call_export::<_, i128>(e("neg"), (i128::MIN,)), Err(ScriptError::Overflow(_))));
+    assert!(matches!(d.call_export::<_, i128>(e("div"), (i128::MIN, -1i128)), Err(ScriptError::Overflow(_))));
+    assert!(matches!(d.call_export::<_, i128>(e("div"), (5i128, 0i128)), Err(ScriptError::DivideByZero(_))));
+    assert_eq!(d.call_export::<_, i128>(e("div"), (i128::MIN, 1i128 << 64)), Ok(i128::MIN >> 64));
+    let r = build_mode(src, CompileMode::Release).unwrap();
+    assert!(matches!(r.call_export::<_, i128>(r.export("div").unwrap(), (i128::MIN + 1, 0i128)), Err(ScriptError::DivideByZero(_))));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(err("#[start] fn main() -> u64 { let s: &[u8] = b\"ab\"; s.len() }"), AssembleFunctionError::ReturnTypeMismatch(_)));
+    assert!(matches!(err("fn f(s: &[u8]) -> u64 { let n: u64 = s.len(); n } #[start] fn main() -> u64 { 0 }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("#[start] fn main() -> u32 { let x = 1u128; x.abs(); 0 }"), AssembleFunctionError::UnknownMethod { .. }));
+    assert!(matches!(err("#[start] fn main() -> u8 { let s: &[u8] = b\"ab\"; s[1u128] }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("#[start] fn main() -> u128 { let x = 1u128; x + 1u64 }"), AssembleFunctionError::TypeMismatch { .. }));
+    assert!(matches!(err("static X: i128 = 1usize; #[start] fn main() -> i128 { X }"), AssembleFunctionError::TypeMismatch { .. }));
+}
+
+#[test]
+fn formatting() {
+    let rec = Rc::new(RefCell::new(Recorder::default()));
+    let o = CompileOptions { logger: Some(SharedLogger(rec.clone())), ..CompileOptions::default() };
+    let src = "#[start] fn main() -> i32 { let a = 0u128; let b = 1i128 << 127; let s: &[u8] = b\"abc\"; println!(\"{} {} {:?} {}\", !a, b, -3isize, s.len()); 0 }";
+    let j = JitMemory::from_assembly_buf(&compile_with_options(parse_file(src).unwrap(), &o).unwrap()).unwrap();
+    assert_eq!(j.call::<i32>(), Ok(0));
+    assert_eq!(rec.borrow().0, format!("{} {} -3 3\n", u128::MAX, i128::MIN));
+}
+
+#[test]
+fn readme() {
+    let src = "fn fnv1a(data: &[u8]) -> u128 {
+    let mut hash = 0x6c62272e07bb0142u128 << 64 | 0x62b821756295c58d;
+    for b in data {
+        hash ^= *b as u128;
+        hash = hash.wrapping_mul(1 << 88 | 0x13b);
+    }
+    hash
+}
+fn mul_high(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) >> 64) as u64
+}
+#[start] fn main() -> u64 { (fnv1a(b\"a\") >> 64) as u64 ^ mul_high(3, 1 << 63) }";
+    let j = build_mode(src, CompileMode::Debug).unwrap();
+    let mut h = 0x6c62272e07bb0142_62b821756295c58du128; h ^= b'a' as u128; h = h.wrapping_mul((1 << 88) | 0x13b);
+    assert_eq!(j.call::<u64>(), Ok((h >> 64) as u64 ^ 1));
+}
+
+#[test]
+fn methods() {
+    let src = "
+        #[export] fn wrapping(a: u128, b: u128) -> (u128, u128, u128) { (a.wrapping_add(b), a.wrapping_sub(b), a.wrapping_mul(b)) }
+        #[export] fn saturating(a: i128, b: i128) -> (i128, i128) { (a.saturating_add(b), a.saturating_sub(b)) }
+        #[export] fn usaturating(a: u128, b: u128) -> (u128, u128) { (a.saturating_add(b), a.saturating_sub(b)) }
+        #[export] fn checked(a: i128, b: i128) -> u8 {
+            a.checked_add(b).is_some() as u8 | (a.checked_sub(b).is_some() as u8) << 1 | (a.checked_mul(b).is_some() as u8) << 2
+        }
+        #[export] fn checked_value(a: u128, b: u128) -> u128 { a.checked_mul(b).unwrap_or(7) }
+        #[export] fn overflowing(a: u128, b: u128) -> (u128, bool) { a.overflowing_sub(b) }
+        #[export] fn ioverflowing(a: i128, b: i128) -> (i128, bool) { a.overflowing_mul(b) }
+        #[export] fn bounds(a: i128, b: i128) -> (i128, i128, i128) { (a.min(b), a.max(b), a.clamp(-10, 10)) }
+        #[export] fn ubounds(a: u128, b: u128) -> (u128, u128) { (a.min(b), a.max(b)) }
+        #[export] fn abs(a: i128) -> i128 { a.abs() }
+        #[export] fn pow(a: i128, n: u32) -> i128 { a.pow(n) }
+        #[export] fn rotate(a: u128, n: u32) -> (u128, u128) { (a.rotate_left(n), a.rotate_right(n)) }
+        #[export] fn bits(a: u128) -> (u32, u32, u32) { (a.count_ones(), a.leading_zeros(), a.trailing_zeros()) }
+        #[export] fn swap(a: i128) -> i128 { a.swap_bytes() }
+    ";
+    let values = [0u128, 1, 2, 10, u64::MAX as u128, 1 << 64, 3 << 100, u128::MAX / 3, u128::MAX - 1, u128::MAX, i128::MAX as u128, i128::MIN as u128];
+    for &mode in &[CompileMode::Release, CompileMode::Debug] {
+        let j = build_mode(src, mode).unwrap();
+        let e = |name: &str| j.export(name).unwrap();
+        for &a in &values {
+            let (x, y) = (a, a as i128);
+            assert_eq!(j.call_export::<_, (i128, i128, i128)>(e("bounds"), (y, -3i128)), Ok((y.min(-3), y.max(-3), y.clamp(-10, 10))));
+            if y != i128::MIN {
+                assert_eq!(j.call_export::<_, i128>(e("abs"), (y,)), Ok(y.abs()));
+            }
+            assert_eq!(j.call_export::<_, (u128, u128)>(e("rotate"), (x, 67u32)), Ok((x.rotate_left(67), x.rotate_right(67))));
+            assert_eq!(j.call_export::<_, (u32, u32, u32)>(e("bits"), (x,)), Ok((x.count_ones(), x.leading_zeros(), x.trailing_zeros())));
+            assert_eq!(j.call_export::<_, i128>(e("swap"), (y,)), Ok(y.swap_bytes()));
+            for &b in &values {
+                let (u, v) = (b, b as i128);
+                assert_eq!(j.call_export::<_, (u128, u128, u128)>(e("wrapping"), (x, u)), Ok((x.wrapping_add(u), x.wrapping_sub(u), x.wrapping_mul(u))));
+                assert_eq!(j.call_export::<_, (i128, i128)>(e("saturating"), (y, v)), Ok((y.saturating_add(v), y.saturating_sub(v))));
+                assert_eq!(j.call_export::<_, (u128, u128)>(e("usaturating"), (x, u)), Ok((x.saturating_add(u), x.saturating_sub(u))));
+                let checked = y.checked_add(v).is_some() as u8 | (y.checked_sub(v).is_some() as u8) << 1 | (y.checked_mul(v).is_some() as u8) << 2;
+                assert_eq!(j.call_export::<_, u8>(e("checked"), (y, v)), Ok(checked), "{} {}", y, v);
+                assert_eq!(j.call_export::<_, u128>(e("checked_value"), (x, u)), Ok(x.checked_mul(u).unwrap_or(7)));
+                assert_eq!(j.call_export::<_, (u128, bool)>(e("overflowing"), (x, u)), Ok(x.overflowing_sub(u)));
+                assert_eq!(j.call_export::<_, (i128, bool)>(e("ioverflowing"), (y, v)), Ok(y.overflowing_mul(v)));
+                assert_eq!(j.call_export::<_, (u128, u128)>(e("ubounds"), (x, u)), Ok((x.min(u), x.max(u))));
+            }
+        }
+        assert_eq!(j.call_export::<_, i128>(e("pow"), (-3i128, 75u32)), Ok((-3i128).pow(75)));
+        let overflow = j.call_export::<_, i128>(e("pow"), (3i128, 81u32));
+        let abs = j.call_export::<_, i128>(e("abs"), (i128::MIN,));
+        match mode {
+            CompileMode::Debug => {
+                assert!(matches!(overflow, Err(ScriptError::Overflow(_))));
+                assert!(matches!(abs, Err(ScriptError::Overflow(_))));
+            },
+            _ => {
+                assert_eq!(overflow, Ok(3i128.wrapping_pow(81)));
+                assert_eq!(abs, Ok(i128::MIN));
+            },
+        }
+    }
+}